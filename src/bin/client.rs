@@ -1,505 +1,3301 @@
-use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use native_tls::TlsConnector;
-use serde::{Deserialize, Serialize};
-use serde_json;
-use sha2::{Digest, Sha256};
-use std::env;
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use dotenv::dotenv;
+use futures::stream::{self, StreamExt};
+use sensex_conduit::client::{
+    session_file_for, ClockOffset, MAX_CLOCK_SKEW, MAX_IN_MEMORY, MAX_RESPONSE_SIZE, SESSION_FILE,
+    SPOOL_CHECKPOINT,
+};
+use sensex_conduit::compression::Compression;
+use sensex_conduit::encryption::{OutputCipher, ENCRYPTED_EXTENSION};
+use sensex_conduit::protocol::{signing_payload, AuthRequest, ResultFormat, WireEncoding, SIGNATURE_SCHEME_V2};
+use sensex_conduit::retry::{
+    ReconnectDelay, RetryPolicy, MAX_ATTEMPTS, RECONNECT_DELAY, RECONNECT_JITTER, RETRY_DELAY,
+};
+use sensex_conduit::gateway::{
+    check_header, check_wazuh_token, CONNECT_TIMEOUT, DEFAULT_USER_AGENT, POOL_IDLE_TIMEOUT, POOL_MAX_IDLE_PER_HOST, REQUEST_TIMEOUT, TOKEN_REFRESH_BUFFER,
+};
+use sensex_conduit::inventory::load_agents_file;
+use sensex_conduit::output::set_quiet;
+use sensex_conduit::projection::FieldProjection;
+use sensex_conduit::selftest;
+use sensex_conduit::scan::{
+    filter_agents, load_query_files, query_name, JsonOutput, QueryOrder, ResultCache, Retention, Sample, SampleSize, ScanItem, Warmup, DEADLINE_REACHED,
+};
+use sensex_conduit::signing::{InvalidSignature, SignatureAlgorithm, SignatureEncoding};
+use sensex_conduit::sink::{CombinedSink, FileSink, OutputSink, StdoutSink};
+use sensex_conduit::template::{path_component, OutputTemplate, DEFAULT_OUTPUT_TEMPLATE};
+use sensex_conduit::tls::{ClientIdentity, TlsOptions, TlsVersion, DEFAULT_SERVER_NAME};
+use sensex_conduit::vars::QueryVars;
+use sensex_conduit::{
+    info, plan, scan, verify_saved_result, AdaptiveState, Agent, AgentStatus, ClientConfig, FailedItem, GatewayAuth, GatewayConfig, Group, HttpOptions, OrganizeBy,
+    QueryOutcome, QueryTimings, Result, SavedSignature, ScanConfig, ScanPlan, ScanReport,
+};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::time::sleep;
-use tokio_native_tls::TlsConnector as TokioTlsConnector;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::watch;
 use uuid::Uuid;
-use reqwest;
-use std::collections::HashMap;
-use dotenv::dotenv;
+#[cfg(feature = "gateway")]
+use {
+    sensex_conduit::client::SessionExpired,
+    sensex_conduit::Client,
+    sensex_conduit::protocol::ReceivedResponse,
+    sensex_conduit::sink::HttpSink,
+    sensex_conduit::tls::{connect_with_retry, TlsConfig},
+    std::time::Instant,
+};
 
-const MAX_RETRIES: u32 = 3;
-const RETRY_DELAY: Duration = Duration::from_secs(1);
-const RECONNECT_DELAY: Duration = Duration::from_secs(2);
-const SESSION_FILE: &str = "session.json";
 const WQL_QUERIES_DIR: &str = "wql_queries";
-const BUFFER_SIZE: usize = 8192;
+const OUTPUT_DIR: &str = "query_results";
+/// Name of the --sink combined file in the output directory.
+const COMBINED_FILE: &str = "results.json";
+const QUERY_DEPTH: usize = 8;
+/// Exit status of a scan stopped by --deadline or --max-duration, as timeout(1) uses.
+const DEADLINE_EXIT_CODE: i32 = 124;
+const GATEWAY_URL: &str = "http://localhost:3001";
+const PING_QUERY: &str = r#"{"size":0,"query":{"match_all":{}}}"#;
+
+const CONFIG_PRECEDENCE: &str = "\
+Configuration precedence (highest first):
+  1. command-line flags
+  2. the --config file (TOML or YAML)
+  3. process environment variables
+  4. .env.<NAME> when --env NAME is given
+  5. .env
+  6. built-in defaults";
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "client",
+    version,
+    about = "Run WQL queries against Wazuh agents through a conduit server",
+    after_long_help = CONFIG_PRECEDENCE
+)]
+struct Cli {
+    /// Load `.env.<NAME>` (falling back to `.env`) before reading the environment
+    #[arg(long = "env", value_name = "NAME", global = true)]
+    env_name: Option<String>,
+
+    /// TOML or YAML file with gateway, credential, TLS and scan settings
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+
+    /// Print only errors (to stderr); also hides the progress bar and scan summary
+    #[arg(short, long, global = true, env = "CONDUIT_QUIET", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+// Parsed once per process, so the size difference between variants is moot.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Discover groups and agents, then run every WQL query against each agent
+    Scan(ScanArgs),
+    #[cfg(feature = "gateway")]
+    /// Check gateway authentication, group listing and conduit connectivity without scanning
+    #[command(alias = "healthcheck")]
+    AuthTest(AuthTestArgs),
+    /// List the WQL query files that a scan would execute
+    ListQueries(QueryArgs),
+    #[cfg(feature = "gateway")]
+    /// Print the gateway's groups and their agents without running any queries
+    Topology(TopologyArgs),
+    /// Show what a request signature covers, or check a response signature, without a server
+    SignDebug(SignDebugArgs),
+    /// Check request signing and response verification against known-answer vectors
+    SelfTest,
+    /// Decrypt result files written with --encrypt-output
+    Decrypt(DecryptArgs),
+    /// Check saved results against the server signature recorded by --include-metadata
+    Verify(VerifyArgs),
+    /// Check every result with a .meta.json sidecar under a directory, offline, and tally the failures
+    VerifyDir(VerifyDirArgs),
+}
+
+#[derive(Debug, Args)]
+struct ScanArgs {
+    /// Conduit server address, e.g. 192.168.1.100:8080
+    #[arg(env = "CONDUIT_SERVER", value_parser = parse_server_addr, required_unless_present = "inventory")]
+    server: Option<String>,
+
+    /// Scan every conduit server listed in this YAML or TOML file instead of SERVER
+    #[arg(long, value_name = "PATH", env = "CONDUIT_INVENTORY", conflicts_with = "interval")]
+    inventory: Option<PathBuf>,
+
+    /// Number of inventory servers scanned at the same time
+    #[arg(long, env = "CONDUIT_SERVER_CONCURRENCY", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    server_concurrency: u64,
+
+    #[command(flatten)]
+    gateway: GatewayArgs,
+
+    #[command(flatten)]
+    conduit: ConduitArgs,
+
+    #[command(flatten)]
+    tls: TlsArgs,
+
+    #[command(flatten)]
+    retry: RetryArgs,
+
+    #[command(flatten)]
+    queries: QueryArgs,
+
+    /// Only query this agent id (repeatable); skips group discovery
+    #[arg(long = "agent", value_name = "ID", conflicts_with = "groups")]
+    agents: Vec<String>,
+
+    /// Only scan the group with this name (repeatable)
+    #[arg(long = "group", value_name = "NAME")]
+    groups: Vec<String>,
+
+    /// Only scan agents reporting to this Wazuh cluster node (repeatable);
+    /// agents of a single-node manager belong to node "default"
+    #[arg(long = "node", value_name = "NAME")]
+    nodes: Vec<String>,
+
+    /// Only scan agents with one of these connection statuses (comma-separated);
+    /// agents that report no status are scanned anyway. Discovered groups
+    /// default to active agents; agents named by --agent or --agents-file are
+    /// scanned whatever their status unless this is given
+    #[arg(long = "status", value_name = "STATUS", env = "CONDUIT_STATUS", value_delimiter = ',', value_enum)]
+    statuses: Vec<AgentStatus>,
+
+    /// Query an agent that is in several of the scanned groups once, under the first, instead of once per group
+    #[arg(long, env = "CONDUIT_UNIQUE_AGENTS", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    unique_agents: bool,
+
+    /// Fail a manager's discovery when Wazuh reports failed_items in a group or agent listing
+    #[arg(long, env = "CONDUIT_STRICT_FAILED_ITEMS", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    strict_failed_items: bool,
+
+    /// JSON object of shared values for {{name}} placeholders in every query;
+    /// {{agent_id}} and {{agent_name}} always come from the agent
+    #[arg(long, value_name = "PATH", env = "CONDUIT_VARS")]
+    vars: Option<PathBuf>,
+
+    /// Fail queries that use an undefined {{name}}, and --vars that redefine an agent field
+    #[arg(long, env = "CONDUIT_STRICT_VARS", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    strict_vars: bool,
+
+    /// Scan the agents listed in this JSON or CSV file ({id, name, group}) without gateway discovery
+    #[arg(long, value_name = "PATH", env = "CONDUIT_AGENTS_FILE")]
+    agents_file: Option<PathBuf>,
+
+    /// Directory where query results are written
+    #[arg(long, env = "OUTPUT_DIR", default_value = OUTPUT_DIR)]
+    output_dir: PathBuf,
+
+    /// How result files are foldered under the output directory
+    #[arg(long, value_enum, env = "CONDUIT_ORGANIZE_BY", default_value_t = OrganizeBy::Group)]
+    organize_by: OrganizeBy,
+
+    /// Result file name pattern; placeholders: {group}, {agent_id}, {agent_name},
+    /// {query}, {timestamp}, {date}, {run}, {ext}
+    #[arg(long, env = "CONDUIT_OUTPUT_TEMPLATE", default_value = DEFAULT_OUTPUT_TEMPLATE)]
+    output_template: OutputTemplate,
+
+    /// Keep only the N newest result files of each agent and query, deleting
+    /// older ones whenever a new result is saved
+    #[arg(long, value_name = "N", env = "CONDUIT_RETAIN", value_parser = clap::value_parser!(u64).range(1..))]
+    retain: Option<u64>,
+
+    /// Delete result files of an agent and query older than this, e.g. 7d,
+    /// whenever a new result is saved
+    #[arg(long, value_name = "AGE", env = "CONDUIT_RETAIN_AGE", value_parser = parse_duration)]
+    retain_age: Option<Duration>,
+
+    /// Result format to request, unless a query's <name>.meta declares its own
+    /// ({"format": "csv"}); servers without format support always send JSON
+    #[arg(long, value_enum, env = "CONDUIT_FORMAT", default_value_t = ResultFormat::Json)]
+    format: ResultFormat,
+
+    /// How JSON results are written (a result that does not parse is saved as
+    /// received instead of being reformatted)
+    #[arg(long, value_enum, env = "CONDUIT_JSON_OUTPUT", default_value_t = JsonOutput::Passthrough)]
+    json_output: JsonOutput,
+
+    /// Same as --json-output pretty
+    #[arg(long, env = "CONDUIT_PRETTY", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new(), conflicts_with = "json_output")]
+    pretty: bool,
+
+    /// Trim JSON results to these comma-separated fields before saving them, e.g.
+    /// hits.hits._source.rule,hits.total; a query's .meta file may list its own ({"fields": [...]})
+    #[arg(long, value_name = "FIELDS", env = "CONDUIT_FIELDS")]
+    fields: Option<FieldProjection>,
+
+    /// Write a <result>.meta.json sidecar with the server, session, signature and query hash of each result
+    #[arg(long, env = "CONDUIT_INCLUDE_METADATA", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    include_metadata: bool,
+
+    /// Compress each result file as it is written, before any encryption
+    #[arg(long, value_enum, env = "CONDUIT_COMPRESS", default_value_t = Compression::None)]
+    compress: Compression,
+
+    /// Where results go
+    #[arg(long, value_enum, env = "CONDUIT_SINK", default_value_t = Sink::File)]
+    sink: Sink,
+
+    /// Endpoint each result is POSTed to with --sink http
+    #[arg(long, value_name = "URL", env = "CONDUIT_SINK_URL", required_if_eq("sink", "http"))]
+    sink_url: Option<String>,
+
+    /// JSON array file every result is gathered into with --sink combined (default: results.json in the output directory)
+    #[arg(long, value_name = "PATH", env = "CONDUIT_COMBINED_FILE")]
+    combined_file: Option<PathBuf>,
+
+    /// Encrypt each result file with AES-256-GCM
+    #[arg(long, env = "CONDUIT_ENCRYPT_OUTPUT", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    encrypt_output: bool,
+
+    /// Encrypt the cached session file with the same key
+    #[arg(long, env = "CONDUIT_ENCRYPT_SESSION", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    encrypt_session: bool,
+
+    #[command(flatten)]
+    key: KeyArgs,
+
+    /// Scan at most this many randomly chosen agents per group
+    #[arg(long, value_name = "N", env = "CONDUIT_SAMPLE", conflicts_with = "sample_percent", value_parser = clap::value_parser!(u64).range(1..))]
+    sample: Option<u64>,
+
+    /// Scan this percentage of each group's agents, chosen at random
+    #[arg(long, value_name = "PERCENT", env = "CONDUIT_SAMPLE_PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    sample_percent: Option<u8>,
+
+    /// Seed for --sample/--sample-percent; the same seed picks the same agents (random if unset)
+    #[arg(long, env = "CONDUIT_SAMPLE_SEED")]
+    sample_seed: Option<u64>,
+
+    /// Number of groups scanned concurrently
+    #[arg(long, env = "CONDUIT_GROUP_CONCURRENCY", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    group_concurrency: u64,
+
+    /// Number of agents of one group scanned concurrently
+    #[arg(long, env = "CONDUIT_MAX_CONCURRENCY_PER_GROUP", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    max_concurrency_per_group: u64,
+
+    /// Number of agents scanned concurrently across all groups (default:
+    /// --group-concurrency times --max-concurrency-per-group)
+    #[arg(long, value_name = "N", env = "CONDUIT_MAX_CONCURRENCY", value_parser = clap::value_parser!(u64).range(1..))]
+    max_concurrency: Option<u64>,
+
+    /// Adapt the number of conduit exchanges in flight to the server's latency
+    /// and errors, starting at one and never above the agents scanned concurrently
+    #[arg(long, env = "CONDUIT_ADAPTIVE_CONCURRENCY", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    adaptive_concurrency: bool,
+
+    /// Keep the concurrency --adaptive-concurrency learned in this file, so
+    /// the next scan starts from it rather than from one exchange
+    #[arg(long, value_name = "PATH", env = "CONDUIT_CONCURRENCY_STATE", requires = "adaptive_concurrency", conflicts_with = "inventory")]
+    concurrency_state: Option<PathBuf>,
+
+    /// Run the queries against one agent first and scan the others only if
+    /// they all succeed, so a broken query fails once instead of fleet-wide
+    #[arg(long, env = "CONDUIT_WARMUP", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    warmup: bool,
+
+    /// Agent to warm up with (implies --warmup; default: the first agent scanned)
+    #[arg(long, value_name = "ID", env = "CONDUIT_WARMUP_AGENT")]
+    warmup_agent: Option<String>,
+
+    /// Scan the other agents even when a warmup query failed
+    #[arg(long, env = "CONDUIT_WARMUP_CONTINUE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    warmup_continue: bool,
+
+    /// Time one agent's queries may take in total, e.g. 90s or 5m; its
+    /// remaining queries are skipped once it runs out (default: no limit)
+    #[arg(long, env = "CONDUIT_AGENT_TIMEOUT", value_parser = parse_duration)]
+    agent_timeout: Option<Duration>,
+
+    /// Stop the scan at this time (RFC 3339, e.g. 2026-10-15T06:00:00Z): no query starts
+    /// after it, and the run exits with status 124
+    #[arg(long, env = "CONDUIT_DEADLINE", value_parser = parse_deadline, conflicts_with_all = ["max_duration", "interval"])]
+    deadline: Option<SystemTime>,
+
+    /// Stop the scan this long after it starts, e.g. 45m; like --deadline
+    #[arg(long, env = "CONDUIT_MAX_DURATION", value_parser = parse_duration, conflicts_with = "interval")]
+    max_duration: Option<Duration>,
+
+    /// How long queries still running at the deadline may take to finish before they are abandoned
+    #[arg(long, env = "CONDUIT_DEADLINE_GRACE", value_parser = parse_duration, default_value = "30s")]
+    deadline_grace: Duration,
+
+    /// Reuse saved results younger than this, e.g. 6h, instead of querying again (default: no cache)
+    #[arg(long, env = "CONDUIT_CACHE_TTL", value_parser = parse_duration)]
+    cache_ttl: Option<Duration>,
+
+    /// Where cached results are kept (default: .cache under the output directory)
+    #[arg(long, value_name = "PATH", env = "CONDUIT_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Query every agent again, replacing cached results
+    #[arg(long, env = "CONDUIT_REFRESH", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    refresh: bool,
+
+    /// Neither read nor write the result cache, whatever --cache-ttl says
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "refresh")]
+    no_cache: bool,
+
+    /// After the scan, rerun the failed queries up to this many more times
+    #[arg(long, value_name = "N", env = "CONDUIT_RETRY_PASSES", default_value_t = 0)]
+    retry_passes: u32,
+
+    /// Send a query answered with a success but no data again up to this many
+    /// times; one still empty is reported as an empty result, not saved
+    #[arg(long, value_name = "N", env = "CONDUIT_RETRY_EMPTY", default_value_t = 0)]
+    retry_empty: u32,
+
+    /// Write a JSON summary of every query's outcome here ("-" for stdout)
+    #[arg(long, value_name = "PATH", env = "CONDUIT_SUMMARY_JSON")]
+    summary_json: Option<PathBuf>,
+
+    /// Write each query's connect, send, first-byte and receive times as CSV here
+    #[arg(long, value_name = "PATH", env = "CONDUIT_TIMINGS_CSV", conflicts_with_all = ["inventory", "interval"])]
+    timings_csv: Option<PathBuf>,
+
+    /// Run only the queries that failed or were skipped in this earlier --summary-json
+    #[arg(long, value_name = "SUMMARY", conflicts_with_all = ["inventory", "interval"])]
+    rerun: Option<PathBuf>,
+
+    /// Pick the queries, groups and agents to scan from numbered lists (needs a terminal)
+    #[arg(long, conflicts_with_all = ["inventory", "rerun"])]
+    interactive: bool,
+
+    /// Print each selected query as it would be sent to every --agent, and the
+    /// server it would go to, without connecting; agent names come from --agents-file
+    #[arg(long, requires = "agents", conflicts_with_all = ["inventory", "interactive", "interval"])]
+    print_query: bool,
+
+    /// Discover the agents, print how many queries each group would run and
+    /// how long the scan should take, and exit without sending any
+    #[arg(long, conflicts_with_all = ["inventory", "interactive", "interval", "print_query"])]
+    plan: bool,
+
+    /// How long --plan assumes one query takes, in milliseconds
+    #[arg(long, value_name = "MS", env = "CONDUIT_QUERY_COST_MS", default_value_t = 2000, value_parser = clap::value_parser!(u64).range(1..))]
+    query_cost_ms: u64,
+
+    /// Show an overall progress bar (only when stdout is a terminal)
+    #[arg(long, env = "CONDUIT_PROGRESS", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    progress: bool,
+
+    /// Repeat the scan on this schedule, e.g. 90s, 15m or 2h (bare numbers are seconds)
+    #[arg(long, env = "CONDUIT_INTERVAL", value_parser = parse_duration)]
+    interval: Option<Duration>,
+
+    /// Stop after this many runs; without it --interval runs until Ctrl-C
+    #[arg(long, env = "CONDUIT_REPEAT", requires = "interval", value_parser = clap::value_parser!(u64).range(1..))]
+    repeat: Option<u64>,
+
+    /// What to do with scheduled runs that come due while a run is still going
+    #[arg(long, value_enum, env = "CONDUIT_OVERLAP", default_value_t = Overlap::Skip)]
+    overlap: Overlap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Sink {
+    /// Write result files under the output directory
+    File,
+    /// Print results to stdout, silencing progress messages
+    Stdout,
+    /// POST each result to --sink-url, with its group, agent and query in X-Conduit-* headers
+    Http,
+    /// Gather every JSON result into one array in --combined-file, written when the scan ends
+    Combined,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Overlap {
+    /// Drop the missed runs and wait for the next slot
+    Skip,
+    /// Start the missed runs back to back
+    Queue,
+}
+
+#[cfg(feature = "gateway")]
+#[derive(Debug, Args)]
+struct AuthTestArgs {
+    /// Conduit server address, e.g. 192.168.1.100:8080
+    #[arg(env = "CONDUIT_SERVER", value_parser = parse_server_addr)]
+    server: String,
+
+    #[command(flatten)]
+    gateway: GatewayArgs,
+
+    #[command(flatten)]
+    conduit: ConduitArgs,
+
+    #[command(flatten)]
+    tls: TlsArgs,
+
+    #[command(flatten)]
+    retry: RetryArgs,
+
+    /// Read and write the cached session file encrypted
+    #[arg(long, env = "CONDUIT_ENCRYPT_SESSION", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    encrypt_session: bool,
+
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+#[cfg(feature = "gateway")]
+#[derive(Debug, Args)]
+struct TopologyArgs {
+    #[command(flatten)]
+    gateway: GatewayArgs,
+
+    #[command(flatten)]
+    retry: RetryArgs,
+
+    /// `json` nests agents under their groups; `csv` has one row per agent and loads as an --agents-file
+    #[arg(long, value_enum, default_value_t = TopologyFormat::Json)]
+    format: TopologyFormat,
+
+    /// Write the topology here instead of stdout
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    /// Log more of each gateway exchange: -vv truncated bodies, -vvv full bodies (secrets are always redacted)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(Debug, Args)]
+struct SignDebugArgs {
+    /// WQL query of the sample request; only signed with --sign-query
+    #[arg(long, default_value = PING_QUERY)]
+    query: String,
+
+    /// Request timestamp in unix seconds (default: now)
+    #[arg(long)]
+    timestamp: Option<u64>,
+
+    /// Request nonce (default: a fresh UUID)
+    #[arg(long)]
+    nonce: Option<String>,
+
+    /// Session id of the sample request; only signed with --sign-query
+    #[arg(long)]
+    session_id: Option<String>,
+
+    /// Instead of signing a request, check --signature over this exact response data with --server-key
+    #[arg(long, value_name = "DATA", requires = "signature")]
+    verify_data: Option<String>,
+
+    /// Base64 signature to check against --verify-data
+    #[arg(long, requires = "verify_data")]
+    signature: Option<String>,
+
+    #[command(flatten)]
+    conduit: ConduitArgs,
+}
+
+#[cfg(feature = "gateway")]
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum TopologyFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Args)]
+struct DecryptArgs {
+    /// Encrypted files to decrypt; each is written next to it without the .enc suffix
+    #[arg(required = true, value_name = "FILE")]
+    files: Vec<PathBuf>,
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+    /// Write the plaintext here instead; only valid with a single input file
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+#[derive(Debug, Args)]
+struct VerifyArgs {
+    /// Result files saved with --include-metadata; each is checked against its .meta.json sidecar
+    #[arg(required = true, value_name = "FILE")]
+    files: Vec<PathBuf>,
+
+    /// Key the server signed its responses with
+    #[arg(long, env = "CONDUIT_SERVER_KEY", default_value = "server_key", hide_env_values = true)]
+    server_key: String,
+
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+#[derive(Debug, Args)]
+struct VerifyDirArgs {
+    /// Directory of archived results, searched recursively for .meta.json sidecars
+    #[arg(value_name = "PATH")]
+    dir: PathBuf,
+
+    /// Key the server signed its responses with
+    #[arg(long, env = "CONDUIT_SERVER_KEY", default_value = "server_key", hide_env_values = true)]
+    server_key: String,
+
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+#[derive(Debug, Args)]
+struct KeyArgs {
+    /// Passphrase the encryption key is derived from with Argon2id
+    #[arg(long, env = "CONDUIT_ENCRYPTION_PASSPHRASE", hide_env_values = true, conflicts_with = "encryption_key_file")]
+    encryption_passphrase: Option<String>,
+
+    /// File holding a 32-byte encryption key, raw or base64-encoded
+    #[arg(long, value_name = "PATH", env = "CONDUIT_ENCRYPTION_KEY_FILE")]
+    encryption_key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct GatewayArgs {
+    /// Base URL of the Wazuh API gateway, or unix:///path/to.sock for one on a Unix domain socket
+    #[arg(long, env = "GATEWAY_URL", default_value = GATEWAY_URL)]
+    gateway_url: String,
+
+    /// Wazuh manager API endpoint forwarded to the gateway; ignored when the
+    /// config file lists [[managers]]
+    #[arg(long, env = "WAZUH_URL")]
+    wazuh_url: Option<String>,
+
+    /// Wazuh API username
+    #[arg(long, env = "WAZUH_USERNAME")]
+    wazuh_username: Option<String>,
+
+    /// Wazuh API password
+    #[arg(long, env = "WAZUH_PASSWORD", hide_env_values = true)]
+    wazuh_password: Option<String>,
+
+    /// Wazuh API token (a JWT) to use instead of signing in; renewed with the username and password if those are set too
+    #[arg(long, visible_alias = "token", env = "WAZUH_TOKEN", hide_env_values = true)]
+    wazuh_token: Option<String>,
+
+    /// Idle gateway connections kept open per host
+    #[arg(long, env = "GATEWAY_POOL_MAX_IDLE_PER_HOST", default_value_t = POOL_MAX_IDLE_PER_HOST)]
+    gateway_pool_max_idle: usize,
+
+    /// Seconds an idle gateway connection is kept before closing
+    #[arg(long, env = "GATEWAY_POOL_IDLE_TIMEOUT_SECS", default_value_t = POOL_IDLE_TIMEOUT.as_secs())]
+    gateway_pool_idle_timeout_secs: u64,
+
+    /// Seconds allowed for establishing a gateway connection
+    #[arg(long, env = "GATEWAY_CONNECT_TIMEOUT_SECS", default_value_t = CONNECT_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    gateway_connect_timeout_secs: u64,
+
+    /// Seconds allowed for a whole gateway request, including reading the response
+    #[arg(long, env = "GATEWAY_TIMEOUT_SECS", default_value_t = REQUEST_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    gateway_timeout_secs: u64,
+
+    /// Speak HTTP/2 to a plain-HTTP gateway without negotiation (TLS gateways negotiate it automatically)
+    #[arg(long, env = "GATEWAY_HTTP2_PRIOR_KNOWLEDGE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    gateway_http2: bool,
+
+    /// Renew the Wazuh token once it is this many seconds from expiring
+    #[arg(long, env = "WAZUH_TOKEN_REFRESH_BUFFER_SECS", default_value_t = TOKEN_REFRESH_BUFFER.as_secs())]
+    token_refresh_buffer_secs: u64,
+
+    /// Bearer token for an authenticating proxy in front of the gateway (not the Wazuh token)
+    #[arg(long, env = "GATEWAY_AUTH_TOKEN", hide_env_values = true, conflicts_with = "gateway_auth_user")]
+    gateway_auth_token: Option<String>,
+
+    /// Basic-auth user for an authenticating proxy in front of the gateway (not the Wazuh user)
+    #[arg(long, env = "GATEWAY_AUTH_USER")]
+    gateway_auth_user: Option<String>,
+
+    /// Basic-auth password for --gateway-auth-user
+    #[arg(long, env = "GATEWAY_AUTH_PASSWORD", hide_env_values = true, requires = "gateway_auth_user")]
+    gateway_auth_password: Option<String>,
+
+    /// User-Agent sent on gateway calls
+    #[arg(long, env = "GATEWAY_USER_AGENT", default_value = DEFAULT_USER_AGENT)]
+    gateway_user_agent: String,
+
+    /// Extra header sent on every gateway call, as "Name: value"; repeatable
+    /// (one per line in the environment variable)
+    #[arg(long = "gateway-header", value_name = "NAME: VALUE", env = "GATEWAY_HEADERS", value_delimiter = '\n', hide_env_values = true, value_parser = parse_header)]
+    gateway_headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Args)]
+struct ConduitArgs {
+    /// Client identifier presented to the conduit server
+    #[arg(long, env = "CONDUIT_CLIENT_ID", default_value = "client1")]
+    client_id: String,
+
+    /// Key used to sign requests
+    #[arg(long, env = "CONDUIT_CLIENT_KEY", default_value = "test_key_1", hide_env_values = true)]
+    client_key: String,
+
+    /// Key used to verify server responses
+    #[arg(long, env = "CONDUIT_SERVER_KEY", default_value = "server_key", hide_env_values = true)]
+    server_key: String,
+
+    /// Maximum allowed difference, in seconds, between a response timestamp and the request time
+    #[arg(long, env = "CONDUIT_MAX_CLOCK_SKEW", default_value_t = MAX_CLOCK_SKEW.as_secs())]
+    max_clock_skew: u64,
+
+    /// Seconds to add to the local clock in request timestamps, or "server" to measure the offset from the first response
+    #[arg(long, value_name = "SECONDS|server", env = "CONDUIT_CLOCK_OFFSET", value_parser = parse_clock_offset, allow_hyphen_values = true)]
+    clock_offset: Option<ClockOffset>,
+
+    /// Responses larger than this many bytes are streamed to disk instead of buffered
+    #[arg(long, env = "CONDUIT_MAX_IN_MEMORY", default_value_t = MAX_IN_MEMORY)]
+    max_in_memory: usize,
+
+    /// Abort any response larger than this many bytes
+    #[arg(long, env = "CONDUIT_MAX_RESPONSE_SIZE", default_value_t = MAX_RESPONSE_SIZE, value_parser = clap::value_parser!(u64).range(1..))]
+    max_response_size: u64,
+
+    /// Sync a response being streamed to disk every this many bytes (0: only once it is complete)
+    #[arg(long, value_name = "BYTES", env = "CONDUIT_SPOOL_CHECKPOINT", default_value_t = SPOOL_CHECKPOINT)]
+    spool_checkpoint: u64,
+
+    /// Log more of each exchange: -v TLS handshakes, -vv truncated gateway bodies, -vvv full bodies (secrets are always redacted)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Also sign the session id and query; the server must support this
+    #[arg(long, env = "CONDUIT_SIGN_QUERY", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    sign_query: bool,
+
+    /// Fail responses that do not echo the query hash instead of warning once
+    /// (implied by --sign-query)
+    #[arg(long, env = "CONDUIT_REQUIRE_QUERY_HASH", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    require_query_hash: bool,
+
+    /// Algorithm requests and responses are signed with; the server must support it
+    #[arg(long, value_enum, env = "CONDUIT_SIGNATURE_ALGORITHM", default_value_t = SignatureAlgorithm::Sha256)]
+    signature_algorithm: SignatureAlgorithm,
+
+    /// How signatures are written; hex for servers that sign in hex
+    #[arg(long, value_enum, env = "CONDUIT_SIGNATURE_ENCODING", default_value_t = SignatureEncoding::Base64)]
+    signature_encoding: SignatureEncoding,
+
+    /// Envelope encoding to ask the server for; servers without MessagePack support keep answering in JSON
+    #[arg(long, value_enum, env = "CONDUIT_WIRE_ENCODING", default_value_t = WireEncoding::Json)]
+    wire_encoding: WireEncoding,
+}
+
+#[derive(Debug, Args)]
+struct RetryArgs {
+    /// Attempts made for gateway calls, conduit connects and conduit queries
+    #[arg(long, env = "CONDUIT_MAX_ATTEMPTS", default_value_t = MAX_ATTEMPTS, value_parser = clap::value_parser!(u32).range(1..))]
+    max_attempts: u32,
+
+    /// Initial delay between attempts in milliseconds, doubled after each failure
+    #[arg(long, env = "CONDUIT_RETRY_DELAY_MS", default_value_t = RETRY_DELAY.as_millis() as u64)]
+    retry_delay_ms: u64,
+
+    /// Pause in milliseconds before opening each fresh conduit connection
+    #[arg(long, env = "CONDUIT_RECONNECT_DELAY_MS", default_value_t = RECONNECT_DELAY.as_millis() as u64)]
+    reconnect_delay_ms: u64,
+
+    /// Random jitter applied to the reconnect delay, as a fraction between 0 and 1
+    #[arg(long, env = "CONDUIT_RECONNECT_JITTER", default_value_t = RECONNECT_JITTER, value_parser = parse_fraction)]
+    reconnect_jitter: f64,
+}
+
+#[derive(Debug, Args)]
+struct TlsArgs {
+    /// Accept any certificate from the conduit server (disables verification)
+    #[arg(long, env = "CONDUIT_INSECURE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    insecure: bool,
+
+    /// PEM CA certificate to trust for the conduit server (repeatable)
+    #[arg(long = "cacert", value_name = "PATH", env = "CONDUIT_CACERT", value_delimiter = ',')]
+    ca_certs: Vec<PathBuf>,
+
+    /// Name sent as SNI and required in the conduit server's certificate
+    #[arg(long, value_name = "NAME", env = "CONDUIT_TLS_SERVER_NAME", default_value = DEFAULT_SERVER_NAME)]
+    tls_server_name: String,
+
+    /// Oldest TLS version to accept from the conduit server; 1.3 requires TLS 1.3
+    #[arg(long, value_enum, value_name = "VERSION", env = "CONDUIT_TLS_MIN_VERSION", default_value_t = TlsVersion::Tls12)]
+    tls_min_version: TlsVersion,
+
+    /// Newest TLS version to offer (default: the newest the TLS library supports)
+    #[arg(long, value_enum, value_name = "VERSION", env = "CONDUIT_TLS_MAX_VERSION")]
+    tls_max_version: Option<TlsVersion>,
+
+    /// PEM client certificate to present when the conduit server asks for one
+    #[arg(long, value_name = "PATH", env = "CONDUIT_TLS_CLIENT_CERT", requires = "tls_client_key")]
+    tls_client_cert: Option<PathBuf>,
+
+    /// PEM (PKCS#8) private key for --tls-client-cert
+    #[arg(long, value_name = "PATH", env = "CONDUIT_TLS_CLIENT_KEY", requires = "tls_client_cert")]
+    tls_client_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct QueryArgs {
+    /// Directory containing WQL query files (*.json)
+    #[arg(long, env = "WQL_QUERIES_DIR", default_value = WQL_QUERIES_DIR)]
+    queries_dir: PathBuf,
+
+    /// Only run this query (repeatable): its file stem, with subdirectories for nested ones (e.g. windows/logons)
+    #[arg(long = "query", value_name = "NAME")]
+    queries: Vec<String>,
+
+    /// How many levels of subdirectories to search for query files
+    #[arg(long, env = "CONDUIT_QUERY_DEPTH", default_value_t = QUERY_DEPTH)]
+    query_depth: usize,
+
+    /// Order queries run in; "listed" keeps the order of the --query flags
+    #[arg(long, value_enum, env = "CONDUIT_QUERY_ORDER", default_value_t = QueryOrder::Name)]
+    query_order: QueryOrder,
+}
+
+/// Settings accepted by `--config`. Every value is exported as the environment
+/// variable backing the matching flag, so flags still win over the file while
+/// the file wins over the inherited environment.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    gateway: GatewaySection,
+    #[serde(default)]
+    conduit: ConduitSection,
+    #[serde(default)]
+    tls: TlsSection,
+    #[serde(default)]
+    scan: ScanSection,
+    #[serde(default)]
+    encryption: EncryptionSection,
+    /// Several Wazuh managers to scan in one run, replacing the single
+    /// manager given by `[gateway]`, --wazuh-url and friends.
+    #[serde(default)]
+    managers: Vec<ManagerSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GatewaySection {
+    url: Option<String>,
+    wazuh_url: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    /// Wazuh API token used instead of signing in
+    token: Option<String>,
+    /// Name of an environment variable holding the Wazuh password
+    password_env: Option<String>,
+    /// File whose (trimmed) contents are the Wazuh password
+    password_file: Option<PathBuf>,
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout_secs: Option<u64>,
+    connect_timeout_secs: Option<u64>,
+    timeout_secs: Option<u64>,
+    http2_prior_knowledge: Option<bool>,
+    token_refresh_buffer_secs: Option<u64>,
+    /// Bearer token for a proxy in front of the gateway
+    auth_token: Option<String>,
+    /// Basic-auth user and password for a proxy in front of the gateway
+    auth_user: Option<String>,
+    auth_password: Option<String>,
+    user_agent: Option<String>,
+    /// Extra headers sent on every gateway call, by name
+    headers: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManagerSection {
+    name: String,
+    /// Gateway for this manager; defaults to --gateway-url
+    gateway_url: Option<String>,
+    wazuh_url: String,
+    username: String,
+    password: Option<String>,
+    password_env: Option<String>,
+    password_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConduitSection {
+    server: Option<String>,
+    client_id: Option<String>,
+    client_key: Option<String>,
+    server_key: Option<String>,
+    max_clock_skew: Option<u64>,
+    clock_offset: Option<String>,
+    max_in_memory: Option<usize>,
+    max_response_size: Option<u64>,
+    spool_checkpoint: Option<u64>,
+    sign_query: Option<bool>,
+    require_query_hash: Option<bool>,
+    signature_algorithm: Option<String>,
+    signature_encoding: Option<String>,
+    wire_encoding: Option<String>,
+    max_attempts: Option<u32>,
+    retry_delay_ms: Option<u64>,
+    reconnect_delay_ms: Option<u64>,
+    reconnect_jitter: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsSection {
+    insecure: Option<bool>,
+    #[serde(default)]
+    cacert: Vec<PathBuf>,
+    server_name: Option<String>,
+    min_version: Option<String>,
+    max_version: Option<String>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScanSection {
+    queries_dir: Option<PathBuf>,
+    query_depth: Option<usize>,
+    query_order: Option<String>,
+    vars: Option<PathBuf>,
+    strict_vars: Option<bool>,
+    unique_agents: Option<bool>,
+    strict_failed_items: Option<bool>,
+    output_dir: Option<PathBuf>,
+    agents_file: Option<PathBuf>,
+    inventory: Option<PathBuf>,
+    server_concurrency: Option<u64>,
+    organize_by: Option<String>,
+    output_template: Option<String>,
+    retain: Option<u64>,
+    retain_age: Option<String>,
+    query_cost_ms: Option<u64>,
+    group_concurrency: Option<u64>,
+    max_concurrency_per_group: Option<u64>,
+    max_concurrency: Option<u64>,
+    adaptive_concurrency: Option<bool>,
+    concurrency_state: Option<PathBuf>,
+    warmup: Option<bool>,
+    warmup_agent: Option<String>,
+    warmup_continue: Option<bool>,
+    retry_passes: Option<u32>,
+    retry_empty: Option<u32>,
+    summary_json: Option<PathBuf>,
+    timings_csv: Option<PathBuf>,
+    agent_timeout: Option<String>,
+    deadline: Option<String>,
+    max_duration: Option<String>,
+    deadline_grace: Option<String>,
+    cache_ttl: Option<String>,
+    cache_dir: Option<PathBuf>,
+    sample: Option<u64>,
+    sample_percent: Option<u8>,
+    sample_seed: Option<u64>,
+    progress: Option<bool>,
+    interval: Option<String>,
+    repeat: Option<u64>,
+    overlap: Option<String>,
+    format: Option<String>,
+    json_output: Option<String>,
+    fields: Option<String>,
+    pretty: Option<bool>,
+    include_metadata: Option<bool>,
+    compress: Option<String>,
+    sink: Option<String>,
+    sink_url: Option<String>,
+    combined_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EncryptionSection {
+    output: Option<bool>,
+    session: Option<bool>,
+    key_file: Option<PathBuf>,
+}
+
+/// Conduit servers listed in a `--inventory` file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServerInventory {
+    servers: Vec<ServerSection>,
+}
+
+/// One conduit server; unset options fall back to the flags and config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServerSection {
+    /// Names the server's output directory and session file.
+    name: String,
+    address: String,
+    /// Name sent as SNI and required in the server certificate.
+    sni: Option<String>,
+    insecure: Option<bool>,
+    #[serde(default)]
+    cacert: Vec<PathBuf>,
+    client_id: Option<String>,
+    client_key: Option<String>,
+    server_key: Option<String>,
+}
+
+impl ServerSection {
+    fn apply(&self, base: &ScanConfig) -> ScanConfig {
+        let mut config = base.clone();
+        let name = path_component(&self.name);
+        config.server = self.address.clone();
+        config.output_dir.push(&name);
+        if let Some(cache) = &mut config.cache {
+            cache.dir.push(&name);
+        }
+        config.client.session_file = session_file_for(&config.client.session_file, &name);
+        if let Some(sni) = &self.sni {
+            config.tls.server_name = Some(sni.clone());
+        }
+        if let Some(insecure) = self.insecure {
+            config.tls.insecure = insecure;
+        }
+        // CAs given for all servers are moot for one that skips verification.
+        if self.insecure == Some(true) || !self.cacert.is_empty() {
+            config.tls.ca_certs = self.cacert.clone();
+        }
+        if let Some(client_id) = &self.client_id {
+            config.client.client_id = client_id.clone();
+        }
+        if let Some(client_key) = &self.client_key {
+            config.client.client_key = client_key.clone();
+        }
+        if let Some(server_key) = &self.server_key {
+            config.client.server_key = server_key.clone();
+        }
+        config
+    }
+}
+
+fn load_server_inventory(path: &Path) -> Result<Vec<ServerSection>> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read inventory {}: {}", path.display(), e))?;
+    let inventory: ServerInventory = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&content)
+            .map_err(|e| format!("Invalid YAML inventory {}: {}", path.display(), e))?,
+        Some("toml") => toml::from_str(&content)
+            .map_err(|e| format!("Invalid TOML inventory {}: {}", path.display(), e))?,
+        _ => return Err(format!(
+            "Unsupported inventory format for {}: expected a .yaml, .yml or .toml file",
+            path.display()
+        ).into()),
+    };
+    if inventory.servers.is_empty() {
+        return Err(format!("Inventory {} lists no servers", path.display()).into());
+    }
+    let mut names: Vec<String> = Vec::new();
+    for server in &inventory.servers {
+        parse_server_addr(&server.address)
+            .map_err(|e| format!("Inventory {}: server {}: {}", path.display(), server.name, e))?;
+        let name = path_component(&server.name);
+        if server.name.is_empty() || names.contains(&name) {
+            return Err(format!(
+                "Inventory {}: every server needs a unique, non-empty name ({:?})",
+                path.display(),
+                server.name
+            ).into());
+        }
+        names.push(name);
+    }
+    Ok(inventory.servers)
+}
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Response {
-    status: bool,
-    data: String,
-    session_id: String,
-    timestamp: u64,
-    signature: String,
+impl ConfigFile {
+    fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&content)
+                .map_err(|e| format!("Invalid YAML config {}: {}", path.display(), e))?,
+            Some("toml") => toml::from_str(&content)
+                .map_err(|e| format!("Invalid TOML config {}: {}", path.display(), e))?,
+            _ => return Err(format!(
+                "Unsupported config format for {}: expected a .toml, .yaml or .yml file",
+                path.display()
+            ).into()),
+        };
+        Ok(parsed)
+    }
+
+    /// Flattens the file into `(ENV_VAR, value)` pairs for the flags it sets.
+    fn env_overrides(&self) -> Result<Vec<(&'static str, String)>> {
+        let password = resolve_password(
+            "gateway",
+            &self.gateway.password,
+            &self.gateway.password_env,
+            &self.gateway.password_file,
+        )?;
+
+        let join_paths = |paths: &[PathBuf]| {
+            paths.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>().join(",")
+        };
+        let path_string = |p: &PathBuf| p.to_string_lossy().to_string();
+
+        let entries = [
+            ("GATEWAY_URL", self.gateway.url.clone()),
+            ("WAZUH_URL", self.gateway.wazuh_url.clone()),
+            ("WAZUH_USERNAME", self.gateway.username.clone()),
+            ("WAZUH_PASSWORD", password),
+            ("WAZUH_TOKEN", self.gateway.token.clone()),
+            ("GATEWAY_POOL_MAX_IDLE_PER_HOST", self.gateway.pool_max_idle_per_host.map(|v| v.to_string())),
+            ("GATEWAY_POOL_IDLE_TIMEOUT_SECS", self.gateway.pool_idle_timeout_secs.map(|v| v.to_string())),
+            ("GATEWAY_CONNECT_TIMEOUT_SECS", self.gateway.connect_timeout_secs.map(|v| v.to_string())),
+            ("GATEWAY_TIMEOUT_SECS", self.gateway.timeout_secs.map(|v| v.to_string())),
+            ("GATEWAY_HTTP2_PRIOR_KNOWLEDGE", self.gateway.http2_prior_knowledge.map(|v| v.to_string())),
+            ("GATEWAY_AUTH_TOKEN", self.gateway.auth_token.clone()),
+            ("GATEWAY_AUTH_USER", self.gateway.auth_user.clone()),
+            ("GATEWAY_AUTH_PASSWORD", self.gateway.auth_password.clone()),
+            ("GATEWAY_USER_AGENT", self.gateway.user_agent.clone()),
+            (
+                "GATEWAY_HEADERS",
+                self.gateway.headers.as_ref().map(|headers| {
+                    headers.iter().map(|(name, value)| format!("{}: {}", name, value)).collect::<Vec<_>>().join("\n")
+                }),
+            ),
+            ("WAZUH_TOKEN_REFRESH_BUFFER_SECS", self.gateway.token_refresh_buffer_secs.map(|v| v.to_string())),
+            ("CONDUIT_SERVER", self.conduit.server.clone()),
+            ("CONDUIT_CLIENT_ID", self.conduit.client_id.clone()),
+            ("CONDUIT_CLIENT_KEY", self.conduit.client_key.clone()),
+            ("CONDUIT_SERVER_KEY", self.conduit.server_key.clone()),
+            ("CONDUIT_MAX_CLOCK_SKEW", self.conduit.max_clock_skew.map(|v| v.to_string())),
+            ("CONDUIT_CLOCK_OFFSET", self.conduit.clock_offset.clone()),
+            ("CONDUIT_MAX_IN_MEMORY", self.conduit.max_in_memory.map(|v| v.to_string())),
+            ("CONDUIT_MAX_RESPONSE_SIZE", self.conduit.max_response_size.map(|v| v.to_string())),
+            ("CONDUIT_SPOOL_CHECKPOINT", self.conduit.spool_checkpoint.map(|v| v.to_string())),
+            ("CONDUIT_SIGN_QUERY", self.conduit.sign_query.map(|v| v.to_string())),
+            ("CONDUIT_REQUIRE_QUERY_HASH", self.conduit.require_query_hash.map(|v| v.to_string())),
+            ("CONDUIT_SIGNATURE_ALGORITHM", self.conduit.signature_algorithm.clone()),
+            ("CONDUIT_SIGNATURE_ENCODING", self.conduit.signature_encoding.clone()),
+            ("CONDUIT_WIRE_ENCODING", self.conduit.wire_encoding.clone()),
+            ("CONDUIT_MAX_ATTEMPTS", self.conduit.max_attempts.map(|v| v.to_string())),
+            ("CONDUIT_RETRY_DELAY_MS", self.conduit.retry_delay_ms.map(|v| v.to_string())),
+            ("CONDUIT_RECONNECT_DELAY_MS", self.conduit.reconnect_delay_ms.map(|v| v.to_string())),
+            ("CONDUIT_RECONNECT_JITTER", self.conduit.reconnect_jitter.map(|v| v.to_string())),
+            ("CONDUIT_INSECURE", self.tls.insecure.map(|v| v.to_string())),
+            ("CONDUIT_CACERT", (!self.tls.cacert.is_empty()).then(|| join_paths(&self.tls.cacert))),
+            ("CONDUIT_TLS_SERVER_NAME", self.tls.server_name.clone()),
+            ("CONDUIT_TLS_MIN_VERSION", self.tls.min_version.clone()),
+            ("CONDUIT_TLS_MAX_VERSION", self.tls.max_version.clone()),
+            ("CONDUIT_TLS_CLIENT_CERT", self.tls.client_cert.as_ref().map(path_string)),
+            ("CONDUIT_TLS_CLIENT_KEY", self.tls.client_key.as_ref().map(path_string)),
+            ("WQL_QUERIES_DIR", self.scan.queries_dir.as_ref().map(path_string)),
+            ("CONDUIT_QUERY_DEPTH", self.scan.query_depth.map(|v| v.to_string())),
+            ("CONDUIT_QUERY_ORDER", self.scan.query_order.clone()),
+            ("CONDUIT_VARS", self.scan.vars.as_ref().map(path_string)),
+            ("CONDUIT_STRICT_VARS", self.scan.strict_vars.map(|v| v.to_string())),
+            ("CONDUIT_UNIQUE_AGENTS", self.scan.unique_agents.map(|v| v.to_string())),
+            ("CONDUIT_STRICT_FAILED_ITEMS", self.scan.strict_failed_items.map(|v| v.to_string())),
+            ("OUTPUT_DIR", self.scan.output_dir.as_ref().map(path_string)),
+            ("CONDUIT_AGENTS_FILE", self.scan.agents_file.as_ref().map(path_string)),
+            ("CONDUIT_INVENTORY", self.scan.inventory.as_ref().map(path_string)),
+            ("CONDUIT_SERVER_CONCURRENCY", self.scan.server_concurrency.map(|v| v.to_string())),
+            ("CONDUIT_ORGANIZE_BY", self.scan.organize_by.clone()),
+            ("CONDUIT_OUTPUT_TEMPLATE", self.scan.output_template.clone()),
+            ("CONDUIT_RETAIN", self.scan.retain.map(|v| v.to_string())),
+            ("CONDUIT_RETAIN_AGE", self.scan.retain_age.clone()),
+            ("CONDUIT_QUERY_COST_MS", self.scan.query_cost_ms.map(|v| v.to_string())),
+            ("CONDUIT_GROUP_CONCURRENCY", self.scan.group_concurrency.map(|v| v.to_string())),
+            ("CONDUIT_MAX_CONCURRENCY_PER_GROUP", self.scan.max_concurrency_per_group.map(|v| v.to_string())),
+            ("CONDUIT_MAX_CONCURRENCY", self.scan.max_concurrency.map(|v| v.to_string())),
+            ("CONDUIT_ADAPTIVE_CONCURRENCY", self.scan.adaptive_concurrency.map(|v| v.to_string())),
+            ("CONDUIT_CONCURRENCY_STATE", self.scan.concurrency_state.as_ref().map(|v| v.display().to_string())),
+            ("CONDUIT_WARMUP", self.scan.warmup.map(|v| v.to_string())),
+            ("CONDUIT_WARMUP_AGENT", self.scan.warmup_agent.clone()),
+            ("CONDUIT_WARMUP_CONTINUE", self.scan.warmup_continue.map(|v| v.to_string())),
+            ("CONDUIT_RETRY_PASSES", self.scan.retry_passes.map(|v| v.to_string())),
+            ("CONDUIT_RETRY_EMPTY", self.scan.retry_empty.map(|v| v.to_string())),
+            ("CONDUIT_SUMMARY_JSON", self.scan.summary_json.as_ref().map(path_string)),
+            ("CONDUIT_TIMINGS_CSV", self.scan.timings_csv.as_ref().map(path_string)),
+            ("CONDUIT_AGENT_TIMEOUT", self.scan.agent_timeout.clone()),
+            ("CONDUIT_DEADLINE", self.scan.deadline.clone()),
+            ("CONDUIT_MAX_DURATION", self.scan.max_duration.clone()),
+            ("CONDUIT_DEADLINE_GRACE", self.scan.deadline_grace.clone()),
+            ("CONDUIT_CACHE_TTL", self.scan.cache_ttl.clone()),
+            ("CONDUIT_CACHE_DIR", self.scan.cache_dir.as_ref().map(path_string)),
+            ("CONDUIT_SAMPLE", self.scan.sample.map(|v| v.to_string())),
+            ("CONDUIT_SAMPLE_PERCENT", self.scan.sample_percent.map(|v| v.to_string())),
+            ("CONDUIT_SAMPLE_SEED", self.scan.sample_seed.map(|v| v.to_string())),
+            ("CONDUIT_PROGRESS", self.scan.progress.map(|v| v.to_string())),
+            ("CONDUIT_INTERVAL", self.scan.interval.clone()),
+            ("CONDUIT_REPEAT", self.scan.repeat.map(|v| v.to_string())),
+            ("CONDUIT_OVERLAP", self.scan.overlap.clone()),
+            ("CONDUIT_FORMAT", self.scan.format.clone()),
+            ("CONDUIT_JSON_OUTPUT", self.scan.json_output.clone()),
+            ("CONDUIT_FIELDS", self.scan.fields.clone()),
+            ("CONDUIT_PRETTY", self.scan.pretty.map(|v| v.to_string())),
+            ("CONDUIT_INCLUDE_METADATA", self.scan.include_metadata.map(|v| v.to_string())),
+            ("CONDUIT_COMPRESS", self.scan.compress.clone()),
+            ("CONDUIT_SINK", self.scan.sink.clone()),
+            ("CONDUIT_SINK_URL", self.scan.sink_url.clone()),
+            ("CONDUIT_COMBINED_FILE", self.scan.combined_file.as_ref().map(path_string)),
+            ("CONDUIT_ENCRYPT_OUTPUT", self.encryption.output.map(|v| v.to_string())),
+            ("CONDUIT_ENCRYPT_SESSION", self.encryption.session.map(|v| v.to_string())),
+            ("CONDUIT_ENCRYPTION_KEY_FILE", self.encryption.key_file.as_ref().map(path_string)),
+        ];
+
+        Ok(entries
+            .into_iter()
+            .filter_map(|(var, value)| value.map(|v| (var, v)))
+            .collect())
+    }
+}
+
+/// Resolves a config section's `password`, `password_env` or `password_file`,
+/// at most one of which may be set.
+fn resolve_password(
+    section: &str,
+    password: &Option<String>,
+    password_env: &Option<String>,
+    password_file: &Option<PathBuf>,
+) -> Result<Option<String>> {
+    match (password, password_env, password_file) {
+        (Some(password), None, None) => Ok(Some(password.clone())),
+        (None, Some(var), None) => Ok(Some(std::env::var(var).map_err(|_| {
+            format!("Config {}.password_env refers to unset variable {}", section, var)
+        })?)),
+        (None, None, Some(path)) => Ok(Some(
+            fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read password file {}: {}", path.display(), e))?
+                .trim()
+                .to_string(),
+        )),
+        (None, None, None) => Ok(None),
+        _ => Err(format!(
+            "Config {}: set only one of password, password_env or password_file",
+            section
+        ).into()),
+    }
+}
+
+impl ManagerSection {
+    fn into_gateway(self, default_gateway_url: &str) -> Result<GatewayConfig> {
+        let section = format!("managers.{}", self.name);
+        let password = resolve_password(&section, &self.password, &self.password_env, &self.password_file)?
+            .ok_or_else(|| format!("Config {}: a password is required", section))?;
+        Ok(GatewayConfig {
+            name: Some(self.name),
+            url: self.gateway_url.unwrap_or_else(|| default_gateway_url.to_string()),
+            wazuh_url: self.wazuh_url,
+            username: self.username,
+            password,
+            token: None,
+        })
+    }
+}
+
+/// Finds `--name value` or `--name=value` before clap parses the full command
+/// line, so env files and the config file can feed clap's `env` fallbacks.
+fn bootstrap_option(args: &[String], name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let prefix = format!("{}=", flag);
+    args.iter().enumerate().find_map(|(i, arg)| {
+        if arg == &flag {
+            args.get(i + 1).cloned()
+        } else {
+            arg.strip_prefix(&prefix).map(str::to_string)
+        }
+    })
+}
+
+/// Loads env files and the `--config` file, returning its `[[managers]]` list.
+fn load_environment(args: &[String]) -> Result<Vec<ManagerSection>> {
+    // dotenv never overrides variables that are already set, so loading the
+    // environment-specific file first gives it priority over `.env`.
+    if let Some(name) = bootstrap_option(args, "env") {
+        let path = format!(".env.{}", name);
+        match dotenv::from_filename(&path) {
+            Ok(_) => info!("Loaded environment from {}", path),
+            Err(_) => info!("No {} found, falling back to .env", path),
+        }
+    }
+    dotenv().ok();
+
+    if let Some(path) = bootstrap_option(args, "config") {
+        let config = ConfigFile::load(Path::new(&path))?;
+        for (var, value) in config.env_overrides()? {
+            std::env::set_var(var, value);
+        }
+        return Ok(config.managers);
+    }
+    Ok(Vec::new())
+}
+
+fn parse_fraction(value: &str) -> std::result::Result<f64, String> {
+    let fraction: f64 = value.parse().map_err(|_| format!("not a number: {}", value))?;
+    if (0.0..=1.0).contains(&fraction) {
+        Ok(fraction)
+    } else {
+        Err(format!("must be between 0 and 1, got {}", value))
+    }
+}
+
+/// Parses `90`, `90s`, `15m` or `2h` into a non-zero duration.
+fn parse_duration(value: &str) -> std::result::Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number: u64 = number.parse().map_err(|_| format!("not a duration: {}", value))?;
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return Err(format!("unknown unit in {}; use s, m, h or d", value)),
+    };
+    match number.checked_mul(scale) {
+        Some(0) => Err("must be greater than zero".into()),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!("too large: {}", value)),
+    }
+}
+
+/// Parses a whole number of seconds, which may be negative, or `server`.
+fn parse_clock_offset(value: &str) -> std::result::Result<ClockOffset, String> {
+    match value.trim() {
+        "server" => Ok(ClockOffset::Server),
+        seconds => seconds
+            .parse()
+            .map(ClockOffset::Fixed)
+            .map_err(|_| format!("not a number of seconds or \"server\": {}", value)),
+    }
+}
+
+/// Parses a `Name: value` gateway header.
+fn parse_header(value: &str) -> std::result::Result<(String, String), String> {
+    let (name, header_value) = value.split_once(':').ok_or("expected \"Name: value\"")?;
+    let (name, header_value) = (name.trim(), header_value.trim());
+    check_header(name, header_value)?;
+    Ok((name.to_string(), header_value.to_string()))
+}
+
+fn parse_deadline(value: &str) -> std::result::Result<SystemTime, String> {
+    let time = OffsetDateTime::parse(value.trim(), &Rfc3339)
+        .map_err(|e| format!("not an RFC 3339 time such as 2026-10-15T06:00:00Z: {}", e))?;
+    Ok(SystemTime::from(time))
+}
+
+fn parse_server_addr(addr: &str) -> std::result::Result<String, String> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => {
+            port.parse::<u16>()
+                .map_err(|_| format!("invalid port in server address: {}", addr))?;
+            Ok(addr.to_string())
+        }
+        _ => Err(format!("server address must be in host:port form, got: {}", addr)),
+    }
+}
+
+impl ConduitArgs {
+    fn client_config(&self, session_cipher: Option<OutputCipher>) -> ClientConfig {
+        ClientConfig {
+            client_id: self.client_id.clone(),
+            client_key: self.client_key.clone(),
+            server_key: self.server_key.clone(),
+            max_clock_skew: Duration::from_secs(self.max_clock_skew),
+            clock_offset: self.clock_offset.unwrap_or_default(),
+            max_in_memory: self.max_in_memory,
+            max_response_size: self.max_response_size,
+            spool_checkpoint: self.spool_checkpoint,
+            session_file: PathBuf::from(SESSION_FILE),
+            session_cipher,
+            sign_query: self.sign_query,
+            require_query_hash: self.require_query_hash,
+            format: ResultFormat::default(),
+            signature_algorithm: self.signature_algorithm,
+            signature_encoding: self.signature_encoding,
+            wire_encoding: self.wire_encoding,
+            verbosity: self.verbose,
+        }
+    }
+}
+
+impl GatewayArgs {
+    fn http_options(&self) -> HttpOptions {
+        HttpOptions {
+            pool_max_idle_per_host: self.gateway_pool_max_idle,
+            pool_idle_timeout: Duration::from_secs(self.gateway_pool_idle_timeout_secs),
+            connect_timeout: Duration::from_secs(self.gateway_connect_timeout_secs),
+            request_timeout: Duration::from_secs(self.gateway_timeout_secs),
+            http2_prior_knowledge: self.gateway_http2,
+            auth: match (&self.gateway_auth_token, &self.gateway_auth_user) {
+                (Some(token), _) => Some(GatewayAuth::Bearer(token.clone())),
+                (None, Some(username)) => Some(GatewayAuth::Basic {
+                    username: username.clone(),
+                    password: self.gateway_auth_password.clone(),
+                }),
+                (None, None) => None,
+            },
+            user_agent: self.gateway_user_agent.clone(),
+            headers: self.gateway_headers.clone(),
+            ..HttpOptions::default()
+        }
+    }
+
+    /// The single manager described by the flags (or their environment variables).
+    fn single_manager(&self) -> Result<GatewayConfig> {
+        let token = self.wazuh_token.clone();
+        if let Some(token) = &token {
+            check_supplied_token(token, self.wazuh_username.is_some())?;
+        }
+        match (&self.wazuh_url, &self.wazuh_username, &self.wazuh_password) {
+            (Some(wazuh_url), Some(username), Some(password)) => Ok(GatewayConfig {
+                name: None,
+                url: self.gateway_url.clone(),
+                wazuh_url: wazuh_url.clone(),
+                username: username.clone(),
+                password: password.clone(),
+                token,
+            }),
+            // A supplied token needs no credentials, but then cannot be renewed.
+            (Some(wazuh_url), None, None) if token.is_some() => Ok(GatewayConfig {
+                name: None,
+                url: self.gateway_url.clone(),
+                wazuh_url: wazuh_url.clone(),
+                username: String::new(),
+                password: String::new(),
+                token,
+            }),
+            (wazuh_url, username, password) => {
+                let credentials_given = username.is_some() || password.is_some();
+                let missing: Vec<&str> = [
+                    (wazuh_url.is_none(), "WAZUH_URL (--wazuh-url)"),
+                    (username.is_none() && (token.is_none() || credentials_given), "WAZUH_USERNAME (--wazuh-username)"),
+                    (password.is_none() && (token.is_none() || credentials_given), "WAZUH_PASSWORD (--wazuh-password)"),
+                ]
+                .into_iter()
+                .filter(|(missing, _)| *missing)
+                .map(|(_, name)| name)
+                .collect();
+                Err(format!(
+                    "Missing required Wazuh settings: {}. Set them in the environment, a .env file, \
+                     the [gateway] section of --config, or as flags",
+                    missing.join(", ")
+                ).into())
+            }
+        }
+    }
+}
+
+/// Rejects a `--wazuh-token` that is not shaped like a JWT and warns when it
+/// has already expired.
+fn check_supplied_token(token: &str, renewable: bool) -> Result<()> {
+    let expiry = check_wazuh_token(token)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    if let Some(expired_at) = expiry.filter(|exp| *exp <= now) {
+        let outcome = match renewable {
+            true => "it will be renewed with the Wazuh credentials",
+            false => "the gateway will likely reject it",
+        };
+        eprintln!("Warning: the supplied Wazuh token expired {}s ago; {}", now - expired_at, outcome);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "gateway")]
+/// Starts the Wazuh session with the manager's supplied token, or by
+/// authenticating, and says which.
+async fn sign_in(client: &mut Client, manager: &GatewayConfig) -> Result<String> {
+    match &manager.token {
+        Some(token) => {
+            client.set_wazuh_token(token.clone());
+            if !manager.username.is_empty() {
+                client.set_wazuh_credentials(&manager.username, &manager.password);
+            }
+            Ok("supplied token used".to_string())
+        }
+        None => client.authenticate(&manager.username, &manager.password).await.map(|_| "token issued".to_string()),
+    }
+}
+
+impl KeyArgs {
+    fn cipher(&self) -> Result<Option<OutputCipher>> {
+        match (&self.encryption_passphrase, &self.encryption_key_file) {
+            (Some(passphrase), _) => Ok(Some(OutputCipher::from_passphrase(passphrase)?)),
+            (None, Some(path)) => Ok(Some(OutputCipher::from_key_file(path)?)),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Builds the cipher for a flag that requires one, such as `--encrypt-output`.
+    fn require_cipher(&self, flag: &str) -> Result<OutputCipher> {
+        self.cipher()?.ok_or_else(|| {
+            format!("{} requires --encryption-passphrase or --encryption-key-file", flag).into()
+        })
+    }
+}
+
+impl RetryArgs {
+    fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            base_delay: Duration::from_millis(self.retry_delay_ms),
+        }
+    }
+
+    fn reconnect_delay(&self) -> ReconnectDelay {
+        ReconnectDelay {
+            base: Duration::from_millis(self.reconnect_delay_ms),
+            jitter: self.reconnect_jitter,
+        }
+    }
+}
+
+impl TlsArgs {
+    /// `verbose` is the conduit `-v` count; any of it logs each handshake.
+    fn options(&self, verbose: u8) -> TlsOptions {
+        TlsOptions {
+            insecure: self.insecure,
+            ca_certs: self.ca_certs.clone(),
+            server_name: Some(self.tls_server_name.clone()),
+            min_version: self.tls_min_version,
+            max_version: self.tls_max_version,
+            client_identity: self.tls_client_cert.clone().zip(self.tls_client_key.clone()).map(|(cert, key)| {
+                ClientIdentity { cert, key }
+            }),
+            log_handshake: verbose > 0,
+        }
+    }
+}
+
+impl ScanArgs {
+    fn into_config(self, managers: Vec<ManagerSection>) -> Result<ScanConfig> {
+        let inventory = self.agents_file.as_deref().map(load_agents_file).transpose()?;
+        // Only discovered groups default to active agents; named ones are
+        // scanned as asked for.
+        let statuses = match self.statuses.is_empty() && self.agents.is_empty() && inventory.is_none() {
+            true => vec![AgentStatus::Active],
+            false => self.statuses.clone(),
+        };
+        let query_vars = match &self.vars {
+            Some(path) => QueryVars::load(path, self.strict_vars)?,
+            None => QueryVars::new(Default::default(), self.strict_vars)?,
+        };
+        let cache = self.cache_ttl.filter(|_| !self.no_cache).map(|ttl| ResultCache {
+            dir: self.cache_dir.clone().unwrap_or_else(|| self.output_dir.join(".cache")),
+            ttl,
+            refresh: self.refresh,
+        });
+        let managers = if inventory.is_some() {
+            Vec::new()
+        } else if !cfg!(feature = "gateway") {
+            return Err("Built without the gateway feature: supply an explicit agent inventory with --agents-file".into());
+        } else if managers.is_empty() {
+            vec![self.gateway.single_manager()?]
+        } else {
+            managers
+                .into_iter()
+                .map(|m| m.into_gateway(&self.gateway.gateway_url))
+                .collect::<Result<_>>()?
+        };
+        let cipher = if self.encrypt_output || self.encrypt_session {
+            let flag = if self.encrypt_output { "--encrypt-output" } else { "--encrypt-session" };
+            Some(self.key.require_cipher(flag)?)
+        } else {
+            None
+        };
+        let size = match (self.sample, self.sample_percent) {
+            (Some(count), _) => Some(SampleSize::Count(count as usize)),
+            (None, Some(percent)) => Some(SampleSize::Percent(percent)),
+            (None, None) => None,
+        };
+        let sample = size.map(|size| {
+            let seed = self.sample_seed.unwrap_or_else(rand::random);
+            info!("Sampling agents with seed {} (pass --sample-seed {} to repeat)", seed, seed);
+            Sample { size, seed }
+        });
+        let client = ClientConfig {
+            format: self.format,
+            ..self.conduit.client_config(cipher.clone().filter(|_| self.encrypt_session))
+        };
+        if self.include_metadata && self.sink != Sink::File {
+            return Err("--include-metadata writes sidecars next to result files and needs --sink file".into());
+        }
+        let retention = Retention { keep: self.retain.map(|keep| keep as usize), max_age: self.retain_age };
+        if !retention.is_unlimited() && self.sink != Sink::File {
+            return Err("--retain and --retain-age prune result files and need --sink file".into());
+        }
+        let sink: Arc<dyn OutputSink> = match self.sink {
+            Sink::File => Arc::new(FileSink),
+            Sink::Stdout => Arc::new(StdoutSink),
+            #[cfg(not(feature = "gateway"))]
+            Sink::Http => return Err("Built without the gateway feature: --sink http is unavailable".into()),
+            #[cfg(feature = "gateway")]
+            Sink::Http => {
+                // The gateway's proxy credentials are not sent to the ingestion endpoint.
+                let http = HttpOptions { auth: None, ..self.gateway.http_options() }.build()?;
+                Arc::new(HttpSink::new(self.sink_url.clone().unwrap_or_default(), http, self.retry.policy()))
+            }
+            Sink::Combined => {
+                if self.format != ResultFormat::Json || self.compress != Compression::None || self.encrypt_output {
+                    return Err("--sink combined gathers plain JSON results; it cannot be used with --format csv/raw, \
+                                --compress or --encrypt-output".into());
+                }
+                let path = self.combined_file.clone().unwrap_or_else(|| self.output_dir.join(COMBINED_FILE));
+                Arc::new(CombinedSink::new(path))
+            }
+        };
+        Ok(ScanConfig {
+            client,
+            tls: self.tls.options(self.conduit.verbose),
+            retry: self.retry.policy(),
+            reconnect_delay: self.retry.reconnect_delay(),
+            server: self.server.unwrap_or_default(),
+            http: self.gateway.http_options(),
+            token_refresh_buffer: Duration::from_secs(self.gateway.token_refresh_buffer_secs),
+            managers,
+            queries_dir: self.queries.queries_dir,
+            queries: self.queries.queries,
+            query_depth: self.queries.query_depth,
+            query_order: self.queries.query_order,
+            query_vars,
+            agents: self.agents,
+            groups: self.groups,
+            nodes: self.nodes,
+            statuses,
+            unique_agents: self.unique_agents,
+            strict_failed_items: self.strict_failed_items,
+            output_dir: self.output_dir,
+            organize_by: self.organize_by,
+            output_template: self.output_template,
+            retention,
+            output_cipher: cipher.filter(|_| self.encrypt_output),
+            group_concurrency: self.group_concurrency as usize,
+            max_concurrency_per_group: self.max_concurrency_per_group as usize,
+            max_concurrency: self.max_concurrency.map(|limit| limit as usize),
+            adaptive_concurrency: self.adaptive_concurrency,
+            adaptive_state: self.concurrency_state.as_deref().and_then(load_adaptive_state),
+            warmup: (self.warmup || self.warmup_agent.is_some()).then(|| Warmup {
+                agent: self.warmup_agent.clone(),
+                continue_on_failure: self.warmup_continue,
+            }),
+            sample,
+            retry_passes: self.retry_passes,
+            retry_empty: self.retry_empty,
+            agent_timeout: self.agent_timeout,
+            deadline: self.deadline.or_else(|| self.max_duration.map(|limit| SystemTime::now() + limit)),
+            deadline_grace: self.deadline_grace,
+            cache,
+            only: None,
+            progress: self.progress,
+            json_output: if self.pretty { JsonOutput::Pretty } else { self.json_output },
+            fields: self.fields,
+            include_metadata: self.include_metadata,
+            compression: self.compress,
+            sink,
+            inventory,
+            run: 1,
+            wazuh_tokens: Default::default(),
+        })
+    }
+}
+
+fn print_summary(report: &ScanReport) {
+    info!("\nScan summary ({:.1}s):", report.duration.as_secs_f64());
+    for failure in &report.manager_failures {
+        info!("  manager {}: not scanned: {}", failure.manager, failure.message);
+    }
+    for item in &report.failed_items {
+        info!("  not listed by Wazuh: {}", item);
+    }
+    for group in &report.groups {
+        let succeeded = group.queries.iter().filter(|r| r.succeeded()).count();
+        let name = match &group.manager {
+            Some(manager) => format!("{}/{}", manager, group.group.name),
+            None => group.group.name.clone(),
+        };
+        info!(
+            "  {}: {}/{} queries succeeded",
+            name,
+            succeeded,
+            group.queries.len()
+        );
+        for result in group.queries.iter().filter(|r| !r.succeeded()) {
+            let reason = match &result.outcome {
+                QueryOutcome::Rejected { message } => format!("rejected by server: {}", message),
+                QueryOutcome::Error { message } => message.clone(),
+                QueryOutcome::Skipped { reason } => format!("skipped: {}", reason),
+                QueryOutcome::Empty => "empty result".to_string(),
+                QueryOutcome::Saved { .. } => unreachable!(),
+            };
+            info!("    - {} / {}: {}", result.agent.name, result.query, reason);
+        }
+    }
+    let skipped = match (report.skipped(), report.empty()) {
+        (0, 0) => String::new(),
+        (n, 0) => format!(", {} skipped", n),
+        (0, e) => format!(", {} empty", e),
+        (n, e) => format!(", {} skipped, {} empty", n, e),
+    };
+    let cached = report
+        .results()
+        .filter(|r| matches!(r.outcome, QueryOutcome::Saved { cached: true, .. }))
+        .count();
+    let succeeded = match cached {
+        0 => report.succeeded().to_string(),
+        n => format!("{} ({} from cache)", report.succeeded(), n),
+    };
+    let bytes: u64 = report.results().map(|r| r.bytes).sum();
+    let stored: u64 = report
+        .results()
+        .map(|r| match r.outcome {
+            QueryOutcome::Saved { stored_bytes, .. } => stored_bytes,
+            _ => 0,
+        })
+        .sum();
+    let stored = match report.results().any(|r| r.succeeded()) {
+        true => format!(" ({} bytes stored)", stored),
+        false => String::new(),
+    };
+    info!("Total: {} succeeded, {} failed{}, {} bytes{}", succeeded, report.failed(), skipped, bytes, stored);
+    let mut formats: Vec<(ResultFormat, usize)> = Vec::new();
+    for result in report.results() {
+        if let QueryOutcome::Saved { format, .. } = result.outcome {
+            match formats.iter_mut().find(|(f, _)| *f == format) {
+                Some((_, count)) => *count += 1,
+                None => formats.push((format, 1)),
+            }
+        }
+    }
+    if !formats.is_empty() {
+        let formats: Vec<String> = formats.iter().map(|(f, n)| format!("{} {}", f.as_str(), n)).collect();
+        info!("Formats: {}", formats.join(", "));
+    }
+}
+
+/// Bumped whenever [`Summary`] changes shape, so `--rerun` can refuse files it would misread.
+const SUMMARY_VERSION: u32 = 1;
+
+/// What `--summary-json` writes and `--rerun` reads back.
+#[derive(Debug, serde::Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Summary {
+    version: u32,
+    duration_secs: f64,
+    #[serde(default)]
+    manager_failures: Vec<SummaryFailure>,
+    /// Groups and agents Wazuh left out of its listings.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    failed_items: Vec<FailedItem>,
+    results: Vec<SummaryEntry>,
+}
+
+#[derive(Debug, serde::Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SummaryFailure {
+    manager: String,
+    message: String,
+}
+
+#[derive(Debug, serde::Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SummaryEntry {
+    #[serde(default)]
+    manager: Option<String>,
+    group: String,
+    agent_id: String,
+    agent_name: String,
+    query: String,
+    status: SummaryStatus,
+    #[serde(default)]
+    path: Option<PathBuf>,
+    #[serde(default)]
+    message: Option<String>,
+    bytes: u64,
+    /// Size of the saved result after compression and encryption.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stored_bytes: Option<u64>,
+    latency_ms: u64,
+    /// Phases of the exchange that answered the query, in milliseconds since
+    /// it began connecting; absent when nothing was received.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timings: Option<SummaryTimings>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SummaryTimings {
+    connect_ms: u64,
+    send_ms: u64,
+    first_byte_ms: u64,
+    receive_ms: u64,
+}
+
+impl From<QueryTimings> for SummaryTimings {
+    fn from(timings: QueryTimings) -> Self {
+        let ms = |d: Duration| d.as_millis() as u64;
+        Self {
+            connect_ms: ms(timings.connected),
+            send_ms: ms(timings.sent),
+            first_byte_ms: ms(timings.first_byte),
+            receive_ms: ms(timings.received),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SummaryStatus {
+    Saved,
+    Cached,
+    Rejected,
+    Error,
+    Skipped,
+    Empty,
+}
+
+impl SummaryStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Saved => "saved",
+            Self::Cached => "cached",
+            Self::Rejected => "rejected",
+            Self::Error => "error",
+            Self::Skipped => "skipped",
+            Self::Empty => "empty",
+        }
+    }
+}
+
+impl SummaryEntry {
+    fn item(&self) -> ScanItem {
+        ScanItem {
+            manager: self.manager.clone(),
+            group: self.group.clone(),
+            agent_id: self.agent_id.clone(),
+            query: self.query.clone(),
+        }
+    }
+}
+
+impl Summary {
+    fn new(report: &ScanReport) -> Self {
+        let results = report
+            .groups
+            .iter()
+            .flat_map(|group| group.queries.iter().map(move |result| (group, result)))
+            .map(|(group, result)| {
+                let (status, path, message, stored_bytes) = match &result.outcome {
+                    QueryOutcome::Saved { path, cached, stored_bytes, .. } => {
+                        let status = if *cached { SummaryStatus::Cached } else { SummaryStatus::Saved };
+                        (status, Some(path.clone()), None, Some(*stored_bytes))
+                    }
+                    QueryOutcome::Rejected { message } => (SummaryStatus::Rejected, None, Some(message.clone()), None),
+                    QueryOutcome::Error { message } => (SummaryStatus::Error, None, Some(message.clone()), None),
+                    QueryOutcome::Skipped { reason } => (SummaryStatus::Skipped, None, Some(reason.clone()), None),
+                    QueryOutcome::Empty => (SummaryStatus::Empty, None, None, None),
+                };
+                SummaryEntry {
+                    manager: group.manager.clone(),
+                    group: group.group.name.clone(),
+                    agent_id: result.agent.id.clone(),
+                    agent_name: result.agent.name.clone(),
+                    query: result.query.clone(),
+                    status,
+                    path,
+                    message,
+                    bytes: result.bytes,
+                    stored_bytes,
+                    latency_ms: result.latency.as_millis() as u64,
+                    timings: result.timings.map(SummaryTimings::from),
+                }
+            })
+            .collect();
+        Self {
+            version: SUMMARY_VERSION,
+            duration_secs: report.duration.as_secs_f64(),
+            manager_failures: report
+                .manager_failures
+                .iter()
+                .map(|f| SummaryFailure { manager: f.manager.clone(), message: f.message.clone() })
+                .collect(),
+            failed_items: report.failed_items.clone(),
+            results,
+        }
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read summary {}: {}", path.display(), e))?;
+        let summary: Self = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid summary {}: {}", path.display(), e))?;
+        if summary.version != SUMMARY_VERSION {
+            return Err(format!(
+                "Summary {} has version {}; this client reads version {}",
+                path.display(),
+                summary.version,
+                SUMMARY_VERSION
+            ).into());
+        }
+        Ok(summary)
+    }
+
+    /// The items that did not produce a result: errors, rejections, skips and empty results.
+    fn unfinished(&self) -> HashSet<ScanItem> {
+        self.results
+            .iter()
+            .filter(|entry| !matches!(entry.status, SummaryStatus::Saved | SummaryStatus::Cached))
+            .map(SummaryEntry::item)
+            .collect()
+    }
+
+    /// Replaces the entries this rerun covered with its outcomes, keeping the rest.
+    fn merge(mut self, rerun: Summary) -> Self {
+        for entry in rerun.results {
+            match self.results.iter_mut().find(|e| e.item() == entry.item()) {
+                Some(existing) => *existing = entry,
+                None => self.results.push(entry),
+            }
+        }
+        self.manager_failures = rerun.manager_failures;
+        self.failed_items = rerun.failed_items;
+        self.duration_secs += rerun.duration_secs;
+        self
+    }
+
+    /// One row per result; the timing columns are empty for results that
+    /// were not received, such as cached, skipped or failed ones.
+    fn timings_csv(&self) -> String {
+        let mut csv = String::from(
+            "manager,group,agent_id,agent_name,query,status,bytes,connect_ms,send_ms,first_byte_ms,receive_ms,latency_ms\n",
+        );
+        for entry in &self.results {
+            let timings = entry.timings.map_or([""; 4].map(String::from), |t| {
+                [t.connect_ms, t.send_ms, t.first_byte_ms, t.receive_ms].map(|ms| ms.to_string())
+            });
+            let row = [
+                entry.manager.as_deref().unwrap_or(""),
+                &entry.group,
+                &entry.agent_id,
+                &entry.agent_name,
+                &entry.query,
+                entry.status.as_str(),
+                &entry.bytes.to_string(),
+                &timings[0],
+                &timings[1],
+                &timings[2],
+                &timings[3],
+                &entry.latency_ms.to_string(),
+            ];
+            csv.push_str(&row.map(csv_field).join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        if path == Path::new("-") {
+            println!("{}", json);
+            return Ok(());
+        }
+        fs::write(path, json + "\n").map_err(|e| format!("Failed to write summary {}: {}", path.display(), e))?;
+        info!("Summary written to {}", path.display());
+        Ok(())
+    }
+}
+
+/// The adaptive concurrency saved by `--concurrency-state`. A missing file
+/// is a first run; an unreadable one is warned about and started afresh.
+fn load_adaptive_state(path: &Path) -> Option<AdaptiveState> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            eprintln!("Warning: failed to read concurrency state {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    serde_json::from_str(&content)
+        .map_err(|e| eprintln!("Warning: ignoring invalid concurrency state {}: {}", path.display(), e))
+        .ok()
+}
+
+/// Saves the adaptive concurrency a scan ended with for the next one. A
+/// failure only costs the next scan its head start, so it is a warning.
+fn save_adaptive_state(path: &Path, state: Option<&AdaptiveState>) {
+    let Some(state) = state else {
+        return;
+    };
+    let saved = serde_json::to_string_pretty(state)
+        .map_err(|e| e.to_string())
+        .and_then(|json| fs::write(path, json + "\n").map_err(|e| e.to_string()));
+    if let Err(e) = saved {
+        eprintln!("Warning: failed to save concurrency state {}: {}", path.display(), e);
+    }
+}
+
+/// Narrows `config` to the unfinished items of `previous`, checking that
+/// every query it names still exists.
+fn apply_rerun(config: &mut ScanConfig, previous: &Summary, path: &Path) -> Result<()> {
+    let only = previous.unfinished();
+    if only.is_empty() {
+        return Err(format!("Summary {} has no failed or skipped queries to rerun", path.display()).into());
+    }
+    let query_files = load_query_files(&config.queries_dir, &config.queries, config.query_depth, config.query_order)?;
+    let known: HashSet<String> = query_files.iter().map(|f| query_name(&config.queries_dir, f)).collect();
+    let mut missing: Vec<&str> = only.iter().map(|item| item.query.as_str()).filter(|q| !known.contains(*q)).collect();
+    if !missing.is_empty() {
+        missing.sort_unstable();
+        missing.dedup();
+        return Err(format!(
+            "Summary {} lists queries that are not in the current query set: {}",
+            path.display(),
+            missing.join(", ")
+        ).into());
+    }
+    info!("Rerunning {} queries from {}", only.len(), path.display());
+    config.only = Some(only);
+    Ok(())
+}
+
+async fn run_scan(args: ScanArgs, managers: Vec<ManagerSection>) -> Result<()> {
+    if args.print_query {
+        return print_queries(&args, &mut io::stdout());
+    }
+    let (interval, repeat, overlap) = (args.interval, args.repeat, args.overlap);
+    let servers = args.inventory.as_deref().map(load_server_inventory).transpose()?;
+    let server_concurrency = args.server_concurrency as usize;
+    let summary_path = args.summary_json.clone();
+    let timings_path = args.timings_csv.clone();
+    let state_path = args.concurrency_state.clone();
+    if args.sink == Sink::Combined && servers.is_some() {
+        return Err("--sink combined writes one file per scan and cannot gather the servers of an --inventory".into());
+    }
+    if args.sink == Sink::Stdout {
+        if summary_path.as_deref() == Some(Path::new("-")) {
+            return Err("--summary-json - and --sink stdout would both write to stdout".into());
+        }
+        // Progress messages would be mixed into the results on stdout.
+        set_quiet(true);
+    }
+    let rerun = match &args.rerun {
+        Some(path) => Some((Summary::load(path)?, path.clone())),
+        None => None,
+    };
+    let interactive = args.interactive;
+    let (planning, query_cost) = (args.plan, Duration::from_millis(args.query_cost_ms));
+    let mut config = args.into_config(managers)?;
+    if interactive {
+        if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+            return Err("--interactive needs a terminal on stdin and stdout".into());
+        }
+        select_interactively(&mut config, &mut io::stdin().lock(), &mut io::stdout()).await?;
+    }
+    if let Some(servers) = servers {
+        return run_inventory_scan(config, servers, server_concurrency, summary_path.as_deref()).await;
+    }
+    if let Some((previous, path)) = &rerun {
+        apply_rerun(&mut config, previous, path)?;
+    }
+    if planning {
+        return print_plan(&plan(&config).await?, query_cost, config.deadline, &mut io::stdout());
+    }
+    match interval {
+        Some(interval) => run_scan_loop(config, interval, repeat, overlap, summary_path.as_deref(), state_path.as_deref()).await,
+        None => {
+            let report = scan(config).await?;
+            if let Some(path) = &state_path {
+                save_adaptive_state(path, report.adaptive_state.as_ref());
+            }
+            match report.deadline_reached() {
+                true => info!("\nScan stopped at its deadline"),
+                false => info!("\nAll queries completed"),
+            }
+            print_summary(&report);
+            if let Some(path) = &timings_path {
+                fs::write(path, Summary::new(&report).timings_csv())
+                    .map_err(|e| format!("Failed to write timings {}: {}", path.display(), e))?;
+                info!("Timings written to {}", path.display());
+            }
+            if let Some(path) = &summary_path {
+                let summary = match rerun {
+                    Some((previous, _)) => previous.merge(Summary::new(&report)),
+                    None => Summary::new(&report),
+                };
+                summary.write(path)?;
+            }
+            if report.deadline_reached() {
+                exit_at_deadline(&[&report], summary_path.as_deref());
+            }
+            scan_outcome(&report)
+        }
+    }
+}
+
+/// Renders every selected query for each `--agent` and writes it under the
+/// server it would be sent to. Without an `--agents-file` entry for the
+/// agent its name is unknown, so `{{agent_name}}` is flagged as unresolved
+/// like any undefined variable, and the command fails if any query has one.
+fn print_queries(args: &ScanArgs, output: &mut impl Write) -> Result<()> {
+    let queries = &args.queries;
+    let query_files = load_query_files(&queries.queries_dir, &queries.queries, queries.query_depth, queries.query_order)?;
+    if query_files.is_empty() {
+        return Err(format!("No WQL query files found in {} directory", queries.queries_dir.display()).into());
+    }
+    let inventory = args.agents_file.as_deref().map(load_agents_file).transpose()?.unwrap_or_default();
+    let vars = match &args.vars {
+        Some(path) => QueryVars::load(path, args.strict_vars)?,
+        None => QueryVars::default(),
+    };
+    let server = args.server.as_deref().unwrap_or_default();
+
+    let mut unresolved = 0;
+    for id in &args.agents {
+        let name = inventory.iter().find(|agent| &agent.id == id).map(|agent| agent.name.as_str());
+        for query_file in &query_files {
+            let (query, undefined) = vars.render(&fs::read_to_string(query_file)?, id, name);
+            let query_name = query_name(&queries.queries_dir, query_file);
+            writeln!(output, "# {} for agent {} ({}) via {}", query_name, id, name.unwrap_or("name unknown"), server)?;
+            writeln!(output, "{}", query.trim_end())?;
+            if !undefined.is_empty() {
+                unresolved += 1;
+                let names: Vec<String> = undefined.iter().map(|name| format!("{{{{{}}}}}", name)).collect();
+                writeln!(output, "# unresolved: {}", names.join(", "))?;
+            }
+            writeln!(output)?;
+        }
+    }
+    if unresolved > 0 {
+        let total = args.agents.len() * query_files.len();
+        return Err(format!("{} of {} rendered queries have unresolved placeholders", unresolved, total).into());
+    }
+    Ok(())
+}
+
+/// Writes each group's agent and query counts, the totals and the estimated
+/// duration at `query_cost` per query, warning when that runs past the
+/// deadline. Fails when a manager could not be planned.
+fn print_plan(plan: &ScanPlan, query_cost: Duration, deadline: Option<SystemTime>, output: &mut impl Write) -> Result<()> {
+    for group in &plan.groups {
+        let name = match &group.manager {
+            Some(manager) => format!("{}/{}", manager, group.group.name),
+            None => group.group.name.clone(),
+        };
+        writeln!(output, "{}: {} agents, {} queries", name, group.agents, group.queries)?;
+    }
+    for failure in &plan.manager_failures {
+        writeln!(output, "{}: discovery failed: {}", failure.manager, failure.message)?;
+    }
+    writeln!(
+        output,
+        "Total: {} queries on {} agents in {} groups ({} query files)",
+        plan.total_queries(),
+        plan.total_agents(),
+        plan.groups.len(),
+        plan.query_files
+    )?;
+    let estimate = plan.estimate(query_cost);
+    writeln!(
+        output,
+        "Estimated duration: {} at {} ms per query, {} groups at a time, {} agents each",
+        format_estimate(estimate),
+        query_cost.as_millis(),
+        plan.group_concurrency,
+        plan.max_concurrency_per_group
+    )?;
+    if let Some(deadline) = deadline {
+        let left = deadline.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO);
+        if estimate > left {
+            writeln!(output, "Warning: the deadline is {} away, so queries would be skipped", format_estimate(left))?;
+        }
+    }
+    if !plan.manager_failures.is_empty() {
+        return Err(format!("Discovery failed on {} of the managers", plan.manager_failures.len()).into());
+    }
+    Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AuthRequest {
-    client_id: String,
-    timestamp: u64,
-    nonce: String,
-    signature: String,
-    session_id: Option<String>,
-    wql_query: String,
+/// `45.0s`, `12m 05s` or `3h 20m`.
+fn format_estimate(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{:.1}s", duration.as_secs_f64()),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct SessionInfo {
-    session_id: String,
-    client_id: String,
-    created_at: u64,
-    last_used: u64,
-}
+/// Lists the queries, then the groups (those named by `--group`, if any) and
+/// then their agents on the `--node` nodes with a `--status` status, and
+/// narrows `config` to the picked queries and agents. Agents come from the
+/// `--agents-file` inventory, or are discovered through the one manager,
+/// whose token the scan then reuses.
+async fn select_interactively(config: &mut ScanConfig, input: &mut impl BufRead, output: &mut impl Write) -> Result<()> {
+    let query_files = load_query_files(&config.queries_dir, &config.queries, config.query_depth, config.query_order)?;
+    let query_names: Vec<String> = query_files.iter().map(|f| query_name(&config.queries_dir, f)).collect();
+    if query_names.is_empty() {
+        return Err(format!("No WQL query files found in {} directory", config.queries_dir.display()).into());
+    }
+    let picked = prompt_selection("Queries", &query_names, input, output)?;
+    config.queries = picked.into_iter().map(|i| query_names[i].clone()).collect();
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Group {
-    id: String,
-    name: String,
-}
+    let mut groups: Vec<(Group, Vec<Agent>)> = match &config.inventory {
+        Some(agents) => {
+            let mut groups: Vec<(Group, Vec<Agent>)> = Vec::new();
+            for agent in agents {
+                for name in &agent.groups {
+                    match groups.iter_mut().find(|(group, _)| &group.name == name) {
+                        Some((_, members)) => members.push(agent.clone()),
+                        None => groups.push((Group { id: name.clone(), name: name.clone() }, vec![agent.clone()])),
+                    }
+                }
+            }
+            groups
+        }
+        #[cfg(not(feature = "gateway"))]
+        None => return Err("Built without the gateway feature: --interactive needs an --agents-file".into()),
+        #[cfg(feature = "gateway")]
+        None => {
+            let [manager] = config.managers.as_slice() else {
+                return Err("--interactive discovers agents through a single Wazuh manager".into());
+            };
+            let mut client = Client::new(config.client.clone(), config.retry)
+                .with_gateway(manager.url.clone(), manager.wazuh_url.clone())
+                .with_http_options(&config.http)?;
+            sign_in(&mut client, manager).await?;
+            let mut groups = Vec::new();
+            for group in client.fetch_groups().await? {
+                let agents = client.fetch_agents(&group.id).await?;
+                groups.push((group, agents));
+            }
+            if let Some(token) = client.wazuh_token() {
+                config.wazuh_tokens.insert(manager.label().to_string(), token.to_string());
+            }
+            groups
+        }
+    };
+    if !config.groups.is_empty() {
+        groups.retain(|(group, _)| config.groups.contains(&group.name));
+    }
+    let groups = filter_agents(config, groups);
+    if groups.is_empty() {
+        return Err("No groups were discovered".into());
+    }
+    let group_names: Vec<String> =
+        groups.iter().map(|(group, agents)| format!("{} ({} agents)", group.name, agents.len())).collect();
+    let picked = prompt_selection("Groups", &group_names, input, output)?;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Agent {
-    id: String,
-    name: String,
+    let mut agents: Vec<&Agent> = Vec::new();
+    for (_, members) in picked.into_iter().map(|i| &groups[i]) {
+        for agent in members {
+            if !agents.iter().any(|seen| seen.id == agent.id) {
+                agents.push(agent);
+            }
+        }
+    }
+    if agents.is_empty() {
+        return Err("The picked groups have no agents".into());
+    }
+    let agent_names: Vec<String> = agents.iter().map(|agent| format!("{} ({})", agent.name, agent.id)).collect();
+    let picked = prompt_selection("Agents", &agent_names, input, output)?;
+    config.agents = picked.into_iter().map(|i| agents[i].id.clone()).collect();
+    config.groups.clear();
+    Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct WazuhRequest {
-    endpoint: String,
-    token: String,
-    params: HashMap<String, String>,
+/// Prints `items` numbered from 1 and reads a selection (see
+/// [`parse_selection`]), asking again until one is valid.
+fn prompt_selection(title: &str, items: &[String], input: &mut impl BufRead, output: &mut impl Write) -> Result<Vec<usize>> {
+    writeln!(output, "\n{}:", title)?;
+    for (i, item) in items.iter().enumerate() {
+        writeln!(output, "  {:>3}. {}", i + 1, item)?;
+    }
+    loop {
+        write!(output, "Select (e.g. 1,3-5; empty for all): ")?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Err("Selection aborted".into());
+        }
+        match parse_selection(&line, items.len()) {
+            Ok(picked) => return Ok(picked),
+            Err(e) => writeln!(output, "{}", e)?,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct WazuhAuthRequest {
-    endpoint: String,
-    username: String,
-    password: String,
+/// Parses `1,3-5` style input into zero-based indices below `count`, in
+/// list order without duplicates. Empty input and `all` pick everything.
+fn parse_selection(input: &str, count: usize) -> Result<Vec<usize>> {
+    let input = input.trim();
+    if input.is_empty() || input.eq_ignore_ascii_case("all") {
+        return Ok((0..count).collect());
+    }
+    let mut picked = vec![false; count];
+    for part in input.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let number = |text: &str| match text.trim().parse::<usize>() {
+            Ok(n) if (1..=count).contains(&n) => Ok(n - 1),
+            _ => Err(format!("{:?} is not a number from 1 to {}", text.trim(), count)),
+        };
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (number(first)?, number(last)?),
+            None => (number(part)?, number(part)?),
+        };
+        if first > last {
+            return Err(format!("Range {} runs backwards", part).into());
+        }
+        picked[first..=last].iter_mut().for_each(|p| *p = true);
+    }
+    Ok((0..count).filter(|&i| picked[i]).collect())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct WazuhAuthResponse {
-    token: Option<String>,
-    error: Option<String>,
+fn scan_outcome(report: &ScanReport) -> Result<()> {
+    if !report.manager_failures.is_empty() {
+        return Err(format!("{} manager(s) could not be scanned", report.manager_failures.len()).into());
+    }
+    if report.failed() > 0 {
+        return Err(format!("{} of {} queries failed", report.failed(), report.total()).into());
+    }
+    Ok(())
 }
 
-struct Client {
-    client_id: String,
-    client_key: String,
-    server_key: String,
-    session: Option<SessionInfo>,
-    http_client: reqwest::Client,
-    wazuh_endpoint: String,
-    wazuh_token: Option<String>,
-}
+/// Scans each inventory server with its own connection settings, keeping
+/// results and the cached session apart per server. A server that fails
+/// does not stop the others.
+async fn run_inventory_scan(
+    config: ScanConfig,
+    servers: Vec<ServerSection>,
+    concurrency: usize,
+    summary_path: Option<&Path>,
+) -> Result<()> {
+    let total = servers.len();
+    let scans = servers.into_iter().map(|server| {
+        let config = server.apply(&config);
+        async move {
+            info!("\nScanning server {} ({})", server.name, config.server);
+            (server.name, scan(config).await)
+        }
+    });
+    let results: Vec<(String, Result<ScanReport>)> = stream::iter(scans).buffered(concurrency).collect().await;
 
-impl Client {
-    fn new(client_id: String, client_key: String, server_key: String, wazuh_endpoint: String) -> Self {
-        let session = Self::load_session(&client_id);
-        let http_client = reqwest::Client::new();
-        Self {
-            client_id,
-            client_key,
-            server_key,
-            session,
-            http_client,
-            wazuh_endpoint,
-            wazuh_token: None,
-        }
-    }
-
-    fn load_session(client_id: &str) -> Option<SessionInfo> {
-        if let Ok(content) = fs::read_to_string(SESSION_FILE) {
-            if let Ok(session) = serde_json::from_str::<SessionInfo>(&content) {
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                
-                if now - session.created_at <= 3600 && session.client_id == client_id {
-                    println!("Loaded existing session: {}", session.session_id);
-                    return Some(session);
+    let mut failed = Vec::new();
+    for (name, result) in &results {
+        info!("\nServer {}:", name);
+        let outcome = match result {
+            Ok(report) => {
+                print_summary(report);
+                if let Some(path) = summary_path {
+                    // One summary per server, like its output directory and session file.
+                    let path = match path == Path::new("-") {
+                        true => path.to_path_buf(),
+                        false => session_file_for(path, &path_component(name)),
+                    };
+                    Summary::new(report).write(&path)?;
                 }
+                scan_outcome(report)
             }
+            Err(e) => Err(e.to_string().into()),
+        };
+        if let Err(e) = outcome {
+            info!("Server {} had failures: {}", name, e);
+            failed.push(name.as_str());
         }
-        None
     }
+    let reports: Vec<&ScanReport> = results.iter().filter_map(|(_, result)| result.as_ref().ok()).collect();
+    if reports.iter().any(|report| report.deadline_reached()) {
+        exit_at_deadline(&reports, None);
+    }
+    if !failed.is_empty() {
+        return Err(format!("{} of {} servers had failures: {}", failed.len(), total, failed.join(", ")).into());
+    }
+    Ok(())
+}
+
+/// Ends a scan that hit --deadline or --max-duration with its own exit
+/// status, once the summary has been written.
+fn exit_at_deadline(reports: &[&ScanReport], summary_path: Option<&Path>) -> ! {
+    let unstarted = reports
+        .iter()
+        .flat_map(|report| report.results())
+        .filter(|r| matches!(&r.outcome, QueryOutcome::Skipped { reason } if reason == DEADLINE_REACHED))
+        .count();
+    let hint = match summary_path.filter(|path| *path != Path::new("-")) {
+        Some(path) => format!("; run them later with --rerun {}", path.display()),
+        None => String::new(),
+    };
+    eprintln!("Deadline reached: {} queries were not started{}", unstarted, hint);
+    process::exit(DEADLINE_EXIT_CODE);
+}
 
-    fn save_session(&self) -> Result<()> {
-        if let Some(session) = &self.session {
-            let content = serde_json::to_string_pretty(session)?;
-            fs::write(SESSION_FILE, content)?;
-            println!("Session saved: {}", session.session_id);
+/// Runs the scan every `interval`, measured from each run's scheduled start,
+/// until `repeat` runs are done or Ctrl-C is pressed. Ctrl-C lets the current
+/// run finish; a second press exits at once. The conduit session is reused
+/// through the session file, and Wazuh tokens and the learned adaptive
+/// concurrency are carried between runs.
+async fn run_scan_loop(
+    mut config: ScanConfig,
+    interval: Duration,
+    repeat: Option<u64>,
+    overlap: Overlap,
+    summary_path: Option<&Path>,
+    state_path: Option<&Path>,
+) -> Result<()> {
+    let (stop_tx, mut stop) = watch::channel(false);
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if stop_tx.send_replace(true) {
+                process::exit(130);
+            }
+            eprintln!("\nStopping after the current run; press Ctrl-C again to abort");
+        }
+    });
+
+    let mut next_start = tokio::time::Instant::now();
+    let mut failed_runs = 0;
+    loop {
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        match repeat {
+            Some(total) => info!("\n=== Run {}/{} (started at {}) ===", config.run, total, started_at),
+            None => info!("\n=== Run {} (started at {}) ===", config.run, started_at),
+        }
+        let report = scan(config.clone()).await?;
+        info!("\nRun {} completed", config.run);
+        print_summary(&report);
+        if let Some(path) = summary_path {
+            Summary::new(&report).write(path)?;
+        }
+        if let Err(e) = scan_outcome(&report) {
+            eprintln!("Run {} failed: {}", config.run, e);
+            failed_runs += 1;
+        }
+        config.wazuh_tokens = report.wazuh_tokens;
+        if let Some(path) = state_path {
+            save_adaptive_state(path, report.adaptive_state.as_ref());
+        }
+        config.adaptive_state = report.adaptive_state;
+
+        if repeat.is_some_and(|total| config.run >= total) || *stop.borrow() {
+            break;
         }
-        Ok(())
-    }
 
-    fn sign_request(&self, data: &str) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(data.as_bytes());
-        hasher.update(self.client_key.as_bytes());
-        BASE64.encode(hasher.finalize())
-    }
-
-    fn verify_response(&self, response_data: &str, signature: &str) -> bool {
-        let mut hasher = Sha256::new();
-        hasher.update(response_data.as_bytes());
-        hasher.update(self.server_key.as_bytes());
-        let expected = BASE64.encode(hasher.finalize());
-        expected == signature
-    }
-
-    async fn stream_response(
-        stream: &mut tokio_native_tls::TlsStream<TcpStream>,
-    ) -> Result<String> {
-        let mut response_data = Vec::new();
-        let mut buffer = vec![0u8; BUFFER_SIZE];
-        let mut total_bytes = 0;
-        
-        print!("\rReceiving data: 0 bytes");
-        std::io::stdout().flush()?;
-
-        loop {
-            match stream.read(&mut buffer).await {
-                Ok(0) => {
-                    if total_bytes == 0 {
-                        return Err("Connection closed by server".into());
+        next_start += interval;
+        let now = tokio::time::Instant::now();
+        if next_start < now {
+            match overlap {
+                Overlap::Skip => {
+                    let mut skipped = 0;
+                    while next_start < now {
+                        next_start += interval;
+                        skipped += 1;
                     }
-                    break;
-                },
-                Ok(n) => {
-                    response_data.extend_from_slice(&buffer[..n]);
-                    total_bytes += n;
-                    print!("\rReceiving data: {} bytes", total_bytes);
-                    std::io::stdout().flush()?;
+                    info!("Scan overran the interval; skipped {} scheduled run(s)", skipped);
                 }
-                Err(e) => return Err(format!("Failed to read response: {}", e).into()),
+                Overlap::Queue => info!("Scan overran the interval; starting the next run now"),
             }
         }
-        println!("\nReceived total: {} bytes", total_bytes);
-
-        String::from_utf8(response_data)
-            .map_err(|e| format!("Invalid UTF-8 sequence: {}", e).into())
-    }
-
-    async fn send_request(
-        &mut self, 
-        stream: &mut tokio_native_tls::TlsStream<TcpStream>,
-        wql_query: String
-    ) -> Result<Response> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)?
-            .as_secs();
-        
-        let nonce = Uuid::new_v4().to_string();
-        
-        let data_to_sign = format!("{}:{}:{}", 
-            self.client_id,
-            timestamp,
-            nonce
-        );
-
-        let signature = self.sign_request(&data_to_sign);
+        info!("Next run in {}s", next_start.saturating_duration_since(now).as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_start) => {}
+            _ = stop.changed() => break,
+        }
+        config.run += 1;
+    }
 
-        let request = AuthRequest {
-            client_id: self.client_id.clone(),
-            timestamp,
-            nonce,
-            signature,
-            session_id: self.session.as_ref().map(|s| s.session_id.clone()),
-            wql_query,
-        };
+    if failed_runs > 0 {
+        return Err(format!("{} of {} runs had failures", failed_runs, config.run).into());
+    }
+    Ok(())
+}
 
-        let request_json = serde_json::to_string(&request)?;
-        println!("Sending request...");
-        stream.write_all(request_json.as_bytes()).await?;
-        stream.flush().await?;
+/// Prints the outcome of one health-check stage and returns whether it passed.
+#[cfg(feature = "gateway")]
+fn report_stage(name: &str, started: Instant, outcome: &Result<String>) -> bool {
+    let elapsed = started.elapsed().as_millis();
+    match outcome {
+        Ok(detail) => {
+            println!("[PASS] {} ({} ms): {}", name, elapsed, detail);
+            true
+        }
+        Err(e) => {
+            println!("[FAIL] {} ({} ms): {}", name, elapsed, e);
+            false
+        }
+    }
+}
 
-        println!("Waiting for response...");
-        let response_str = Self::stream_response(stream).await?;
-        
-        let mut response: Response = serde_json::from_str(&response_str)?;
-        
-        let signature = response.signature.clone();
-        response.signature = String::new();
-        let response_data = serde_json::to_string(&response)?;
-        
-        if !self.verify_response(&response_data, &signature) {
-            return Err("Invalid response signature".into());
+#[cfg(feature = "gateway")]
+async fn ping_conduit(client: &mut Client, server: &str, tls: &TlsConfig) -> Result<String> {
+    let spool_path = std::env::temp_dir().join(format!("conduit_ping_{}.partial", Uuid::new_v4()));
+    let mut renewed_session = false;
+    let ReceivedResponse { response, spooled_to, .. } = loop {
+        let mut stream = connect_with_retry(server, tls, client.retry_policy()).await?;
+        match client.send_request(&mut stream, PING_QUERY.to_string(), &spool_path).await {
+            Err(e) if !renewed_session && e.is::<SessionExpired>() => renewed_session = true,
+            outcome => break outcome?,
         }
+    };
+    if let Some(path) = spooled_to {
+        let _ = fs::remove_file(path);
+    }
+    if response.status {
+        Ok(format!("signed response received, session {}", response.session_id))
+    } else {
+        Err(format!("server reported failure: {}", response.data).into())
+    }
+}
 
-        response.signature = signature;
+#[cfg(feature = "gateway")]
+async fn run_auth_test(args: AuthTestArgs) -> Result<()> {
+    let tls = TlsConfig::new(&args.tls.options(args.conduit.verbose))?;
+    let session_cipher = match args.encrypt_session {
+        true => Some(args.key.require_cipher("--encrypt-session")?),
+        false => None,
+    };
+    let manager = args.gateway.single_manager()?;
+    let mut client = Client::new(args.conduit.client_config(session_cipher), args.retry.policy())
+        .with_gateway(manager.url.clone(), manager.wazuh_url.clone())
+        .with_http_options(&args.gateway.http_options())?;
+    client.set_token_refresh_buffer(Duration::from_secs(args.gateway.token_refresh_buffer_secs));
+    let mut all_passed = true;
 
-        self.session = Some(SessionInfo {
-            session_id: response.session_id.clone(),
-            client_id: self.client_id.clone(),
-            created_at: timestamp,
-            last_used: timestamp,
-        });
-        self.save_session()?;
+    let started = Instant::now();
+    let auth = sign_in(&mut client, &manager).await;
+    let authenticated = report_stage("gateway authentication", started, &auth);
+    all_passed &= authenticated;
 
-        Ok(response)
+    if authenticated {
+        let started = Instant::now();
+        let groups = client
+            .fetch_groups()
+            .await
+            .map(|groups| format!("{} groups visible", groups.len()));
+        all_passed &= report_stage("group listing", started, &groups);
+    } else {
+        println!("[SKIP] group listing: requires a gateway token");
+        all_passed = false;
     }
 
-    async fn authenticate(&mut self, username: &str, password: &str) -> Result<()> {
-        let auth_request = WazuhAuthRequest {
-            endpoint: self.wazuh_endpoint.clone(),
-            username: username.to_string(),
-            password: password.to_string(),
-        };
+    let started = Instant::now();
+    let ping = ping_conduit(&mut client, &args.server, &tls).await;
+    all_passed &= report_stage("conduit ping", started, &ping);
+
+    if all_passed {
+        println!("All health checks passed");
+        Ok(())
+    } else {
+        Err("One or more health checks failed".into())
+    }
+}
 
-        let response = self.http_client.post("http://localhost:3001/auth")
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .json(&auth_request)
-            .send()
-            .await?;
+#[cfg(feature = "gateway")]
+#[derive(serde::Serialize)]
+struct GroupTopology {
+    group: String,
+    agents: Vec<Agent>,
+}
 
-        let status = response.status();
-        let body = response.text().await?;
+#[cfg(feature = "gateway")]
+async fn run_topology(args: TopologyArgs) -> Result<()> {
+    // Progress messages would be mixed into the topology on stdout.
+    if args.output.is_none() {
+        set_quiet(true);
+    }
+    let manager = args.gateway.single_manager()?;
+    // Discovery only: no conduit identity is needed and no session is loaded or saved.
+    let config = ClientConfig {
+        client_id: String::new(),
+        client_key: String::new(),
+        server_key: String::new(),
+        max_clock_skew: MAX_CLOCK_SKEW,
+        clock_offset: ClockOffset::None,
+        max_in_memory: MAX_IN_MEMORY,
+        max_response_size: MAX_RESPONSE_SIZE,
+        spool_checkpoint: SPOOL_CHECKPOINT,
+        session_file: PathBuf::new(),
+        session_cipher: None,
+        sign_query: false,
+        require_query_hash: false,
+        format: ResultFormat::default(),
+        signature_algorithm: SignatureAlgorithm::default(),
+        signature_encoding: SignatureEncoding::default(),
+        wire_encoding: WireEncoding::default(),
+        verbosity: args.verbose,
+    };
+    let mut client = Client::new(config, args.retry.policy())
+        .with_gateway(manager.url.clone(), manager.wazuh_url.clone())
+        .with_http_options(&args.gateway.http_options())?;
+    client.set_token_refresh_buffer(Duration::from_secs(args.gateway.token_refresh_buffer_secs));
+    sign_in(&mut client, &manager).await?;
 
-        println!("Auth response status: {}", status);
-        println!("Auth response body: {}", body);
+    let mut topology = Vec::new();
+    for group in client.fetch_groups().await? {
+        let agents = client.fetch_agents(&group.id).await?;
+        topology.push(GroupTopology { group: group.name, agents });
+    }
 
-        if status.is_success() {
-            let auth_response: WazuhAuthResponse = serde_json::from_str(&body)?;
-            if let Some(token) = auth_response.token {
-                self.wazuh_token = Some(token);
-                Ok(())
-            } else {
-                Err("Authentication failed: No token received".into())
+    let content = match args.format {
+        TopologyFormat::Json => serde_json::to_string_pretty(&topology)? + "\n",
+        TopologyFormat::Csv => topology_csv(&topology),
+    };
+    match &args.output {
+        Some(path) => {
+            fs::write(path, content).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            let agents: usize = topology.iter().map(|g| g.agents.len()).sum();
+            info!("Wrote {} groups and {} agent entries to {}", topology.len(), agents, path.display());
+        }
+        None => print!("{}", content),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "gateway")]
+/// One row per agent, in order of first appearance, with all of its groups
+/// `;`-separated in the `group` column as `load_agents_file` expects.
+fn topology_csv(topology: &[GroupTopology]) -> String {
+    let mut agents: Vec<(&Agent, Vec<&str>)> = Vec::new();
+    for group in topology {
+        for agent in &group.agents {
+            match agents.iter_mut().find(|(seen, _)| seen.id == agent.id) {
+                Some((_, groups)) => groups.push(&group.group),
+                None => agents.push((agent, vec![&group.group])),
             }
-        } else {
-            Err(format!("Authentication failed: {}", body).into())
         }
     }
 
-    async fn fetch_groups(&self) -> Result<Vec<Group>> {
-        for attempt in 1..=MAX_RETRIES {
-            let wazuh_request = WazuhRequest {
-                endpoint: self.wazuh_endpoint.clone(),
-                token: self.wazuh_token.clone().unwrap(),
-                params: HashMap::new(),
-            };
+    let mut csv = String::from("id,name,group,platform,node,ip,status\n");
+    for (agent, groups) in agents {
+        let row = [
+            agent.id.as_str(),
+            agent.name.as_str(),
+            &groups.join(";"),
+            agent.platform.as_deref().unwrap_or(""),
+            agent.node.as_deref().unwrap_or(""),
+            agent.ip.as_deref().unwrap_or(""),
+            agent.status.as_deref().unwrap_or(""),
+        ];
+        csv.push_str(&row.map(csv_field).join(","));
+        csv.push('\n');
+    }
+    csv
+}
 
-            let response = self.http_client.post("http://localhost:3001/groups")
-                .header(reqwest::header::CONTENT_TYPE, "application/json")
-                .json(&wazuh_request)
-                .send()
-                .await?;
-            
-            let status = response.status();
-            let body = response.text().await?;
-            
-            println!("Response status: {}", status);
-            println!("Response body: {}", body);
-            
-            if status.is_success() {
-                let json: serde_json::Value = serde_json::from_str(&body)?;
-                if let Some(affected_items) = json["data"]["affected_items"].as_array() {
-                    let groups: Vec<Group> = affected_items
-                        .iter()
-                        .filter_map(|item| {
-                            Some(Group {
-                                id: item["name"].as_str()?.to_string(),
-                                name: item["name"].as_str()?.to_string(),
-                            })
-                        })
-                        .collect();
-                    println!("Parsed {} groups", groups.len());
-                    return Ok(groups);
-                } else {
-                    println!("Unexpected response structure: {:?}", json);
-                }
-            } else {
-                println!("Request failed with status: {}", status);
+/// Quotes a CSV field that holds a separator, quote or newline.
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+/// Uses the same [`Signer`](sensex_conduit::signing::Signer) and signing
+/// payload as `Client`, so the output can be compared with the server's.
+fn run_sign_debug(args: SignDebugArgs) -> Result<()> {
+    let conduit = &args.conduit;
+    let signer = conduit.signature_algorithm.signer();
+    println!("algorithm: {}", conduit.signature_algorithm.as_str());
+
+    if let (Some(data), Some(signature)) = (&args.verify_data, &args.signature) {
+        let expected = signer.sign(data.as_bytes(), conduit.server_key.as_bytes(), conduit.signature_encoding);
+        println!("expected signature: {}", expected);
+        return match signer.verify(data.as_bytes(), conduit.server_key.as_bytes(), signature, conduit.signature_encoding) {
+            Ok(()) => {
+                println!("signature: valid");
+                Ok(())
             }
-            
-            if attempt < MAX_RETRIES {
-                println!("Retrying in {} seconds...", RETRY_DELAY.as_secs());
-                sleep(RETRY_DELAY).await;
+            Err(InvalidSignature::Mismatch) => Err("Signature does not match the data and server key".into()),
+            Err(e) => Err(e.into()),
+        };
+    }
+
+    let timestamp = match args.timestamp {
+        Some(timestamp) => timestamp,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    let request = AuthRequest {
+        client_id: conduit.client_id.clone(),
+        timestamp,
+        nonce: args.nonce.clone().unwrap_or_else(|| Uuid::new_v4().to_string()),
+        signature: String::new(),
+        session_id: args.session_id.clone(),
+        wql_query: args.query.clone(),
+        request_id: String::new(),
+        signature_scheme: conduit.sign_query.then(|| SIGNATURE_SCHEME_V2.to_string()),
+        signature_algorithm: signer.algorithm().map(str::to_string),
+        signature_encoding: (conduit.signature_encoding != SignatureEncoding::Base64)
+            .then(|| conduit.signature_encoding.as_str().to_string()),
+        format: ResultFormat::default(),
+        encoding: None,
+    };
+    let data_to_sign = signing_payload(&request).expect("only known schemes are built");
+    println!("scheme: {}", request.signature_scheme.as_deref().unwrap_or("v1"));
+    println!("data_to_sign: {}", data_to_sign);
+    println!("signature: {}", signer.sign(data_to_sign.as_bytes(), conduit.client_key.as_bytes(), conduit.signature_encoding));
+    Ok(())
+}
+
+/// Lists every known-answer check and fails if any of them did.
+fn run_self_test(output: &mut impl Write) -> Result<()> {
+    let checks = selftest::run();
+    let mut failed = 0;
+    for check in &checks {
+        match &check.failure {
+            None => writeln!(output, "ok    {}", check.name)?,
+            Some(failure) => {
+                failed += 1;
+                writeln!(output, "FAIL  {}: {}", check.name, failure)?;
             }
         }
-        
-        Err(format!("Failed to fetch groups after {} attempts", MAX_RETRIES).into())
     }
+    if failed > 0 {
+        return Err(format!("{} of {} known-answer checks failed; this build does not sign as servers expect", failed, checks.len()).into());
+    }
+    writeln!(output, "All {} known-answer checks passed", checks.len())?;
+    Ok(())
+}
 
-    async fn fetch_agents(&self, group_id: &str) -> Result<Vec<Agent>> {
-        for attempt in 1..=MAX_RETRIES {
-            let mut params = HashMap::new();
-            params.insert("group_id".to_string(), group_id.to_string());
+fn run_list_queries(args: QueryArgs) -> Result<()> {
+    let query_files = load_query_files(&args.queries_dir, &args.queries, args.query_depth, args.query_order)?;
+    if query_files.is_empty() {
+        eprintln!("No WQL query files found in {} directory", args.queries_dir.display());
+        process::exit(1);
+    }
+    for query_file in query_files {
+        println!("{}", query_file.display());
+    }
+    Ok(())
+}
 
-            let wazuh_request = WazuhRequest {
-                endpoint: self.wazuh_endpoint.clone(),
-                token: self.wazuh_token.clone().unwrap(),
-                params,
-            };
+fn run_decrypt(args: DecryptArgs) -> Result<()> {
+    if args.output.is_some() && args.files.len() > 1 {
+        return Err("--output can only be used with a single input file".into());
+    }
+    let cipher = args.key.require_cipher("decrypt")?;
+    for file in &args.files {
+        let output = match &args.output {
+            Some(output) => output.clone(),
+            None if file.extension().is_some_and(|ext| ext == ENCRYPTED_EXTENSION) => file.with_extension(""),
+            None => return Err(format!(
+                "{} has no .{} suffix; pass --output to choose where to write it",
+                file.display(),
+                ENCRYPTED_EXTENSION
+            ).into()),
+        };
+        cipher
+            .decrypt_file(file, &output)
+            .map_err(|e| format!("Failed to decrypt {}: {}", file.display(), e))?;
+        info!("Decrypted {} -> {}", file.display(), output.display());
+    }
+    Ok(())
+}
 
-            let response = self.http_client.post(&format!("http://localhost:3001/groups/{}/agents", group_id))
-                .header(reqwest::header::CONTENT_TYPE, "application/json")
-                .json(&wazuh_request)
-                .send()
-                .await?;
-            
-            let status = response.status();
-            let body = response.text().await?;
-            
-            println!("Response status: {}", status);
-            println!("Response body: {}", body);
-            
-            if status.is_success() {
-                let json: serde_json::Value = serde_json::from_str(&body)?;
-                if let Some(affected_items) = json["data"]["affected_items"].as_array() {
-                    let agents: Vec<Agent> = affected_items
-                        .iter()
-                        .filter_map(|item| {
-                            Some(Agent {
-                                id: item["id"].as_str()?.to_string(),
-                                name: item["name"].as_str()?.to_string(),
-                            })
-                        })
-                        .collect();
-                    println!("Parsed {} agents for group {}", agents.len(), group_id);
-                    return Ok(agents);
-                } else {
-                    println!("Unexpected response structure: {:?}", json);
-                }
-            } else {
-                println!("Request failed with status: {}", status);
-            }
-            
-            if attempt < MAX_RETRIES {
-                println!("Retrying in {} seconds...", RETRY_DELAY.as_secs());
-                sleep(RETRY_DELAY).await;
+fn run_verify(args: VerifyArgs) -> Result<()> {
+    let cipher = args.key.cipher()?;
+    let mut failed = 0;
+    for file in &args.files {
+        match verify_file(file, &args.server_key, cipher.as_ref()) {
+            Ok(()) => println!("{}: signature valid", file.display()),
+            Err(e) => {
+                failed += 1;
+                println!("{}: {}", file.display(), e);
             }
         }
-        
-        Err(format!("Failed to fetch agents for group {} after {} attempts", group_id, MAX_RETRIES).into())
     }
+    if failed > 0 {
+        return Err(format!("{} of {} results failed verification", failed, args.files.len()).into());
+    }
+    Ok(())
 }
 
-fn get_wql_query_files() -> Result<Vec<PathBuf>> {
-    let mut query_files = Vec::new();
-    for entry in fs::read_dir(WQL_QUERIES_DIR)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file() && path.extension().map_or(false, |ext| ext == "json") {
-            query_files.push(path);
-        }
+/// Verifies every result under `args.dir` that has a sidecar, then prints
+/// the tally and each failure. Files without a sidecar are not results of
+/// `--include-metadata` and are passed over.
+fn run_verify_dir(args: VerifyDirArgs) -> Result<()> {
+    let cipher = args.key.cipher()?;
+    let mut results = Vec::new();
+    find_archived_results(&args.dir, &mut results).map_err(|e| format!("Failed to read {}: {}", args.dir.display(), e))?;
+    if results.is_empty() {
+        return Err(format!("No results with .meta.json sidecars under {}", args.dir.display()).into());
+    }
+    results.sort();
+    let failures: Vec<(&PathBuf, String)> = results
+        .iter()
+        .filter_map(|file| verify_file(file, &args.server_key, cipher.as_ref()).err().map(|e| (file, e.to_string())))
+        .collect();
+    println!("{} passed, {} failed", results.len() - failures.len(), failures.len());
+    for (file, e) in &failures {
+        println!("FAIL  {}: {}", file.display(), e);
     }
-    Ok(query_files)
+    if !failures.is_empty() {
+        return Err(format!("{} of {} results failed verification", failures.len(), results.len()).into());
+    }
+    Ok(())
 }
 
-async fn connect_with_retry(
-    addr: &str,
-    connector: &TokioTlsConnector,
-) -> Result<tokio_native_tls::TlsStream<TcpStream>> {
-    let mut last_error = None;
-    for _ in 0..MAX_RETRIES {
-        match TcpStream::connect(addr).await {
-            Ok(stream) => {
-                return Ok(connector.connect("localhost", stream).await?);
-            }
-            Err(e) => {
-                last_error = Some(e);
-                sleep(RETRY_DELAY).await;
+/// Collects the result of every `.meta.json` sidecar under `dir`, whether
+/// or not the result itself is still there. Symlinks are not followed.
+fn find_archived_results(dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_archived_results(&entry.path(), found)?;
+        } else if file_type.is_file() {
+            let name = entry.file_name();
+            if let Some(result) = name.to_str().and_then(|name| name.strip_suffix(".meta.json")) {
+                found.push(dir.join(result));
             }
         }
     }
-    Err(format!("Failed to connect after {} retries: {:?}", MAX_RETRIES, last_error.unwrap()).into())
+    Ok(())
+}
+
+/// Recovers the data `file` was received with and checks it against the
+/// signature in its sidecar.
+fn verify_file(file: &Path, server_key: &str, cipher: Option<&OutputCipher>) -> Result<()> {
+    let saved = SavedSignature::load(file)?;
+    if !saved.verifiable {
+        return Err("cannot be verified: it was reformatted or its server did not echo the format and query hash".into());
+    }
+    let mut bytes = fs::read(file).map_err(|e| format!("Failed to read: {}", e))?;
+    if saved.encrypted {
+        let cipher = cipher.ok_or("is encrypted; pass --encryption-passphrase or --encryption-key-file")?;
+        bytes = cipher.decrypt(&bytes)?;
+    }
+    let bytes = saved.compression.decompress(&bytes)?;
+    let data = String::from_utf8(bytes).map_err(|_| "cannot be verified: the result is not UTF-8")?;
+    verify_saved_result(&data, &saved, server_key)?;
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    dotenv().ok();
+    let args: Vec<String> = std::env::args().collect();
+    // Set before clap parses so env file messages are silenced too.
+    set_quiet(args.iter().any(|arg| arg == "-q" || arg == "--quiet"));
+    let managers = load_environment(&args)?;
 
-    let server_addr = env::args().nth(1).unwrap_or_else(|| {
-        eprintln!("Usage: client <server_address:port>");
-        eprintln!("Example: client 192.168.1.100:8080");
-        process::exit(1);
-    });
+    let cli = Cli::parse_from(args);
+    set_quiet(cli.quiet);
+    match cli.command {
+        Command::Scan(args) => run_scan(args, managers).await,
+        #[cfg(feature = "gateway")]
+        Command::AuthTest(args) => run_auth_test(args).await,
+        Command::ListQueries(args) => run_list_queries(args),
+        #[cfg(feature = "gateway")]
+        Command::Topology(args) => run_topology(args).await,
+        Command::SignDebug(args) => run_sign_debug(args),
+        Command::SelfTest => run_self_test(&mut io::stdout()),
+        Command::Decrypt(args) => run_decrypt(args),
+        Command::Verify(args) => run_verify(args),
+        Command::VerifyDir(args) => run_verify_dir(args),
+    }
+}
 
-    println!("Loading WQL query files...");
-    let query_files = get_wql_query_files()?;
-    if query_files.is_empty() {
-        eprintln!("No WQL query files found in {} directory", WQL_QUERIES_DIR);
-        process::exit(1);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use sensex_conduit::{ManagerFailure, PlannedGroup};
+
+    fn parse(args: &[&str]) -> std::result::Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("client").chain(args.iter().copied()))
     }
 
-    let wazuh_url = env::var("WAZUH_URL").expect("WAZUH_URL must be set in .env file");
-    let wazuh_username = env::var("WAZUH_USERNAME").expect("WAZUH_USERNAME must be set in .env file");
-    let wazuh_password = env::var("WAZUH_PASSWORD").expect("WAZUH_PASSWORD must be set in .env file");
-
-    let mut client = Client::new(
-        "client1".to_string(),
-        "test_key_1".to_string(),
-        "server_key".to_string(),
-        wazuh_url,
-    );
-
-    // Authenticate and get a token
-    client.authenticate(&wazuh_username, &wazuh_password).await?;
-    
-    let connector = TlsConnector::builder()
-        .danger_accept_invalid_certs(true)
-        .build()?;
-    let connector = TokioTlsConnector::from(connector);
-    
-    let output_dir = "query_results";
-    fs::create_dir_all(output_dir)?;
-
-    println!("Fetching groups...");
-    let groups = client.fetch_groups().await?;
-    println!("Fetched {} groups", groups.len());
-
-    for group in groups {
-        // Create a directory for the group
-        let group_dir = format!("{}/{}", output_dir, group.name.replace(" ", "_"));
-        fs::create_dir_all(&group_dir)?;
-        println!("Created directory for group: {}", group_dir);
-
-        println!("Fetching agents for group: {}", group.name);
-        let agents = client.fetch_agents(&group.id).await?;
-        println!("Fetched {} agents for group {}", agents.len(), group.name);
-
-        for agent in agents {
-            for query_file in &query_files {
-                println!("\nExecuting query for agent {}: {:?}", agent.name, query_file);
-                
-                let mut query_content = fs::read_to_string(&query_file)?;
-                query_content = query_content.replace("{{agent_id}}", &agent.id);
-                query_content = query_content.replace("{{agent_name}}", &agent.name);
-                
-                println!("Connecting to server at {}...", server_addr);
-                let mut stream = connect_with_retry(&server_addr, &connector).await?;
-                println!("TLS connection established");
-                
-                let response = client.send_request(&mut stream, query_content).await?;
-                
-                if response.status {
-                    let query_name = query_file.file_stem().unwrap().to_string_lossy();
-                    let output_file = format!("{}/{}_{}_{}.json", 
-                        group_dir,
-                        query_name,
-                        agent.name.replace(" ", "_"),
-                        SystemTime::now()
-                            .duration_since(UNIX_EPOCH)?
-                            .as_secs()
-                    );
-                    
-                    fs::write(&output_file, &response.data)?;
-                    println!("Query result saved to: {}", output_file);
-                } else {
-                    eprintln!("Query failed: {}", response.data);
-                }
-                
-                sleep(RECONNECT_DELAY).await;
-            }
+    fn scan_args(args: &[&str]) -> ScanArgs {
+        match parse(&[&["scan"], args].concat()).expect("valid scan arguments").command {
+            Command::Scan(args) => args,
+            other => panic!("expected a scan, got {:?}", other),
         }
     }
 
-    println!("\nAll queries completed");
-    Ok(())
+    #[test]
+    fn cli_definition_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn scan_takes_the_server_as_a_positional_argument() {
+        let args = scan_args(&["10.0.0.5:8080"]);
+        assert_eq!(args.server.as_deref(), Some("10.0.0.5:8080"));
+        assert_eq!(args.output_dir, PathBuf::from(OUTPUT_DIR));
+        assert_eq!(args.queries.queries_dir, PathBuf::from(WQL_QUERIES_DIR));
+    }
+
+    #[test]
+    fn server_address_needs_host_and_port() {
+        assert!(parse(&["scan", "10.0.0.5"]).is_err());
+        assert!(parse(&["scan", ":8080"]).is_err());
+        assert!(parse(&["scan", "conduit:notaport"]).is_err());
+        assert_eq!(parse_server_addr("[::1]:8080").as_deref(), Ok("[::1]:8080"));
+    }
+
+    #[test]
+    fn global_flags_are_accepted_after_the_subcommand() {
+        let cli = parse(&["list-queries", "--quiet", "--env", "staging"]).unwrap();
+        assert!(cli.quiet);
+        assert_eq!(cli.env_name.as_deref(), Some("staging"));
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        let error = parse(&["scan", "localhost:8080", "--no-such-flag"]).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::UnknownArgument);
+    }
+
+    #[test]
+    fn agent_and_group_filters_are_repeatable_and_exclusive() {
+        let args = scan_args(&["localhost:8080", "--group", "web", "--group", "db"]);
+        assert_eq!(args.groups, ["web", "db"]);
+        let args = scan_args(&["localhost:8080", "--agent", "001", "--agent", "002"]);
+        assert_eq!(args.agents, ["001", "002"]);
+
+        let error = parse(&["scan", "localhost:8080", "--agent", "001", "--group", "web"]).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn healthcheck_is_an_alias_of_auth_test() {
+        let cli = parse(&["healthcheck", "localhost:8080"]).unwrap();
+        assert!(matches!(cli.command, Command::AuthTest(args) if args.server == "localhost:8080"));
+    }
+
+    #[test]
+    fn certificates_are_verified_unless_insecure_is_passed() {
+        assert!(!scan_args(&["127.0.0.1:8080"]).tls.options(0).insecure);
+        assert!(scan_args(&["127.0.0.1:8080", "--insecure"]).tls.options(0).insecure);
+    }
+
+    #[test]
+    fn cacert_is_repeatable_and_comma_separated() {
+        let args = scan_args(&["127.0.0.1:8080", "--cacert", "a.pem", "--cacert", "b.pem,c.pem"]);
+        assert_eq!(args.tls.ca_certs, [PathBuf::from("a.pem"), PathBuf::from("b.pem"), PathBuf::from("c.pem")]);
+    }
+
+    #[test]
+    fn bootstrap_options_are_found_in_either_spelling() {
+        let args: Vec<String> = ["client", "--env", "staging", "scan", "--config=conduit.toml"].map(String::from).to_vec();
+        assert_eq!(bootstrap_option(&args, "env").as_deref(), Some("staging"));
+        assert_eq!(bootstrap_option(&args, "config").as_deref(), Some("conduit.toml"));
+        assert_eq!(bootstrap_option(&args, "quiet"), None);
+    }
+
+    #[test]
+    fn config_passwords_come_from_exactly_one_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("password");
+        fs::write(&file, "from-file\n").unwrap();
+        let password = Some("inline".to_string());
+
+        assert_eq!(resolve_password("gateway", &password, &None, &None).unwrap().as_deref(), Some("inline"));
+        assert_eq!(resolve_password("gateway", &None, &None, &Some(file.clone())).unwrap().as_deref(), Some("from-file"));
+        assert_eq!(resolve_password("gateway", &None, &None, &None).unwrap(), None);
+        assert!(resolve_password("gateway", &password, &None, &Some(file)).is_err());
+    }
+
+    #[test]
+    fn config_files_become_the_environment_of_the_flags() {
+        let config: ConfigFile = toml::from_str(
+            "[gateway]\nurl = \"http://gateway\"\n[tls]\ncacert = [\"a.pem\", \"b.pem\"]\n[scan]\norganize_by = \"os\"\n",
+        )
+        .unwrap();
+        let overrides = config.env_overrides().unwrap();
+        assert_eq!(
+            overrides,
+            [
+                ("GATEWAY_URL", "http://gateway".to_string()),
+                ("CONDUIT_CACERT", "a.pem,b.pem".to_string()),
+                ("CONDUIT_ORGANIZE_BY", "os".to_string()),
+            ]
+        );
+    }
+
+    /// The scan configuration `args` produce, with the Wazuh settings filled in.
+    fn scan_config(args: &[&str]) -> ScanConfig {
+        let required = ["127.0.0.1:8080", "--wazuh-url", "https://wazuh.test:55000", "--wazuh-username", "wazuh"];
+        let args = [&required[..], &["--wazuh-password", "secret"], args].concat();
+        scan_args(&args).into_config(Vec::new()).expect("valid scan configuration")
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn only_discovered_groups_default_to_active_agents() {
+        assert_eq!(scan_config(&[]).statuses, [AgentStatus::Active]);
+        assert!(scan_config(&["--agent", "001"]).statuses.is_empty());
+        let dir = tempfile::tempdir().unwrap();
+        let agents_file = dir.path().join("agents.csv");
+        fs::write(&agents_file, "id,name,group\n001,web-1,web\n").unwrap();
+        assert!(scan_config(&["--agents-file", agents_file.to_str().unwrap()]).statuses.is_empty());
+        let statuses = scan_config(&["--agent", "001", "--status", "disconnected,never_connected"]).statuses;
+        assert_eq!(statuses, [AgentStatus::Disconnected, AgentStatus::NeverConnected]);
+        assert_eq!(scan_config(&["--status", "all"]).statuses, [AgentStatus::All]);
+        assert!(parse(&["scan", "localhost:8080", "--status", "actve"]).is_err());
+    }
+
+    #[test]
+    fn print_query_renders_each_query_for_each_agent_and_flags_unresolved_placeholders() {
+        let dir = tempfile::tempdir().unwrap();
+        let queries = dir.path().join("queries");
+        fs::create_dir(&queries).unwrap();
+        fs::write(queries.join("alerts.json"), r#"{"agent":"{{agent_id}}","name":"{{agent_name}}","gte":"{{since}}"}"#).unwrap();
+        fs::write(dir.path().join("vars.json"), r#"{"since":"now-1h"}"#).unwrap();
+        fs::write(dir.path().join("agents.json"), r#"[{"id":"001","name":"web-1","group":"web"}]"#).unwrap();
+        let path = |name: &str| dir.path().join(name).display().to_string();
+        let args = scan_args(&[
+            "127.0.0.1:8080",
+            "--print-query",
+            "--queries-dir",
+            &path("queries"),
+            "--vars",
+            &path("vars.json"),
+            "--agents-file",
+            &path("agents.json"),
+            "--agent",
+            "001",
+        ]);
+        let mut output = Vec::new();
+        print_queries(&args, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "# alerts for agent 001 (web-1) via 127.0.0.1:8080\n{\"agent\":\"001\",\"name\":\"web-1\",\"gte\":\"now-1h\"}\n\n"
+        );
+
+        let args = scan_args(&["127.0.0.1:8080", "--print-query", "--queries-dir", &path("queries"), "--agent", "002"]);
+        let mut output = Vec::new();
+        let error = print_queries(&args, &mut output).unwrap_err();
+        assert_eq!(error.to_string(), "1 of 1 rendered queries have unresolved placeholders");
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\"agent\":\"002\",\"name\":\"{{agent_name}}\""), "{}", output);
+        assert!(output.contains("# unresolved: {{agent_name}}, {{since}}\n"), "{}", output);
+
+        assert!(parse(&["scan", "127.0.0.1:8080", "--print-query"]).is_err());
+    }
+
+    #[test]
+    fn the_plan_lists_each_group_and_estimates_past_the_deadline() {
+        let group = |manager: &str, name: &str, agents, queries| PlannedGroup {
+            manager: Some(manager.to_string()),
+            group: Group { id: name.to_string(), name: name.to_string() },
+            agents,
+            queries,
+        };
+        let mut plan = ScanPlan {
+            groups: vec![group("east", "web", 2, 4), group("east", "db", 1, 2), group("west", "web", 30, 60)],
+            manager_failures: Vec::new(),
+            query_files: 2,
+            group_concurrency: 2,
+            max_concurrency_per_group: 1,
+        };
+        let deadline = SystemTime::now() + Duration::from_secs(90);
+        let mut output = Vec::new();
+        print_plan(&plan, Duration::from_millis(1500), Some(deadline), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(
+            "east/web: 2 agents, 4 queries\neast/db: 1 agents, 2 queries\nwest/web: 30 agents, 60 queries\n\
+             Total: 66 queries on 33 agents in 3 groups (2 query files)\n\
+             Estimated duration: 1m 36s at 1500 ms per query, 2 groups at a time, 1 agents each\n\
+             Warning: the deadline is "
+        ), "{}", output);
+
+        plan.manager_failures.push(ManagerFailure { manager: "north".to_string(), message: "unreachable".to_string() });
+        let mut output = Vec::new();
+        let error = print_plan(&plan, Duration::from_secs(1), None, &mut output).unwrap_err();
+        assert_eq!(error.to_string(), "Discovery failed on 1 of the managers");
+        assert!(String::from_utf8(output).unwrap().contains("north: discovery failed: unreachable\n"));
+
+        assert!(parse(&["scan", "127.0.0.1:8080", "--plan", "--print-query", "--agent", "001"]).is_err());
+    }
+
+    #[test]
+    fn the_concurrency_state_is_saved_and_a_missing_or_bad_file_starts_afresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("concurrency.json");
+        assert_eq!(load_adaptive_state(&path), None);
+
+        let state = AdaptiveState { key: "127.0.0.1:8080 max 4".to_string(), limit: 3, fastest: Some(Duration::from_millis(40)) };
+        save_adaptive_state(&path, Some(&state));
+        assert_eq!(load_adaptive_state(&path), Some(state));
+        save_adaptive_state(&path, None);
+        assert!(load_adaptive_state(&path).is_some());
+
+        fs::write(&path, "not json").unwrap();
+        assert_eq!(load_adaptive_state(&path), None);
+        assert!(parse(&["scan", "127.0.0.1:8080", "--concurrency-state", "state.json"]).is_err());
+    }
+
+    #[test]
+    fn the_self_test_lists_each_check_and_passes() {
+        assert!(matches!(parse(&["self-test"]).unwrap().command, Command::SelfTest));
+        let mut output = Vec::new();
+        run_self_test(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("ok    request v2 hmac-sha256/base64\n"), "{}", output);
+        assert!(output.contains("ok    rfc 4231 hmac-sha512/hex\n"), "{}", output);
+        assert!(output.ends_with("All 14 known-answer checks passed\n"), "{}", output);
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn retention_limits_need_file_output() {
+        let retention = scan_config(&["--retain", "5", "--retain-age", "7d"]).retention;
+        assert_eq!((retention.keep, retention.max_age), (Some(5), Some(Duration::from_secs(7 * 86_400))));
+        assert!(scan_config(&[]).retention.is_unlimited());
+        assert!(parse(&["scan", "127.0.0.1:8080", "--retain", "0"]).is_err());
+
+        let required = ["127.0.0.1:8080", "--wazuh-url", "https://wazuh.test:55000", "--wazuh-username", "wazuh"];
+        let args = scan_args(&[&required[..], &["--wazuh-password", "secret", "--retain", "5", "--sink", "stdout"]].concat());
+        let error = args.into_config(Vec::new()).err().unwrap();
+        assert_eq!(error.to_string(), "--retain and --retain-age prune result files and need --sink file");
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn the_progress_bar_is_shown_only_when_asked_for() {
+        assert!(!scan_config(&[]).progress);
+        assert!(scan_config(&["--progress"]).progress);
+    }
+
+    #[test]
+    fn every_missing_wazuh_setting_is_listed() {
+        let error = scan_args(&["127.0.0.1:8080"]).gateway.single_manager().err().unwrap().to_string();
+        for name in ["WAZUH_URL", "WAZUH_USERNAME", "WAZUH_PASSWORD"] {
+            assert!(error.contains(name), "{}", error);
+        }
+
+        let args = ["127.0.0.1:8080", "--wazuh-url", "https://wazuh.test:55000", "--wazuh-username", "wazuh"];
+        let error = scan_args(&args).gateway.single_manager().err().unwrap().to_string();
+        assert!(error.contains("WAZUH_PASSWORD") && !error.contains("WAZUH_URL"), "{}", error);
+    }
+
+    #[test]
+    fn a_supplied_wazuh_token_stands_in_for_the_credentials() {
+        let token = "eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiJ3YXp1aCJ9.sig";
+        let args = ["127.0.0.1:8080", "--wazuh-url", "https://wazuh.test:55000", "--token", token];
+        let manager = scan_args(&args).gateway.single_manager().unwrap();
+        assert_eq!(manager.token.as_deref(), Some(token));
+        assert!(manager.username.is_empty());
+
+        // Credentials that would renew it must be complete.
+        let error = scan_args(&[&args[..], &["--wazuh-username", "wazuh"]].concat())
+            .gateway
+            .single_manager()
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("WAZUH_PASSWORD") && !error.contains("WAZUH_USERNAME"), "{}", error);
+
+        let error = scan_args(&["127.0.0.1:8080", "--wazuh-url", "https://wazuh.test:55000", "--token", "opaque"])
+            .gateway
+            .single_manager()
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("not a JWT"), "{}", error);
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn gateway_pool_flags_reach_the_http_options() {
+        let config = scan_config(&[
+            "--gateway-pool-max-idle", "4",
+            "--gateway-pool-idle-timeout-secs", "30",
+            "--gateway-connect-timeout-secs", "2",
+            "--gateway-timeout-secs", "9",
+            "--gateway-http2",
+        ]);
+        let expected = HttpOptions {
+            pool_max_idle_per_host: 4,
+            pool_idle_timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(2),
+            request_timeout: Duration::from_secs(9),
+            http2_prior_knowledge: true,
+            ..HttpOptions::default()
+        };
+        assert_eq!(config.http, expected);
+        assert_eq!(scan_config(&[]).http, HttpOptions::default());
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn gateway_headers_are_parsed_and_validated() {
+        let config = scan_config(&[
+            "--gateway-header", "X-Route: soc-eu",
+            "--gateway-header", "X-Trace:a:b",
+            "--gateway-user-agent", "soc-scanner/2",
+        ]);
+        let headers = [("X-Route", "soc-eu"), ("X-Trace", "a:b")].map(|(n, v)| (n.to_string(), v.to_string()));
+        assert_eq!(config.http.headers, headers);
+        assert_eq!(config.http.user_agent, "soc-scanner/2");
+        assert_eq!(scan_config(&[]).http.user_agent, DEFAULT_USER_AGENT);
+
+        for bad in ["X-Route", "X Route: soc", "Host: gateway.test"] {
+            assert!(parse(&["scan", "127.0.0.1:1", "--gateway-header", bad]).is_err(), "{}", bad);
+        }
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn a_deadline_is_a_fixed_time_or_a_duration_from_now() {
+        let config = scan_config(&["--deadline", "2026-10-15T06:00:00+02:00"]);
+        assert_eq!(config.deadline, Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_792_036_800)));
+        assert_eq!(config.deadline_grace, Duration::from_secs(30));
+
+        let before = SystemTime::now();
+        let config = scan_config(&["--max-duration", "2h", "--deadline-grace", "1m"]);
+        let deadline = config.deadline.unwrap().duration_since(before).unwrap();
+        assert!(deadline >= Duration::from_secs(7200) && deadline < Duration::from_secs(7260), "{:?}", deadline);
+        assert_eq!(config.deadline_grace, Duration::from_secs(60));
+
+        assert!(parse(&["scan", "127.0.0.1:1", "--deadline", "06:00"]).is_err());
+        assert!(parse(&["scan", "127.0.0.1:1", "--deadline", "2026-10-15T06:00:00Z", "--max-duration", "1h"]).is_err());
+        assert!(scan_config(&[]).deadline.is_none());
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn json_output_defaults_to_passthrough_and_pretty_is_its_shorthand() {
+        assert_eq!(scan_config(&[]).json_output, JsonOutput::Passthrough);
+        assert_eq!(scan_config(&["--json-output", "compact"]).json_output, JsonOutput::Compact);
+        assert_eq!(scan_config(&["--pretty"]).json_output, JsonOutput::Pretty);
+        assert!(parse(&["scan", "127.0.0.1:1", "--pretty", "--json-output", "compact"]).is_err());
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn the_clock_offset_is_signed_seconds_or_measured_from_the_server() {
+        assert_eq!(scan_config(&["--clock-offset", "-90"]).client.clock_offset, ClockOffset::Fixed(-90));
+        assert_eq!(scan_config(&["--clock-offset", "server"]).client.clock_offset, ClockOffset::Server);
+        assert_eq!(scan_config(&[]).client.clock_offset, ClockOffset::None);
+        assert!(parse(&["scan", "127.0.0.1:1", "--clock-offset", "5m"]).is_err());
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn inventory_servers_get_their_own_output_session_and_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inventory.toml");
+        fs::write(
+            &path,
+            "[[servers]]\nname = \"east\"\naddress = \"10.0.0.1:8080\"\n\n\
+             [[servers]]\nname = \"west 2\"\naddress = \"10.0.0.2:8080\"\nsni = \"conduit.west\"\ninsecure = true\n",
+        )
+        .unwrap();
+        let servers = load_server_inventory(&path).unwrap();
+        let base = scan_config(&["--cacert", "ca.pem"]);
+        let configs: Vec<ScanConfig> = servers.iter().map(|server| server.apply(&base)).collect();
+
+        assert_eq!(configs[0].server, "10.0.0.1:8080");
+        assert_eq!(configs[0].output_dir, base.output_dir.join("east"));
+        assert_eq!(configs[0].client.session_file, PathBuf::from("session.east.json"));
+        assert_eq!(configs[0].tls.ca_certs, [PathBuf::from("ca.pem")]);
+        assert_eq!(configs[1].output_dir, base.output_dir.join("west_2"));
+        assert_eq!(configs[1].tls.server_name.as_deref(), Some("conduit.west"));
+        assert!(configs[1].tls.insecure && configs[1].tls.ca_certs.is_empty());
+    }
+
+    #[test]
+    fn inventories_with_duplicate_names_or_bad_addresses_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let load = |content: &str| {
+            let path = dir.path().join("inventory.yaml");
+            fs::write(&path, content).unwrap();
+            load_server_inventory(&path).unwrap_err().to_string()
+        };
+        let twice = "servers:\n  - {name: east, address: '10.0.0.1:8080'}\n  - {name: east, address: '10.0.0.2:8080'}\n";
+        assert!(load(twice).contains("unique, non-empty name"), "{}", load(twice));
+        let bad = "servers:\n  - {name: east, address: 'no port'}\n";
+        assert!(load(bad).contains("server east"), "{}", load(bad));
+        assert!(load("servers: []\n").contains("lists no servers"));
+    }
+
+    #[test]
+    fn selections_are_numbers_and_ranges_from_one() {
+        assert_eq!(parse_selection("", 3).unwrap(), [0, 1, 2]);
+        assert_eq!(parse_selection(" ALL\n", 3).unwrap(), [0, 1, 2]);
+        assert_eq!(parse_selection("3, 1-2,2", 4).unwrap(), [0, 1, 2]);
+        assert_eq!(parse_selection("4-4", 4).unwrap(), [3]);
+        for bad in ["0", "5", "2-1", "x", "1-"] {
+            assert!(parse_selection(bad, 4).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn an_invalid_selection_is_asked_for_again() {
+        let items = ["alerts", "logons", "vulns"].map(String::from);
+        let mut input = io::Cursor::new("7\n1,3\n");
+        let mut output = Vec::new();
+        assert_eq!(prompt_selection("Queries", &items, &mut input, &mut output).unwrap(), [0, 2]);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("    2. logons"), "{}", output);
+        assert!(output.contains("\"7\" is not a number from 1 to 3"), "{}", output);
+
+        let error = prompt_selection("Queries", &items, &mut io::Cursor::new(""), &mut Vec::new()).unwrap_err();
+        assert_eq!(error.to_string(), "Selection aborted");
+    }
+
+    #[tokio::test]
+    async fn the_scan_is_narrowed_to_the_picked_queries_and_agents() {
+        let dir = tempfile::tempdir().unwrap();
+        let queries = dir.path().join("queries");
+        fs::create_dir(&queries).unwrap();
+        for name in ["alerts", "logons", "vulns"] {
+            fs::write(queries.join(format!("{}.json", name)), "{}").unwrap();
+        }
+        let agents = dir.path().join("agents.csv");
+        fs::write(&agents, "id,name,group\n001,web-1,web\n002,db-1,db\n003,web-2,web\n").unwrap();
+        let mut config = scan_config(&[
+            "--queries-dir",
+            queries.to_str().unwrap(),
+            "--agents-file",
+            agents.to_str().unwrap(),
+            "--group",
+            "web",
+        ]);
+
+        // Queries 1 and 3, then the web group, then its second agent.
+        let mut input = io::Cursor::new("1,3\n1\n2\n");
+        let mut output = Vec::new();
+        select_interactively(&mut config, &mut input, &mut output).await.unwrap();
+
+        assert_eq!(config.queries, ["alerts", "vulns"]);
+        assert_eq!(config.agents, ["003"]);
+        assert!(config.groups.is_empty());
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("1. web (2 agents)") && !output.contains("db ("), "{}", output);
+        assert!(output.contains("2. web-2 (003)"), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn only_agents_on_the_requested_nodes_and_statuses_are_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let queries = dir.path().join("queries");
+        fs::create_dir(&queries).unwrap();
+        fs::write(queries.join("alerts.json"), "{}").unwrap();
+        let agents = dir.path().join("agents.csv");
+        let inventory = "id,name,group,node,status\n\
+                         001,web-1,web,worker-1,active\n\
+                         002,web-2,web,worker-1,disconnected\n\
+                         003,web-3,web,worker-2,active\n\
+                         004,db-1,db,worker-2,active\n";
+        fs::write(&agents, inventory).unwrap();
+        let args = ["--queries-dir", queries.to_str().unwrap(), "--agents-file", agents.to_str().unwrap(), "--node", "worker-1"];
+        let mut config = scan_config(&[&args[..], &["--status", "active"]].concat());
+
+        let mut input = io::Cursor::new("\n\n\n");
+        let mut output = Vec::new();
+        select_interactively(&mut config, &mut input, &mut output).await.unwrap();
+
+        assert_eq!(config.agents, ["001"]);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("1. web (1 agents)") && !output.contains("db ("), "{}", output);
+        assert!(!output.contains("web-2") && !output.contains("web-3"), "{}", output);
+
+        // Every status is listed unless --status narrows it.
+        let mut config = scan_config(&args);
+        let mut input = io::Cursor::new("\n\n\n");
+        select_interactively(&mut config, &mut input, &mut Vec::new()).await.unwrap();
+        assert_eq!(config.agents, ["001", "002"]);
+    }
 }
@@ -7,12 +7,13 @@ use governor::{
 use native_tls::{Identity, TlsAcceptor};
 use nonzero_ext::nonzero;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha512};
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::sync::Mutex;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{env, fs, io::prelude::*};
+use std::{env, fs};
 use std::{process::Command, sync::Arc};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
@@ -21,6 +22,11 @@ use uuid::Uuid;
 
 type Result<T> = std::result::Result<T, String>;
 
+const SESSION_EXPIRED: &str = "session_expired";
+const SIGNATURE_SCHEME_V2: &str = "v2";
+/// `AuthRequest.encoding` asking for MessagePack responses.
+const MSGPACK: &str = "msgpack";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Response {
     status: bool,
@@ -28,6 +34,23 @@ struct Response {
     session_id: String,
     timestamp: u64,
     signature: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    format: Option<ResultFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    query_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ResultFormat {
+    #[default]
+    Json,
+    Csv,
+    Raw,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,6 +61,18 @@ struct AuthRequest {
     signature: String,
     session_id: Option<String>,
     wql_query: String,
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(default)]
+    signature_scheme: Option<String>,
+    #[serde(default)]
+    signature_algorithm: Option<String>,
+    #[serde(default)]
+    signature_encoding: Option<String>,
+    #[serde(default)]
+    format: ResultFormat,
+    #[serde(default)]
+    encoding: Option<String>,
 }
 
 #[derive(Debug)]
@@ -52,14 +87,17 @@ struct ServerState {
     rate_limiter: RateLimiter<NotKeyed, InMemoryState, DefaultClock>,
     sessions: Mutex<HashMap<String, Session>>,
     client_keys: Mutex<HashMap<String, String>>,
+    /// Reject requests whose signature does not cover the session and query.
+    require_query_signature: bool,
 }
 
 impl ServerState {
-    fn new() -> Self {
+    fn new(require_query_signature: bool) -> Self {
         Self {
             rate_limiter: RateLimiter::direct(Quota::per_second(nonzero!(10u32))),
             sessions: Mutex::new(HashMap::new()),
             client_keys: Mutex::new(HashMap::new()),
+            require_query_signature,
         }
     }
 
@@ -69,14 +107,11 @@ impl ServerState {
         Ok(())
     }
 
-    fn verify_signature(&self, client_id: &str, data: &str, signature: &str) -> Result<bool> {
+    fn verify_signature(&self, request: &AuthRequest, data: &str) -> Result<bool> {
         let keys = self.client_keys.lock().unwrap();
-        if let Some(key) = keys.get(client_id) {
-            let mut hasher = Sha256::new();
-            hasher.update(data.as_bytes());
-            hasher.update(key.as_bytes());
-            let expected = BASE64.encode(hasher.finalize());
-            Ok(expected == signature)
+        if let Some(key) = keys.get(&request.client_id) {
+            let expected = sign(request.signature_algorithm.as_deref(), request.signature_encoding.as_deref(), data, key)?;
+            Ok(expected == request.signature)
         } else {
             Err("Unknown client".into())
         }
@@ -161,24 +196,73 @@ fn verify_timestamp(timestamp: u64) -> bool {
     }
 }
 
-fn sign_response(response: &str, key: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(response.as_bytes());
-    hasher.update(key.as_bytes());
-    BASE64.encode(hasher.finalize())
+/// Must match `protocol::query_hash` in the library.
+fn query_hash(wql_query: &str) -> String {
+    BASE64.encode(Sha256::digest(wql_query.as_bytes()))
+}
+
+/// Must match `protocol::signing_payload` in the library byte for byte.
+fn signing_payload(request: &AuthRequest) -> Result<String> {
+    match request.signature_scheme.as_deref() {
+        None => Ok(format!("{}:{}:{}", request.client_id, request.timestamp, request.nonce)),
+        Some(SIGNATURE_SCHEME_V2) => Ok(format!(
+            "{}:{}:{}:{}:{}:{}",
+            SIGNATURE_SCHEME_V2,
+            request.client_id,
+            request.timestamp,
+            request.nonce,
+            request.session_id.as_deref().unwrap_or(""),
+            query_hash(&request.wql_query)
+        )),
+        Some(other) => Err(format!("Unsupported signature scheme: {}", other)),
+    }
 }
 
-async fn execute_curl_command(query: &str) -> Result<(bool, String)> {
+/// Must match `signing::SignatureAlgorithm` and `signing::SignatureEncoding`
+/// in the library; no algorithm means SHA-256 over the data followed by the
+/// key, and no encoding means base64.
+fn sign(algorithm: Option<&str>, encoding: Option<&str>, data: &str, key: &str) -> Result<String> {
+    let signature = match algorithm {
+        None => {
+            let mut hasher = Sha256::new();
+            hasher.update(data.as_bytes());
+            hasher.update(key.as_bytes());
+            hasher.finalize().to_vec()
+        }
+        Some("hmac-sha256") => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).map_err(|e| e.to_string())?;
+            mac.update(data.as_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+        Some("hmac-sha512") => {
+            let mut mac = Hmac::<Sha512>::new_from_slice(key.as_bytes()).map_err(|e| e.to_string())?;
+            mac.update(data.as_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+        Some(other) => return Err(format!("Unsupported signature algorithm: {}", other)),
+    };
+    match encoding {
+        None => Ok(BASE64.encode(signature)),
+        Some("hex") => Ok(signature.iter().map(|b| format!("{:02x}", b)).collect()),
+        Some(other) => Err(format!("Unsupported signature encoding: {}", other)),
+    }
+}
+
+async fn execute_curl_command(query: &str, format: ResultFormat) -> Result<(bool, String)> {
     // 創建臨時文件來存儲查詢
     let temp_file = format!("temp_query_{}.json", Uuid::new_v4());
     fs::write(&temp_file, query)
         .map_err(|e| format!("Failed to write temp query file: {}", e))?;
 
     let output = Command::new("curl")
-        .args(&[
+        .args([
             "-k",
             "-u", "admin:aD?VhljrN55GGbO?twN6IL+zCxKYKeNT",
-            "https://localhost:9200/wazuh-alerts-4.x-*/_search?pretty",
+            if format == ResultFormat::Json {
+                "https://localhost:9200/wazuh-alerts-4.x-*/_search?pretty"
+            } else {
+                "https://localhost:9200/wazuh-alerts-4.x-*/_search"
+            },
             "-H", "Content-Type: application/json",
             "-d", &format!("@{}", temp_file)
         ])
@@ -192,12 +276,59 @@ async fn execute_curl_command(query: &str) -> Result<(bool, String)> {
     let stderr = String::from_utf8_lossy(&output.stderr).to_string();
 
     if output.status.success() {
+        if format == ResultFormat::Csv {
+            return Ok(match hits_to_csv(&stdout) {
+                Ok(csv) => (true, csv),
+                Err(e) => (false, format!("Failed to convert result to CSV: {}", e)),
+            });
+        }
         Ok((true, stdout))
     } else {
         Ok((false, format!("Error: {}\nDebug info: {}", stderr, stdout)))
     }
 }
 
+/// One row per `hits.hits[]._source`, with a header of every top-level
+/// field in first-seen order. Nested values are written as JSON.
+fn hits_to_csv(search_response: &str) -> Result<String> {
+    let json: serde_json::Value = serde_json::from_str(search_response).map_err(|e| e.to_string())?;
+    let hits = json["hits"]["hits"].as_array().ok_or("response has no hits.hits array")?;
+    let sources: Vec<&serde_json::Map<String, serde_json::Value>> =
+        hits.iter().filter_map(|hit| hit["_source"].as_object()).collect();
+
+    let mut columns: Vec<&str> = Vec::new();
+    for source in &sources {
+        for key in source.keys() {
+            if !columns.contains(&key.as_str()) {
+                columns.push(key);
+            }
+        }
+    }
+
+    let escape = |field: &str| {
+        if field.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    };
+    let mut csv = columns.iter().map(|c| escape(c)).collect::<Vec<_>>().join(",");
+    csv.push('\n');
+    for source in sources {
+        let row: Vec<String> = columns
+            .iter()
+            .map(|column| match source.get(*column) {
+                None | Some(serde_json::Value::Null) => String::new(),
+                Some(serde_json::Value::String(s)) => escape(s),
+                Some(other) => escape(&other.to_string()),
+            })
+            .collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    Ok(csv)
+}
+
 async fn handle_client(
     mut stream: tokio_native_tls::TlsStream<TcpStream>,
     state: Arc<ServerState>,
@@ -214,24 +345,53 @@ async fn handle_client(
         return Err("Received data too short".into());
     }
 
-    let auth_request: AuthRequest = serde_json::from_slice(&buf[..n])
-        .map_err(|e| format!("Failed to parse request: {}", e))?;
+    // Must match `protocol::WireEncoding::of` in the library: MessagePack
+    // requests are maps, JSON ones objects.
+    let auth_request: AuthRequest = match buf[0] {
+        0x80..=0x8f | 0xde | 0xdf => rmp_serde::from_slice(&buf[..n]).map_err(|e| e.to_string()),
+        _ => serde_json::from_slice(&buf[..n]).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| format!("Failed to parse request: {}", e))?;
+    let msgpack = auth_request.encoding.as_deref() == Some(MSGPACK);
 
-    println!("Received request from client_id: {}", auth_request.client_id);
+    println!(
+        "Received request {} from client_id: {}",
+        auth_request.request_id.as_deref().unwrap_or("-"),
+        auth_request.client_id
+    );
     
     if !verify_timestamp(auth_request.timestamp) {
         return Err("Invalid timestamp".into());
     }
 
+    if state.require_query_signature && auth_request.signature_scheme.is_none() {
+        return Err("Request signature does not cover the query".into());
+    }
+    let data_to_verify = signing_payload(&auth_request)?;
+
+    if !state.verify_signature(&auth_request, &data_to_verify)? {
+        return Err("Invalid signature".into());
+    }
+
     let session_id = if let Some(sid) = auth_request.session_id.clone() {
         println!("Validating existing session: {}", sid);
         if !state.validate_session(&sid, &auth_request.client_id) {
-            println!("Creating new session as validation failed");
-            state.create_session(auth_request.client_id.clone())
-        } else {
-            println!("Using existing session");
-            sid
+            println!("Session {} expired or unknown", sid);
+            let algorithm = auth_request.signature_algorithm.as_deref();
+            return send_response(&mut stream, algorithm, auth_request.signature_encoding.as_deref(), msgpack, Response {
+                status: false,
+                data: "Session expired".to_string(),
+                session_id: String::new(),
+                timestamp: now_secs(),
+                signature: String::new(),
+                error_code: Some(SESSION_EXPIRED.to_string()),
+                request_id: auth_request.request_id,
+                format: None,
+                query_hash: Some(query_hash(&auth_request.wql_query)),
+            }).await;
         }
+        println!("Using existing session");
+        sid
     } else {
         println!("Creating new session");
         state.create_session(auth_request.client_id.clone())
@@ -243,47 +403,57 @@ async fn handle_client(
         return Err("Nonce already used".into());
     }
 
-    let data_to_verify = format!("{}:{}:{}", 
-        auth_request.client_id,
-        auth_request.timestamp,
-        auth_request.nonce
-    );
-
-    if !state.verify_signature(&auth_request.client_id, &data_to_verify, &auth_request.signature)? {
-        return Err("Invalid signature".into());
-    }
-
     println!("Executing WQL query...");
-    let (status, data) = execute_curl_command(&auth_request.wql_query).await?;
+    let (status, data) = execute_curl_command(&auth_request.wql_query, auth_request.format).await?;
     println!("Query execution completed");
 
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
-    let response = Response {
+    let algorithm = auth_request.signature_algorithm.as_deref();
+    send_response(&mut stream, algorithm, auth_request.signature_encoding.as_deref(), msgpack, Response {
         status,
         data,
-        session_id: session_id.clone(),
-        timestamp,
+        session_id,
+        timestamp: now_secs(),
         signature: String::new(),
-    };
+        error_code: None,
+        request_id: auth_request.request_id,
+        format: Some(auth_request.format),
+        query_hash: Some(query_hash(&auth_request.wql_query)),
+    }).await
+}
 
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Signs `response` over its JSON and sends it as JSON or, when `msgpack`,
+/// as a MessagePack map keyed by field name.
+async fn send_response(
+    stream: &mut tokio_native_tls::TlsStream<TcpStream>,
+    algorithm: Option<&str>,
+    encoding: Option<&str>,
+    msgpack: bool,
+    response: Response,
+) -> Result<()> {
     let response_json = serde_json::to_string(&response)
         .map_err(|e| e.to_string())?;
 
-    let signature = sign_response(&response_json, "server_key");
+    let signature = sign(algorithm, encoding, &response_json, "server_key")?;
     let response = Response {
         signature,
         ..response
     };
 
-    let response_json = serde_json::to_string(&response)
-        .map_err(|e| e.to_string())?;
+    let response_bytes = if msgpack {
+        rmp_serde::to_vec_named(&response).map_err(|e| e.to_string())?
+    } else {
+        serde_json::to_vec(&response).map_err(|e| e.to_string())?
+    };
 
-    println!("Sending response ({} bytes)...", response_json.len());
-    stream.write_all(response_json.as_bytes()).await.map_err(|e| e.to_string())?;
+    println!("Sending response ({} bytes)...", response_bytes.len());
+    stream.write_all(&response_bytes).await.map_err(|e| e.to_string())?;
     stream.flush().await.map_err(|e| e.to_string())?;
     println!("Response sent successfully");
     
@@ -306,7 +476,12 @@ async fn main() -> Result<()> {
     let acceptor = Arc::new(acceptor);
 
     println!("Initializing server state...");
-    let state = Arc::new(ServerState::new());
+    let require_query_signature = env::var("CONDUIT_REQUIRE_QUERY_SIGNATURE")
+        .is_ok_and(|v| matches!(v.as_str(), "1" | "true" | "yes"));
+    if require_query_signature {
+        println!("Requiring signatures over the session and query");
+    }
+    let state = Arc::new(ServerState::new(require_query_signature));
     state.load_client_keys()?;
 
     println!("Starting server on {}...", addr);
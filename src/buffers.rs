@@ -0,0 +1,94 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Read buffers shared by a client and its clones, so concurrent queries
+/// reuse a few allocations instead of each allocating its own.
+///
+/// At most `max_idle` buffers are kept between uses; any more returned at
+/// once are freed, so a burst of concurrency does not pin memory afterwards.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    idle: Mutex<Vec<Vec<u8>>>,
+    buffer_size: usize,
+    max_idle: usize,
+    created: AtomicUsize,
+}
+
+impl BufferPool {
+    pub(crate) fn new(buffer_size: usize, max_idle: usize) -> Arc<Self> {
+        Arc::new(Self { idle: Mutex::new(Vec::new()), buffer_size, max_idle, created: AtomicUsize::new(0) })
+    }
+
+    /// A buffer of `buffer_size` bytes, returned to the pool when dropped. A
+    /// reused buffer still holds what was last read into it.
+    pub(crate) fn take(self: &Arc<Self>) -> PooledBuffer {
+        let reused = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let buffer = reused.unwrap_or_else(|| {
+            self.created.fetch_add(1, Ordering::Relaxed);
+            vec![0; self.buffer_size]
+        });
+        PooledBuffer { buffer, pool: self.clone() }
+    }
+
+    /// How many buffers the pool has allocated so far.
+    #[cfg(test)]
+    pub(crate) fn created(&self) -> usize {
+        self.created.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut idle = self.pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.pool.max_idle {
+            idle.push(std::mem::take(&mut self.buffer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returned_buffers_are_handed_out_again() {
+        let pool = BufferPool::new(16, 2);
+        let first = pool.take();
+        let address = first.as_ptr();
+        drop(first);
+        assert_eq!(pool.take().as_ptr(), address);
+        assert_eq!(pool.created(), 1);
+    }
+
+    #[test]
+    fn only_max_idle_buffers_are_kept() {
+        let pool = BufferPool::new(16, 2);
+        let buffers: Vec<PooledBuffer> = (0..4).map(|_| pool.take()).collect();
+        assert_eq!(pool.created(), 4);
+        drop(buffers);
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+
+        let _again: Vec<PooledBuffer> = (0..3).map(|_| pool.take()).collect();
+        assert_eq!(pool.created(), 5);
+    }
+}
@@ -0,0 +1,1256 @@
+use crate::buffers::BufferPool;
+use crate::encryption::OutputCipher;
+use crate::info;
+use crate::protocol::{
+    query_hash, signing_payload, AuthRequest, ExchangeTimings, ReceivedResponse, Response, ResultFormat, WireEncoding, SESSION_EXPIRED,
+    SIGNATURE_SCHEME_V2,
+};
+use crate::retry::RetryPolicy;
+use crate::session::SessionInfo;
+use crate::signing::{check_signature, InvalidSignature, SignatureAlgorithm, SignatureEncoding, Signer};
+use crate::spool::{ReceivedBody, ResponseSpooler};
+use crate::Result;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+pub const SESSION_FILE: &str = "session.json";
+
+/// Session file kept apart for `name`: `session.json` becomes `session.<name>.json`.
+pub fn session_file_for(session_file: &Path, name: &str) -> PathBuf {
+    let stem = session_file.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let file_name = match session_file.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, name, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, name),
+    };
+    session_file.with_file_name(file_name)
+}
+const BUFFER_SIZE: usize = 8192;
+/// Read buffers kept for reuse between responses, across all clones of a client.
+const MAX_IDLE_BUFFERS: usize = 32;
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(300);
+pub const MAX_IN_MEMORY: usize = 64 * 1024 * 1024;
+pub const MAX_RESPONSE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+pub const SPOOL_CHECKPOINT: u64 = 16 * 1024 * 1024;
+
+/// Identity and protocol limits used when talking to the conduit server.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub client_id: String,
+    /// Key used to sign requests.
+    pub client_key: String,
+    /// Key used to verify server responses.
+    pub server_key: String,
+    /// Maximum allowed difference between a response timestamp and the request time.
+    pub max_clock_skew: Duration,
+    /// Correction applied to the local clock for request timestamps.
+    pub clock_offset: ClockOffset,
+    /// Responses larger than this many bytes are streamed to disk instead of buffered.
+    pub max_in_memory: usize,
+    /// Responses larger than this many bytes are aborted, in memory or spooled.
+    pub max_response_size: u64,
+    /// A spooled response is flushed and synced to disk every this many
+    /// bytes, so a crash loses at most that much; 0 syncs only at the end.
+    pub spool_checkpoint: u64,
+    /// Where the conduit session is cached between runs.
+    pub session_file: PathBuf,
+    /// Encrypts the cached session file when set.
+    pub session_cipher: Option<OutputCipher>,
+    /// Sign the session id and query too ([`SIGNATURE_SCHEME_V2`]), not just
+    /// `client_id:timestamp:nonce`. The server must support the scheme.
+    pub sign_query: bool,
+    /// Reject responses that do not echo the query hash, rather than
+    /// warning once. Always the case with `sign_query`.
+    pub require_query_hash: bool,
+    /// Result format requested for every query.
+    pub format: ResultFormat,
+    /// Algorithm requests and responses are signed with. The server must
+    /// support it; [`Client::with_signer`] plugs in one of your own.
+    pub signature_algorithm: SignatureAlgorithm,
+    /// How signatures are written, both ways. Named in every request that is
+    /// not base64, but servers that always sign in hex need it set too.
+    pub signature_encoding: SignatureEncoding,
+    /// Encoding asked of the server for its responses, and used for requests
+    /// once it has answered in it.
+    pub wire_encoding: WireEncoding,
+    /// How much of each gateway response is logged: status and size by
+    /// default, bodies cut short at 2 and whole at 3. Tokens and passwords
+    /// are redacted at every level.
+    pub verbosity: u8,
+}
+
+/// Supplies the nonce of every request; [`RandomNonce`] unless replaced
+/// with [`Client::with_nonce_source`].
+pub trait NonceSource: Send + Sync {
+    fn nonce(&self) -> String;
+}
+
+/// A random UUID v4 per request.
+pub struct RandomNonce;
+
+impl NonceSource for RandomNonce {
+    fn nonce(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+/// Supplies the timestamp of every request; [`SystemClock`] unless replaced
+/// with [`Client::with_clock`].
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// How request timestamps are corrected on a host whose clock is off.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClockOffset {
+    /// Requests carry the local time.
+    #[default]
+    None,
+    /// Seconds added to the local time, negative for a clock that runs ahead.
+    Fixed(i64),
+    /// Measured from the first response the server signs for one of this
+    /// client's requests, then applied to every later request. The request
+    /// that measures it still carries the local time, so a server that
+    /// refuses it outright needs a [`Fixed`](Self::Fixed) offset instead.
+    Server,
+}
+
+/// Returned by [`Client::send_request`] when the server rejected the cached
+/// session. The session has already been cleared, so resending the query
+/// starts a new one. This is distinct from an authentication failure, where
+/// the server closes the connection without a signed response.
+#[derive(Debug)]
+pub struct SessionExpired;
+
+impl fmt::Display for SessionExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server reported the session as expired")
+    }
+}
+
+impl std::error::Error for SessionExpired {}
+
+/// Characters of a response body a [`ProtocolError`] quotes.
+pub const PROTOCOL_SNIPPET_CHARS: usize = 200;
+
+/// Returned by [`Client::send_request`] when the server answered with
+/// something other than a conduit response, such as the HTML error page of
+/// a proxy in the way. The cached session is left as it was, and the
+/// exchange is not worth retrying.
+#[derive(Debug)]
+pub struct ProtocolError {
+    /// Why the body is not a response.
+    pub reason: String,
+    /// The start of the body, at most [`PROTOCOL_SNIPPET_CHARS`] characters.
+    pub snippet: String,
+    /// Whether the body went on past `snippet`.
+    pub truncated: bool,
+}
+
+impl ProtocolError {
+    fn new(reason: impl fmt::Display, body: &[u8]) -> Self {
+        let body = String::from_utf8_lossy(body);
+        let body = body.trim();
+        let snippet: String = body.chars().take(PROTOCOL_SNIPPET_CHARS).collect();
+        let truncated = snippet.len() < body.len();
+        Self { reason: reason.to_string(), snippet, truncated }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Protocol error: the server did not send a conduit response ({}); it sent {:?}", self.reason, self.snippet)?;
+        if self.truncated {
+            write!(f, "...")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Conduit protocol client. The gateway half (token, discovery) lives in `gateway`.
+#[derive(Clone)]
+pub struct Client {
+    client_id: String,
+    client_key: String,
+    server_key: String,
+    max_clock_skew: Duration,
+    clock_offset: ClockOffset,
+    /// Offset measured for [`ClockOffset::Server`], shared by clones.
+    measured_offset: Arc<OnceLock<i64>>,
+    max_in_memory: usize,
+    max_response_size: u64,
+    spool_checkpoint: u64,
+    pub(crate) retry: RetryPolicy,
+    session: Option<SessionInfo>,
+    session_file: PathBuf,
+    session_cipher: Option<OutputCipher>,
+    sign_query: bool,
+    require_query_hash: bool,
+    /// Whether a response without the query hash echo was warned about,
+    /// shared by clones.
+    warned_missing_hash: Arc<AtomicBool>,
+    format: ResultFormat,
+    signer: Arc<dyn Signer>,
+    signature_encoding: SignatureEncoding,
+    wire_encoding: WireEncoding,
+    /// Whether the server has answered in [`WireEncoding::MessagePack`],
+    /// shared by clones.
+    server_reads_msgpack: Arc<AtomicBool>,
+    nonces: Arc<dyn NonceSource>,
+    clock: Arc<dyn Clock>,
+    buffers: Arc<BufferPool>,
+    #[cfg_attr(not(feature = "gateway"), allow(dead_code))]
+    pub(crate) verbosity: u8,
+    #[cfg(feature = "gateway")]
+    pub(crate) gateway: crate::gateway::GatewayState,
+}
+
+impl Client {
+    /// Builds a client for the conduit exchange alone; see `with_gateway` for
+    /// token issuance and discovery.
+    pub fn new(config: ClientConfig, retry: RetryPolicy) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let session = SessionInfo::load(&config.session_file, &config.client_id, config.session_cipher.as_ref(), now);
+        Self {
+            client_id: config.client_id,
+            client_key: config.client_key,
+            server_key: config.server_key,
+            max_clock_skew: config.max_clock_skew,
+            clock_offset: config.clock_offset,
+            measured_offset: Arc::new(OnceLock::new()),
+            max_in_memory: config.max_in_memory,
+            max_response_size: config.max_response_size,
+            spool_checkpoint: config.spool_checkpoint,
+            retry,
+            session,
+            session_file: config.session_file,
+            session_cipher: config.session_cipher,
+            sign_query: config.sign_query,
+            require_query_hash: config.require_query_hash,
+            warned_missing_hash: Arc::new(AtomicBool::new(false)),
+            format: config.format,
+            signer: config.signature_algorithm.signer(),
+            signature_encoding: config.signature_encoding,
+            wire_encoding: config.wire_encoding,
+            server_reads_msgpack: Arc::new(AtomicBool::new(false)),
+            nonces: Arc::new(RandomNonce),
+            clock: Arc::new(SystemClock),
+            buffers: BufferPool::new(BUFFER_SIZE, MAX_IDLE_BUFFERS),
+            verbosity: config.verbosity,
+            #[cfg(feature = "gateway")]
+            gateway: Default::default(),
+        }
+    }
+
+    fn save_session(&self) -> Result<()> {
+        match &self.session {
+            Some(session) => session.save(&self.session_file, self.session_cipher.as_ref()),
+            None => Ok(()),
+        }
+    }
+
+    /// Signs requests and verifies responses with `signer` instead of the
+    /// configured algorithm.
+    pub fn with_signer(mut self, signer: Arc<dyn Signer>) -> Self {
+        self.signer = signer;
+        self
+    }
+
+    /// Takes request nonces from `nonces` instead of random UUIDs.
+    pub fn with_nonce_source(mut self, nonces: Arc<dyn NonceSource>) -> Self {
+        self.nonces = nonces;
+        self
+    }
+
+    /// Takes request timestamps from `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Seconds added to the local time in request timestamps.
+    fn clock_offset(&self) -> i64 {
+        match self.clock_offset {
+            ClockOffset::None => 0,
+            ClockOffset::Fixed(offset) => offset,
+            ClockOffset::Server => self.measured_offset.get().copied().unwrap_or(0),
+        }
+    }
+
+    /// Takes the server's clock offset from the timestamp of a response to a
+    /// request sent at `local`, unless one was measured already.
+    fn measure_clock_offset(&self, response: &Response, local: u64) {
+        if self.clock_offset != ClockOffset::Server {
+            return;
+        }
+        self.measured_offset.get_or_init(|| {
+            let offset = response.timestamp as i64 - local as i64;
+            if offset.unsigned_abs() > self.max_clock_skew.as_secs() {
+                eprintln!(
+                    "Warning: the local clock is {}s {} the server's; correcting request timestamps",
+                    offset.unsigned_abs(),
+                    if offset > 0 { "behind" } else { "ahead of" }
+                );
+            }
+            info!("Measured a clock offset of {}s from the server", offset);
+            offset
+        });
+    }
+
+    /// How the next request is written: JSON until the server has answered
+    /// a request asking for MessagePack in it, which shows it reads it too.
+    fn request_encoding(&self) -> WireEncoding {
+        match self.wire_encoding {
+            WireEncoding::MessagePack if self.server_reads_msgpack.load(Ordering::Relaxed) => WireEncoding::MessagePack,
+            _ => WireEncoding::Json,
+        }
+    }
+
+    fn sign_request(&self, data: &str) -> String {
+        self.signer.sign(data.as_bytes(), self.client_key.as_bytes(), self.signature_encoding)
+    }
+
+    fn verify_response(&self, response: Response) -> std::result::Result<Response, InvalidSignature> {
+        verify_envelope(response, self.signer.as_ref(), &self.server_key, self.signature_encoding)
+    }
+
+    fn check_response_freshness(
+        &self,
+        response: &Response,
+        request_timestamp: u64,
+        expected_session_id: Option<&str>,
+    ) -> Result<()> {
+        let skew = response.timestamp.abs_diff(request_timestamp);
+        if skew > self.max_clock_skew.as_secs() {
+            return Err(format!(
+                "Stale response: timestamp {} is {}s away from request time {} (max skew {}s)",
+                response.timestamp,
+                skew,
+                request_timestamp,
+                self.max_clock_skew.as_secs()
+            ).into());
+        }
+
+        if let Some(expected) = expected_session_id {
+            if response.session_id != expected {
+                return Err(format!(
+                    "Session mismatch: expected {}, server returned {}",
+                    expected, response.session_id
+                ).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn clear_session(&mut self) {
+        if self.session.take().is_some() {
+            let _ = fs::remove_file(&self.session_file);
+            info!("Cleared cached session");
+        }
+    }
+
+    async fn stream_response(
+        &self,
+        stream: &mut (impl AsyncRead + Unpin),
+        max_in_memory: usize,
+        max_response_size: u64,
+        format: ResultFormat,
+        spool_path: &Path,
+        first_byte: &mut Option<Instant>,
+    ) -> Result<ReceivedBody> {
+        let mut response_data = Vec::new();
+        let mut buffer = self.buffers.take();
+        let mut total_bytes = 0;
+        let mut spooler: Option<ResponseSpooler> = None;
+        let mut spool_rejected = false;
+        
+        let quiet = crate::output::is_quiet();
+        if !quiet {
+            print!("\rReceiving data: 0 bytes");
+            std::io::stdout().flush()?;
+        }
+
+        loop {
+            match stream.read(&mut buffer).await {
+                Ok(0) => {
+                    if total_bytes == 0 {
+                        return Err(Box::new(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            "Connection closed by server",
+                        )));
+                    }
+                    break;
+                },
+                Ok(n) => {
+                    first_byte.get_or_insert_with(Instant::now);
+                    total_bytes += n;
+                    if total_bytes as u64 > max_response_size {
+                        info!();
+                        return Err(format!(
+                            "Response exceeds the {} byte limit (--max-response-size); aborting",
+                            max_response_size
+                        ).into());
+                    }
+                    if let Some(spooler) = spooler.as_mut() {
+                        spooler.feed(&buffer[..n]).await?;
+                    } else {
+                        response_data.extend_from_slice(&buffer[..n]);
+                        if !spool_rejected && response_data.len() > max_in_memory {
+                            let digest = self.signer.begin(self.server_key.as_bytes());
+                            spooler = ResponseSpooler::start(spool_path, &response_data, digest, self.spool_checkpoint).await?;
+                            if spooler.is_some() {
+                                info!("\nResponse exceeds {} bytes, streaming to {}", max_in_memory, spool_path.display());
+                                response_data = Vec::new();
+                            } else {
+                                spool_rejected = true;
+                            }
+                        }
+                    }
+                    if !quiet {
+                        print!("\rReceiving data: {} bytes", total_bytes);
+                        std::io::stdout().flush()?;
+                    }
+                }
+                Err(e) => {
+                    return Err(Box::new(std::io::Error::new(
+                        e.kind(),
+                        format!("Failed to read response: {}", e),
+                    )));
+                }
+            }
+        }
+        info!("\nReceived total: {} bytes", total_bytes);
+
+        if let Some(spooler) = spooler {
+            return spooler.finish().await;
+        }
+        // A spooler only starts on a JSON envelope, so MessagePack is always held in memory.
+        if response_data.first().copied().and_then(WireEncoding::of) == Some(WireEncoding::MessagePack) {
+            return Ok(ReceivedBody::MessagePack(response_data));
+        }
+
+        match String::from_utf8(response_data) {
+            Ok(text) => Ok(ReceivedBody::Memory(text)),
+            Err(e) => {
+                let offset = e.utf8_error().valid_up_to();
+                let invalid = format!("Response is not valid UTF-8 at byte {}: {}", offset, e.utf8_error());
+                if format != ResultFormat::Raw {
+                    return Err(Box::new(ProtocolError::new(invalid, e.as_bytes())));
+                }
+                // Raw results may hold bytes that are not UTF-8; the spooler
+                // copies `data` to disk verbatim while verifying the signature.
+                let digest = self.signer.begin(self.server_key.as_bytes());
+                match ResponseSpooler::start(spool_path, e.as_bytes(), digest, self.spool_checkpoint).await? {
+                    Some(spooler) => spooler.finish().await,
+                    None => Err(invalid.into()),
+                }
+            }
+        }
+    }
+
+    /// Sends `wql_query` and verifies the response. Payloads larger than the
+    /// in-memory threshold are written to `spool_path` instead of `Response.data`.
+    ///
+    /// `stream` is normally a [`TlsStream`](crate::tls::TlsStream), but any
+    /// byte stream works, e.g. a `tokio::io::duplex` pair in place of a server.
+    pub async fn send_request<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+        wql_query: String,
+        spool_path: &Path,
+    ) -> Result<ReceivedResponse> {
+        self.send_request_as(stream, wql_query, self.format, spool_path).await
+    }
+
+    /// [`send_request`](Self::send_request), asking for `format` instead of
+    /// the configured one.
+    pub async fn send_request_as<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+        wql_query: String,
+        format: ResultFormat,
+        spool_path: &Path,
+    ) -> Result<ReceivedResponse> {
+        let started = Instant::now();
+        let local = self.clock.now().duration_since(UNIX_EPOCH)?.as_secs();
+        let timestamp = local.saturating_add_signed(self.clock_offset());
+        let nonce = self.nonces.nonce();
+        let session_id = self.session.as_ref().map(|s| s.session_id.clone());
+        let request_id = Uuid::new_v4().to_string();
+
+        let mut request = AuthRequest {
+            client_id: self.client_id.clone(),
+            timestamp,
+            nonce,
+            signature: String::new(),
+            session_id: session_id.clone(),
+            wql_query,
+            request_id: request_id.clone(),
+            signature_scheme: self.sign_query.then(|| SIGNATURE_SCHEME_V2.to_string()),
+            signature_algorithm: self.signer.algorithm().map(str::to_string),
+            signature_encoding: (self.signature_encoding != SignatureEncoding::Base64)
+                .then(|| self.signature_encoding.as_str().to_string()),
+            format,
+            encoding: (self.wire_encoding != WireEncoding::Json).then(|| self.wire_encoding.as_str().to_string()),
+        };
+        let data_to_sign = signing_payload(&request).expect("client only sends known schemes");
+        request.signature = self.sign_request(&data_to_sign);
+
+        let request_bytes = self.request_encoding().encode(&request)?;
+        info!("Sending request {}...", request_id);
+        stream.write_all(&request_bytes).await?;
+        stream.flush().await?;
+        let sent = started.elapsed();
+
+        info!("Waiting for response...");
+        let mut first_byte = None;
+        let body = match self.stream_response(stream, self.max_in_memory, self.max_response_size, format, spool_path, &mut first_byte).await {
+            Ok(body) => body,
+            Err(e) => {
+                let _ = fs::remove_file(spool_path);
+                return Err(e);
+            }
+        };
+        let received = started.elapsed();
+        let timings = ExchangeTimings {
+            sent,
+            first_byte: first_byte.map_or(received, |at| at.duration_since(started)),
+            received,
+        };
+
+        let answered_in = match body {
+            ReceivedBody::MessagePack(_) => WireEncoding::MessagePack,
+            _ => WireEncoding::Json,
+        };
+        let (response, spooled_to) = match body {
+            ReceivedBody::Memory(response_str) => {
+                let response: Response = match serde_json::from_str(&response_str) {
+                    Ok(response) => response,
+                    // A response cut short is a transport failure, retried as one.
+                    Err(e) if e.is_eof() => return Err(Box::new(e)),
+                    Err(e) => return Err(Box::new(ProtocolError::new(e, response_str.as_bytes()))),
+                };
+                (self.verify_response(response)?, None)
+            }
+            ReceivedBody::MessagePack(bytes) => {
+                let response: Response = WireEncoding::MessagePack
+                    .decode(&bytes)
+                    .map_err(|e| ProtocolError::new(format!("not a MessagePack response: {}", e), &bytes))?;
+                (self.verify_response(response)?, None)
+            }
+            ReceivedBody::Spooled { path, envelope, digest } => {
+                if let Err(e) = check_signature(&digest.finish(), &envelope.signature, self.signature_encoding) {
+                    let _ = fs::remove_file(&path);
+                    return Err(Box::new(e));
+                }
+                (*envelope, Some(path))
+            }
+        };
+
+        if response.request_id.as_deref() != Some(request_id.as_str()) {
+            if let Some(path) = &spooled_to {
+                let _ = fs::remove_file(path);
+            }
+            return Err(format!(
+                "Response request_id {:?} does not match request {}",
+                response.request_id, request_id
+            ).into());
+        }
+
+        let sent_hash = query_hash(&request.wql_query);
+        let hash_error = match response.query_hash.as_deref() {
+            Some(hash) if hash == sent_hash => None,
+            Some(hash) => Some(format!("Response is for a different query: query_hash {:?} does not match {}", hash, sent_hash)),
+            // Only servers that predate the echo omit it, and they cannot sign v2.
+            None if self.sign_query || self.require_query_hash => {
+                Some(format!("Response has no query_hash to show it answers query {}", sent_hash))
+            }
+            None => {
+                if !self.warned_missing_hash.swap(true, Ordering::Relaxed) {
+                    eprintln!("Warning: the server does not echo the query hash, so responses are not checked against the query sent");
+                }
+                None
+            }
+        };
+        if let Some(error) = hash_error {
+            if let Some(path) = &spooled_to {
+                let _ = fs::remove_file(path);
+            }
+            return Err(error.into());
+        }
+
+        // The echoed request_id and query_hash tie the response to this request,
+        // so its timestamp is the server's time now.
+        self.measure_clock_offset(&response, local);
+        let timestamp = local.saturating_add_signed(self.clock_offset());
+
+        if response.error_code.as_deref() == Some(SESSION_EXPIRED) {
+            self.check_response_freshness(&response, timestamp, None)?;
+            self.clear_session();
+            return Err(Box::new(SessionExpired));
+        }
+
+        // Checked before the freshness check so a garbage id cannot evict a good cached session.
+        if Uuid::parse_str(&response.session_id).is_err() {
+            if let Some(path) = &spooled_to {
+                let _ = fs::remove_file(path);
+            }
+            return Err(format!("Server returned a malformed session_id: {:?}", response.session_id).into());
+        }
+
+        if let Err(e) = self.check_response_freshness(&response, timestamp, session_id.as_deref()) {
+            if let Some(path) = &spooled_to {
+                let _ = fs::remove_file(path);
+            }
+            // Drop a session the server no longer honours so the next request starts a fresh one.
+            if session_id.as_deref().is_some_and(|sid| sid != response.session_id) {
+                self.clear_session();
+            }
+            return Err(e);
+        }
+
+        // Sessions age by the local clock they are loaded with.
+        self.session = Some(SessionInfo::new(response.session_id.clone(), self.client_id.clone(), local));
+        self.save_session()?;
+
+        if answered_in == WireEncoding::MessagePack && !self.server_reads_msgpack.swap(true, Ordering::Relaxed) {
+            info!("Server answers in MessagePack; later requests are sent in it too");
+        }
+
+        Ok(ReceivedResponse { response, spooled_to, timings })
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+}
+
+/// Checks `response.signature` over the rest of the response, serialized as
+/// the server serialized it before signing: with an empty signature.
+pub(crate) fn verify_envelope(
+    mut response: Response,
+    signer: &dyn Signer,
+    server_key: &str,
+    encoding: SignatureEncoding,
+) -> std::result::Result<Response, InvalidSignature> {
+    let signature = std::mem::take(&mut response.signature);
+    let unsigned = serde_json::to_string(&response).expect("responses always serialize");
+    signer.verify(unsigned.as_bytes(), server_key.as_bytes(), &signature, encoding)?;
+    response.signature = signature;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const SERVER_KEY: &str = "server_key";
+    const NOW: u64 = 1_700_000_000;
+    const SESSION_ID: &str = "0f8fad5b-d9cb-469f-a165-70867728950e";
+    const QUERY: &str = r#"{"query":{"match_all":{}}}"#;
+
+    fn config(dir: &Path) -> ClientConfig {
+        ClientConfig {
+            client_id: "client1".to_string(),
+            client_key: "test_key_1".to_string(),
+            server_key: SERVER_KEY.to_string(),
+            max_clock_skew: MAX_CLOCK_SKEW,
+            clock_offset: ClockOffset::None,
+            max_in_memory: MAX_IN_MEMORY,
+            max_response_size: MAX_RESPONSE_SIZE,
+            spool_checkpoint: 0,
+            session_file: dir.join(SESSION_FILE),
+            session_cipher: None,
+            sign_query: false,
+            require_query_hash: false,
+            format: ResultFormat::Json,
+            signature_algorithm: SignatureAlgorithm::Sha256,
+            signature_encoding: SignatureEncoding::Base64,
+            wire_encoding: WireEncoding::Json,
+            verbosity: 0,
+        }
+    }
+
+    fn client(config: ClientConfig) -> Client {
+        Client::new(config, RetryPolicy::default())
+    }
+
+    /// The successful response the server would send to `request`.
+    fn reply(request: &AuthRequest) -> Response {
+        Response {
+            status: true,
+            data: r#"{"hits":{"hits":[]}}"#.to_string(),
+            session_id: SESSION_ID.to_string(),
+            timestamp: request.timestamp,
+            signature: String::new(),
+            error_code: None,
+            request_id: Some(request.request_id.clone()),
+            format: Some(request.format),
+            query_hash: Some(query_hash(&request.wql_query)),
+        }
+    }
+
+    /// `response` serialized and signed the way the server signs it.
+    fn signed(response: Response) -> Vec<u8> {
+        signed_as(response, SignatureEncoding::Base64)
+    }
+
+    /// `response` signed over its JSON and sent as MessagePack.
+    fn signed_msgpack(mut response: Response) -> Vec<u8> {
+        let unsigned = serde_json::to_string(&response).unwrap();
+        response.signature = SignatureAlgorithm::Sha256.signer().sign(unsigned.as_bytes(), SERVER_KEY.as_bytes(), SignatureEncoding::Base64);
+        WireEncoding::MessagePack.encode(&response).unwrap()
+    }
+
+    fn signed_as(mut response: Response, encoding: SignatureEncoding) -> Vec<u8> {
+        let unsigned = serde_json::to_string(&response).unwrap();
+        response.signature = SignatureAlgorithm::Sha256.signer().sign(unsigned.as_bytes(), SERVER_KEY.as_bytes(), encoding);
+        serde_json::to_vec(&response).unwrap()
+    }
+
+    /// Runs one exchange with a server that reads the request, answers it
+    /// with the bytes `respond` returns and closes the connection.
+    async fn exchange(
+        client: &mut Client,
+        spool: &Path,
+        respond: impl FnOnce(AuthRequest) -> Vec<u8> + Send + 'static,
+    ) -> Result<ReceivedResponse> {
+        let (mut near, mut far) = duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut buffer = [0u8; 4096];
+            let request = loop {
+                let n = far.read(&mut buffer).await.unwrap();
+                assert!(n > 0, "client closed before sending a whole request");
+                received.extend_from_slice(&buffer[..n]);
+                let encoding = WireEncoding::of(received[0]).expect("a JSON or MessagePack request");
+                if let Ok(request) = encoding.decode::<AuthRequest>(&received) {
+                    break request;
+                }
+            };
+            let _ = far.write_all(&respond(request)).await;
+            let _ = far.shutdown().await;
+        });
+        let result = client.send_request(&mut near, QUERY.to_string(), spool).await;
+        server.await.unwrap();
+        result
+    }
+
+    fn cached_session(client: &mut Client, session_id: &str) {
+        client.session = Some(SessionInfo::new(session_id.to_string(), "client1".to_string(), NOW));
+        client.save_session().unwrap();
+    }
+
+    #[tokio::test]
+    async fn stale_response_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        let error = exchange(&mut client, &dir.path().join("spool"), |request| {
+            signed(Response { timestamp: request.timestamp - 301, ..reply(&request) })
+        })
+        .await
+        .unwrap_err();
+        assert!(error.to_string().starts_with("Stale response"), "{}", error);
+        assert!(client.session.is_none());
+    }
+
+    #[tokio::test]
+    async fn response_within_the_skew_is_accepted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        let received = exchange(&mut client, &dir.path().join("spool"), |request| {
+            signed(Response { timestamp: request.timestamp + 300, ..reply(&request) })
+        })
+        .await
+        .unwrap();
+        assert_eq!(received.response.session_id, SESSION_ID);
+    }
+
+    #[tokio::test]
+    async fn a_fixed_clock_offset_shifts_the_signed_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ClientConfig { clock_offset: ClockOffset::Fixed(-120), ..config(dir.path()) };
+        let mut client = client(config).with_clock(Arc::new(FixedClock));
+        exchange(&mut client, &dir.path().join("spool"), |request| {
+            assert_eq!(request.timestamp, NOW - 120);
+            assert_eq!(signing_payload(&request).unwrap(), format!("client1:{}:{}", NOW - 120, request.nonce));
+            signed(reply(&request))
+        })
+        .await
+        .unwrap();
+        assert_eq!(client.session.as_ref().unwrap().created_at, NOW);
+    }
+
+    #[tokio::test]
+    async fn a_server_clock_offset_is_measured_once_and_corrects_later_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ClientConfig { clock_offset: ClockOffset::Server, ..config(dir.path()) };
+        let mut client = client(config).with_clock(Arc::new(FixedClock));
+        // Far beyond the allowed skew, yet the echoed request_id proves the response fresh.
+        exchange(&mut client, &dir.path().join("spool"), |request| {
+            assert_eq!(request.timestamp, NOW);
+            signed(Response { timestamp: NOW + 900, ..reply(&request) })
+        })
+        .await
+        .unwrap();
+
+        let mut clone = client.clone();
+        for client in [&mut client, &mut clone] {
+            exchange(client, &dir.path().join("spool"), |request| {
+                assert_eq!(request.timestamp, NOW + 900);
+                // A later reply with another offset does not move the measured one.
+                signed(Response { timestamp: NOW + 1000, ..reply(&request) })
+            })
+            .await
+            .unwrap();
+        }
+        assert_eq!(client.clock_offset(), 900);
+    }
+
+    #[tokio::test]
+    async fn messagepack_requests_follow_once_the_server_answers_in_messagepack() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = dir.path().join("spool");
+        let mut client = client(ClientConfig { wire_encoding: WireEncoding::MessagePack, ..config(dir.path()) });
+
+        // A server that ignores the offer answers in JSON, and requests stay JSON.
+        exchange(&mut client, &spool, |request| {
+            assert_eq!(request.encoding.as_deref(), Some("msgpack"));
+            signed(reply(&request))
+        })
+        .await
+        .unwrap();
+        assert_eq!(client.request_encoding(), WireEncoding::Json);
+
+        let tampered = exchange(&mut client, &spool, |request| {
+            let mut wire = signed_msgpack(reply(&request));
+            let response: Response = WireEncoding::MessagePack.decode(&wire).unwrap();
+            wire = WireEncoding::MessagePack.encode(&Response { data: "[]".to_string(), ..response }).unwrap();
+            wire
+        })
+        .await
+        .unwrap_err();
+        assert!(tampered.downcast_ref::<InvalidSignature>().is_some(), "{}", tampered);
+        assert_eq!(client.request_encoding(), WireEncoding::Json);
+
+        let received = exchange(&mut client, &spool, |request| signed_msgpack(reply(&request))).await.unwrap();
+        assert_eq!(received.response.data, r#"{"hits":{"hits":[]}}"#);
+        let mut clone = client.clone();
+        assert_eq!(clone.request_encoding(), WireEncoding::MessagePack);
+        exchange(&mut clone, &spool, |request| signed_msgpack(reply(&request))).await.unwrap();
+
+        let mut json_only = self::client(config(dir.path()));
+        exchange(&mut json_only, &spool, |request| {
+            assert_eq!(request.encoding, None);
+            signed(reply(&request))
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn mismatched_session_clears_the_cached_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        cached_session(&mut client, "6f1c8b0e-3a52-4b8e-9d42-1f3e2c7a9b10");
+        let error = exchange(&mut client, &dir.path().join("spool"), |request| {
+            assert_eq!(request.session_id.as_deref(), Some("6f1c8b0e-3a52-4b8e-9d42-1f3e2c7a9b10"));
+            signed(reply(&request))
+        })
+        .await
+        .unwrap_err();
+        assert!(error.to_string().starts_with("Session mismatch"), "{}", error);
+        assert!(client.session.is_none());
+        assert!(!dir.path().join(SESSION_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn large_response_is_spooled_and_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = dir.path().join("spool");
+        let data = format!("[{}]", vec!["\"escaped\"\n"; 200].join(","));
+        let mut client = client(ClientConfig { max_in_memory: 256, ..config(dir.path()) });
+        let sent = data.clone();
+        let received = exchange(&mut client, &spool, move |request| signed(Response { data: sent, ..reply(&request) }))
+            .await
+            .unwrap();
+        assert_eq!(received.spooled_to.as_deref(), Some(spool.as_path()));
+        assert_eq!(received.response.data, "");
+        assert_eq!(fs::read_to_string(&spool).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn tampered_spooled_response_is_rejected_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = dir.path().join("spool");
+        let mut client = client(ClientConfig { max_in_memory: 256, ..config(dir.path()) });
+        let error = exchange(&mut client, &spool, |request| {
+            let wire = signed(Response { data: "x".repeat(1024), ..reply(&request) });
+            String::from_utf8(wire).unwrap().replacen("xxxx", "xxxy", 1).into_bytes()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid response signature");
+        assert!(!spool.exists());
+    }
+
+    #[tokio::test]
+    async fn session_expired_clears_the_cached_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        cached_session(&mut client, SESSION_ID);
+        let error = exchange(&mut client, &dir.path().join("spool"), |request| {
+            signed(Response {
+                status: false,
+                data: "Session expired".to_string(),
+                session_id: String::new(),
+                error_code: Some(SESSION_EXPIRED.to_string()),
+                format: None,
+                ..reply(&request)
+            })
+        })
+        .await
+        .unwrap_err();
+        assert!(error.is::<SessionExpired>(), "{}", error);
+        assert!(client.session.is_none());
+        assert!(!dir.path().join(SESSION_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn a_malformed_session_id_keeps_the_cached_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        cached_session(&mut client, SESSION_ID);
+        for session_id in ["", "not-a-uuid"] {
+            let error = exchange(&mut client, &dir.path().join("spool"), move |request| {
+                signed(Response { session_id: session_id.to_string(), ..reply(&request) })
+            })
+            .await
+            .unwrap_err();
+            assert!(error.to_string().starts_with("Server returned a malformed session_id"), "{}", error);
+            assert_eq!(client.session.as_ref().unwrap().session_id, SESSION_ID);
+            assert!(dir.path().join(SESSION_FILE).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_over_the_size_limit_are_aborted() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = dir.path().join("spool");
+        for max_in_memory in [MAX_IN_MEMORY, 256] {
+            let mut client = client(ClientConfig { max_in_memory, max_response_size: 4096, ..config(dir.path()) });
+            let error = exchange(&mut client, &spool, |request| {
+                signed(Response { data: "x".repeat(8192), ..reply(&request) })
+            })
+            .await
+            .unwrap_err();
+            assert!(error.to_string().contains("exceeds the 4096 byte limit"), "{}", error);
+            assert!(!spool.exists(), "the partial spool file was left behind");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_response_to_another_request_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        for request_id in [Some("8d3f5a0e-1b2c-4d5e-8f90-a1b2c3d4e5f6"), None] {
+            let error = exchange(&mut client, &dir.path().join("spool"), move |request| {
+                assert!(Uuid::parse_str(&request.request_id).is_ok(), "{}", request.request_id);
+                signed(Response { request_id: request_id.map(str::to_string), ..reply(&request) })
+            })
+            .await
+            .unwrap_err();
+            assert!(error.to_string().contains("does not match request"), "{}", error);
+        }
+        assert!(client.session.is_none());
+    }
+
+    #[tokio::test]
+    async fn sign_query_signs_the_session_and_query_too() {
+        let dir = tempfile::tempdir().unwrap();
+        for sign_query in [false, true] {
+            let mut client = client(ClientConfig { sign_query, ..config(dir.path()) });
+            cached_session(&mut client, SESSION_ID);
+            exchange(&mut client, &dir.path().join("spool"), move |request| {
+                assert_eq!(request.signature_scheme.as_deref(), sign_query.then_some(SIGNATURE_SCHEME_V2));
+                let payload = signing_payload(&request).unwrap();
+                assert_eq!(payload.contains(SESSION_ID), sign_query, "{}", payload);
+                let expected = SignatureAlgorithm::Sha256.signer().sign(payload.as_bytes(), b"test_key_1", SignatureEncoding::Base64);
+                assert_eq!(request.signature, expected);
+                signed(reply(&request))
+            })
+            .await
+            .unwrap();
+        }
+    }
+
+    struct FixedNonce;
+
+    impl NonceSource for FixedNonce {
+        fn nonce(&self) -> String {
+            "00000000-0000-4000-8000-000000000001".to_string()
+        }
+    }
+
+    struct FixedClock;
+
+    impl Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            UNIX_EPOCH + Duration::from_secs(NOW)
+        }
+    }
+
+    #[tokio::test]
+    async fn a_fixed_nonce_and_clock_give_a_known_signature() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path())).with_nonce_source(Arc::new(FixedNonce)).with_clock(Arc::new(FixedClock));
+        exchange(&mut client, &dir.path().join("spool"), |request| {
+            assert_eq!((request.timestamp, request.nonce.as_str()), (NOW, "00000000-0000-4000-8000-000000000001"));
+            assert_eq!(signing_payload(&request).unwrap(), "client1:1700000000:00000000-0000-4000-8000-000000000001");
+            assert_eq!(request.signature, "mkkImnpVChpIkic7nK5QISIfMznMwgLKpf9Au7tjdFk=");
+            signed(reply(&request))
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_good_response_is_returned_and_its_session_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        let received = exchange(&mut client, &dir.path().join("spool"), |request| signed(reply(&request))).await.unwrap();
+        assert!(received.response.status);
+        assert_eq!(received.response.data, r#"{"hits":{"hits":[]}}"#);
+        assert!(received.spooled_to.is_none());
+        assert_eq!(client.session.as_ref().unwrap().session_id, SESSION_ID);
+        assert!(dir.path().join(SESSION_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn read_buffers_are_reused_across_responses_and_clones() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        let mut clone = client.clone();
+        for _ in 0..3 {
+            exchange(&mut client, &dir.path().join("spool"), |request| signed(reply(&request))).await.unwrap();
+            exchange(&mut clone, &dir.path().join("spool"), |request| signed(reply(&request))).await.unwrap();
+        }
+        assert_eq!(client.buffers.created(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_truncated_response_is_a_retryable_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = dir.path().join("spool");
+        for max_in_memory in [MAX_IN_MEMORY, 256] {
+            let mut client = client(ClientConfig { max_in_memory, ..config(dir.path()) });
+            let error = exchange(&mut client, &spool, |request| {
+                let mut wire = signed(Response { data: "x".repeat(1024), ..reply(&request) });
+                wire.truncate(wire.len() - 100);
+                wire
+            })
+            .await
+            .unwrap_err();
+            assert!(crate::retry::is_retryable(error.as_ref()), "{}", error);
+            assert!(client.session.is_none());
+            assert!(!spool.exists(), "the partial spool file was left behind");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_body_that_is_not_a_response_is_a_protocol_error_quoting_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        cached_session(&mut client, SESSION_ID);
+        let page = format!("<html><head><title>502 Bad Gateway</title></head><body>{}</body></html>\n", "nginx ".repeat(50));
+        let bodies = [
+            (page.clone(), &page[..PROTOCOL_SNIPPET_CHARS], true),
+            ("upstream connect error\n".to_string(), "upstream connect error", false),
+            (r#"{"error":"forbidden"}"#.to_string(), r#"{"error":"forbidden"}"#, false),
+        ];
+        for (body, snippet, truncated) in bodies {
+            let wire = body.clone().into_bytes();
+            let error = exchange(&mut client, &dir.path().join("spool"), move |_| wire).await.unwrap_err();
+            let protocol = error.downcast_ref::<ProtocolError>().unwrap_or_else(|| panic!("{}", error));
+            assert_eq!((protocol.snippet.as_str(), protocol.truncated), (snippet, truncated));
+            assert!(error.to_string().starts_with("Protocol error: the server did not send a conduit response"), "{}", error);
+            assert!(error.to_string().contains(&format!("{:?}", snippet)), "{}", error);
+            assert!(!crate::retry::is_retryable(error.as_ref()), "{}", error);
+            assert_eq!(client.session.as_ref().unwrap().session_id, SESSION_ID);
+            assert!(dir.path().join(SESSION_FILE).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn a_response_with_a_bad_signature_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        let error = exchange(&mut client, &dir.path().join("spool"), |request| {
+            let wire = signed(reply(&request));
+            String::from_utf8(wire).unwrap().replacen("hits", "hitz", 1).into_bytes()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid response signature");
+        assert!(client.session.is_none());
+        assert!(!dir.path().join(SESSION_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn a_server_closing_without_a_response_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        let error = exchange(&mut client, &dir.path().join("spool"), |_| Vec::new()).await.unwrap_err();
+        let io = error.downcast_ref::<std::io::Error>().expect("an I/O error");
+        assert_eq!(io.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(client.session.is_none());
+    }
+
+    #[tokio::test]
+    async fn the_request_names_its_algorithm_and_other_signatures_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let algorithm = SignatureAlgorithm::HmacSha256;
+        let mut client = client(ClientConfig { signature_algorithm: algorithm, ..config(dir.path()) });
+        let error = exchange(&mut client, &dir.path().join("spool"), move |request| {
+            assert_eq!(request.signature_algorithm.as_deref(), Some("hmac-sha256"));
+            let expected = algorithm.signer().sign(signing_payload(&request).unwrap().as_bytes(), b"test_key_1", SignatureEncoding::Base64);
+            assert_eq!(request.signature, expected);
+            // Signed with the original SHA-256 scheme rather than the one named.
+            signed(reply(&request))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid response signature");
+    }
+
+    #[tokio::test]
+    async fn hex_signatures_are_sent_and_verified_when_hex_is_configured() {
+        let dir = tempfile::tempdir().unwrap();
+        for max_in_memory in [MAX_IN_MEMORY, 16] {
+            let config = ClientConfig { signature_encoding: SignatureEncoding::Hex, max_in_memory, ..config(dir.path()) };
+            let mut client = client(config);
+            let received = exchange(&mut client, &dir.path().join("spool"), |request| {
+                assert_eq!(request.signature_encoding.as_deref(), Some("hex"));
+                let payload = signing_payload(&request).unwrap();
+                let expected = SignatureAlgorithm::Sha256.signer().sign(payload.as_bytes(), b"test_key_1", SignatureEncoding::Hex);
+                assert_eq!(request.signature, expected);
+                signed_as(reply(&request), SignatureEncoding::Hex)
+            })
+            .await
+            .unwrap();
+            assert!(received.response.status);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_signature_in_the_wrong_encoding_is_an_invalid_signature() {
+        let dir = tempfile::tempdir().unwrap();
+        for max_in_memory in [MAX_IN_MEMORY, 16] {
+            let spool = dir.path().join("spool");
+            let mut client = client(ClientConfig { max_in_memory, ..config(dir.path()) });
+            let error = exchange(&mut client, &spool, |request| {
+                assert_eq!(request.signature_encoding, None);
+                signed_as(reply(&request), SignatureEncoding::Hex)
+            })
+            .await
+            .unwrap_err();
+            let invalid = error.downcast_ref::<InvalidSignature>().expect("an invalid signature");
+            assert_eq!(
+                *invalid,
+                InvalidSignature::Encoding { expected: SignatureEncoding::Base64, found: Some(SignatureEncoding::Hex) }
+            );
+            assert!(!spool.exists());
+        }
+    }
+
+    /// `response` signed with `data` holding `bytes` as sent, which need not
+    /// be UTF-8.
+    fn signed_bytes(response: Response, bytes: &[u8]) -> Vec<u8> {
+        let placeholder = b"@DATA@";
+        let unsigned = serde_json::to_vec(&Response { data: "@DATA@".to_string(), ..response }).unwrap();
+        let at = unsigned.windows(placeholder.len()).position(|w| w == placeholder).unwrap();
+        let unsigned = [&unsigned[..at], bytes, &unsigned[at + placeholder.len()..]].concat();
+        let signature = SignatureAlgorithm::Sha256.signer().sign(&unsigned, SERVER_KEY.as_bytes(), SignatureEncoding::Base64);
+        let empty = br#""signature":"""#;
+        let field = unsigned.windows(empty.len()).position(|w| w == empty).unwrap() + empty.len() - 1;
+        [&unsigned[..field], signature.as_bytes(), &unsigned[field..]].concat()
+    }
+
+    #[tokio::test]
+    async fn raw_results_that_are_not_utf8_are_written_verbatim() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = dir.path().join("spool");
+        let mut client = client(ClientConfig { format: ResultFormat::Raw, ..config(dir.path()) });
+        let received = exchange(&mut client, &spool, |request| signed_bytes(reply(&request), b"\x1f\x8b\xff ok"))
+            .await
+            .unwrap();
+        assert_eq!(received.spooled_to.as_deref(), Some(spool.as_path()));
+        assert_eq!(fs::read(&spool).unwrap(), b"\x1f\x8b\xff ok");
+    }
+
+    #[tokio::test]
+    async fn text_results_that_are_not_utf8_report_the_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        let error = exchange(&mut client, &dir.path().join("spool"), |request| signed_bytes(reply(&request), b"ok\xff"))
+            .await
+            .unwrap_err();
+        // `data` comes first in the envelope, after `{"status":true,"data":"`.
+        let offset = format!("not valid UTF-8 at byte {}", 23 + 2);
+        assert!(error.to_string().contains(&offset), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn a_missing_query_hash_is_accepted_only_unless_signed_or_required() {
+        let dir = tempfile::tempdir().unwrap();
+        for (sign_query, require_query_hash) in [(false, false), (true, false), (false, true)] {
+            let mut client = client(ClientConfig { sign_query, require_query_hash, ..config(dir.path()) });
+            cached_session(&mut client, SESSION_ID);
+            let result = exchange(&mut client, &dir.path().join("spool"), |request| {
+                signed(Response { query_hash: None, ..reply(&request) })
+            })
+            .await;
+            match result {
+                Ok(_) => {
+                    assert!(!sign_query && !require_query_hash);
+                    assert!(client.warned_missing_hash.load(Ordering::Relaxed));
+                }
+                Err(error) => {
+                    assert!(sign_query || require_query_hash, "{}", error);
+                    assert!(error.to_string().contains("has no query_hash"), "{}", error);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_response_for_another_query_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client(config(dir.path()));
+        let error = exchange(&mut client, &dir.path().join("spool"), |request| {
+            signed(Response { query_hash: Some(query_hash("SELECT * FROM processes")), ..reply(&request) })
+        })
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("different query"), "{}", error);
+    }
+}
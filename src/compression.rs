@@ -0,0 +1,116 @@
+//! Compression of result files as they are written.
+//!
+//! Results are compressed before they are encrypted, since ciphertext does
+//! not compress. A compressed result keeps its format extension and gains the
+//! compression one, e.g. `alerts.json.gz` or `alerts.json.zst.enc`.
+
+use crate::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Write results as received
+    #[default]
+    None,
+    /// gzip at the default level
+    Gzip,
+    /// Zstandard at the default level
+    Zstd,
+}
+
+impl Compression {
+    /// Suffix appended to compressed result files.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gz"),
+            Self::Zstd => Some("zst"),
+        }
+    }
+
+    pub fn compress_stream(self, mut reader: impl Read, mut writer: impl Write) -> Result<()> {
+        match self {
+            Self::None => {
+                io::copy(&mut reader, &mut writer)?;
+            }
+            Self::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(&mut writer, flate2::Compression::default());
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Self::Zstd => zstd::stream::copy_encode(reader, &mut writer, zstd::DEFAULT_COMPRESSION_LEVEL)?,
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn decompress_stream(self, mut reader: impl Read, mut writer: impl Write) -> Result<()> {
+        match self {
+            Self::None => {
+                io::copy(&mut reader, &mut writer)?;
+            }
+            Self::Gzip => {
+                io::copy(&mut flate2::read::MultiGzDecoder::new(reader), &mut writer)?;
+            }
+            Self::Zstd => zstd::stream::copy_decode(reader, &mut writer)?,
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.compress_stream(data, &mut out)?;
+        Ok(out)
+    }
+
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len());
+        self.decompress_stream(data, &mut out)?;
+        Ok(out)
+    }
+
+    /// Compresses `src` into `dst`, removing `dst` again on failure.
+    pub fn compress_file(self, src: &Path, dst: &Path) -> Result<()> {
+        let reader = BufReader::new(File::open(src)?);
+        let result = File::create(dst)
+            .map_err(Into::into)
+            .and_then(|file| self.compress_stream(reader, BufWriter::new(file)));
+        if result.is_err() {
+            let _ = fs::remove_file(dst);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_results_decompress_to_the_original_bytes() {
+        let data = br#"{"hits":{"hits":[{"_source":{"rule":{"level":3}}}]}}"#.repeat(200);
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compress(&data).unwrap();
+            if compression != Compression::None {
+                assert!(compressed.len() < data.len() / 10, "{:?} left {} bytes", compression, compressed.len());
+            }
+            assert_eq!(compression.decompress(&compressed).unwrap(), data, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn files_are_compressed_as_a_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("result.json"), dir.path().join("result.json.zst"));
+        fs::write(&src, b"line\n".repeat(10_000)).unwrap();
+        Compression::Zstd.compress_file(&src, &dst).unwrap();
+        let compressed = fs::read(&dst).unwrap();
+        assert_eq!(Compression::Zstd.decompress(&compressed).unwrap(), fs::read(&src).unwrap());
+    }
+}
@@ -0,0 +1,216 @@
+use crate::info;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// An exchange slower than this many times the fastest one seen counts as a
+/// sign that the server is struggling.
+const LATENCY_TOLERANCE: u32 = 2;
+
+/// Limit on concurrent conduit exchanges that adapts to how the server copes
+/// (`--adaptive-concurrency`), additive-increase, multiplicative-decrease.
+///
+/// The limit starts at one and grows by one after a window of fast,
+/// successful exchanges as long as the limit, up to `max`. A retryable error,
+/// or an exchange slower than [`LATENCY_TOLERANCE`] times the fastest seen,
+/// halves it; at most once per window, so one burst of slow responses is
+/// only counted once.
+pub(crate) struct AdaptiveConcurrency {
+    semaphore: Arc<Semaphore>,
+    max: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    limit: usize,
+    /// Permits to retire as they are released, when the limit dropped below
+    /// the number in use.
+    debt: usize,
+    /// Fast, successful exchanges since the limit last changed.
+    window: usize,
+    /// Exchanges recorded since the limit was last lowered.
+    since_decrease: usize,
+    fastest: Option<Duration>,
+}
+
+/// What an [`AdaptiveConcurrency`] learned in one scan, for the next to
+/// start from rather than from one exchange at a time
+/// (`ScanConfig.adaptive_state`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdaptiveState {
+    /// The server and ceiling the state was learned under, as
+    /// `host:port max N`; a scan with others starts afresh.
+    pub key: String,
+    /// Exchanges allowed at the same time when the scan ended.
+    pub limit: usize,
+    /// The fastest exchange seen, the baseline slow responses are judged by.
+    pub fastest: Option<Duration>,
+}
+
+/// Held for the length of one exchange; see [`AdaptiveConcurrency::acquire`].
+pub(crate) struct ConcurrencyPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    owner: Arc<AdaptiveConcurrency>,
+}
+
+impl AdaptiveConcurrency {
+    pub(crate) fn new(max: usize) -> Arc<Self> {
+        Self::resume(max, 1, None)
+    }
+
+    /// A limit starting where an earlier one left off: at `limit`, capped
+    /// by `max`, judging latency against `fastest`.
+    pub(crate) fn resume(max: usize, limit: usize, fastest: Option<Duration>) -> Arc<Self> {
+        let max = max.max(1);
+        let limit = limit.clamp(1, max);
+        Arc::new(Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            max,
+            state: Mutex::new(State { limit, debt: 0, window: 0, since_decrease: usize::MAX, fastest }),
+        })
+    }
+
+    /// The limit and latency baseline as they stand, filed under `key`.
+    pub(crate) fn snapshot(&self, key: String) -> AdaptiveState {
+        let state = self.lock();
+        AdaptiveState { key, limit: state.limit, fastest: state.fastest }
+    }
+
+    /// Waits until another exchange may start.
+    pub(crate) async fn acquire(self: &Arc<Self>) -> ConcurrencyPermit {
+        let permit = self.semaphore.clone().acquire_owned().await.expect("the semaphore is never closed");
+        ConcurrencyPermit { permit: Some(permit), owner: self.clone() }
+    }
+
+    /// Exchanges allowed at the same time.
+    #[cfg(test)]
+    pub(crate) fn limit(&self) -> usize {
+        self.lock().limit
+    }
+
+    /// Adjusts the limit after an exchange that took `latency` and failed in
+    /// a way worth retrying unless `ok`.
+    pub(crate) fn record(&self, latency: Duration, ok: bool) {
+        let mut state = self.lock();
+        state.since_decrease = state.since_decrease.saturating_add(1);
+        if ok {
+            state.fastest = Some(state.fastest.map_or(latency, |fastest| fastest.min(latency)));
+        }
+        let slow = state.fastest.is_some_and(|fastest| latency > fastest * LATENCY_TOLERANCE);
+
+        if !ok || slow {
+            if state.since_decrease < state.limit || state.limit == 1 {
+                return;
+            }
+            let lowered = (state.limit / 2).max(1);
+            let retired = state.limit - lowered;
+            state.debt += retired - self.semaphore.forget_permits(retired);
+            state.limit = lowered;
+            state.window = 0;
+            state.since_decrease = 0;
+            info!(
+                "Lowered conduit concurrency to {} after {} ({:.0} ms)",
+                lowered,
+                if ok { "a slow response" } else { "an error" },
+                latency.as_secs_f64() * 1000.0
+            );
+            return;
+        }
+
+        state.window += 1;
+        if state.window >= state.limit && state.limit < self.max {
+            match state.debt {
+                0 => self.semaphore.add_permits(1),
+                _ => state.debt -= 1,
+            }
+            state.limit += 1;
+            state.window = 0;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        let mut state = self.owner.lock();
+        if state.debt > 0 {
+            state.debt -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Duration = Duration::from_millis(10);
+
+    #[tokio::test]
+    async fn the_limit_grows_while_responses_are_fast_and_halves_as_latency_rises() {
+        let concurrency = AdaptiveConcurrency::new(8);
+        for _ in 0..40 {
+            concurrency.record(FAST, true);
+        }
+        assert_eq!(concurrency.limit(), 8);
+        assert_eq!(concurrency.semaphore.available_permits(), 8);
+
+        let mut limits = Vec::new();
+        for latency in [15, 30, 45, 60, 80, 100, 130, 160, 200, 250, 300, 400] {
+            concurrency.record(Duration::from_millis(latency), true);
+            limits.push(concurrency.limit());
+        }
+        assert!(limits.windows(2).all(|pair| pair[1] <= pair[0]), "{:?}", limits);
+        assert_eq!(limits, [8, 4, 4, 4, 4, 2, 2, 1, 1, 1, 1, 1]);
+        assert_eq!(concurrency.semaphore.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn errors_lower_the_limit_and_never_below_one() {
+        let concurrency = AdaptiveConcurrency::new(4);
+        for _ in 0..10 {
+            concurrency.record(FAST, true);
+        }
+        assert_eq!(concurrency.limit(), 4);
+        for _ in 0..10 {
+            concurrency.record(FAST, false);
+        }
+        assert_eq!(concurrency.limit(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_resumed_limit_starts_where_the_snapshot_left_off() {
+        let concurrency = AdaptiveConcurrency::new(4);
+        for _ in 0..10 {
+            concurrency.record(FAST, true);
+        }
+        let state = concurrency.snapshot("server max 4".to_string());
+        assert_eq!((state.limit, state.fastest), (4, Some(FAST)));
+
+        let resumed = AdaptiveConcurrency::resume(4, state.limit, state.fastest);
+        assert_eq!((resumed.limit(), resumed.semaphore.available_permits()), (4, 4));
+        resumed.record(FAST * 3, true);
+        assert_eq!(resumed.limit(), 2);
+        assert_eq!(AdaptiveConcurrency::resume(3, state.limit, None).limit(), 3);
+    }
+
+    #[tokio::test]
+    async fn permits_in_use_are_retired_as_they_are_released() {
+        let concurrency = AdaptiveConcurrency::new(4);
+        for _ in 0..10 {
+            concurrency.record(FAST, true);
+        }
+        let permits: Vec<ConcurrencyPermit> = futures::future::join_all((0..4).map(|_| concurrency.acquire())).await;
+        concurrency.record(FAST * 3, true);
+        assert_eq!(concurrency.limit(), 2);
+        assert_eq!(concurrency.semaphore.available_permits(), 0);
+        drop(permits);
+        assert_eq!(concurrency.semaphore.available_permits(), 2);
+    }
+}
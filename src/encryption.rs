@@ -0,0 +1,340 @@
+//! At-rest encryption for query results and the session cache.
+//!
+//! An encrypted file starts with a header (magic, key derivation parameters and
+//! a stream nonce) followed by AES-256-GCM segments in the STREAM construction,
+//! so large spooled results are encrypted without loading them into memory.
+//! The header is authenticated as associated data of every segment, and a
+//! truncated or reordered file fails to decrypt.
+
+use crate::Result;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use rand::RngCore;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Extension appended to encrypted result files.
+pub const ENCRYPTED_EXTENSION: &str = "enc";
+
+const MAGIC: &[u8; 6] = b"SXENC1";
+const KDF_KEY_FILE: u8 = 0;
+const KDF_ARGON2ID: u8 = 1;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+/// The 12-byte GCM nonce minus the 5 bytes STREAM uses for its counter.
+const NONCE_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+const SEGMENT_SIZE: usize = 64 * 1024;
+/// Upper bounds on Argon2 parameters read from a header, so a crafted file
+/// cannot make decryption allocate or spin without limit.
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+
+#[derive(Clone)]
+enum Secret {
+    KeyFile,
+    Passphrase { passphrase: String, params: Params, salt: [u8; SALT_LEN] },
+}
+
+/// Encrypts and decrypts files with a key read from a key file or derived
+/// from a passphrase with Argon2id.
+#[derive(Clone)]
+pub struct OutputCipher {
+    key: Key<Aes256Gcm>,
+    secret: Secret,
+}
+
+impl fmt::Debug for OutputCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kdf = match self.secret {
+            Secret::KeyFile => "key file",
+            Secret::Passphrase { .. } => "argon2id",
+        };
+        f.debug_struct("OutputCipher").field("kdf", &kdf).finish_non_exhaustive()
+    }
+}
+
+impl OutputCipher {
+    /// Derives a key from `passphrase` with a fresh random salt. Every file
+    /// written by this cipher shares that salt, so the (deliberately slow)
+    /// derivation runs once per process.
+    pub fn from_passphrase(passphrase: &str) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let params = Params::default();
+        let key = derive_key(passphrase, &params, &salt)?;
+        Ok(Self {
+            key,
+            secret: Secret::Passphrase { passphrase: passphrase.to_string(), params, salt },
+        })
+    }
+
+    /// Reads a 32-byte key stored raw or base64-encoded in `path`.
+    pub fn from_key_file(path: &Path) -> Result<Self> {
+        let content = fs::read(path)
+            .map_err(|e| format!("Failed to read key file {}: {}", path.display(), e))?;
+        let key = if content.len() == KEY_LEN {
+            content
+        } else {
+            BASE64
+                .decode(String::from_utf8_lossy(&content).trim())
+                .ok()
+                .filter(|key| key.len() == KEY_LEN)
+                .ok_or_else(|| format!(
+                    "Key file {} must contain {} raw bytes or their base64 encoding",
+                    path.display(),
+                    KEY_LEN
+                ))?
+        };
+        Ok(Self {
+            key: *Key::<Aes256Gcm>::from_slice(&key),
+            secret: Secret::KeyFile,
+        })
+    }
+
+    fn header(&self, nonce: &[u8; NONCE_LEN]) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        match &self.secret {
+            Secret::KeyFile => header.push(KDF_KEY_FILE),
+            Secret::Passphrase { params, salt, .. } => {
+                header.push(KDF_ARGON2ID);
+                header.extend_from_slice(&params.m_cost().to_le_bytes());
+                header.extend_from_slice(&params.t_cost().to_le_bytes());
+                header.extend_from_slice(&params.p_cost().to_le_bytes());
+                header.extend_from_slice(salt);
+            }
+        }
+        header.extend_from_slice(nonce);
+        header
+    }
+
+    /// Reads a header from `reader`, returning it with the key and nonce it selects.
+    fn read_header(&self, reader: &mut impl Read) -> Result<(Vec<u8>, Key<Aes256Gcm>, [u8; NONCE_LEN])> {
+        let mut header = vec![0u8; MAGIC.len() + 1];
+        reader.read_exact(&mut header).map_err(|_| "Not an encrypted conduit file")?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err("Not an encrypted conduit file".into());
+        }
+
+        let key = match (header[MAGIC.len()], &self.secret) {
+            (KDF_KEY_FILE, Secret::KeyFile) => self.key,
+            (KDF_ARGON2ID, Secret::Passphrase { passphrase, params: own_params, salt: own_salt }) => {
+                let mut kdf = [0u8; 12 + SALT_LEN];
+                reader.read_exact(&mut kdf).map_err(|_| "Truncated encryption header")?;
+                header.extend_from_slice(&kdf);
+
+                let word = |i: usize| u32::from_le_bytes(kdf[i * 4..i * 4 + 4].try_into().unwrap());
+                let (m_cost, t_cost, p_cost) = (word(0), word(1), word(2));
+                let salt = &kdf[12..];
+                if m_cost > MAX_M_COST || t_cost > MAX_T_COST || p_cost > MAX_P_COST {
+                    return Err("Unsupported key derivation parameters in header".into());
+                }
+                let own = (own_params.m_cost(), own_params.t_cost(), own_params.p_cost());
+                if salt == own_salt && (m_cost, t_cost, p_cost) == own {
+                    self.key
+                } else {
+                    let params = Params::new(m_cost, t_cost, p_cost, Some(KEY_LEN))
+                        .map_err(|e| format!("Invalid key derivation parameters in header: {}", e))?;
+                    derive_key(passphrase, &params, salt)?
+                }
+            }
+            (KDF_KEY_FILE, _) => return Err("File was encrypted with a key file, not a passphrase".into()),
+            (KDF_ARGON2ID, _) => return Err("File was encrypted with a passphrase, not a key file".into()),
+            (other, _) => return Err(format!("Unknown key derivation id {} in header", other).into()),
+        };
+
+        let mut nonce = [0u8; NONCE_LEN];
+        reader.read_exact(&mut nonce).map_err(|_| "Truncated encryption header")?;
+        header.extend_from_slice(&nonce);
+        Ok((header, key, nonce))
+    }
+
+    /// Encrypts everything read from `reader` into `writer`.
+    pub fn encrypt_stream(&self, mut reader: impl Read, mut writer: impl Write) -> Result<()> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let header = self.header(&nonce);
+        writer.write_all(&header)?;
+
+        let mut encryptor = EncryptorBE32::from_aead(Aes256Gcm::new(&self.key), (&nonce).into());
+        let mut segment = read_segment(&mut reader, SEGMENT_SIZE)?;
+        loop {
+            let next = read_segment(&mut reader, SEGMENT_SIZE)?;
+            let payload = Payload { msg: &segment, aad: &header };
+            if next.is_empty() {
+                let sealed = encryptor.encrypt_last(payload).map_err(|_| "Encryption failed")?;
+                writer.write_all(&sealed)?;
+                break;
+            }
+            let sealed = encryptor.encrypt_next(payload).map_err(|_| "Encryption failed")?;
+            writer.write_all(&sealed)?;
+            segment = next;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Decrypts a stream produced by [`OutputCipher::encrypt_stream`], failing
+    /// if the key is wrong or the data was modified or truncated.
+    pub fn decrypt_stream(&self, mut reader: impl Read, mut writer: impl Write) -> Result<()> {
+        let (header, key, nonce) = self.read_header(&mut reader)?;
+        let mut decryptor = DecryptorBE32::from_aead(Aes256Gcm::new(&key), (&nonce).into());
+        let tampered = "Decryption failed: wrong key or the file was modified";
+
+        let mut segment = read_segment(&mut reader, SEGMENT_SIZE + TAG_LEN)?;
+        loop {
+            let next = read_segment(&mut reader, SEGMENT_SIZE + TAG_LEN)?;
+            let payload = Payload { msg: &segment, aad: &header };
+            if next.is_empty() {
+                writer.write_all(&decryptor.decrypt_last(payload).map_err(|_| tampered)?)?;
+                break;
+            }
+            writer.write_all(&decryptor.decrypt_next(payload).map_err(|_| tampered)?)?;
+            segment = next;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(plaintext.len() + 64);
+        self.encrypt_stream(plaintext, &mut out)?;
+        Ok(out)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len());
+        self.decrypt_stream(data, &mut out)?;
+        Ok(out)
+    }
+
+    pub fn encrypt_file(&self, src: &Path, dst: &Path) -> Result<()> {
+        let reader = BufReader::new(File::open(src)?);
+        let result = File::create(dst)
+            .map_err(Into::into)
+            .and_then(|file| self.encrypt_stream(reader, BufWriter::new(file)));
+        if result.is_err() {
+            let _ = fs::remove_file(dst);
+        }
+        result
+    }
+
+    /// Decrypts `src` into `dst`, removing `dst` again if authentication fails.
+    pub fn decrypt_file(&self, src: &Path, dst: &Path) -> Result<()> {
+        let reader = BufReader::new(File::open(src)?);
+        let result = File::create(dst)
+            .map_err(Into::into)
+            .and_then(|file| self.decrypt_stream(reader, BufWriter::new(file)));
+        if result.is_err() {
+            let _ = fs::remove_file(dst);
+        }
+        result
+    }
+}
+
+fn derive_key(passphrase: &str, params: &Params, salt: &[u8]) -> Result<Key<Aes256Gcm>> {
+    let mut key = Key::<Aes256Gcm>::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+/// Reads up to `len` bytes, returning fewer only at end of input.
+fn read_segment(reader: &mut impl Read, len: usize) -> Result<Vec<u8>> {
+    let mut segment = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut segment)?;
+    Ok(segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_file(dir: &Path, key: &[u8]) -> OutputCipher {
+        let path = dir.join("key");
+        fs::write(&path, key).unwrap();
+        OutputCipher::from_key_file(&path).unwrap()
+    }
+
+    #[test]
+    fn data_round_trips_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let cipher = key_file(dir.path(), &[7; KEY_LEN]);
+        for len in [0, 1, SEGMENT_SIZE, 2 * SEGMENT_SIZE + 5] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let sealed = cipher.encrypt(&plaintext).unwrap();
+            assert!(sealed.starts_with(MAGIC));
+            assert_eq!(cipher.decrypt(&sealed).unwrap(), plaintext, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn a_base64_key_file_is_the_same_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = key_file(dir.path(), &[9; KEY_LEN]);
+        let encoded = key_file(dir.path(), format!("{}\n", BASE64.encode([9; KEY_LEN])).as_bytes());
+        assert_eq!(encoded.decrypt(&raw.encrypt(b"secret").unwrap()).unwrap(), b"secret");
+
+        fs::write(dir.path().join("short"), [1; 16]).unwrap();
+        assert!(OutputCipher::from_key_file(&dir.path().join("short")).is_err());
+    }
+
+    #[test]
+    fn modified_truncated_and_foreign_files_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let cipher = key_file(dir.path(), &[7; KEY_LEN]);
+        let sealed = cipher.encrypt(&vec![b'x'; SEGMENT_SIZE + 100]).unwrap();
+
+        let mut flipped = sealed.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(cipher.decrypt(&flipped).is_err());
+
+        let mut header = sealed.clone();
+        header[MAGIC.len() + 1] ^= 1;
+        assert!(cipher.decrypt(&header).is_err());
+
+        let cut = &sealed[..MAGIC.len() + 1 + NONCE_LEN + SEGMENT_SIZE + TAG_LEN];
+        assert!(cipher.decrypt(cut).is_err());
+
+        let other = key_file(dir.path(), &[8; KEY_LEN]);
+        assert!(other.decrypt(&sealed).is_err());
+        assert!(cipher.decrypt(b"{\"plain\":true}").is_err());
+    }
+
+    #[test]
+    fn a_passphrase_decrypts_what_another_process_encrypted() {
+        let sealed = OutputCipher::from_passphrase("correct horse").unwrap().encrypt(b"inventory").unwrap();
+
+        // A new cipher has a new salt, so it derives the key again from the header.
+        let later = OutputCipher::from_passphrase("correct horse").unwrap();
+        assert_eq!(later.decrypt(&sealed).unwrap(), b"inventory");
+        assert!(OutputCipher::from_passphrase("wrong horse").unwrap().decrypt(&sealed).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let error = key_file(dir.path(), &[7; KEY_LEN]).decrypt(&sealed).unwrap_err();
+        assert!(error.to_string().contains("passphrase"), "{}", error);
+    }
+
+    #[test]
+    fn files_are_removed_when_decryption_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let cipher = key_file(dir.path(), &[7; KEY_LEN]);
+        let (plain, sealed, opened) = (dir.path().join("plain"), dir.path().join("sealed"), dir.path().join("opened"));
+        fs::write(&plain, "results").unwrap();
+        cipher.encrypt_file(&plain, &sealed).unwrap();
+        cipher.decrypt_file(&sealed, &opened).unwrap();
+        assert_eq!(fs::read_to_string(&opened).unwrap(), "results");
+
+        let other = key_file(dir.path(), &[8; KEY_LEN]);
+        fs::remove_file(&opened).unwrap();
+        assert!(other.decrypt_file(&sealed, &opened).is_err());
+        assert!(!opened.exists());
+    }
+}
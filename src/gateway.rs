@@ -0,0 +1,1126 @@
+use crate::Result;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+#[cfg(feature = "gateway")]
+use {
+    crate::client::Client,
+    crate::info,
+    base64::engine::general_purpose::STANDARD,
+    std::collections::HashMap,
+    reqwest_middleware::{ClientWithMiddleware, Middleware, Next},
+    std::fmt,
+    std::io,
+    std::path::{Path, PathBuf},
+    std::sync::Arc,
+    task_local_extensions::Extensions,
+    std::time::{SystemTime, UNIX_EPOCH},
+    tokio::time::sleep,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Agent {
+    pub id: String,
+    pub name: String,
+    pub groups: Vec<String>,
+    pub platform: Option<String>,
+    /// Cluster node the agent reports to; absent on single-node deployments.
+    pub node: Option<String>,
+    pub ip: Option<String>,
+    /// Connection status as Wazuh reports it, e.g. `active` or `disconnected`.
+    pub status: Option<String>,
+}
+
+/// An entry of a Wazuh listing's `data.failed_items`: items the API could
+/// not process and left out of the listing, so the scan never sees them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailedItem {
+    /// Manager the listing came from, when managers are named; set by the scan.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manager: Option<String>,
+    /// What was being listed, e.g. `agents for group web`.
+    pub listing: String,
+    /// Ids of the items, as Wazuh names them.
+    pub ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<i64>,
+    pub message: String,
+}
+
+impl FailedItem {
+    /// Reads the `data.failed_items` of a Wazuh listing of `listing`.
+    #[cfg(feature = "gateway")]
+    pub(crate) fn from_listing(json: &serde_json::Value, listing: &str) -> Vec<Self> {
+        let Some(failed) = json["data"]["failed_items"].as_array() else {
+            return Vec::new();
+        };
+        failed
+            .iter()
+            .map(|item| FailedItem {
+                manager: None,
+                listing: listing.to_string(),
+                ids: item["id"]
+                    .as_array()
+                    .map(|ids| ids.iter().map(|id| id.as_str().map_or_else(|| id.to_string(), str::to_string)).collect())
+                    .unwrap_or_default(),
+                code: item["error"]["code"].as_i64(),
+                message: item["error"]["message"].as_str().unwrap_or("no reason given").to_string(),
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for FailedItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(manager) = &self.manager {
+            write!(f, "manager {}: ", manager)?;
+        }
+        write!(f, "{} {}: {}", self.listing, self.ids.join(", "), self.message)?;
+        match self.code {
+            Some(code) => write!(f, " (code {})", code),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Node of agents whose manager reports none, as in a single-node deployment.
+pub const IMPLICIT_NODE: &str = "default";
+
+pub const POOL_MAX_IDLE_PER_HOST: usize = 16;
+pub const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+pub const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+/// Items requested per page (`limit`) when listing groups and agents.
+pub const PAGE_SIZE: usize = 500;
+/// `User-Agent` of gateway calls unless [`HttpOptions::user_agent`] is changed.
+pub const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+/// Headers the client sets itself, which [`HttpOptions::headers`] may not add.
+const RESERVED_HEADERS: [&str; 5] = ["authorization", "content-length", "content-type", "host", "user-agent"];
+/// Tokens are renewed once they are this close to their `exp` claim.
+pub const TOKEN_REFRESH_BUFFER: Duration = Duration::from_secs(60);
+/// Assumed lifetime of a token whose `exp` claim cannot be read.
+#[cfg(feature = "gateway")]
+const UNKNOWN_TOKEN_LIFETIME: Duration = Duration::from_secs(300);
+/// Scheme of a gateway URL naming a Unix domain socket, as in
+/// `unix:///run/wazuh-gateway.sock`.
+pub const UNIX_SOCKET_SCHEME: &str = "unix://";
+/// Base URL of the calls to a gateway on a Unix domain socket; its host is
+/// sent as the `Host` header.
+#[cfg(feature = "gateway")]
+const UNIX_SOCKET_BASE: &str = "http://localhost";
+
+/// Credentials for an authenticating proxy in front of the gateway, sent as
+/// the `Authorization` header of every gateway call. They are separate from
+/// the Wazuh username and password, which travel in the request body.
+#[derive(Clone, PartialEq)]
+pub enum GatewayAuth {
+    Bearer(String),
+    Basic { username: String, password: Option<String> },
+}
+
+impl std::fmt::Debug for GatewayAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bearer(_) => f.write_str("Bearer([REDACTED])"),
+            Self::Basic { username, .. } => write!(f, "Basic {{ username: {:?}, password: [REDACTED] }}", username),
+        }
+    }
+}
+
+/// Connection pool, timeout and proxy authentication settings for the
+/// gateway HTTP client.
+///
+/// HTTP/2 is negotiated through ALPN whenever the gateway is reached over
+/// TLS and offers it; `http2_prior_knowledge` forces it for a plain-HTTP
+/// gateway known to speak HTTP/2.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpOptions {
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Limit on a whole request, from sending it to reading the body.
+    pub request_timeout: Duration,
+    pub tcp_keepalive: Duration,
+    pub http2_prior_knowledge: bool,
+    pub auth: Option<GatewayAuth>,
+    pub user_agent: String,
+    /// Extra headers sent on every call, e.g. for a WAF's routing rules;
+    /// each must pass [`check_header`].
+    pub headers: Vec<(String, String)>,
+    /// Layers the calls of a [`Client`] pass through; other users of
+    /// [`build`](Self::build) do without them.
+    #[cfg(feature = "gateway")]
+    pub middleware: GatewayMiddleware,
+}
+
+/// A `reqwest-middleware` stack for the gateway calls of a [`Client`]:
+/// `authenticate` and every `fetch_*` request go through it, outermost
+/// layer first, before reaching the gateway.
+///
+/// The default stack is empty. The client already retries transient
+/// failures with its [`RetryPolicy`](crate::retry::RetryPolicy) and renews
+/// its Wazuh token before it expires, so layers doing either would only
+/// repeat that; tracing, metrics and custom auth layers fit in here.
+#[cfg(feature = "gateway")]
+#[derive(Clone, Default)]
+pub struct GatewayMiddleware {
+    stack: Vec<Arc<dyn Middleware>>,
+}
+
+#[cfg(feature = "gateway")]
+impl GatewayMiddleware {
+    /// Adds `middleware` inside the layers added before it.
+    pub fn with(mut self, middleware: impl Middleware) -> Self {
+        self.stack.push(Arc::new(middleware));
+        self
+    }
+
+    /// Adds a layer that may be shared with other stacks.
+    pub fn with_arc(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.stack.push(middleware);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+}
+
+#[cfg(feature = "gateway")]
+impl fmt::Debug for GatewayMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GatewayMiddleware({} layers)", self.stack.len())
+    }
+}
+
+/// Stacks are equal when they hold the same layers, not merely alike ones.
+#[cfg(feature = "gateway")]
+impl PartialEq for GatewayMiddleware {
+    fn eq(&self, other: &Self) -> bool {
+        self.stack.len() == other.stack.len() && self.stack.iter().zip(&other.stack).all(|(a, b)| Arc::ptr_eq(a, b))
+    }
+}
+
+/// Checks that `name: value` is a valid header the client does not already
+/// set itself. The value is left out of the error, as it may be a secret.
+pub fn check_header(name: &str, value: &str) -> std::result::Result<(), String> {
+    // A name is an HTTP token (RFC 9110 section 5.6.2); a value is visible
+    // ASCII, spaces and tabs.
+    let token = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    if name.is_empty() || !name.bytes().all(token) {
+        return Err(format!("invalid header name {:?}", name));
+    }
+    if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+        return Err(format!("the {} header is set by the client itself", name));
+    }
+    if !value.bytes().all(|b| b == b'\t' || (b' '..=b'~').contains(&b)) {
+        return Err(format!("invalid value for header {}", name));
+    }
+    Ok(())
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: POOL_MAX_IDLE_PER_HOST,
+            pool_idle_timeout: POOL_IDLE_TIMEOUT,
+            connect_timeout: CONNECT_TIMEOUT,
+            request_timeout: REQUEST_TIMEOUT,
+            tcp_keepalive: TCP_KEEPALIVE,
+            http2_prior_knowledge: false,
+            auth: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: Vec::new(),
+            #[cfg(feature = "gateway")]
+            middleware: GatewayMiddleware::default(),
+        }
+    }
+}
+
+#[cfg(feature = "gateway")]
+impl HttpOptions {
+    pub fn build(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .tcp_keepalive(self.tcp_keepalive);
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        Ok(builder.default_headers(self.default_headers()?).build()?)
+    }
+
+    /// The `User-Agent`, extra headers and proxy credentials sent on every call.
+    fn default_headers(&self) -> Result<reqwest::header::HeaderMap> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::USER_AGENT,
+            reqwest::header::HeaderValue::from_str(&self.user_agent).map_err(|_| "Gateway user agent is not a valid header value")?,
+        );
+        for (name, value) in &self.headers {
+            check_header(name, value).map_err(|e| format!("Gateway header: {}", e))?;
+            headers.append(reqwest::header::HeaderName::from_bytes(name.as_bytes())?, reqwest::header::HeaderValue::from_str(value)?);
+        }
+        if let Some(auth) = &self.auth {
+            let value = match auth {
+                GatewayAuth::Bearer(token) => format!("Bearer {}", token),
+                GatewayAuth::Basic { username, password } => {
+                    let credentials = format!("{}:{}", username, password.as_deref().unwrap_or(""));
+                    format!("Basic {}", STANDARD.encode(credentials))
+                }
+            };
+            let mut value = reqwest::header::HeaderValue::from_str(&value)
+                .map_err(|_| "Gateway credentials contain characters not allowed in an HTTP header")?;
+            value.set_sensitive(true);
+            headers.insert(reqwest::header::AUTHORIZATION, value);
+        }
+        Ok(headers)
+    }
+
+    /// [`build`](Self::build), wrapped in the `middleware` stack. Inside the
+    /// stack, calls to a gateway on a Unix domain socket leave reqwest,
+    /// which only speaks TCP, for a connection of their own.
+    pub fn build_with_middleware(&self) -> Result<ClientWithMiddleware> {
+        let transport = UnixSocketTransport {
+            headers: self.default_headers()?,
+            connect_timeout: self.connect_timeout,
+            request_timeout: self.request_timeout,
+            http2_prior_knowledge: self.http2_prior_knowledge,
+        };
+        let mut stack = self.middleware.stack.clone();
+        stack.push(Arc::new(transport));
+        Ok(ClientWithMiddleware::new(self.build()?, stack))
+    }
+}
+
+/// Request extension naming the socket a call is sent over.
+#[cfg(feature = "gateway")]
+#[derive(Clone)]
+struct UnixSocket(PathBuf);
+
+/// Innermost layer of every gateway client: sends a call carrying a
+/// [`UnixSocket`] over a fresh connection to it, with the headers and
+/// timeouts reqwest would have applied, and passes any other call on.
+#[cfg(feature = "gateway")]
+struct UnixSocketTransport {
+    headers: reqwest::header::HeaderMap,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    http2_prior_knowledge: bool,
+}
+
+#[cfg(feature = "gateway")]
+#[async_trait::async_trait]
+impl Middleware for UnixSocketTransport {
+    async fn handle(
+        &self,
+        request: reqwest::Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        let Some(UnixSocket(path)) = extensions.get::<UnixSocket>().cloned() else {
+            return next.run(request, extensions).await;
+        };
+        match tokio::time::timeout(self.request_timeout, self.send(&path, request)).await {
+            Ok(response) => response,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out")),
+        }
+        .map_err(|e| reqwest_middleware::Error::middleware(io::Error::new(e.kind(), format!("gateway socket {}: {}", path.display(), e))))
+    }
+}
+
+#[cfg(feature = "gateway")]
+impl UnixSocketTransport {
+    #[cfg(unix)]
+    async fn send(&self, path: &Path, request: reqwest::Request) -> io::Result<reqwest::Response> {
+        let stream = match tokio::time::timeout(self.connect_timeout, tokio::net::UnixStream::connect(path)).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")),
+        };
+        let (mut sender, connection) =
+            hyper::client::conn::Builder::new().http2_only(self.http2_prior_knowledge).handshake(stream).await.map_err(io::Error::other)?;
+        tokio::spawn(connection);
+
+        let url = request.url();
+        let target = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let mut headers = request.headers().clone();
+        for name in self.headers.keys() {
+            if !headers.contains_key(name) {
+                for value in self.headers.get_all(name) {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+        let host = url.host_str().unwrap_or("localhost");
+        headers.insert(hyper::header::HOST, hyper::header::HeaderValue::from_str(host).map_err(io::Error::other)?);
+        let body = match request.body() {
+            Some(body) => body.as_bytes().ok_or_else(|| io::Error::other("streamed request bodies are not supported"))?.to_vec(),
+            None => Vec::new(),
+        };
+        let mut call = hyper::Request::builder().method(request.method().clone()).uri(target).body(hyper::Body::from(body)).map_err(io::Error::other)?;
+        *call.headers_mut() = headers;
+
+        let response = sender.send_request(call).await.map_err(io::Error::other)?;
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.map_err(io::Error::other)?;
+        Ok(hyper::Response::from_parts(parts, body).into())
+    }
+
+    #[cfg(not(unix))]
+    async fn send(&self, _path: &Path, _request: reqwest::Request) -> io::Result<reqwest::Response> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform"))
+    }
+}
+
+/// HTTP side of a [`Client`]: the gateway it talks to and the token it holds.
+#[cfg(feature = "gateway")]
+#[derive(Clone)]
+pub(crate) struct GatewayState {
+    http: ClientWithMiddleware,
+    url: String,
+    /// Socket the gateway listens on, when `url` named one.
+    socket: Option<PathBuf>,
+    wazuh_endpoint: String,
+    token: Option<String>,
+    /// Unix time the token lapses, from its `exp` claim or an assumed lifetime.
+    token_expires_at: u64,
+    /// Username and password the token is renewed with.
+    credentials: Option<(String, String)>,
+    refresh_buffer: Option<Duration>,
+    /// Whether `http` sends [`HttpOptions::auth`] on every request.
+    proxy_auth: bool,
+    /// Reported by listings since [`Client::take_failed_items`] last ran.
+    failed_items: Vec<FailedItem>,
+}
+
+#[cfg(feature = "gateway")]
+impl Default for GatewayState {
+    fn default() -> Self {
+        Self {
+            http: HttpOptions::default().build_with_middleware().expect("the default gateway client builds"),
+            url: String::new(),
+            socket: None,
+            wazuh_endpoint: String::new(),
+            token: None,
+            token_expires_at: 0,
+            credentials: None,
+            refresh_buffer: None,
+            proxy_auth: false,
+            failed_items: Vec::new(),
+        }
+    }
+}
+
+/// Reads the `exp` claim of a JWT without verifying its signature; the
+/// gateway does that, the client only needs to know when to renew.
+fn token_expiry(token: &str) -> Option<u64> {
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    claims["exp"].as_u64().or_else(|| claims["exp"].as_f64().map(|exp| exp as u64))
+}
+
+/// Checks that a token supplied by the user is shaped like a JWT: three
+/// non-empty base64url segments, the first two JSON objects. Nothing is
+/// verified; the gateway does that. Returns the `exp` claim, if any.
+pub fn check_wazuh_token(token: &str) -> Result<Option<u64>> {
+    let segments: Vec<&str> = token.split('.').collect();
+    if segments.len() != 3 || segments.iter().any(|s| s.is_empty()) {
+        return Err("Wazuh token is not a JWT: expected three dot-separated segments".into());
+    }
+    for (segment, part) in segments[..2].iter().zip(["header", "payload"]) {
+        let json = URL_SAFE_NO_PAD
+            .decode(segment.trim_end_matches('='))
+            .map_err(|e| format!("Wazuh token {} is not base64url: {}", part, e))?;
+        serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(&json)
+            .map_err(|e| format!("Wazuh token {} is not a JSON object: {}", part, e))?;
+    }
+    Ok(token_expiry(token))
+}
+
+#[cfg(feature = "gateway")]
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(feature = "gateway")]
+#[derive(Debug, Serialize, Deserialize)]
+struct WazuhRequest {
+    endpoint: String,
+    token: String,
+    params: HashMap<String, String>,
+}
+
+#[cfg(feature = "gateway")]
+#[derive(Debug, Serialize, Deserialize)]
+struct WazuhAuthRequest {
+    endpoint: String,
+    username: String,
+    password: String,
+}
+
+#[cfg(feature = "gateway")]
+#[derive(Debug, Serialize, Deserialize)]
+struct WazuhAuthResponse {
+    token: Option<String>,
+    error: Option<String>,
+}
+
+/// Error envelope returned by the Wazuh API, `{"error": N, "message": ...,
+/// "remediation": ...}`. Wazuh 4 calls the message `detail` and adds a
+/// `title`; both shapes are accepted.
+#[cfg(feature = "gateway")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct WazuhApiError {
+    /// HTTP status the gateway answered with.
+    #[serde(skip)]
+    pub status: u16,
+    /// Wazuh error code; 0 when the body carried no envelope.
+    #[serde(rename = "error")]
+    pub code: i64,
+    #[serde(default, alias = "detail")]
+    pub message: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub remediation: Option<String>,
+}
+
+/// Well-known classes of [`WazuhApiError`], which decide whether a call is retried.
+#[cfg(feature = "gateway")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WazuhErrorKind {
+    /// The token is missing, invalid or expired.
+    Unauthorized,
+    /// The user lacks the RBAC permission for the call.
+    Forbidden,
+    /// The agent or group does not exist.
+    NotFound,
+    /// Too many requests per minute; worth retrying after a pause.
+    RateLimited,
+    /// Too many failed logins from this address.
+    LoginBlocked,
+    /// A 5xx from the gateway or the manager; worth retrying.
+    Server,
+    Other,
+}
+
+#[cfg(feature = "gateway")]
+impl WazuhApiError {
+    /// Parses the envelope from a response body, falling back to the raw body
+    /// as the message when it is not one.
+    fn from_response(status: u16, body: &str) -> Self {
+        match serde_json::from_str::<Self>(body) {
+            Ok(error) if error.code != 0 || !error.message.is_empty() => Self { status, ..error },
+            _ => Self {
+                status,
+                code: 0,
+                message: body.trim().chars().take(200).collect(),
+                title: None,
+                remediation: None,
+            },
+        }
+    }
+
+    pub fn kind(&self) -> WazuhErrorKind {
+        match (self.status, self.code) {
+            (_, 6000) => WazuhErrorKind::LoginBlocked,
+            (429, _) | (_, 6001 | 6005) => WazuhErrorKind::RateLimited,
+            (401, _) => WazuhErrorKind::Unauthorized,
+            (403, _) | (_, 4000) => WazuhErrorKind::Forbidden,
+            (404, _) | (_, 1701 | 1710) => WazuhErrorKind::NotFound,
+            (500..=599, _) => WazuhErrorKind::Server,
+            _ => WazuhErrorKind::Other,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.kind(), WazuhErrorKind::RateLimited | WazuhErrorKind::Server)
+    }
+}
+
+#[cfg(feature = "gateway")]
+impl fmt::Display for WazuhApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.code == 0 {
+            write!(f, "Wazuh API returned HTTP {}", self.status)?;
+        } else {
+            write!(f, "Wazuh API error {} (HTTP {})", self.code, self.status)?;
+        }
+        match (&self.title, self.message.is_empty()) {
+            (Some(title), false) => write!(f, ": {}: {}", title, self.message)?,
+            (Some(title), true) => write!(f, ": {}", title)?,
+            (None, false) => write!(f, ": {}", self.message)?,
+            (None, true) => {}
+        }
+        if let Some(remediation) = &self.remediation {
+            write!(f, " (remediation: {})", remediation)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "gateway")]
+impl std::error::Error for WazuhApiError {}
+
+/// The gateway, or a proxy in front of it, answered HTTP 401 with a
+/// `WWW-Authenticate` challenge: the request was refused before it reached
+/// Wazuh. A Wazuh 401 (bad token or login) comes back as a [`WazuhApiError`].
+#[cfg(feature = "gateway")]
+#[derive(Debug, Clone)]
+pub struct GatewayAuthError {
+    /// The `WWW-Authenticate` header, e.g. `Basic realm="gateway"`.
+    pub challenge: String,
+    /// Whether [`HttpOptions::auth`] credentials were sent.
+    pub credentials_sent: bool,
+}
+
+#[cfg(feature = "gateway")]
+impl fmt::Display for GatewayAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gateway refused the request with HTTP 401 ({})", self.challenge)?;
+        match self.credentials_sent {
+            true => write!(f, ": the gateway credentials were rejected"),
+            false => write!(f, ": it requires credentials of its own, separate from the Wazuh login"),
+        }
+    }
+}
+
+#[cfg(feature = "gateway")]
+impl std::error::Error for GatewayAuthError {}
+
+/// Bodies are cut to this many characters at verbosity 2.
+#[cfg(feature = "gateway")]
+const TRUNCATED_BODY_CHARS: usize = 512;
+/// JSON keys whose values never reach the log, matched case-insensitively
+/// as substrings.
+#[cfg(feature = "gateway")]
+const SENSITIVE_KEYS: &[&str] = &["token", "password", "secret", "authorization", "api_key"];
+
+/// Replaces the values of sensitive keys anywhere in a JSON body. Bodies that
+/// are not JSON are only described, since they cannot be redacted reliably.
+#[cfg(feature = "gateway")]
+fn redact_body(body: &str) -> String {
+    fn redact(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    let key = key.to_lowercase();
+                    if SENSITIVE_KEYS.iter().any(|k| key.contains(k)) {
+                        *value = serde_json::Value::String("[REDACTED]".into());
+                    } else {
+                        redact(value);
+                    }
+                }
+            }
+            serde_json::Value::Array(items) => items.iter_mut().for_each(redact),
+            _ => {}
+        }
+    }
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(mut json) => {
+            redact(&mut json);
+            json.to_string()
+        }
+        Err(_) => format!("<{} bytes, not JSON>", body.len()),
+    }
+}
+
+#[cfg(feature = "gateway")]
+impl Client {
+    fn log_response(&self, what: &str, status: reqwest::StatusCode, body: &str) {
+        info!("{} status: {} ({} bytes)", what, status, body.len());
+        if self.verbosity >= 2 {
+            let body = redact_body(body);
+            if self.verbosity == 2 && body.chars().count() > TRUNCATED_BODY_CHARS {
+                let cut: String = body.chars().take(TRUNCATED_BODY_CHARS).collect();
+                info!("{} body: {}... (truncated; -vvv shows all)", what, cut);
+            } else {
+                info!("{} body: {}", what, body);
+            }
+        }
+    }
+
+    /// A [`GatewayAuthError`] when the gateway itself refused `response`.
+    fn check_gateway_auth(&self, response: &reqwest::Response) -> Result<()> {
+        if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(());
+        }
+        match response.headers().get(reqwest::header::WWW_AUTHENTICATE) {
+            Some(challenge) => Err(Box::new(GatewayAuthError {
+                challenge: String::from_utf8_lossy(challenge.as_bytes()).into_owned(),
+                credentials_sent: self.gateway.proxy_auth,
+            })),
+            None => Ok(()),
+        }
+    }
+
+    /// Points the client at a Wazuh API gateway and the manager it fronts.
+    /// A `unix:///path/to.sock` URL reaches the gateway over that Unix domain
+    /// socket; any other goes over TCP.
+    pub fn with_gateway(mut self, gateway_url: String, wazuh_endpoint: String) -> Self {
+        match gateway_url.strip_prefix(UNIX_SOCKET_SCHEME) {
+            Some(path) => {
+                self.gateway.socket = Some(PathBuf::from(path));
+                self.gateway.url = UNIX_SOCKET_BASE.to_string();
+            }
+            None => {
+                self.gateway.socket = None;
+                self.gateway.url = gateway_url.trim_end_matches('/').to_string();
+            }
+        }
+        self.gateway.wazuh_endpoint = wazuh_endpoint;
+        self
+    }
+
+    /// A call to `path` of the gateway, sent over its socket if it has one.
+    fn gateway_post(&self, path: &str) -> reqwest_middleware::RequestBuilder {
+        let request = self.gateway.http.post(format!("{}{}", self.gateway.url, path));
+        match &self.gateway.socket {
+            Some(socket) => request.with_extension(UnixSocket(socket.clone())),
+            None => request,
+        }
+    }
+
+    /// Replaces the default gateway HTTP client with one built from `options`.
+    pub fn with_http_options(mut self, options: &HttpOptions) -> Result<Self> {
+        self.gateway.http = options.build_with_middleware()?;
+        self.gateway.proxy_auth = options.auth.is_some();
+        Ok(self)
+    }
+
+    /// Uses a Wazuh token obtained elsewhere instead of calling `authenticate`.
+    /// Without [`Client::set_wazuh_credentials`] it cannot be renewed.
+    pub fn set_wazuh_token(&mut self, token: String) {
+        self.gateway.token_expires_at = Self::expiry_of(&token);
+        self.gateway.token = Some(token);
+    }
+
+    /// Credentials used to renew the token shortly before it expires;
+    /// `authenticate` records them as well.
+    pub fn set_wazuh_credentials(&mut self, username: &str, password: &str) {
+        self.gateway.credentials = Some((username.to_string(), password.to_string()));
+    }
+
+    /// How close to expiry a token may get before it is renewed.
+    pub fn set_token_refresh_buffer(&mut self, buffer: Duration) {
+        self.gateway.refresh_buffer = Some(buffer);
+    }
+
+    fn expiry_of(token: &str) -> u64 {
+        token_expiry(token).unwrap_or_else(|| {
+            info!(
+                "Wazuh token has no readable exp claim; treating it as valid for {}s",
+                UNKNOWN_TOKEN_LIFETIME.as_secs()
+            );
+            unix_now() + UNKNOWN_TOKEN_LIFETIME.as_secs()
+        })
+    }
+
+    /// Renews the token before a request if it is within the refresh buffer
+    /// of expiring, rather than waiting for the gateway to answer 401.
+    async fn ensure_fresh_token(&mut self) -> Result<()> {
+        let buffer = self.gateway.refresh_buffer.unwrap_or(TOKEN_REFRESH_BUFFER).as_secs();
+        let remaining = self.gateway.token_expires_at.saturating_sub(unix_now());
+        if self.gateway.token.is_some() && remaining > buffer {
+            return Ok(());
+        }
+        match self.gateway.credentials.clone() {
+            Some((username, password)) => {
+                info!("Wazuh token expires in {}s; renewing it", remaining);
+                self.authenticate(&username, &password).await
+            }
+            None if self.gateway.token.is_some() => {
+                info!("Wazuh token expires in {}s and cannot be renewed without credentials", remaining);
+                Ok(())
+            }
+            None => Err("No Wazuh token: call authenticate first".into()),
+        }
+    }
+
+    /// The token currently held, from `authenticate` or `set_wazuh_token`.
+    pub fn wazuh_token(&self) -> Option<&str> {
+        self.gateway.token.as_deref()
+    }
+
+    pub async fn authenticate(&mut self, username: &str, password: &str) -> Result<()> {
+        let auth_request = WazuhAuthRequest {
+            endpoint: self.gateway.wazuh_endpoint.clone(),
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+
+        let response = self.gateway_post("/auth")
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .json(&auth_request)
+            .send()
+            .await?;
+        self.check_gateway_auth(&response)?;
+
+        let status = response.status();
+        let body = response.text().await?;
+
+        self.log_response("Auth response", status, &body);
+
+        if status.is_success() {
+            let auth_response: WazuhAuthResponse = serde_json::from_str(&body)?;
+            if let Some(token) = auth_response.token {
+                self.set_wazuh_token(token);
+                self.set_wazuh_credentials(username, password);
+                Ok(())
+            } else {
+                Err("Authentication failed: No token received".into())
+            }
+        } else {
+            Err(format!("Authentication failed: {}", WazuhApiError::from_response(status.as_u16(), &body)).into())
+        }
+    }
+
+    /// Fetches every page of a listing, [`PAGE_SIZE`] items at a time, until
+    /// `data.total_affected_items` have arrived. A gateway that reports no
+    /// total is read as a single page.
+    async fn fetch_affected_items(
+        &mut self,
+        path: &str,
+        params: HashMap<String, String>,
+        what: &str,
+    ) -> Result<Vec<serde_json::Value>> {
+        let mut items = Vec::new();
+        loop {
+            let mut page_params = params.clone();
+            page_params.insert("offset".to_string(), items.len().to_string());
+            page_params.insert("limit".to_string(), PAGE_SIZE.to_string());
+            let (page, total, failed) = self.fetch_page(path, page_params, what).await?;
+            for item in &failed {
+                eprintln!("Warning: Wazuh could not process {}", item);
+            }
+            self.gateway.failed_items.extend(failed);
+            let done = page.is_empty() || total.is_none_or(|total| items.len() + page.len() >= total);
+            items.extend(page);
+            if done {
+                return Ok(items);
+            }
+        }
+    }
+
+    /// One page of a listing with the total the gateway reports and the items
+    /// Wazuh failed to process, retrying transient failures.
+    async fn fetch_page(
+        &mut self,
+        path: &str,
+        params: HashMap<String, String>,
+        what: &str,
+    ) -> Result<(Vec<serde_json::Value>, Option<usize>, Vec<FailedItem>)> {
+        let max_attempts = self.retry.max_attempts;
+        let mut last_error = None;
+        for attempt in 1..=max_attempts {
+            self.ensure_fresh_token().await?;
+            let wazuh_request = WazuhRequest {
+                endpoint: self.gateway.wazuh_endpoint.clone(),
+                token: self.gateway.token.clone().unwrap(),
+                params: params.clone(),
+            };
+
+            let response = self.gateway_post(path)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .json(&wazuh_request)
+                .send()
+                .await?;
+            self.check_gateway_auth(&response)?;
+
+            let status = response.status();
+            let body = response.text().await?;
+            
+            self.log_response("Response", status, &body);
+            
+            if status.is_success() {
+                let json: serde_json::Value = serde_json::from_str(&body)?;
+                if let Some(affected_items) = json["data"]["affected_items"].as_array() {
+                    let total = json["data"]["total_affected_items"].as_u64().map(|total| total as usize);
+                    return Ok((affected_items.clone(), total, FailedItem::from_listing(&json, what)));
+                } else {
+                    info!("Unexpected response structure: no data.affected_items");
+                }
+            } else {
+                let error = WazuhApiError::from_response(status.as_u16(), &body);
+                info!("Request failed: {}", error);
+                if !error.is_retryable() {
+                    return Err(Box::new(error));
+                }
+                last_error = Some(error);
+            }
+            
+            if attempt < max_attempts {
+                let delay = self.retry.delay(attempt);
+                info!("Retrying in {} ms...", delay.as_millis());
+                sleep(delay).await;
+            }
+        }
+        
+        match last_error {
+            Some(error) => Err(format!("Failed to fetch {} after {} attempts: {}", what, max_attempts, error).into()),
+            None => Err(format!("Failed to fetch {} after {} attempts", what, max_attempts).into()),
+        }
+    }
+
+    /// Items Wazuh left out of the listings fetched since the last call, as
+    /// `data.failed_items` reported them.
+    pub fn take_failed_items(&mut self) -> Vec<FailedItem> {
+        std::mem::take(&mut self.gateway.failed_items)
+    }
+
+    pub async fn fetch_groups(&mut self) -> Result<Vec<Group>> {
+        let items = self.fetch_affected_items("/groups", HashMap::new(), "groups").await?;
+        let groups: Vec<Group> = items
+            .iter()
+            .filter_map(|item| {
+                Some(Group {
+                    id: item["name"].as_str()?.to_string(),
+                    name: item["name"].as_str()?.to_string(),
+                })
+            })
+            .collect();
+        info!("Parsed {} groups", groups.len());
+        Ok(groups)
+    }
+
+    pub async fn fetch_agents(&mut self, group_id: &str) -> Result<Vec<Agent>> {
+        let mut params = HashMap::new();
+        params.insert("group_id".to_string(), group_id.to_string());
+
+        let items = self.fetch_affected_items(
+            &format!("/groups/{}/agents", group_id),
+            params,
+            &format!("agents for group {}", group_id),
+        ).await?;
+        let agents: Vec<Agent> = items.iter().filter_map(Agent::from_item).collect();
+        info!("Parsed {} agents for group {}", agents.len(), group_id);
+        Ok(agents)
+    }
+
+    pub async fn fetch_agents_by_id(&mut self, agent_ids: &[String]) -> Result<Vec<Agent>> {
+        let mut params = HashMap::new();
+        params.insert("agents_list".to_string(), agent_ids.join(","));
+
+        let items = self.fetch_affected_items("/agents", params, "agents").await?;
+        let agents: Vec<Agent> = items.iter().filter_map(Agent::from_item).collect();
+        info!("Parsed {} agents", agents.len());
+        Ok(agents)
+    }
+}
+
+impl Agent {
+    #[cfg(feature = "gateway")]
+    pub(crate) fn from_item(item: &serde_json::Value) -> Option<Self> {
+        Some(Agent {
+            id: item["id"].as_str()?.to_string(),
+            name: item["name"].as_str()?.to_string(),
+            groups: item["group"]
+                .as_array()
+                .map(|groups| {
+                    groups
+                        .iter()
+                        .filter_map(|g| g.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            platform: item["os"]["platform"].as_str().map(str::to_string),
+            node: item["node_name"].as_str().filter(|n| !n.is_empty()).map(str::to_string),
+            ip: item["ip"].as_str().map(str::to_string),
+            status: item["status"].as_str().map(str::to_string),
+        })
+    }
+
+    /// The cluster node the agent reports to, or [`IMPLICIT_NODE`].
+    pub fn node_name(&self) -> &str {
+        self.node.as_deref().unwrap_or(IMPLICIT_NODE)
+    }
+
+    /// Buckets the raw `os.platform` value into `windows`, `linux`, `macos` or `unknown`.
+    pub fn platform_family(&self) -> &'static str {
+        const LINUX_PLATFORMS: &[&str] = &[
+            "linux", "ubuntu", "debian", "centos", "rhel", "redhat", "fedora", "amzn",
+            "sles", "opensuse", "opensuse-leap", "opensuse-tumbleweed", "arch", "alpine",
+            "rocky", "almalinux", "ol", "raspbian", "kali", "gentoo",
+        ];
+
+        match self.platform.as_deref().map(str::to_ascii_lowercase).as_deref() {
+            Some("windows") => "windows",
+            Some("darwin") | Some("macos") => "macos",
+            Some(p) if LINUX_PLATFORMS.contains(&p) => "linux",
+            _ => "unknown",
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(platform: Option<&str>) -> Agent {
+        Agent {
+            id: "001".to_string(),
+            name: "web-1".to_string(),
+            groups: vec!["web".to_string()],
+            platform: platform.map(str::to_string),
+            node: None,
+            ip: None,
+            status: None,
+        }
+    }
+
+    #[test]
+    fn platforms_are_bucketed_into_families() {
+        assert_eq!(agent(Some("windows")).platform_family(), "windows");
+        assert_eq!(agent(Some("darwin")).platform_family(), "macos");
+        assert_eq!(agent(Some("CentOS")).platform_family(), "linux");
+        assert_eq!(agent(Some("amzn")).platform_family(), "linux");
+        assert_eq!(agent(Some("solaris")).platform_family(), "unknown");
+        assert_eq!(agent(None).platform_family(), "unknown");
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn failed_items_are_read_from_a_listing() {
+        let json: serde_json::Value = serde_json::from_str(
+            r#"{"data": {"affected_items": [], "failed_items": [
+                {"error": {"code": 1701, "message": "Agent does not exist", "remediation": "..."}, "id": ["007", 8]},
+                {"id": []}
+            ]}}"#,
+        )
+        .unwrap();
+        let failed = FailedItem::from_listing(&json, "agents");
+        assert_eq!(failed.len(), 2);
+        assert_eq!((failed[0].ids.as_slice(), failed[0].code), (["007".to_string(), "8".to_string()].as_slice(), Some(1701)));
+        assert_eq!(failed[0].to_string(), "agents 007, 8: Agent does not exist (code 1701)");
+        assert_eq!(failed[1].to_string(), "agents : no reason given");
+        assert!(FailedItem::from_listing(&serde_json::json!({"data": {"affected_items": []}}), "groups").is_empty());
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn wazuh_error_envelopes_are_decoded() {
+        let error = WazuhApiError::from_response(
+            400,
+            r#"{"error": 1701, "message": "Agent does not exist", "remediation": "Check the agent ID"}"#,
+        );
+        assert_eq!((error.status, error.code), (400, 1701));
+        assert_eq!(error.message, "Agent does not exist");
+        assert_eq!(error.remediation.as_deref(), Some("Check the agent ID"));
+        assert_eq!(
+            error.to_string(),
+            "Wazuh API error 1701 (HTTP 400): Agent does not exist (remediation: Check the agent ID)"
+        );
+
+        let error = WazuhApiError::from_response(401, r#"{"title": "Unauthorized", "detail": "Invalid token", "error": 6002}"#);
+        assert_eq!(error.message, "Invalid token");
+        assert_eq!(error.to_string(), "Wazuh API error 6002 (HTTP 401): Unauthorized: Invalid token");
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn a_body_that_is_not_an_envelope_becomes_the_message() {
+        let error = WazuhApiError::from_response(502, "  <html>Bad Gateway</html>\n");
+        assert_eq!(error.code, 0);
+        assert_eq!(error.message, "<html>Bad Gateway</html>");
+        assert_eq!(error.to_string(), "Wazuh API returned HTTP 502: <html>Bad Gateway</html>");
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn well_known_codes_decide_the_retry() {
+        let cases = [
+            (401, r#"{"error": 6002, "detail": "Invalid token"}"#, WazuhErrorKind::Unauthorized, false),
+            (403, r#"{"error": 4000, "detail": "Permission denied"}"#, WazuhErrorKind::Forbidden, false),
+            (200, r#"{"error": 4000, "message": "Permission denied"}"#, WazuhErrorKind::Forbidden, false),
+            (404, r#"{"error": 1710, "message": "Group does not exist"}"#, WazuhErrorKind::NotFound, false),
+            (429, r#"{"error": 6001, "detail": "Maximum number of requests per minute reached"}"#, WazuhErrorKind::RateLimited, true),
+            (400, r#"{"error": 6005, "message": "Maximum number of requests"}"#, WazuhErrorKind::RateLimited, true),
+            (403, r#"{"error": 6000, "detail": "Too many failed login attempts"}"#, WazuhErrorKind::LoginBlocked, false),
+            (503, "Service Unavailable", WazuhErrorKind::Server, true),
+            (400, r#"{"error": 1000, "message": "Bad request"}"#, WazuhErrorKind::Other, false),
+        ];
+        for (status, body, kind, retryable) in cases {
+            let error = WazuhApiError::from_response(status, body);
+            assert_eq!(error.kind(), kind, "{}", body);
+            assert_eq!(error.is_retryable(), retryable, "{}", body);
+        }
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn sensitive_values_are_redacted_at_any_depth() {
+        let body = r#"{"data":{"token":"eyJ","items":[{"name":"web","Password":"pw","api_key_id":7}]}}"#;
+        assert_eq!(
+            redact_body(body),
+            r#"{"data":{"items":[{"Password":"[REDACTED]","api_key_id":"[REDACTED]","name":"web"}],"token":"[REDACTED]"}}"#
+        );
+        assert_eq!(redact_body("token=eyJ"), "<9 bytes, not JSON>");
+    }
+
+    #[cfg(feature = "gateway")]
+    #[tokio::test]
+    async fn the_request_timeout_is_applied_to_the_built_client() {
+        // Connections complete in the backlog but are never answered.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let options = HttpOptions {
+            pool_max_idle_per_host: 1,
+            pool_idle_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_millis(200),
+            ..HttpOptions::default()
+        };
+
+        let started = std::time::Instant::now();
+        let error = options.build().unwrap().get(&url).send().await.unwrap_err();
+        assert!(error.is_timeout(), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2), "timed out after {:?}", started.elapsed());
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn the_exp_claim_is_read_without_verifying_the_token() {
+        let claims = |json: &str| format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(json));
+        assert_eq!(token_expiry(&claims(r#"{"exp": 1700000000}"#)), Some(1_700_000_000));
+        assert_eq!(token_expiry(&claims(r#"{"exp": 1700000000.5}"#)), Some(1_700_000_000));
+        assert_eq!(token_expiry(&claims(r#"{"sub": "wazuh"}"#)), None);
+        assert_eq!(token_expiry("opaque-token"), None);
+    }
+
+    #[cfg(feature = "gateway")]
+    #[test]
+    fn supplied_tokens_must_be_shaped_like_a_jwt() {
+        let token = |header: &str, payload: &str| {
+            format!("{}.{}.sig", URL_SAFE_NO_PAD.encode(header), URL_SAFE_NO_PAD.encode(payload))
+        };
+        assert_eq!(check_wazuh_token(&token(r#"{"alg":"HS256"}"#, r#"{"exp":1700000000}"#)).unwrap(), Some(1_700_000_000));
+        assert_eq!(check_wazuh_token(&token("{}", r#"{"sub":"wazuh"}"#)).unwrap(), None);
+
+        for (bad, error) in [
+            ("opaque-token".to_string(), "three dot-separated segments"),
+            ("a..c".to_string(), "three dot-separated segments"),
+            ("!!!.e30.sig".to_string(), "header is not base64url"),
+            (token("{}", "[1]"), "payload is not a JSON object"),
+        ] {
+            let message = check_wazuh_token(&bad).unwrap_err().to_string();
+            assert!(message.contains(error), "{:?}: {}", bad, message);
+        }
+    }
+}
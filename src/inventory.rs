@@ -0,0 +1,188 @@
+//! Agent lists read from a file, for scans that skip gateway discovery.
+
+use crate::gateway::Agent;
+use crate::Result;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+const REQUIRED_COLUMNS: &[&str] = &["id", "name", "group"];
+
+#[derive(Deserialize)]
+struct AgentEntry {
+    id: Option<String>,
+    name: Option<String>,
+    group: Option<GroupField>,
+    platform: Option<String>,
+    node: Option<String>,
+    ip: Option<String>,
+    status: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GroupField {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Reads agents from a JSON array of `{id, name, group}` objects or a CSV
+/// file with an `id,name,group` header, chosen by the `.json`/`.csv`
+/// extension. `group` may be a list in JSON, or `;`-separated in CSV; the
+/// first group decides where results are filed. Optional `platform` and
+/// `node` fields feed `--organize-by os` and `--organize-by node`; `ip` and
+/// `status` are kept as informational, so `client topology --format csv`
+/// output loads as is.
+pub fn load_agents_file(path: &Path) -> Result<Vec<Agent>> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read agents file {}: {}", path.display(), e))?;
+    let entries = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str::<Vec<AgentEntry>>(&content)
+            .map_err(|e| format!("Invalid agents file {}: {}", path.display(), e))?,
+        Some("csv") => parse_csv(&content).map_err(|e| format!("Invalid agents file {}: {}", path.display(), e))?,
+        _ => return Err(format!("Agents file {} must end in .json or .csv", path.display()).into()),
+    };
+
+    let mut agents: Vec<Agent> = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.into_iter().enumerate() {
+        let missing = |field: &str| format!("Agents file {}: entry {} has no {}", path.display(), index + 1, field);
+        let id = entry.id.filter(|v| !v.trim().is_empty()).ok_or_else(|| missing("id"))?;
+        let name = entry.name.filter(|v| !v.trim().is_empty()).ok_or_else(|| missing("name"))?;
+        let groups: Vec<String> = match entry.group {
+            Some(GroupField::One(group)) => group.split(';').map(|g| g.trim().to_string()).collect(),
+            Some(GroupField::Many(groups)) => groups,
+            None => Vec::new(),
+        };
+        let groups: Vec<String> = groups.into_iter().filter(|g| !g.is_empty()).collect();
+        if groups.is_empty() {
+            return Err(missing("group").into());
+        }
+        if agents.iter().any(|a| a.id == id) {
+            return Err(format!("Agents file {}: agent id {} is listed twice", path.display(), id).into());
+        }
+        agents.push(Agent {
+            id,
+            name,
+            groups,
+            platform: entry.platform.filter(|p| !p.is_empty()),
+            node: entry.node.filter(|n| !n.is_empty()),
+            ip: entry.ip.filter(|ip| !ip.is_empty()),
+            status: entry.status.filter(|s| !s.is_empty()),
+        });
+    }
+    if agents.is_empty() {
+        return Err(format!("Agents file {} lists no agents", path.display()).into());
+    }
+    Ok(agents)
+}
+
+fn parse_csv(content: &str) -> std::result::Result<Vec<AgentEntry>, String> {
+    let mut lines = content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let (_, header) = lines.next().ok_or("file is empty")?;
+    let columns: Vec<String> = split_csv_line(header)?.into_iter().map(|c| c.trim().to_lowercase()).collect();
+    let missing: Vec<&str> = REQUIRED_COLUMNS
+        .iter()
+        .filter(|c| !columns.iter().any(|column| column == *c))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("header is missing column(s): {}", missing.join(", ")));
+    }
+
+    let position = |name: &str| columns.iter().position(|c| c == name);
+    let (id, name, group) = (position("id"), position("name"), position("group"));
+    let (platform, node) = (position("platform"), position("node"));
+    let (ip, status) = (position("ip"), position("status"));
+    lines
+        .map(|(number, line)| {
+            let fields = split_csv_line(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            let field = |index: Option<usize>| index.and_then(|i| fields.get(i)).map(|v| v.trim().to_string());
+            Ok(AgentEntry {
+                id: field(id),
+                name: field(name),
+                group: field(group).map(GroupField::One),
+                platform: field(platform),
+                node: field(node),
+                ip: field(ip),
+                status: field(status),
+            })
+        })
+        .collect()
+}
+
+/// Splits one CSV record, honouring double-quoted fields with `""` escapes.
+fn split_csv_line(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".into());
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, content: &str) -> Result<Vec<Agent>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        fs::write(&path, content).unwrap();
+        load_agents_file(&path)
+    }
+
+    #[test]
+    fn csv_and_json_files_list_the_same_agents() {
+        let csv = load("agents.csv", "ID,Name,Group,Platform\n001,\"web, primary\",web;edge,ubuntu\n002,db-1,db,\n").unwrap();
+        let json = load(
+            "agents.json",
+            r#"[{"id": "001", "name": "web, primary", "group": ["web", "edge"], "platform": "ubuntu"},
+                {"id": "002", "name": "db-1", "group": "db"}]"#,
+        )
+        .unwrap();
+        for agents in [csv, json] {
+            let parsed: Vec<_> = agents.iter().map(|a| (a.id.as_str(), a.name.as_str(), a.groups.clone(), a.platform.as_deref())).collect();
+            assert_eq!(
+                parsed,
+                [
+                    ("001", "web, primary", vec!["web".to_string(), "edge".to_string()], Some("ubuntu")),
+                    ("002", "db-1", vec!["db".to_string()], None),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn missing_fields_are_rejected() {
+        let error = load("agents.csv", "id,name\n001,web-1\n").unwrap_err();
+        assert!(error.to_string().ends_with("header is missing column(s): group"), "{}", error);
+        let error = load("agents.json", r#"[{"id": "001", "group": "web"}]"#).unwrap_err();
+        assert!(error.to_string().ends_with("entry 1 has no name"), "{}", error);
+        let error = load("agents.csv", "id,name,group\n001,web-1, ;\n").unwrap_err();
+        assert!(error.to_string().ends_with("entry 1 has no group"), "{}", error);
+        let error = load("agents.csv", "id,name,group\n001,web-1,web\n001,web-2,web\n").unwrap_err();
+        assert!(error.to_string().ends_with("agent id 001 is listed twice"), "{}", error);
+        let error = load("agents.csv", "id,name,group\n").unwrap_err();
+        assert!(error.to_string().ends_with("lists no agents"), "{}", error);
+        let error = load("agents.txt", "001").unwrap_err();
+        assert!(error.to_string().ends_with("must end in .json or .csv"), "{}", error);
+    }
+}
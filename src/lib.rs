@@ -0,0 +1,45 @@
+//! Client library for running WQL queries against Wazuh agents through a
+//! sensex conduit server.
+//!
+//! [`scan`] drives a full run: it obtains a Wazuh token from the gateway,
+//! discovers groups and agents, sends each signed query over TLS and returns a
+//! [`ScanReport`] describing every result. [`scan_stream`] runs the same scan
+//! but yields each result as it completes.
+
+mod buffers;
+pub mod client;
+pub mod compression;
+mod concurrency;
+pub mod encryption;
+pub mod gateway;
+pub mod inventory;
+pub mod output;
+mod progress;
+pub mod projection;
+pub mod protocol;
+pub mod retry;
+pub mod scan;
+pub mod selftest;
+mod session;
+pub mod signing;
+pub mod sink;
+mod spool;
+pub mod template;
+pub mod tls;
+pub mod vars;
+
+pub use client::{Client, ClientConfig};
+pub use concurrency::AdaptiveState;
+pub use gateway::{Agent, FailedItem, GatewayAuth, Group, HttpOptions};
+#[cfg(feature = "gateway")]
+pub use gateway::{GatewayAuthError, GatewayMiddleware, WazuhApiError, WazuhErrorKind};
+/// The `reqwest-middleware` version [`GatewayMiddleware`] layers are written against.
+#[cfg(feature = "gateway")]
+pub use reqwest_middleware;
+pub use scan::{
+    plan, scan, scan_stream, verify_saved_result, AgentStatus, GatewayConfig, GroupResult, ManagerFailure, OrganizeBy, PlannedGroup, QueryOrder, QueryOutcome,
+    QueryResult, QueryTimings, ResultCache, Retention, SavedSignature, ScanConfig, ScanItem, ScanPlan, ScanReport, ScanStream,
+    StreamedResult,
+};
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
@@ -0,0 +1,28 @@
+//! Informational console output, which `--quiet` turns off.
+//!
+//! Progress messages go through [`info!`](crate::info) instead of `println!`
+//! so scripted runs can silence them in one place. Errors and warnings are
+//! written with `eprintln!` and are never silenced.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Silences (or restores) informational output for the whole process.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// `println!` that prints nothing after [`set_quiet(true)`](set_quiet).
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !$crate::output::is_quiet() {
+            println!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,64 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::IsTerminal;
+
+/// Overall scan progress: completed/total queries, current group and ETA.
+/// The total grows as each manager's agents are discovered. Without a
+/// terminal on stdout, or with `--quiet`, every method is a no-op, so piped
+/// logs stay clean.
+pub(crate) struct ScanProgress {
+    bar: Option<ProgressBar>,
+}
+
+impl ScanProgress {
+    pub(crate) fn new(enabled: bool) -> Self {
+        let bar = (enabled && !crate::output::is_quiet() && std::io::stdout().is_terminal()).then(|| {
+            let bar = ProgressBar::new(0);
+            bar.set_style(
+                ProgressStyle::with_template("[{bar:30}] {pos}/{len} queries  {msg}  ETA {eta}")
+                    .expect("valid progress template")
+                    .progress_chars("=> "),
+            );
+            bar
+        });
+        Self { bar }
+    }
+
+    pub(crate) fn add_queries(&self, count: u64) {
+        if let Some(bar) = &self.bar {
+            bar.inc_length(count);
+        }
+    }
+
+    pub(crate) fn set_group(&self, group: &str) {
+        if let Some(bar) = &self.bar {
+            bar.set_message(group.to_string());
+        }
+    }
+
+    pub(crate) fn query_done(&self) {
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_with_message("done");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_disabled_bar_draws_nothing() {
+        let progress = ScanProgress::new(false);
+        assert!(progress.bar.is_none());
+        progress.add_queries(3);
+        progress.set_group("web");
+        progress.query_done();
+        progress.finish();
+    }
+}
@@ -0,0 +1,165 @@
+//! Field projections (`--fields`) that trim JSON results before they are
+//! saved.
+//!
+//! A projection is a comma-separated list of fields, each a dotted path of
+//! object keys such as `hits.hits._source.rule.level`, optionally written
+//! as a JSONPath, `$.hits.hits[*]._source.rule.level`. A path runs through
+//! arrays: every element is projected with the rest of the path, so `[*]`
+//! only spells out what a bare key already does. Fields a result lacks are
+//! left out, and objects left without any of the fields are dropped.
+
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// The fields of a JSON result to keep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldProjection {
+    paths: Vec<Vec<String>>,
+    tree: FieldTree,
+}
+
+/// Paths merged by their leading keys; a node without children keeps the
+/// whole value found there.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct FieldTree(BTreeMap<String, FieldTree>);
+
+impl FieldProjection {
+    /// A projection onto `fields`, each a key path as `--fields` takes it.
+    pub fn new<S: AsRef<str>>(fields: &[S]) -> Result<Self, String> {
+        let mut paths = Vec::new();
+        for field in fields {
+            paths.push(parse_path(field.as_ref())?);
+        }
+        if paths.is_empty() {
+            return Err("no fields given".to_string());
+        }
+        let mut tree = FieldTree::default();
+        for path in &paths {
+            tree.insert(path);
+        }
+        Ok(Self { paths, tree })
+    }
+
+    /// `value` with only the projected fields. A result that is not an
+    /// object or array, or has none of the fields, projects to `{}`.
+    pub fn project(&self, value: &Value) -> Value {
+        self.tree.project(value).unwrap_or_else(|| Value::Object(Map::new()))
+    }
+}
+
+impl FieldTree {
+    fn insert(&mut self, path: &[String]) {
+        let Some((key, rest)) = path.split_first() else {
+            return;
+        };
+        match self.0.get_mut(key) {
+            // A field already kept whole stays whole.
+            Some(node) if node.0.is_empty() => {}
+            Some(node) if rest.is_empty() => node.0.clear(),
+            Some(node) => node.insert(rest),
+            None => {
+                let mut node = FieldTree::default();
+                node.insert(rest);
+                self.0.insert(key.clone(), node);
+            }
+        }
+    }
+
+    fn project(&self, value: &Value) -> Option<Value> {
+        match value {
+            Value::Array(items) => Some(Value::Array(items.iter().filter_map(|item| self.project(item)).collect())),
+            Value::Object(object) => {
+                let mut kept = Map::new();
+                for (key, node) in &self.0 {
+                    let Some(field) = object.get(key) else {
+                        continue;
+                    };
+                    let projected = match node.0.is_empty() {
+                        true => Some(field.clone()),
+                        false => node.project(field),
+                    };
+                    if let Some(projected) = projected {
+                        kept.insert(key.clone(), projected);
+                    }
+                }
+                (!kept.is_empty()).then_some(Value::Object(kept))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Splits `a.b[*].c` or `$.a.b[*].c` into its keys.
+fn parse_path(field: &str) -> Result<Vec<String>, String> {
+    let trimmed = field.trim();
+    let path = trimmed.strip_prefix("$.").unwrap_or(trimmed);
+    let keys: Vec<String> = path.split('.').map(|key| key.strip_suffix("[*]").unwrap_or(key).to_string()).collect();
+    if keys.iter().any(|key| key.is_empty() || key.contains(['[', ']', '*', '$'])) {
+        return Err(format!("invalid field {:?}: expected dotted keys such as hits.hits._source.rule", field));
+    }
+    Ok(keys)
+}
+
+impl FromStr for FieldProjection {
+    type Err = String;
+
+    /// Parses a comma-separated list of fields.
+    fn from_str(fields: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = fields.split(',').filter(|field| !field.trim().is_empty()).collect();
+        Self::new(&fields)
+    }
+}
+
+/// The fields as `--fields` takes them, comma-separated.
+impl fmt::Display for FieldProjection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<String> = self.paths.iter().map(|path| path.join(".")).collect();
+        f.write_str(&fields.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn nested_fields_are_kept_through_arrays_and_missing_ones_left_out() {
+        let result = json!({
+            "took": 5,
+            "hits": {"total": 2, "hits": [
+                {"_id": "a", "_source": {"rule": {"level": 3, "id": "5501"}, "agent": {"id": "001"}, "full_log": "..."}},
+                {"_id": "b", "_source": {"agent": {"id": "002"}}},
+                {"_id": "c"}
+            ]}
+        });
+        let fields: FieldProjection = "$.hits.hits[*]._source.rule.level, hits.hits._source.agent,missing.key".parse().unwrap();
+        assert_eq!(
+            fields.project(&result),
+            json!({"hits": {"hits": [
+                {"_source": {"rule": {"level": 3}, "agent": {"id": "001"}}},
+                {"_source": {"agent": {"id": "002"}}}
+            ]}})
+        );
+        assert_eq!(fields.to_string(), "hits.hits._source.rule.level,hits.hits._source.agent,missing.key");
+        assert_eq!(fields.project(&json!("scalar")), json!({}));
+    }
+
+    #[test]
+    fn a_field_kept_whole_is_not_narrowed_by_a_deeper_one() {
+        let result = json!({"rule": {"level": 3, "id": "5501"}});
+        for spec in ["rule,rule.level", "rule.level,rule"] {
+            let fields: FieldProjection = spec.parse().unwrap();
+            assert_eq!(fields.project(&result), result, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn malformed_fields_are_refused() {
+        for spec in ["", " , ", "hits..hits", "hits.hits[0]", "$..rule", "a.*"] {
+            assert!(spec.parse::<FieldProjection>().is_err(), "{:?}", spec);
+        }
+    }
+}
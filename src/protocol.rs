@@ -0,0 +1,282 @@
+use crate::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use clap::ValueEnum;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// `Response.error_code` sent when the request's `session_id` is unknown or
+/// has expired. The client should drop its session and retry without one.
+pub const SESSION_EXPIRED: &str = "session_expired";
+
+/// `AuthRequest.signature_scheme` for signatures that also cover the session
+/// and the query; see [`signing_payload`].
+pub const SIGNATURE_SCHEME_V2: &str = "v2";
+
+/// Shape of `Response.data`, requested by the client and echoed by the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultFormat {
+    /// The indexer's search response as JSON
+    #[default]
+    Json,
+    /// One row per hit `_source`; nested values are written as JSON
+    Csv,
+    /// The indexer's response as returned, not reformatted or validated;
+    /// bytes that are not UTF-8 are written to disk as received
+    Raw,
+}
+
+impl ResultFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+            Self::Raw => "raw",
+        }
+    }
+
+    /// File extension for results in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+            Self::Raw => "txt",
+        }
+    }
+}
+
+/// How the [`AuthRequest`] and [`Response`] envelopes are written on the wire.
+///
+/// Envelopes are not framed: each is one self-delimiting value, and its first
+/// byte tells the encodings apart (see [`WireEncoding::of`]). Signatures never
+/// cover wire bytes. A request signs its [`signing_payload`] and a response
+/// its JSON serialization, whichever encoding carried them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum WireEncoding {
+    /// JSON, which every server reads
+    #[default]
+    Json,
+    /// MessagePack maps keyed by field name; requests stay JSON until the
+    /// server has answered in MessagePack
+    #[value(name = "msgpack")]
+    MessagePack,
+}
+
+impl WireEncoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::MessagePack => "msgpack",
+        }
+    }
+
+    /// The encoding of an envelope starting with `first`: a JSON object
+    /// starts with `{`, a MessagePack map with a fixmap, map 16 or map 32
+    /// marker.
+    pub fn of(first: u8) -> Option<Self> {
+        match first {
+            b'{' => Some(Self::Json),
+            0x80..=0x8f | 0xde | 0xdf => Some(Self::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, envelope: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Json => serde_json::to_vec(envelope)?,
+            Self::MessagePack => rmp_serde::to_vec_named(envelope)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        Ok(match self {
+            Self::Json => serde_json::from_slice(bytes)?,
+            Self::MessagePack => rmp_serde::from_slice(bytes)?,
+        })
+    }
+}
+
+/// Signed envelope returned by the conduit server for each request.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Response {
+    pub status: bool,
+    pub data: String,
+    pub session_id: String,
+    pub timestamp: u64,
+    pub signature: String,
+    /// Machine-readable failure reason, absent on ordinary responses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    /// Echo of `AuthRequest.request_id`, tying the response to its request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Format of `data`; servers that predate format negotiation omit it and
+    /// always send JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<ResultFormat>,
+    /// [`query_hash`] of the `wql_query` the server received, so a response
+    /// cannot be matched to a different query. Older servers omit it, which
+    /// is only accepted for requests without [`SIGNATURE_SCHEME_V2`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_hash: Option<String>,
+}
+
+/// Signed query request sent to the conduit server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthRequest {
+    pub client_id: String,
+    pub timestamp: u64,
+    pub nonce: String,
+    pub signature: String,
+    pub session_id: Option<String>,
+    pub wql_query: String,
+    /// Fresh UUID per request; the server echoes it in the response.
+    pub request_id: String,
+    /// Absent for the original `client_id:timestamp:nonce` signature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature_scheme: Option<String>,
+    /// [`Signer::algorithm`](crate::signing::Signer::algorithm) of the request
+    /// signature; the response is signed the same way. Absent for SHA-256
+    /// over the data and key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature_algorithm: Option<String>,
+    /// [`SignatureEncoding`](crate::signing::SignatureEncoding) of the request
+    /// signature; the response signature is written the same way. Absent for
+    /// base64.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature_encoding: Option<String>,
+    pub format: ResultFormat,
+    /// [`WireEncoding`] the response is asked for in; absent for JSON. A
+    /// server that predates it answers in JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+/// Standard (padded) base64 SHA-256 of the `wql_query` UTF-8 bytes as sent,
+/// with no trimming or newline normalisation.
+pub fn query_hash(wql_query: &str) -> String {
+    BASE64.encode(Sha256::digest(wql_query.as_bytes()))
+}
+
+/// The exact string `AuthRequest.signature` is computed over.
+///
+/// Without a scheme this is `client_id:timestamp:nonce`. With
+/// [`SIGNATURE_SCHEME_V2`] it is
+/// `v2:client_id:timestamp:nonce:session_id:query_hash`, where `timestamp` is
+/// decimal, `session_id` is empty when there is none, and `query_hash` is
+/// [`query_hash`] of `wql_query`. Returns `None` for an unknown scheme.
+pub fn signing_payload(request: &AuthRequest) -> Option<String> {
+    match request.signature_scheme.as_deref() {
+        None => Some(format!("{}:{}:{}", request.client_id, request.timestamp, request.nonce)),
+        Some(SIGNATURE_SCHEME_V2) => Some(format!(
+            "{}:{}:{}:{}:{}:{}",
+            SIGNATURE_SCHEME_V2,
+            request.client_id,
+            request.timestamp,
+            request.nonce,
+            request.session_id.as_deref().unwrap_or(""),
+            query_hash(&request.wql_query)
+        )),
+        Some(_) => None,
+    }
+}
+
+/// A verified response. When the payload was streamed to disk, `response.data`
+/// is empty and the decoded data lives at `spooled_to`.
+#[derive(Debug)]
+pub struct ReceivedResponse {
+    pub response: Response,
+    pub spooled_to: Option<PathBuf>,
+    pub timings: ExchangeTimings,
+}
+
+/// When each phase of an exchange ended, measured from the start of
+/// `send_request`, so the values never decrease in field order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExchangeTimings {
+    /// The request was written and flushed.
+    pub sent: Duration,
+    /// The first response byte arrived.
+    pub first_byte: Duration,
+    /// The response was read to the end.
+    pub received: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(scheme: Option<&str>, session_id: Option<&str>) -> AuthRequest {
+        AuthRequest {
+            client_id: "client1".to_string(),
+            timestamp: 1_700_000_000,
+            nonce: "n0nce".to_string(),
+            signature: String::new(),
+            session_id: session_id.map(str::to_string),
+            wql_query: String::new(),
+            request_id: "8d3f5a0e-1b2c-4d5e-8f90-a1b2c3d4e5f6".to_string(),
+            signature_scheme: scheme.map(str::to_string),
+            signature_algorithm: None,
+            signature_encoding: None,
+            format: ResultFormat::Json,
+            encoding: None,
+        }
+    }
+
+    #[test]
+    fn query_hashes_cover_the_query_bytes_as_sent() {
+        assert_eq!(query_hash(""), "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+        assert_ne!(query_hash("{}"), query_hash("{}\n"));
+    }
+
+    #[test]
+    fn envelopes_round_trip_through_messagepack() {
+        let mut sent = request(Some(SIGNATURE_SCHEME_V2), Some("s1"));
+        sent.encoding = Some(WireEncoding::MessagePack.as_str().to_string());
+        sent.signature_algorithm = Some("hmac-sha256".to_string());
+        let bytes = WireEncoding::MessagePack.encode(&sent).unwrap();
+        assert_eq!(WireEncoding::of(bytes[0]), Some(WireEncoding::MessagePack));
+        let received: AuthRequest = WireEncoding::MessagePack.decode(&bytes).unwrap();
+        assert_eq!(serde_json::to_value(&received).unwrap(), serde_json::to_value(&sent).unwrap());
+
+        let response = Response {
+            status: true,
+            data: "a,b\n1,\"x\"\n".to_string(),
+            session_id: "s1".to_string(),
+            timestamp: 1_700_000_000,
+            signature: "c2ln".to_string(),
+            error_code: None,
+            request_id: Some(sent.request_id.clone()),
+            format: Some(ResultFormat::Csv),
+            query_hash: Some(query_hash("")),
+        };
+        let bytes = WireEncoding::MessagePack.encode(&response).unwrap();
+        let decoded: Response = WireEncoding::MessagePack.decode(&bytes).unwrap();
+        // Responses are verified over their JSON, which survives the trip.
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), serde_json::to_string(&response).unwrap());
+
+        let json = WireEncoding::Json.encode(&response).unwrap();
+        assert_eq!(WireEncoding::of(json[0]), Some(WireEncoding::Json));
+        assert!(bytes.len() < json.len(), "{} >= {}", bytes.len(), json.len());
+        assert_eq!(WireEncoding::of(b'['), None);
+    }
+
+    #[test]
+    fn v1_payloads_leave_out_the_session_and_query() {
+        let payload = signing_payload(&request(None, Some("s1"))).unwrap();
+        assert_eq!(payload, "client1:1700000000:n0nce");
+    }
+
+    #[test]
+    fn v2_payloads_cover_the_session_and_query() {
+        let hash = query_hash("");
+        let payload = signing_payload(&request(Some(SIGNATURE_SCHEME_V2), Some("s1"))).unwrap();
+        assert_eq!(payload, format!("v2:client1:1700000000:n0nce:s1:{}", hash));
+        let payload = signing_payload(&request(Some(SIGNATURE_SCHEME_V2), None)).unwrap();
+        assert_eq!(payload, format!("v2:client1:1700000000:n0nce::{}", hash));
+        assert_eq!(signing_payload(&request(Some("v3"), None)), None);
+    }
+}
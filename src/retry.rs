@@ -0,0 +1,111 @@
+use rand::Rng;
+use std::time::Duration;
+
+pub const MAX_ATTEMPTS: u32 = 3;
+pub const RETRY_DELAY: Duration = Duration::from_secs(1);
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+pub const RECONNECT_JITTER: f64 = 0.2;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Bounded exponential backoff shared by every retry loop in the client.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: MAX_ATTEMPTS,
+            base_delay: RETRY_DELAY,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay to wait after the given (1-based) failed attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+/// Pause inserted before opening a fresh conduit connection. The actual wait
+/// is `base` scaled by a random factor in `1 ± jitter`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectDelay {
+    pub base: Duration,
+    /// Fraction of `base` to randomise by, between 0 and 1.
+    pub jitter: f64,
+}
+
+impl Default for ReconnectDelay {
+    fn default() -> Self {
+        Self {
+            base: RECONNECT_DELAY,
+            jitter: RECONNECT_JITTER,
+        }
+    }
+}
+
+impl ReconnectDelay {
+    pub fn sample(&self) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 || self.base.is_zero() {
+            return self.base;
+        }
+        self.base.mul_f64(rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter))
+    }
+}
+
+/// Whether an error from connecting or exchanging a request is worth another
+/// attempt. Transport failures are; signature, freshness and protocol errors are not.
+pub fn is_retryable(err: &(dyn std::error::Error + 'static)) -> bool {
+    if err.downcast_ref::<std::io::Error>().is_some() || err.downcast_ref::<native_tls::Error>().is_some() {
+        return true;
+    }
+    err.downcast_ref::<serde_json::Error>().is_some_and(|e| e.is_eof())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_after_each_attempt_up_to_the_cap() {
+        let retry = RetryPolicy { max_attempts: 10, base_delay: Duration::from_secs(1) };
+        let delays: Vec<u64> = (1..=7).map(|attempt| retry.delay(attempt).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(retry.delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn only_transport_errors_are_retryable() {
+        let io: Box<dyn std::error::Error> = Box::new(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        assert!(is_retryable(io.as_ref()));
+
+        let truncated: Box<dyn std::error::Error> = serde_json::from_str::<serde_json::Value>("{\"a\":").unwrap_err().into();
+        assert!(is_retryable(truncated.as_ref()));
+
+        let malformed: Box<dyn std::error::Error> = serde_json::from_str::<serde_json::Value>("}").unwrap_err().into();
+        assert!(!is_retryable(malformed.as_ref()));
+
+        let protocol: Box<dyn std::error::Error> = "Invalid response signature".into();
+        assert!(!is_retryable(protocol.as_ref()));
+    }
+
+    #[test]
+    fn reconnect_delays_stay_within_the_jitter() {
+        let delay = ReconnectDelay { base: Duration::from_millis(1000), jitter: 0.2 };
+        for _ in 0..100 {
+            let sample = delay.sample();
+            assert!((800..=1200).contains(&sample.as_millis()), "{:?}", sample);
+        }
+        let fixed = ReconnectDelay { jitter: 0.0, ..delay };
+        assert_eq!(fixed.sample(), delay.base);
+        let clamped = ReconnectDelay { jitter: 5.0, ..delay };
+        assert!(clamped.sample() <= Duration::from_millis(2000));
+    }
+}
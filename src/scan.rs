@@ -0,0 +1,2855 @@
+use crate::client::{verify_envelope, Client, ClientConfig, SessionExpired};
+use crate::compression::Compression;
+use crate::concurrency::{AdaptiveConcurrency, AdaptiveState};
+use crate::encryption::{OutputCipher, ENCRYPTED_EXTENSION};
+use crate::gateway::{Agent, FailedItem, Group, HttpOptions};
+use crate::info;
+#[cfg(feature = "gateway")]
+use crate::gateway::{WazuhApiError, WazuhErrorKind};
+use crate::progress::ScanProgress;
+use crate::projection::FieldProjection;
+use crate::protocol::{query_hash, ReceivedResponse, Response, ResultFormat};
+use crate::retry::{is_retryable, ReconnectDelay, RetryPolicy};
+use crate::signing::{InvalidSignature, SignatureAlgorithm, SignatureEncoding};
+use crate::sink::{OutputSink, ResultMeta};
+use crate::template::{path_component, OutputTemplate, ResultPattern, TemplateValues};
+use crate::tls::{connect_with_retry, TlsConfig, TlsOptions, TlsStream};
+use crate::vars::QueryVars;
+use crate::Result;
+use clap::ValueEnum;
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::sleep;
+
+/// Gateway endpoint and Wazuh credentials used for token issuance and discovery.
+#[derive(Clone)]
+pub struct GatewayConfig {
+    /// Label for this manager. Required when scanning several; results and
+    /// the cached conduit session are then kept apart per manager.
+    pub name: Option<String>,
+    pub url: String,
+    pub wazuh_url: String,
+    pub username: String,
+    pub password: String,
+    /// Wazuh token to start with instead of authenticating. It is renewed
+    /// with `username` and `password` when those are set.
+    pub token: Option<String>,
+}
+
+/// Everything a scan needs; the `client` binary builds this from its CLI.
+#[derive(Clone)]
+pub struct ScanConfig {
+    /// Conduit server address, e.g. `192.168.1.100:8080`.
+    pub server: String,
+    /// Wazuh managers to discover and scan, in order.
+    pub managers: Vec<GatewayConfig>,
+    /// Pool and timeout settings for gateway HTTP calls.
+    pub http: HttpOptions,
+    /// How close to its `exp` a Wazuh token may get before it is renewed.
+    pub token_refresh_buffer: Duration,
+    pub client: ClientConfig,
+    pub tls: TlsOptions,
+    pub retry: RetryPolicy,
+    pub reconnect_delay: ReconnectDelay,
+    pub queries_dir: PathBuf,
+    /// Query names to run; empty runs every query under `queries_dir`.
+    pub queries: Vec<String>,
+    /// Subdirectory levels of `queries_dir` searched for query files.
+    pub query_depth: usize,
+    /// Order the queries run in.
+    pub query_order: QueryOrder,
+    /// Shared values for `{{name}}` placeholders in every query.
+    pub query_vars: QueryVars,
+    /// Agent ids to query directly, skipping group discovery.
+    pub agents: Vec<String>,
+    /// Group names to restrict discovery to. Ignored when `agents` is set.
+    pub groups: Vec<String>,
+    /// Cluster nodes to scan agents of; empty scans every node. Agents
+    /// without a node belong to [`IMPLICIT_NODE`](crate::gateway::IMPLICIT_NODE).
+    pub nodes: Vec<String>,
+    /// Agent connection statuses to scan, e.g. `active`; empty, or
+    /// [`AgentStatus::All`], scans every status. Agents that report no
+    /// status are scanned regardless, and an agent named in `agents` that is
+    /// left out is warned about.
+    pub statuses: Vec<AgentStatus>,
+    /// Query an agent that belongs to several of the scanned groups once,
+    /// under the first of them, instead of once per group.
+    pub unique_agents: bool,
+    /// Fail a manager's discovery when Wazuh reports `failed_items` in any
+    /// listing, rather than scanning what it did list.
+    pub strict_failed_items: bool,
+    pub output_dir: PathBuf,
+    pub organize_by: OrganizeBy,
+    /// File name pattern for results, relative to the agent's directory.
+    pub output_template: OutputTemplate,
+    /// Results of earlier scans to delete once a query's new result is saved.
+    pub retention: Retention,
+    /// Encrypts each result file, which then gets an `.enc` suffix. Results
+    /// streamed to disk still pass through a plaintext spool file first.
+    pub output_cipher: Option<OutputCipher>,
+    /// Agents to scan as given, skipping gateway authentication, discovery
+    /// and `managers`. Required without the `gateway` feature; the conduit
+    /// exchange itself never carries a Wazuh token.
+    pub inventory: Option<Vec<Agent>>,
+    /// How many groups of one manager are scanned at the same time.
+    pub group_concurrency: usize,
+    /// How many agents of one group are scanned at the same time; each
+    /// agent's queries still run one after another.
+    pub max_concurrency_per_group: usize,
+    /// How many agents are scanned at the same time across all groups;
+    /// `None` leaves it to `group_concurrency` and
+    /// `max_concurrency_per_group`.
+    pub max_concurrency: Option<usize>,
+    /// Start with one conduit exchange at a time and adapt, up to
+    /// [`ScanConfig::exchange_limit`]: more while the server answers
+    /// quickly, fewer once its responses slow down or fail.
+    pub adaptive_concurrency: bool,
+    /// Where an earlier scan's adaptive concurrency left off, from
+    /// [`ScanReport::adaptive_state`], to start from instead of one
+    /// exchange at a time. Ignored when it was learned against another
+    /// server or ceiling.
+    pub adaptive_state: Option<AdaptiveState>,
+    /// Run the queries against one agent first, and stop if any fails.
+    pub warmup: Option<Warmup>,
+    /// Scan only a reproducible subset of each group's agents.
+    pub sample: Option<Sample>,
+    /// Passes rerunning the queries that failed, after the main pass. Each
+    /// pass uses fresh connections and only covers what is still failing.
+    pub retry_passes: u32,
+    /// Times a query answered with a success but no data is sent again, in
+    /// case the agent was not ready. One still empty is reported as
+    /// [`QueryOutcome::Empty`].
+    pub retry_empty: u32,
+    /// Time one agent's queries may take in total. A query still running
+    /// when it runs out fails, and the agent's remaining queries are skipped.
+    pub agent_timeout: Option<Duration>,
+    /// Wall-clock time by which the whole scan stops: no query starts after
+    /// it, and queries still running then are abandoned as failed once
+    /// `deadline_grace` has passed too. The queries not started are skipped
+    /// with [`DEADLINE_REACHED`] as their reason.
+    pub deadline: Option<SystemTime>,
+    pub deadline_grace: Duration,
+    /// Reuse recent results from disk instead of querying again.
+    pub cache: Option<ResultCache>,
+    /// Run only these queries, e.g. the failures of an earlier scan; agents
+    /// and groups left with none are not reported. `None` runs everything.
+    pub only: Option<HashSet<ScanItem>>,
+    /// Show an overall progress bar when stdout is a terminal.
+    pub progress: bool,
+    /// How JSON results are written, whether held in memory or spooled.
+    pub json_output: JsonOutput,
+    /// Fields JSON results are trimmed to before they are saved, unless a
+    /// query's `.meta` companion lists its own.
+    pub fields: Option<FieldProjection>,
+    /// Write a `<result>.meta.json` provenance sidecar next to each result.
+    pub include_metadata: bool,
+    /// Compression applied to each result before it is encrypted and saved.
+    pub compression: Compression,
+    /// Where results go; [`FileSink`](crate::sink::FileSink) writes them under `output_dir`.
+    pub sink: Arc<dyn OutputSink>,
+    /// Iteration number substituted for `{run}` in `output_template`.
+    pub run: u64,
+    /// Wazuh tokens from an earlier scan, keyed by manager label. A cached
+    /// token is tried before authenticating, and replaced if it is rejected.
+    pub wazuh_tokens: HashMap<String, String>,
+}
+
+impl ScanConfig {
+    /// The most conduit exchanges that can be in flight at once: one per
+    /// agent scanned at the same time.
+    pub fn exchange_limit(&self) -> usize {
+        let most = self.group_concurrency.max(1) * self.max_concurrency_per_group.max(1);
+        self.max_concurrency.map_or(most, |limit| limit.clamp(1, most))
+    }
+}
+
+/// One query against one agent, identified the way a [`ScanReport`] files it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ScanItem {
+    /// [`GroupResult::manager`].
+    pub manager: Option<String>,
+    pub group: String,
+    pub agent_id: String,
+    pub query: String,
+}
+
+/// On-disk cache of saved results, one file per manager, agent, query as
+/// rendered for the agent and format. An entry's age is its file's
+/// modification time, so it survives restarts; with `output_cipher` entries
+/// are stored encrypted.
+#[derive(Debug, Clone)]
+pub struct ResultCache {
+    pub dir: PathBuf,
+    /// Entries older than this are queried again.
+    pub ttl: Duration,
+    /// Ignore existing entries but still store fresh results.
+    pub refresh: bool,
+}
+
+impl ResultCache {
+    /// The entry for `query` rendered as `wql`, asked for in `format` and
+    /// trimmed to `fields`. A changed query file or variable, or another
+    /// format or projection, is a new entry.
+    #[allow(clippy::too_many_arguments)]
+    fn entry(
+        &self,
+        manager: Option<&str>,
+        agent: &Agent,
+        query: &str,
+        wql: &str,
+        format: ResultFormat,
+        fields: Option<&FieldProjection>,
+        ext: &str,
+    ) -> PathBuf {
+        let manager = path_component(manager.unwrap_or("default"));
+        let agent_id = path_component(&agent.id);
+        let digest = match fields {
+            Some(fields) => Sha256::digest(format!("{}\n{}\n{}", format.as_str(), fields, wql)),
+            None => Sha256::digest(format!("{}\n{}", format.as_str(), wql)),
+        };
+        let key: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        self.dir.join(manager).join(agent_id).join(format!("{}.{}.{}", query, key, ext))
+    }
+
+    /// The age of `entry` if it exists and is younger than the TTL.
+    fn fresh(&self, entry: &Path) -> Option<Duration> {
+        if self.refresh {
+            return None;
+        }
+        let age = fs::metadata(entry).ok()?.modified().ok()?.elapsed().ok()?;
+        (age < self.ttl).then_some(age)
+    }
+}
+
+/// Which results of earlier scans are kept (`--retain`, `--retain-age`).
+///
+/// Once a query's result is saved, the other files the output template
+/// names for the same agent and query, whatever their `{timestamp}`,
+/// `{date}` and `{run}`, are deleted with their metadata sidecars unless
+/// both limits keep them. Nothing else in the output directory is touched.
+/// Files are ordered by modification time, and the result just saved is
+/// always kept.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    /// Result files kept per agent and query, counting the one just saved.
+    pub keep: Option<usize>,
+    /// Result files older than this are deleted.
+    pub max_age: Option<Duration>,
+}
+
+impl Retention {
+    pub fn is_unlimited(&self) -> bool {
+        self.keep.is_none() && self.max_age.is_none()
+    }
+}
+
+/// Runs the queries against one agent before any other (`--warmup`), so a
+/// broken query fails once instead of on every agent.
+#[derive(Debug, Clone, Default)]
+pub struct Warmup {
+    /// Agent id to warm up with; the first agent scanned when `None` or not
+    /// among the agents scanned.
+    pub agent: Option<String>,
+    /// Scan the other agents even when a warmup query failed. Otherwise
+    /// their queries are skipped with [`WARMUP_FAILED`] as the reason.
+    pub continue_on_failure: bool,
+}
+
+/// Per-group agent sampling. An agent's place in the sample depends only on
+/// the seed and its id, so the same seed picks the same agents however
+/// discovery orders them.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub size: SampleSize,
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SampleSize {
+    /// At most this many agents per group.
+    Count(usize),
+    /// This percentage of each group, rounded up to at least one agent.
+    Percent(u8),
+}
+
+impl Sample {
+    /// Keeps the sampled agents, in their original order.
+    fn apply(&self, agents: Vec<Agent>) -> Vec<Agent> {
+        let keep = match self.size {
+            SampleSize::Count(count) => count,
+            SampleSize::Percent(percent) => (agents.len() * usize::from(percent)).div_ceil(100),
+        };
+        if keep >= agents.len() {
+            return agents;
+        }
+        let mut order: Vec<usize> = (0..agents.len()).collect();
+        order.sort_by_cached_key(|&i| Sha256::digest(format!("{}:{}", self.seed, agents[i].id)));
+        let mut chosen = order[..keep].to_vec();
+        chosen.sort_unstable();
+        agents
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| chosen.binary_search(i).is_ok())
+            .map(|(_, agent)| agent)
+            .collect()
+    }
+}
+
+/// The work a scan would do, from [`plan`]: its targets after every filter,
+/// without a query sent.
+#[derive(Debug)]
+pub struct ScanPlan {
+    /// Groups in the order they would be scanned, each manager's together.
+    pub groups: Vec<PlannedGroup>,
+    /// Managers whose authentication or discovery failed outright.
+    pub manager_failures: Vec<ManagerFailure>,
+    /// Query files loaded, before `ScanConfig.only` narrows them per agent.
+    pub query_files: usize,
+    /// `ScanConfig.group_concurrency`.
+    pub group_concurrency: usize,
+    /// `ScanConfig.max_concurrency_per_group`.
+    pub max_concurrency_per_group: usize,
+}
+
+/// One group of a [`ScanPlan`].
+#[derive(Debug)]
+pub struct PlannedGroup {
+    pub manager: Option<String>,
+    pub group: Group,
+    pub agents: usize,
+    /// Queries to run across the group's agents.
+    pub queries: usize,
+}
+
+impl ScanPlan {
+    pub fn total_agents(&self) -> usize {
+        self.groups.iter().map(|group| group.agents).sum()
+    }
+
+    pub fn total_queries(&self) -> usize {
+        self.groups.iter().map(|group| group.queries).sum()
+    }
+
+    /// How long the scan would take if every query took `per_query`. Each
+    /// group spreads its queries evenly over `max_concurrency_per_group`
+    /// agents at a time; a manager's groups run `group_concurrency` at a
+    /// time, a new one starting only once the oldest still running has
+    /// finished, and managers run one after another. Retries, warmup,
+    /// `max_concurrency` and adaptive concurrency are not accounted for.
+    pub fn estimate(&self, per_query: Duration) -> Duration {
+        let slots = self.group_concurrency.max(1);
+        let mut total = Duration::ZERO;
+        let mut groups = self.groups.iter().peekable();
+        while let Some(first) = groups.next() {
+            // When each group's result is handed back, in order.
+            let mut yielded: Vec<Duration> = Vec::new();
+            let mut next = Some(first);
+            while let Some(group) = next {
+                let start = match yielded.len().checked_sub(slots) {
+                    Some(oldest) => yielded[oldest],
+                    None => Duration::ZERO,
+                };
+                let lanes = self.max_concurrency_per_group.min(group.agents).max(1);
+                let finished = start + per_query * group.queries.div_ceil(lanes) as u32;
+                yielded.push(finished.max(yielded.last().copied().unwrap_or_default()));
+                next = groups.next_if(|next| next.manager == first.manager);
+            }
+            total += yielded.last().copied().unwrap_or_default();
+        }
+        total
+    }
+}
+
+/// Outcome of a whole scan, grouped in discovery order.
+#[derive(Debug)]
+pub struct ScanReport {
+    pub groups: Vec<GroupResult>,
+    /// Managers whose authentication, discovery or output failed outright.
+    pub manager_failures: Vec<ManagerFailure>,
+    /// Groups and agents Wazuh left out of its listings during discovery.
+    pub failed_items: Vec<FailedItem>,
+    pub duration: Duration,
+    /// When the scan started. Result file names use it for `{timestamp}` and
+    /// `{date}`, so a query that is retried overwrites its earlier result.
+    pub started_at: SystemTime,
+    /// Tokens held at the end of the scan, for `ScanConfig.wazuh_tokens`.
+    pub wazuh_tokens: HashMap<String, String>,
+    /// Where adaptive concurrency ended up, for `ScanConfig.adaptive_state`.
+    pub adaptive_state: Option<AdaptiveState>,
+}
+
+#[derive(Debug)]
+pub struct ManagerFailure {
+    pub manager: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct GroupResult {
+    /// Name of the manager the group belongs to, if managers are named.
+    pub manager: Option<String>,
+    pub group: Group,
+    pub queries: Vec<QueryResult>,
+}
+
+/// One query run against one agent.
+#[derive(Debug, Clone)]
+pub struct QueryResult {
+    pub agent: Agent,
+    /// Query name, e.g. `alerts` or `windows/logons`.
+    pub query: String,
+    /// Size of the result payload in bytes.
+    pub bytes: u64,
+    pub latency: Duration,
+    /// Phases of the exchange that answered the query; `None` when no
+    /// answer arrived or the result came from the cache.
+    pub timings: Option<QueryTimings>,
+    pub outcome: QueryOutcome,
+}
+
+/// When each phase of a query's answered attempt ended, measured from the
+/// moment it began connecting, so the values never decrease in field order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryTimings {
+    /// The TLS connection was established.
+    pub connected: Duration,
+    /// The request was written.
+    pub sent: Duration,
+    /// The first response byte arrived.
+    pub first_byte: Duration,
+    /// The response was read to the end.
+    pub received: Duration,
+}
+
+/// [`QueryOutcome::Skipped`] reason of the queries a scan did not start
+/// before its deadline.
+pub const DEADLINE_REACHED: &str = "scan deadline reached";
+/// Reason of the queries skipped because a [`Warmup`] query failed.
+pub const WARMUP_FAILED: &str = "warmup failed";
+
+#[derive(Debug, Clone)]
+pub enum QueryOutcome {
+    /// The server ran the query and the result was written to `path` in
+    /// the format the server reported. A `cached` result was copied from the
+    /// [`ResultCache`] without contacting the server. `stored_bytes` is the
+    /// size of the result as saved, after compression and encryption.
+    Saved { path: PathBuf, format: ResultFormat, cached: bool, stored_bytes: u64 },
+    /// The server reported the query as failed.
+    Rejected { message: String },
+    /// The query could not be completed (transport, signature, I/O, ...).
+    Error { message: String },
+    /// The query was not run because its agent used up `agent_timeout`, the
+    /// scan reached its `deadline` or the `warmup` failed.
+    Skipped { reason: String },
+    /// The server reported success without any data, even after
+    /// `retry_empty` more tries; nothing was saved.
+    Empty,
+}
+
+impl QueryResult {
+    pub fn succeeded(&self) -> bool {
+        matches!(self.outcome, QueryOutcome::Saved { .. })
+    }
+
+    pub fn skipped(&self) -> bool {
+        matches!(self.outcome, QueryOutcome::Skipped { .. })
+    }
+
+    pub fn empty(&self) -> bool {
+        matches!(self.outcome, QueryOutcome::Empty)
+    }
+
+    /// Whether the query ran and failed; skipped and empty queries are neither.
+    pub fn failed(&self) -> bool {
+        !self.succeeded() && !self.skipped() && !self.empty()
+    }
+}
+
+impl GatewayConfig {
+    /// Name used in logs: the manager name, or its Wazuh URL when unnamed.
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.wazuh_url)
+    }
+}
+
+impl ScanReport {
+    pub fn results(&self) -> impl Iterator<Item = &QueryResult> {
+        self.groups.iter().flat_map(|g| g.queries.iter())
+    }
+
+    pub fn total(&self) -> usize {
+        self.results().count()
+    }
+
+    pub fn succeeded(&self) -> usize {
+        self.results().filter(|r| r.succeeded()).count()
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.results().filter(|r| r.skipped()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results().filter(|r| r.failed()).count()
+    }
+
+    pub fn empty(&self) -> usize {
+        self.results().filter(|r| r.empty()).count()
+    }
+
+    /// Whether queries were left unstarted because the scan hit its deadline.
+    pub fn deadline_reached(&self) -> bool {
+        self.results().any(|r| matches!(&r.outcome, QueryOutcome::Skipped { reason } if reason == DEADLINE_REACHED))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum QueryOrder {
+    /// By path under the queries directory, numeric file name prefixes
+    /// compared as numbers (2_logons before 10_alerts)
+    #[default]
+    Name,
+    /// In the order the queries were named, falling back to name order
+    /// when none were
+    Listed,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum JsonOutput {
+    /// As the server sent it
+    #[default]
+    Passthrough,
+    /// Indented, for reading
+    Pretty,
+    /// Without whitespace, for storage
+    Compact,
+}
+
+/// A Wazuh agent connection status to scan (`--status`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AgentStatus {
+    Active,
+    Disconnected,
+    #[value(name = "never_connected")]
+    NeverConnected,
+    Pending,
+    /// Every status
+    All,
+}
+
+impl AgentStatus {
+    /// The status as Wazuh reports it.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AgentStatus::Active => "active",
+            AgentStatus::Disconnected => "disconnected",
+            AgentStatus::NeverConnected => "never_connected",
+            AgentStatus::Pending => "pending",
+            AgentStatus::All => "all",
+        }
+    }
+
+    fn matches(self, status: &str) -> bool {
+        self == AgentStatus::All || status.eq_ignore_ascii_case(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OrganizeBy {
+    /// One directory per Wazuh group
+    Group,
+    /// One directory per OS platform (windows, linux, macos, unknown)
+    Os,
+    /// One directory per agent
+    Agent,
+    /// One directory per Wazuh cluster node
+    Node,
+}
+
+fn output_subdir(organize_by: OrganizeBy, group: &Group, agent: &Agent) -> String {
+    match organize_by {
+        OrganizeBy::Group => path_component(&group.name),
+        OrganizeBy::Os => agent.platform_family().to_string(),
+        OrganizeBy::Agent => path_component(&agent.name),
+        OrganizeBy::Node => path_component(agent.node_name()),
+    }
+}
+
+/// Finds `*.json` query files under `dir`, descending at most `max_depth`
+/// levels of subdirectories (0 reads `dir` alone), in [`QueryOrder::Name`]
+/// order whatever order the filesystem lists them in. A directory reached
+/// again through a symlink is skipped, so link loops cannot recurse forever.
+pub fn get_wql_query_files(dir: &Path, max_depth: usize) -> Result<Vec<PathBuf>> {
+    let mut query_files = Vec::new();
+    collect_query_files(dir, max_depth, &mut HashSet::new(), &mut query_files)?;
+    check_unique_query_names(dir, &query_files)?;
+    Ok(query_files)
+}
+
+fn collect_query_files(
+    dir: &Path,
+    depth_left: usize,
+    visited: &mut HashSet<PathBuf>,
+    query_files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    if !visited.insert(fs::canonicalize(dir)?) {
+        info!("Skipping {}: directory already visited through a symlink", dir.display());
+        return Ok(());
+    }
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort_by_cached_key(|path| name_order(path));
+    for path in paths {
+        if path.is_dir() {
+            if depth_left > 0 {
+                collect_query_files(&path, depth_left - 1, visited, query_files)?;
+            } else {
+                info!("Not descending into {}: deeper than the query depth limit", path.display());
+            }
+        } else if path.is_file() && path.extension().is_some_and(|ext| ext == "json") {
+            query_files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Sort key of a directory entry: names with a numeric prefix first, by
+/// that number, then the rest by name.
+fn name_order(path: &Path) -> (u64, String) {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let digits = name.chars().take_while(char::is_ascii_digit).count();
+    (name[..digits].parse().unwrap_or(u64::MAX), name)
+}
+
+/// A query's name: its path under `dir` without the extension, with `/`
+/// between subdirectories (`windows/logons`). Top-level queries are named
+/// by their file stem.
+pub fn query_name(dir: &Path, query_file: &Path) -> String {
+    query_file
+        .strip_prefix(dir)
+        .unwrap_or(query_file)
+        .with_extension("")
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Results are named after the query, so names that differ only in case
+/// would overwrite each other's results on a case-insensitive filesystem.
+/// Such clashes are an error rather than being renamed silently.
+fn check_unique_query_names(dir: &Path, query_files: &[PathBuf]) -> Result<()> {
+    let mut seen: HashMap<String, &PathBuf> = HashMap::new();
+    let mut clashes = Vec::new();
+    for file in query_files {
+        let name = query_name(dir, file).to_lowercase();
+        if let Some(previous) = seen.insert(name, file) {
+            clashes.push(format!("{} and {}", previous.display(), file.display()));
+        }
+    }
+    if !clashes.is_empty() {
+        return Err(format!(
+            "Query files with the same name would overwrite each other's results: {}; rename one of each pair",
+            clashes.join(", ")
+        ).into());
+    }
+    Ok(())
+}
+
+/// Lists the query files under `dir`, restricted to the given query names
+/// (see [`query_name`]) when non-empty.
+pub fn load_query_files(dir: &Path, names: &[String], max_depth: usize, order: QueryOrder) -> Result<Vec<PathBuf>> {
+    let query_files = get_wql_query_files(dir, max_depth)?;
+    if names.is_empty() {
+        return Ok(query_files);
+    }
+
+    let unknown: Vec<&str> = names
+        .iter()
+        .filter(|name| !query_files.iter().any(|f| query_name(dir, f) == **name))
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!(
+            "Unknown quer{} in {}: {}",
+            if unknown.len() == 1 { "y" } else { "ies" },
+            dir.display(),
+            unknown.join(", ")
+        ).into());
+    }
+
+    Ok(match order {
+        QueryOrder::Name => query_files.into_iter().filter(|f| names.contains(&query_name(dir, f))).collect(),
+        QueryOrder::Listed => {
+            let mut listed: Vec<PathBuf> = Vec::new();
+            for name in names {
+                let file = query_files.iter().find(|f| query_name(dir, f) == *name).expect("unknown names were rejected");
+                if !listed.contains(file) {
+                    listed.push(file.clone());
+                }
+            }
+            listed
+        }
+    })
+}
+
+/// Companion of a query file declaring what its results look like, e.g.
+/// `alerts.meta` holding `{"format": "csv"}` or `{"fields": ["hits.hits._source.rule"]}`
+/// next to `alerts.json`.
+pub fn query_meta_path(query_file: &Path) -> PathBuf {
+    query_file.with_extension("meta")
+}
+
+#[derive(Default, Deserialize)]
+struct QueryMeta {
+    format: Option<String>,
+    /// Fields to trim JSON results to; empty saves them whole.
+    fields: Option<Vec<String>>,
+}
+
+/// The query's `.meta` companion; empty if it has none.
+fn query_meta(query_file: &Path) -> Result<QueryMeta> {
+    let path = query_meta_path(query_file);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(QueryMeta::default()),
+        Err(e) => return Err(format!("Failed to read query metadata {}: {}", path.display(), e).into()),
+    };
+    Ok(serde_json::from_str(&text).map_err(|e| format!("Query metadata {} is not a JSON object: {}", path.display(), e))?)
+}
+
+/// The format named by the query's `.meta` companion, if it has one.
+fn declared_format(query_file: &Path) -> Result<Option<String>> {
+    Ok(query_meta(query_file)?.format)
+}
+
+/// Fields to trim the JSON results of `query_file` to: those its `.meta`
+/// companion lists, or `default` (`--fields`) without a list. An empty
+/// list saves the results whole.
+pub fn query_fields(query_file: &Path, default: Option<&FieldProjection>) -> Result<Option<FieldProjection>> {
+    match query_meta(query_file)?.fields {
+        Some(fields) if fields.is_empty() => Ok(None),
+        Some(fields) => Ok(Some(FieldProjection::new(&fields).map_err(|e| {
+            format!("Query metadata {} has an {}", query_meta_path(query_file).display(), e)
+        })?)),
+        None => Ok(default.cloned()),
+    }
+}
+
+fn known_format(name: &str) -> Option<ResultFormat> {
+    ResultFormat::value_variants().iter().copied().find(|format| format.as_str().eq_ignore_ascii_case(name))
+}
+
+/// Result format to request for `query_file`: the one its `.meta` companion
+/// declares, or `default` (`--format`) without a declaration. A format this
+/// client does not know is requested as JSON.
+pub fn query_format(query_file: &Path, default: ResultFormat) -> Result<ResultFormat> {
+    Ok(match declared_format(query_file)? {
+        Some(name) => known_format(&name).unwrap_or(ResultFormat::Json),
+        None => default,
+    })
+}
+
+/// Buckets agents under their first group, or `default` if they have none.
+fn group_by_first_group(agents: Vec<Agent>) -> Vec<(Group, Vec<Agent>)> {
+    let mut targets: Vec<(Group, Vec<Agent>)> = Vec::new();
+    for agent in agents {
+        let group_name = agent.groups.first().cloned().unwrap_or_else(|| "default".to_string());
+        match targets.iter_mut().find(|(g, _)| g.name == group_name) {
+            Some((_, members)) => members.push(agent),
+            None => targets.push((
+                Group { id: group_name.clone(), name: group_name },
+                vec![agent],
+            )),
+        }
+    }
+    targets
+}
+
+/// Applies the agent and group filters to an explicit inventory, with the
+/// precedence of [`resolve_targets`]: requested agents win over requested
+/// groups. A requested group gathers every listed agent that belongs to it.
+fn inventory_targets(agents: &[Agent], config: &ScanConfig) -> Result<Vec<(Group, Vec<Agent>)>> {
+    if !config.agents.is_empty() {
+        let missing: Vec<&str> = config.agents
+            .iter()
+            .filter(|id| !agents.iter().any(|a| &a.id == *id))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("Unknown agent id(s): {}", missing.join(", ")).into());
+        }
+        let selected = agents.iter().filter(|a| config.agents.contains(&a.id)).cloned().collect();
+        return Ok(group_by_first_group(selected));
+    }
+
+    if !config.groups.is_empty() {
+        let mut targets = Vec::new();
+        let mut missing = Vec::new();
+        for name in &config.groups {
+            let members: Vec<Agent> = agents.iter().filter(|a| a.groups.contains(name)).cloned().collect();
+            if members.is_empty() {
+                missing.push(name.as_str());
+            }
+            targets.push((Group { id: name.clone(), name: name.clone() }, members));
+        }
+        if !missing.is_empty() {
+            return Err(format!("No listed agents in group(s): {}", missing.join(", ")).into());
+        }
+        return Ok(targets);
+    }
+
+    Ok(group_by_first_group(agents.to_vec()))
+}
+
+/// Resolves the `(group, agents)` pairs to scan, honouring the agent and
+/// group filters. Requested agents win: `config.groups` is ignored when
+/// `config.agents` is set, as in [`inventory_targets`].
+///
+/// With several managers a requested agent or group need only exist on some
+/// of them, so `strict` is false and missing ones are skipped instead of failing.
+#[cfg(feature = "gateway")]
+async fn resolve_targets(client: &mut Client, config: &ScanConfig, strict: bool) -> Result<Vec<(Group, Vec<Agent>)>> {
+    if !config.agents.is_empty() {
+        info!("Fetching {} requested agents...", config.agents.len());
+        let agents = client.fetch_agents_by_id(&config.agents).await?;
+        let missing: Vec<&str> = config.agents
+            .iter()
+            .filter(|id| !agents.iter().any(|a| &a.id == *id))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            if strict {
+                return Err(format!("Unknown agent id(s): {}", missing.join(", ")).into());
+            }
+            info!("Agent id(s) not on this manager, skipping: {}", missing.join(", "));
+        }
+
+        return Ok(group_by_first_group(agents));
+    }
+
+    info!("Fetching groups...");
+    let mut groups = client.fetch_groups().await?;
+    info!("Fetched {} groups", groups.len());
+
+    if !config.groups.is_empty() {
+        let missing: Vec<&str> = config.groups
+            .iter()
+            .filter(|name| !groups.iter().any(|g| &g.name == *name))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            if strict {
+                return Err(format!("Unknown group(s): {}", missing.join(", ")).into());
+            }
+            info!("Group(s) not on this manager, skipping: {}", missing.join(", "));
+        }
+        groups.retain(|g| config.groups.contains(&g.name));
+    }
+
+    let mut targets = Vec::with_capacity(groups.len());
+    for group in groups {
+        info!("Fetching agents for group: {}", group.name);
+        let agents = client.fetch_agents(&group.id).await?;
+        info!("Fetched {} agents for group {}", agents.len(), group.name);
+        targets.push((group, agents));
+    }
+    Ok(targets)
+}
+
+/// Connects and runs one query, reconnecting and retrying on transport errors.
+async fn query_with_retry(
+    client: &mut Client,
+    conduit: &mut ConduitConnector,
+    wql_query: &str,
+    format: ResultFormat,
+    spool_path: &Path,
+) -> Result<(ReceivedResponse, QueryTimings)> {
+    let retry = client.retry;
+    let mut attempt = 1;
+    let mut renewed_session = false;
+    loop {
+        let permit = match &conduit.concurrency {
+            Some(concurrency) => Some(concurrency.acquire().await),
+            None => None,
+        };
+        let mut started = Instant::now();
+        let outcome = match conduit.connect(retry).await {
+            Ok((mut stream, connecting)) => {
+                info!("TLS connection established");
+                started = Instant::now();
+                let connected = started.duration_since(connecting);
+                client.send_request_as(&mut stream, wql_query.to_string(), format, spool_path).await.map(|received| {
+                    let timings = received.timings;
+                    let timings = QueryTimings {
+                        connected,
+                        sent: connected + timings.sent,
+                        first_byte: connected + timings.first_byte,
+                        received: connected + timings.received,
+                    };
+                    (received, timings)
+                })
+            }
+            Err(e) => Err(e),
+        };
+        if let Some(concurrency) = &conduit.concurrency {
+            let ok = outcome.as_ref().err().is_none_or(|e| !is_retryable(e.as_ref()));
+            concurrency.record(started.elapsed(), ok);
+        }
+        drop(permit);
+
+        match outcome {
+            Err(e) if !renewed_session && e.is::<SessionExpired>() => {
+                eprintln!("Session expired; retrying with a new session");
+                renewed_session = true;
+            }
+            Err(e) if attempt < retry.max_attempts && is_retryable(e.as_ref()) => {
+                let delay = retry.delay(attempt);
+                eprintln!(
+                    "Query attempt {}/{} failed: {}; retrying in {} ms",
+                    attempt, retry.max_attempts, e, delay.as_millis()
+                );
+                sleep(delay).await;
+                attempt += 1;
+            }
+            outcome => return outcome,
+        }
+    }
+}
+
+/// Opens conduit connections, pacing fresh ones with the reconnect delay. A
+/// connection that is reused skips the delay; the server currently closes
+/// after every response, so in practice only the first connection of a scan
+/// goes without one.
+#[derive(Clone)]
+struct ConduitConnector {
+    server: String,
+    tls: TlsConfig,
+    delay: ReconnectDelay,
+    connected_before: bool,
+    /// Shared by every clone, so it limits exchanges across groups.
+    concurrency: Option<Arc<AdaptiveConcurrency>>,
+    /// Slots for agents scanned at once across groups (`max_concurrency`),
+    /// likewise shared.
+    agent_limit: Option<Arc<Semaphore>>,
+}
+
+impl ConduitConnector {
+    fn new(server: String, tls: TlsConfig, delay: ReconnectDelay) -> Self {
+        Self { server, tls, delay, connected_before: false, concurrency: None, agent_limit: None }
+    }
+
+    /// Returns the connection and when connecting began, after the delay.
+    async fn connect(&mut self, retry: RetryPolicy) -> Result<(TlsStream, Instant)> {
+        if self.connected_before {
+            sleep(self.delay.sample()).await;
+        }
+        self.connected_before = true;
+        info!("Connecting to server at {}...", self.server);
+        let connecting = Instant::now();
+        Ok((connect_with_retry(&self.server, &self.tls, retry).await?, connecting))
+    }
+}
+
+/// Runs one query for one agent and writes its result under `agent_dir`,
+/// giving up once `budget` has passed.
+#[allow(clippy::too_many_arguments)]
+async fn run_query(
+    client: &mut Client,
+    shared: &GroupScan<'_>,
+    conduit: &mut ConduitConnector,
+    group: &Group,
+    agent: &Agent,
+    query_file: &Path,
+    agent_dir: &str,
+    budget: Option<Duration>,
+) -> Result<(QueryOutcome, u64, Option<QueryTimings>)> {
+    let config = shared.config;
+    let query_name = query_name(&config.queries_dir, query_file);
+    let spool_path = spool_path(agent_dir, agent, &query_name);
+    let encrypted_spool_path = spool_path.with_extension("enc.partial");
+    let compressed_spool_path = spool_path.with_extension("compressed.partial");
+    for leftover in [&spool_path, &compressed_spool_path, &encrypted_spool_path] {
+        if leftover.exists() {
+            eprintln!("Discarding incomplete result left by an earlier run: {}", leftover.display());
+            let _ = fs::remove_file(leftover);
+        }
+    }
+    let cache_ext = |format: ResultFormat| format!("{}{}", format.extension(), result_suffix(config));
+    let query_content = config.query_vars.fill(&fs::read_to_string(query_file)?, agent)?;
+    let requested_format = query_format(query_file, config.client.format)?;
+    let fields = query_fields(query_file, config.fields.as_ref())?;
+    let result_meta = |path, format| ResultMeta {
+        manager: shared.manager,
+        group: &group.name,
+        agent_id: &agent.id,
+        agent_name: &agent.name,
+        query: &query_name,
+        format,
+        compression: config.compression,
+        encrypted: config.output_cipher.is_some(),
+        path,
+    };
+
+    if let Some(cache) = &config.cache {
+        let format = requested_format;
+        let entry = cache.entry(shared.manager, agent, &query_name, &query_content, format, fields.as_ref(), &cache_ext(format));
+        if let Some(age) = cache.fresh(&entry) {
+            let output_file = output_file(shared, group, agent, &query_name, agent_dir, format)?;
+            let bytes = fs::copy(&entry, &spool_path)?;
+            let meta = result_meta(Path::new(&output_file), format);
+            deliver(config, &meta, &spool_path).await?;
+            let stored_bytes = bytes;
+            if config.include_metadata {
+                // The sidecar describes the response that was cached, not this copy.
+                match fs::copy(metadata_path(&entry), metadata_path(Path::new(&output_file))) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        eprintln!("No metadata was cached with {}; its result has no sidecar", entry.display());
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            info!("Reused result cached {}s ago: {}", age.as_secs(), config.sink.destination(&meta));
+            if !config.retention.is_unlimited() {
+                prune_results(shared, group, agent, &query_name, agent_dir, format, Path::new(&output_file));
+            }
+            let saved = QueryOutcome::Saved { path: PathBuf::from(output_file), format, cached: true, stored_bytes };
+            return Ok((saved, bytes, None));
+        }
+    }
+
+    let mut empty_retries = 0;
+    let (ReceivedResponse { mut response, spooled_to, .. }, timings) = loop {
+        let query = query_with_retry(client, conduit, &query_content, requested_format, &spool_path);
+        let received = match budget {
+            Some(budget) => match tokio::time::timeout(budget, query).await {
+                Ok(received) => received?,
+                Err(_) => {
+                    let _ = fs::remove_file(&spool_path);
+                    return Err(format!("Agent time budget exhausted during this query ({:.1}s were left)", budget.as_secs_f64()).into());
+                }
+            },
+            None => query.await?,
+        };
+        let (answer, _) = &received;
+        let empty = answer.response.status && answer.spooled_to.is_none() && answer.response.data.is_empty();
+        if !empty {
+            break received;
+        }
+        if empty_retries == config.retry_empty {
+            eprintln!("Query {} for agent {} succeeded without any data; nothing was saved", query_name, agent.name);
+            return Ok((QueryOutcome::Empty, 0, None));
+        }
+        empty_retries += 1;
+        let delay = client.retry.delay(empty_retries);
+        eprintln!(
+            "Query {} for agent {} succeeded without any data; retrying in {} ms ({}/{})",
+            query_name,
+            agent.name,
+            delay.as_millis(),
+            empty_retries,
+            config.retry_empty
+        );
+        sleep(delay).await;
+    };
+
+    let bytes = match &spooled_to {
+        Some(path) => fs::metadata(path)?.len(),
+        None => response.data.len() as u64,
+    };
+
+    if response.status {
+        let format = response.format.unwrap_or_default();
+        // Reformatting rewrites the data the signature was made over.
+        let mut reformatted = false;
+        if format != ResultFormat::Json && fields.is_some() {
+            eprintln!("Warning: {} returned {} and is saved whole; only JSON results are trimmed to fields", query_name, format.as_str());
+        }
+        if format == ResultFormat::Json {
+            match check_json(&mut response.data, spooled_to.as_deref(), config.json_output, fields.as_ref()) {
+                Ok(rewritten) => reformatted = rewritten,
+                Err(e) => {
+                    if let Some(path) = &spooled_to {
+                        let _ = fs::remove_file(path);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let output_file = output_file(shared, group, agent, &query_name, agent_dir, format)?;
+        // The result is staged next to its spool file exactly as it will be
+        // saved, compressed and then encrypted, and handed to the sink.
+        let staged = match &spooled_to {
+            Some(path) => {
+                let mut staged = path.clone();
+                if config.compression != Compression::None {
+                    staged = restage(&staged, &compressed_spool_path, |src, dst| config.compression.compress_file(src, dst))?;
+                }
+                if let Some(cipher) = &config.output_cipher {
+                    staged = restage(&staged, &encrypted_spool_path, |src, dst| cipher.encrypt_file(src, dst))?;
+                }
+                staged
+            }
+            None => {
+                let mut data = Cow::Borrowed(response.data.as_bytes());
+                if config.compression != Compression::None {
+                    data = Cow::Owned(config.compression.compress(&data)?);
+                }
+                if let Some(cipher) = &config.output_cipher {
+                    data = Cow::Owned(cipher.encrypt(&data)?);
+                }
+                stage(&spool_path, &data)?;
+                spool_path.clone()
+            }
+        };
+        let stored_bytes = fs::metadata(&staged)?.len();
+        let entry = config
+            .cache
+            .as_ref()
+            .map(|cache| cache.entry(shared.manager, agent, &query_name, &query_content, format, fields.as_ref(), &cache_ext(format)));
+        if let Some(entry) = &entry {
+            let stored = entry.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::copy(&staged, entry));
+            if let Err(e) = stored {
+                eprintln!("Failed to cache result in {}: {}", entry.display(), e);
+            }
+        }
+        let meta = result_meta(Path::new(&output_file), format);
+        deliver(config, &meta, &staged).await?;
+        info!("Query result saved to: {}", config.sink.destination(&meta));
+        if config.include_metadata {
+            let metadata = ResultMetadata {
+                server: &config.server,
+                manager: shared.manager,
+                group: &group.name,
+                agent_id: &agent.id,
+                agent_name: &agent.name,
+                agent_groups: &agent.groups,
+                query: &query_name,
+                query_hash: query_hash(&query_content),
+                format,
+                session_id: &response.session_id,
+                request_id: response.request_id.as_deref(),
+                timestamp: response.timestamp,
+                signature: &response.signature,
+                signature_algorithm: config.client.signature_algorithm.as_str(),
+                signature_encoding: config.client.signature_encoding,
+                verifiable: !reformatted && response.format.is_some() && response.query_hash.is_some(),
+                compression: config.compression,
+                encrypted: config.output_cipher.is_some(),
+                client_version: env!("CARGO_PKG_VERSION"),
+            };
+            write_metadata(&metadata, Path::new(&output_file))?;
+            if let Some(entry) = &entry {
+                if let Err(e) = fs::copy(metadata_path(Path::new(&output_file)), metadata_path(entry)) {
+                    eprintln!("Failed to cache metadata in {}: {}", entry.display(), e);
+                }
+            }
+        }
+        if !config.retention.is_unlimited() {
+            prune_results(shared, group, agent, &query_name, agent_dir, format, Path::new(&output_file));
+        }
+        Ok((QueryOutcome::Saved { path: PathBuf::from(output_file), format, cached: false, stored_bytes }, bytes, Some(timings)))
+    } else {
+        let message = match &spooled_to {
+            Some(path) => {
+                let message = String::from_utf8_lossy(&fs::read(path)?).to_string();
+                let _ = fs::remove_file(path);
+                message
+            }
+            None => response.data,
+        };
+        eprintln!("Query failed: {}", message);
+        Ok((QueryOutcome::Rejected { message }, bytes, Some(timings)))
+    }
+}
+
+/// Provenance of one saved result, written by [`write_metadata`].
+#[derive(Serialize)]
+struct ResultMetadata<'a> {
+    /// Conduit server address.
+    server: &'a str,
+    manager: Option<&'a str>,
+    group: &'a str,
+    agent_id: &'a str,
+    agent_name: &'a str,
+    /// Every group the agent belongs to, including those it was not queried
+    /// under with `unique_agents`.
+    agent_groups: &'a [String],
+    query: &'a str,
+    /// [`query_hash`] of the query as sent, after agent placeholders were
+    /// filled in, which identifies the version of the query file.
+    query_hash: String,
+    format: ResultFormat,
+    session_id: &'a str,
+    request_id: Option<&'a str>,
+    /// Response timestamp, in unix seconds.
+    timestamp: u64,
+    /// [`Response::signature`](crate::protocol::Response::signature) the result was verified against.
+    signature: &'a str,
+    signature_algorithm: &'static str,
+    signature_encoding: SignatureEncoding,
+    /// Whether the signature can be checked against the saved file, which
+    /// [`verify_saved_result`] needs. It cannot when the result was
+    /// reformatted, or when the server did not echo the format and query
+    /// hash, since the sidecar cannot tell which of them the envelope held.
+    verifiable: bool,
+    compression: Compression,
+    /// Whether the result file is encrypted with the output key.
+    encrypted: bool,
+    client_version: &'static str,
+}
+
+/// What a saved result's signature covers besides its data, read back from
+/// its `--include-metadata` sidecar.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SavedSignature {
+    pub session_id: String,
+    pub request_id: Option<String>,
+    pub timestamp: u64,
+    pub format: ResultFormat,
+    pub query_hash: String,
+    pub signature: String,
+    pub signature_algorithm: SignatureAlgorithm,
+    /// Sidecars written before the encoding was recorded are base64.
+    #[serde(default)]
+    pub signature_encoding: SignatureEncoding,
+    /// Sidecars written before this was recorded are assumed verifiable.
+    #[serde(default = "verifiable_by_default")]
+    pub verifiable: bool,
+    pub compression: Compression,
+    pub encrypted: bool,
+}
+
+fn verifiable_by_default() -> bool {
+    true
+}
+
+impl SavedSignature {
+    /// Reads the sidecar of the result file `result`.
+    pub fn load(result: &Path) -> Result<Self> {
+        let path = metadata_path(result);
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read metadata {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&text).map_err(|e| format!("Metadata {} is not a result sidecar: {}", path.display(), e))?)
+    }
+}
+
+/// Checks offline that `data` is the result the server signed, given the
+/// rest of the signed response from its sidecar and the server's key; the
+/// same check [`Client`] makes when the response arrives. `data` is the
+/// result as received: decrypted and decompressed, but otherwise as saved.
+pub fn verify_saved_result(
+    data: &str,
+    signature: &SavedSignature,
+    server_key: &str,
+) -> std::result::Result<(), InvalidSignature> {
+    let response = Response {
+        status: true,
+        data: data.to_string(),
+        session_id: signature.session_id.clone(),
+        timestamp: signature.timestamp,
+        signature: signature.signature.clone(),
+        error_code: None,
+        request_id: signature.request_id.clone(),
+        format: Some(signature.format),
+        query_hash: Some(signature.query_hash.clone()),
+    };
+    let signer = signature.signature_algorithm.signer();
+    verify_envelope(response, signer.as_ref(), server_key, signature.signature_encoding).map(drop)
+}
+
+/// `result.json` becomes `result.json.meta.json`.
+fn metadata_path(result: &Path) -> PathBuf {
+    let mut path = result.as_os_str().to_owned();
+    path.push(".meta.json");
+    PathBuf::from(path)
+}
+
+fn write_metadata(metadata: &ResultMetadata, result: &Path) -> Result<()> {
+    let path = metadata_path(result);
+    fs::write(&path, serde_json::to_string_pretty(metadata)? + "\n")
+        .map_err(|e| format!("Failed to write metadata {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+/// Where a result is received before it is renamed into place. The name is
+/// fixed per agent and query, so a file left behind by a crash is found, and
+/// the query fetched again, on the next run.
+fn spool_path(agent_dir: &str, agent: &Agent, query_name: &str) -> PathBuf {
+    let name = path_component(&format!("{}_{}", agent.id, query_name));
+    Path::new(agent_dir).join(format!(".{}.partial", name))
+}
+
+/// Writes `bytes` to `partial` and syncs it, removing it again on failure.
+fn stage(partial: &Path, bytes: &[u8]) -> Result<()> {
+    let written = File::create(partial).and_then(|mut file| file.write_all(bytes).and_then(|_| file.sync_all()));
+    if written.is_err() {
+        let _ = fs::remove_file(partial);
+    }
+    Ok(written?)
+}
+
+/// Replaces the staged file `src` with `dst`, made from it by `step`.
+fn restage(src: &Path, dst: &Path, step: impl FnOnce(&Path, &Path) -> Result<()>) -> Result<PathBuf> {
+    let done = step(src, dst);
+    let _ = fs::remove_file(src);
+    if done.is_err() {
+        let _ = fs::remove_file(dst);
+    }
+    done.map(|_| dst.to_path_buf())
+}
+
+/// Hands the result staged in `staged` to the configured sink, discarding
+/// it if the sink fails.
+async fn deliver(config: &ScanConfig, meta: &ResultMeta<'_>, staged: &Path) -> Result<()> {
+    let delivered = config.sink.write_file(meta, staged).await;
+    if delivered.is_err() {
+        let _ = fs::remove_file(staged);
+    }
+    delivered
+}
+
+/// Renders the result file name for `agent_dir`, creating any directories
+/// the template adds. Compressed output gets the compression suffix and
+/// encrypted output the `.enc` suffix after it. The name depends only on the
+/// scan and the query, never on when the result arrived, so every attempt
+/// at a query writes the same file.
+fn output_file(
+    shared: &GroupScan<'_>,
+    group: &Group,
+    agent: &Agent,
+    query_name: &str,
+    agent_dir: &str,
+    format: ResultFormat,
+) -> Result<String> {
+    let config = shared.config;
+    let file_name = config.output_template.render(&TemplateValues {
+        group: &group.name,
+        agent_id: &agent.id,
+        agent_name: &agent.name,
+        query: query_name,
+        timestamp: shared.started_at,
+        run: config.run,
+        ext: format.extension(),
+    });
+    // Every substituted name is a single component, so this only trips if
+    // that ever stops holding.
+    let relative = Path::new(agent_dir).strip_prefix(&config.output_dir).unwrap_or(Path::new(agent_dir)).join(&file_name);
+    if relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("Result path {} would leave the output directory", relative.display()).into());
+    }
+    let output_file = format!("{}/{}", agent_dir, file_name);
+    if let Some(parent) = Path::new(&output_file).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(output_file + &result_suffix(config))
+}
+
+/// What [`output_file`] appends to the rendered name: the compression
+/// suffix and then `.enc`, each with its dot.
+fn result_suffix(config: &ScanConfig) -> String {
+    let suffixes = [config.compression.extension(), config.output_cipher.as_ref().map(|_| ENCRYPTED_EXTENSION)];
+    suffixes.into_iter().flatten().map(|suffix| format!(".{}", suffix)).collect()
+}
+
+/// Deletes the results of earlier scans of this agent and query that
+/// `config.retention` no longer keeps, now that `saved` is in place.
+/// Failures only warn: the new result is saved either way.
+fn prune_results(shared: &GroupScan<'_>, group: &Group, agent: &Agent, query_name: &str, agent_dir: &str, format: ResultFormat, saved: &Path) {
+    let config = shared.config;
+    let retention = config.retention;
+    let values = TemplateValues {
+        group: &group.name,
+        agent_id: &agent.id,
+        agent_name: &agent.name,
+        query: query_name,
+        timestamp: shared.started_at,
+        run: config.run,
+        ext: format.extension(),
+    };
+    let Some(pattern) = config.output_template.pattern(&values, &result_suffix(config)) else {
+        return;
+    };
+    let mut earlier = Vec::new();
+    if let Err(e) = find_results(Path::new(agent_dir), "", pattern.depth(), &pattern, &mut earlier) {
+        eprintln!("Warning: cannot list earlier results in {}: {}", agent_dir, e);
+        return;
+    }
+    earlier.retain(|(path, _)| path != saved);
+    // Newest first; the result just saved takes the first place kept.
+    earlier.sort_by_key(|&(_, modified)| std::cmp::Reverse(modified));
+    let kept = retention.keep.map_or(usize::MAX, |keep| keep.saturating_sub(1));
+    for (index, (path, modified)) in earlier.iter().enumerate() {
+        let too_old = retention.max_age.is_some_and(|max_age| modified.elapsed().is_ok_and(|age| age > max_age));
+        if index < kept && !too_old {
+            continue;
+        }
+        match fs::remove_file(path) {
+            Ok(()) => info!("Removed earlier result {}", path.display()),
+            Err(e) => {
+                eprintln!("Warning: failed to remove earlier result {}: {}", path.display(), e);
+                continue;
+            }
+        }
+        match fs::remove_file(metadata_path(path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                eprintln!("Warning: failed to remove metadata of {}: {}", path.display(), e);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Collects the files under `dir` whose path relative to the agent's
+/// directory, `prefix` plus their name, `pattern` matches, with their
+/// modification times. Symlinks are never followed or matched.
+fn find_results(
+    dir: &Path,
+    prefix: &str,
+    depth_left: usize,
+    pattern: &ResultPattern,
+    found: &mut Vec<(PathBuf, SystemTime)>,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let relative = format!("{}{}", prefix, name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() && depth_left > 0 {
+            find_results(&entry.path(), &format!("{}/", relative), depth_left - 1, pattern, found)?;
+        } else if file_type.is_file() && pattern.matches(&relative) {
+            found.push((entry.path(), entry.metadata()?.modified()?));
+        }
+    }
+    Ok(())
+}
+
+/// Checks a JSON result, trimming it to `fields` and pretty-printing or
+/// compacting it if asked. A result that is only passed through must parse;
+/// one that cannot be reformatted is saved as received, with a warning.
+/// Spooled results are checked without loading them into memory unless they
+/// are to be rewritten, which is then done in place.
+fn check_json(data: &mut String, spooled_to: Option<&Path>, output: JsonOutput, fields: Option<&FieldProjection>) -> Result<bool> {
+    let invalid = |e: serde_json::Error| format!("Result is not valid JSON: {}", e);
+    if output == JsonOutput::Passthrough && fields.is_none() {
+        match spooled_to {
+            Some(path) => serde_json::from_reader::<_, IgnoredAny>(BufReader::new(File::open(path)?)).map_err(invalid)?,
+            None => serde_json::from_str::<IgnoredAny>(data).map_err(invalid)?,
+        };
+        return Ok(false);
+    }
+    let parsed = match spooled_to {
+        Some(path) => serde_json::from_reader::<_, serde_json::Value>(BufReader::new(File::open(path)?)),
+        None => serde_json::from_str::<serde_json::Value>(data),
+    };
+    let value = match parsed {
+        Ok(value) => value,
+        Err(e) => {
+            let what = if fields.is_some() { "trimmed to fields" } else { "reformatted" };
+            eprintln!("Warning: result is not valid JSON and cannot be {} ({}); saving it as received", what, e);
+            return Ok(false);
+        }
+    };
+    let value = match fields {
+        Some(fields) => fields.project(&value),
+        None => value,
+    };
+    match spooled_to {
+        Some(path) => fs::write(path, match output {
+            JsonOutput::Pretty => serde_json::to_vec_pretty(&value)?,
+            _ => serde_json::to_vec(&value)?,
+        })?,
+        None => {
+            *data = match output {
+                JsonOutput::Pretty => serde_json::to_string_pretty(&value)?,
+                _ => serde_json::to_string(&value)?,
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Query results a [`ScanStream`] holds before the scan waits for the consumer.
+const STREAM_BUFFER: usize = 8;
+
+/// A [`QueryResult`] yielded by [`ScanStream`], with the group it was run for.
+#[derive(Debug, Clone)]
+pub struct StreamedResult {
+    /// Name of the manager the group belongs to, if managers are named.
+    pub manager: Option<String>,
+    pub group: Group,
+    pub result: QueryResult,
+}
+
+/// Query results of a running scan, yielded as each query completes.
+///
+/// The scan only makes progress while the stream is polled, so a slow
+/// consumer holds it back rather than letting results pile up. Results of
+/// one group arrive in order; with `group_concurrency` above one, groups
+/// interleave in completion order. Failed queries are yielded too, and again
+/// with their new outcome when a retry pass reruns them.
+pub struct ScanStream {
+    scan: Option<Pin<Box<dyn Future<Output = Result<ScanReport>>>>>,
+    results: mpsc::Receiver<StreamedResult>,
+    report: Option<Result<ScanReport>>,
+}
+
+impl ScanStream {
+    /// Runs the rest of the scan without yielding results and returns its
+    /// report, which lists every result, streamed or not.
+    pub async fn finish(self) -> Result<ScanReport> {
+        drop(self.results);
+        match (self.scan, self.report) {
+            (Some(scan), _) => scan.await,
+            (None, report) => report.expect("a finished scan leaves its report"),
+        }
+    }
+}
+
+impl Stream for ScanStream {
+    type Item = StreamedResult;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<StreamedResult>> {
+        let this = &mut *self;
+        loop {
+            // Closes (yielding `None`) once the finished scan drops the sender.
+            if let Poll::Ready(result) = this.results.poll_recv(cx) {
+                return Poll::Ready(result);
+            }
+            let Some(scan) = this.scan.as_mut() else {
+                return Poll::Pending;
+            };
+            match scan.as_mut().poll(cx) {
+                Poll::Ready(report) => {
+                    this.report = Some(report);
+                    this.scan = None;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Like [`scan`], but yields each query result as it completes. Errors that
+/// abort the scan are returned by [`ScanStream::finish`] after the stream ends.
+pub fn scan_stream(config: ScanConfig) -> ScanStream {
+    let (sender, results) = mpsc::channel(STREAM_BUFFER);
+    ScanStream {
+        scan: Some(Box::pin(run_scan(config, Some(sender)))),
+        results,
+        report: None,
+    }
+}
+
+/// Authenticates, discovers targets and runs every selected query against
+/// every selected agent. Per-query failures are recorded in the report;
+/// authentication and discovery failures abort the scan.
+pub async fn scan(config: ScanConfig) -> Result<ScanReport> {
+    run_scan(config, None).await
+}
+
+/// Loads the queries and discovers the agents a scan of `config` would
+/// target, applying the same filters, without running any query. A manager
+/// that fails is recorded in [`ScanPlan::manager_failures`] as in a scan.
+pub async fn plan(config: &ScanConfig) -> Result<ScanPlan> {
+    let query_files = load_query_files(&config.queries_dir, &config.queries, config.query_depth, config.query_order)?;
+    if query_files.is_empty() {
+        return Err(format!("No WQL query files found in {} directory", config.queries_dir.display()).into());
+    }
+    check_managers(config)?;
+
+    let mut plan = ScanPlan {
+        groups: Vec::new(),
+        manager_failures: Vec::new(),
+        query_files: query_files.len(),
+        group_concurrency: config.group_concurrency,
+        max_concurrency_per_group: config.max_concurrency_per_group,
+    };
+    let mut add = |manager: Option<&str>, targets: Vec<(Group, Vec<Agent>)>| {
+        for (group, agents) in select_targets(config, manager, &query_files, targets) {
+            let queries = agents.iter().map(|agent| queries_for(config, manager, &query_files, &group, agent).len()).sum();
+            plan.groups.push(PlannedGroup { manager: manager.map(str::to_string), group, agents: agents.len(), queries });
+        }
+    };
+    if let Some(agents) = &config.inventory {
+        add(None, inventory_targets(agents, config)?);
+    }
+
+    #[cfg(feature = "gateway")]
+    if config.inventory.is_none() {
+        for manager in &config.managers {
+            let discovered = match manager_client(config, manager) {
+                Ok(mut client) => discover(config, manager, &mut client).await,
+                Err(e) => Err(e),
+            };
+            match discovered {
+                Ok(targets) => add(manager.name.as_deref(), targets),
+                Err(e) => {
+                    eprintln!("Discovery on manager {} failed: {}", manager.label(), e);
+                    plan.manager_failures.push(ManagerFailure {
+                        manager: manager.label().to_string(),
+                        message: e.to_string(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(plan)
+}
+
+async fn run_scan(config: ScanConfig, results: Option<mpsc::Sender<StreamedResult>>) -> Result<ScanReport> {
+    let started = Instant::now();
+
+    info!("Loading WQL query files...");
+    let query_files = load_query_files(&config.queries_dir, &config.queries, config.query_depth, config.query_order)?;
+    if query_files.is_empty() {
+        return Err(format!("No WQL query files found in {} directory", config.queries_dir.display()).into());
+    }
+    for query_file in &query_files {
+        query_fields(query_file, None)?;
+        if let Some(name) = declared_format(query_file)?.filter(|name| known_format(name).is_none()) {
+            eprintln!(
+                "Warning: {} declares unknown format {:?}; its results are requested as JSON",
+                query_meta_path(query_file).display(),
+                name
+            );
+        }
+    }
+    if !config.agents.is_empty() && !config.groups.is_empty() {
+        eprintln!("Warning: agents were requested by id, so the group filter ({}) is ignored", config.groups.join(", "));
+    }
+    if !config.retention.is_unlimited() {
+        let template = &config.output_template;
+        let names_agent = template.uses("agent_id") || template.uses("agent_name") || config.organize_by == OrganizeBy::Agent;
+        if !template.uses("query") || !names_agent {
+            return Err(format!(
+                "Output template {} does not tell each agent's and query's results apart, so none can be pruned safely",
+                template
+            ).into());
+        }
+        if !["timestamp", "date", "run"].iter().any(|placeholder| template.uses(placeholder)) {
+            eprintln!("Warning: output template {} gives every scan's result the same name; there is nothing to prune", template);
+        }
+    }
+
+    check_managers(&config)?;
+
+    let mut conduit = ConduitConnector::new(config.server.clone(), TlsConfig::new(&config.tls)?, config.reconnect_delay);
+    let adaptive_key = format!("{} max {}", config.server, config.exchange_limit());
+    if config.adaptive_concurrency {
+        conduit.concurrency = Some(match &config.adaptive_state {
+            Some(state) if state.key == adaptive_key => {
+                info!("Resuming adaptive concurrency at {} exchanges", state.limit);
+                AdaptiveConcurrency::resume(config.exchange_limit(), state.limit, state.fastest)
+            }
+            Some(_) => {
+                info!("Saved adaptive concurrency was learned under another configuration; starting from one exchange");
+                AdaptiveConcurrency::new(config.exchange_limit())
+            }
+            None => AdaptiveConcurrency::new(config.exchange_limit()),
+        });
+    }
+    conduit.agent_limit = config.max_concurrency.map(|limit| Arc::new(Semaphore::new(limit.max(1))));
+    fs::create_dir_all(&config.output_dir)?;
+
+    let progress = ScanProgress::new(config.progress);
+    let mut report = ScanReport {
+        groups: Vec::new(),
+        manager_failures: Vec::new(),
+        failed_items: Vec::new(),
+        duration: Duration::ZERO,
+        started_at: SystemTime::now(),
+        wazuh_tokens: HashMap::new(),
+        adaptive_state: None,
+    };
+    let started_at = report.started_at.duration_since(UNIX_EPOCH)?.as_secs();
+
+    if let Some(agents) = &config.inventory {
+        let client = Client::new(config.client.clone(), config.retry);
+        let output_dir = config.output_dir.to_string_lossy().to_string();
+        let shared = GroupScan {
+            config: &config,
+            manager: None,
+            progress: &progress,
+            query_files: &query_files,
+            output_dir: &output_dir,
+            started_at,
+            results: results.as_ref(),
+        };
+        let targets = inventory_targets(agents, &config)?;
+        let groups = scan_targets(&shared, client, &mut conduit, targets).await;
+        report.groups.extend(groups);
+    }
+
+    #[cfg(feature = "gateway")]
+    if config.inventory.is_none() {
+        for manager in &config.managers {
+            let scanned = scan_manager(
+                &config,
+                manager,
+                &mut conduit,
+                &progress,
+                &query_files,
+                results.as_ref(),
+                &mut report,
+            ).await;
+            if let Err(e) = scanned {
+                eprintln!("Scan of manager {} failed: {}", manager.label(), e);
+                report.manager_failures.push(ManagerFailure {
+                    manager: manager.label().to_string(),
+                    message: e.to_string(),
+                });
+            }
+        }
+    }
+
+    progress.finish();
+    config.sink.finish().await?;
+    report.duration = started.elapsed();
+    report.adaptive_state = conduit.concurrency.as_ref().map(|concurrency| concurrency.snapshot(adaptive_key));
+    Ok(report)
+}
+
+/// Checks there is something to discover agents from when no inventory is
+/// given: managers, each uniquely named when there are several.
+fn check_managers(config: &ScanConfig) -> Result<()> {
+    if config.inventory.is_some() {
+        return Ok(());
+    }
+    if !cfg!(feature = "gateway") {
+        return Err("Built without the gateway feature: supply an explicit agent inventory".into());
+    }
+    if config.managers.is_empty() {
+        return Err("No Wazuh managers configured".into());
+    }
+    if config.managers.len() > 1 {
+        let mut names: Vec<&str> = Vec::new();
+        for manager in &config.managers {
+            match manager.name.as_deref() {
+                Some(name) if !names.contains(&name) => names.push(name),
+                _ => return Err("Every Wazuh manager needs a unique name when scanning more than one".into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Authenticates against one manager and runs every query on its agents,
+/// appending group results to `report` as they complete.
+#[cfg(feature = "gateway")]
+async fn scan_manager(
+    config: &ScanConfig,
+    manager: &GatewayConfig,
+    conduit: &mut ConduitConnector,
+    progress: &ScanProgress,
+    query_files: &[PathBuf],
+    results: Option<&mpsc::Sender<StreamedResult>>,
+    report: &mut ScanReport,
+) -> Result<()> {
+    let mut output_dir = config.output_dir.clone();
+    if let Some(name) = &manager.name {
+        output_dir.push(path_component(name));
+    }
+    let mut client = manager_client(config, manager)?;
+    let targets = discover(config, manager, &mut client).await?;
+    if let Some(token) = client.wazuh_token() {
+        report.wazuh_tokens.insert(manager.label().to_string(), token.to_string());
+    }
+    let mut failed_items = client.take_failed_items();
+    if config.strict_failed_items && !failed_items.is_empty() {
+        let items: Vec<String> = failed_items.iter().map(FailedItem::to_string).collect();
+        return Err(format!("Wazuh could not process {} listed item(s): {}", items.len(), items.join("; ")).into());
+    }
+    for item in &mut failed_items {
+        item.manager = manager.name.clone();
+    }
+    report.failed_items.extend(failed_items);
+
+    let output_dir = output_dir.to_string_lossy().to_string();
+    fs::create_dir_all(&output_dir)?;
+
+    let shared = GroupScan {
+        config,
+        manager: manager.name.as_deref(),
+        progress,
+        query_files,
+        output_dir: &output_dir,
+        started_at: report.started_at.duration_since(UNIX_EPOCH)?.as_secs(),
+        results,
+    };
+    let groups = scan_targets(&shared, client, conduit, targets).await;
+    report.groups.extend(groups);
+    Ok(())
+}
+
+/// A gateway client for `manager`, keeping its session apart from other
+/// managers' when it is named.
+#[cfg(feature = "gateway")]
+fn manager_client(config: &ScanConfig, manager: &GatewayConfig) -> Result<Client> {
+    let mut client_config = config.client.clone();
+    if let Some(name) = &manager.name {
+        client_config.session_file = crate::client::session_file_for(&client_config.session_file, &path_component(name));
+    }
+    let mut client = Client::new(client_config, config.retry)
+        .with_gateway(manager.url.clone(), manager.wazuh_url.clone())
+        .with_http_options(&config.http)?;
+    client.set_token_refresh_buffer(config.token_refresh_buffer);
+    Ok(client)
+}
+
+/// Obtains a Wazuh token for `manager`, reusing a held or supplied one while
+/// Wazuh accepts it, and resolves its groups and agents.
+#[cfg(feature = "gateway")]
+async fn discover(config: &ScanConfig, manager: &GatewayConfig, client: &mut Client) -> Result<Vec<(Group, Vec<Agent>)>> {
+    let strict = config.managers.len() == 1;
+    // A token held from an earlier run is newer than the one supplied.
+    let has_credentials = !manager.username.is_empty();
+    let token = match config.wazuh_tokens.get(manager.label()) {
+        Some(token) => Some((token, "reused")),
+        None => manager.token.as_ref().map(|token| (token, "supplied")),
+    };
+    Ok(match token {
+        Some((token, kind)) => {
+            info!("Using the {} Wazuh token for {}", kind, manager.label());
+            client.set_wazuh_token(token.clone());
+            if has_credentials {
+                client.set_wazuh_credentials(&manager.username, &manager.password);
+            }
+            match resolve_targets(client, config, strict).await {
+                Err(e) if has_credentials && e
+                    .downcast_ref::<WazuhApiError>()
+                    .is_some_and(|e| e.kind() == WazuhErrorKind::Unauthorized) =>
+                {
+                    info!("The {} token was rejected ({}); authenticating again", kind, e);
+                    client.take_failed_items();
+                    client.authenticate(&manager.username, &manager.password).await?;
+                    resolve_targets(client, config, strict).await?
+                }
+                targets => targets?,
+            }
+        }
+        None => {
+            client.authenticate(&manager.username, &manager.password).await?;
+            resolve_targets(client, config, strict).await?
+        }
+    })
+}
+
+/// Scans resolved `(group, agents)` targets, returning results in discovery order.
+async fn scan_targets(
+    shared: &GroupScan<'_>,
+    client: Client,
+    conduit: &mut ConduitConnector,
+    targets: Vec<(Group, Vec<Agent>)>,
+) -> Vec<GroupResult> {
+    let targets = select_targets(shared.config, shared.manager, shared.query_files, targets);
+    let query_count: usize = targets
+        .iter()
+        .flat_map(|(group, agents)| agents.iter().map(move |agent| shared.query_files_for(group, agent).len()))
+        .sum();
+    shared.progress.add_queries(query_count as u64);
+
+    let mut targets = targets;
+    let warmed_up = match &shared.config.warmup {
+        Some(warmup) => match warm_up(shared, &client, conduit, &mut targets, warmup).await {
+            Ok(warmed_up) => warmed_up,
+            Err(failed) => return failed,
+        },
+        None => None,
+    };
+
+    // Each group gets its own client and connector so groups can run
+    // concurrently; `buffered` yields results in discovery order regardless
+    // of which group finishes first.
+    let jobs: Vec<_> = targets
+        .into_iter()
+        .map(|(group, agents)| {
+            let job = (group, agents, client.clone(), conduit.clone());
+            conduit.connected_before = true;
+            job
+        })
+        .collect();
+    let mut groups: Vec<GroupResult> = stream::iter(jobs)
+        .map(|(group, agents, client, conduit)| {
+            let work = agents.into_iter().map(|agent| (shared.query_files_for(&group, &agent), agent)).collect();
+            scan_group(shared, client, conduit, group, work)
+        })
+        .buffered(shared.config.group_concurrency.max(1))
+        .collect()
+        .await;
+    if let Some((g, warmup)) = warmed_up {
+        groups[g].queries.splice(0..0, warmup);
+    }
+
+    for pass in 1..=shared.config.retry_passes {
+        let failed: Vec<(usize, usize)> = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(g, result)| {
+                result.queries.iter().enumerate().filter(|(_, q)| q.failed()).map(move |(q, _)| (g, q))
+            })
+            .collect();
+        if failed.is_empty() {
+            break;
+        }
+        if time_until_deadline(shared.config) == Some(Duration::ZERO) {
+            info!("\nScan deadline reached; {} failed queries are not retried", failed.len());
+            break;
+        }
+        info!(
+            "\nRetry pass {} of {}: rerunning {} failed queries",
+            pass,
+            shared.config.retry_passes,
+            failed.len()
+        );
+        shared.progress.add_queries(failed.len() as u64);
+
+        // Failed queries rerun like the first pass did, with groups side by
+        // side. An agent's results are adjacent in its group, so its failed
+        // queries are one run of `failed` and come back in the same order.
+        let mut jobs: Vec<(usize, Vec<usize>, Vec<AgentWork>)> = Vec::new();
+        for (g, q) in failed {
+            let result = &groups[g].queries[q];
+            let Some(query_file) =
+                shared.query_files.iter().find(|f| query_name(&shared.config.queries_dir, f) == result.query)
+            else {
+                continue;
+            };
+            if jobs.last().is_none_or(|(last, ..)| *last != g) {
+                jobs.push((g, Vec::new(), Vec::new()));
+            }
+            let (_, indices, work) = jobs.last_mut().expect("a job for the group");
+            match work.last_mut() {
+                Some((files, agent)) if agent.id == result.agent.id => files.push(query_file),
+                _ => work.push((vec![query_file], result.agent.clone())),
+            }
+            indices.push(q);
+        }
+        let jobs: Vec<_> = jobs
+            .into_iter()
+            .map(|(g, indices, work)| {
+                let job = (g, indices, work, groups[g].group.clone(), client.clone(), conduit.clone());
+                conduit.connected_before = true;
+                job
+            })
+            .collect();
+        let reruns: Vec<(usize, Vec<usize>, GroupResult)> = stream::iter(jobs)
+            .map(|(g, indices, work, group, client, conduit)| async move {
+                (g, indices, scan_group(shared, client, conduit, group, work).await)
+            })
+            .buffered(shared.config.group_concurrency.max(1))
+            .collect()
+            .await;
+        let mut recovered = 0;
+        for (g, indices, rerun) in reruns {
+            for (q, result) in indices.into_iter().zip(rerun.queries) {
+                recovered += usize::from(result.succeeded());
+                groups[g].queries[q] = result;
+            }
+        }
+        info!("Retry pass {}: {} queries succeeded", pass, recovered);
+    }
+    groups
+}
+
+/// Narrows resolved targets to the agents a scan queries: duplicates,
+/// nodes, statuses, the sample and `ScanConfig.only`, in that order.
+fn select_targets(
+    config: &ScanConfig,
+    manager: Option<&str>,
+    query_files: &[PathBuf],
+    targets: Vec<(Group, Vec<Agent>)>,
+) -> Vec<(Group, Vec<Agent>)> {
+    let targets = match config.unique_agents {
+        true => unique_agents(targets),
+        false => targets,
+    };
+    let targets = filter_agents(config, targets);
+    let targets: Vec<(Group, Vec<Agent>)> = match &config.sample {
+        Some(sample) => targets
+            .into_iter()
+            .map(|(group, agents)| {
+                let total = agents.len();
+                let agents = sample.apply(agents);
+                info!("Sampled {} of {} agents in group {}", agents.len(), total, group.name);
+                (group, agents)
+            })
+            .collect(),
+        None => targets,
+    };
+    match &config.only {
+        Some(_) => targets
+            .into_iter()
+            .map(|(group, mut agents)| {
+                agents.retain(|agent| !queries_for(config, manager, query_files, &group, agent).is_empty());
+                (group, agents)
+            })
+            .filter(|(_, agents)| !agents.is_empty())
+            .collect(),
+        None => targets,
+    }
+}
+
+/// Runs the warmup agent's queries and takes the agent out of `targets`.
+/// Returns the index of its group with its results, to be put before the
+/// rest of the group's; `None` when there is no agent to warm up with. When
+/// a warmup query failed and the scan should stop, the error holds every
+/// result: the warmup's and the other agents' queries, skipped.
+async fn warm_up(
+    shared: &GroupScan<'_>,
+    client: &Client,
+    conduit: &mut ConduitConnector,
+    targets: &mut [(Group, Vec<Agent>)],
+    warmup: &Warmup,
+) -> std::result::Result<Option<(usize, Vec<QueryResult>)>, Vec<GroupResult>> {
+    let position = |wanted: Option<&str>| {
+        targets.iter().enumerate().find_map(|(g, (_, agents))| {
+            agents.iter().position(|agent| wanted.is_none_or(|id| agent.id == id)).map(|a| (g, a))
+        })
+    };
+    let found = match warmup.agent.as_deref() {
+        Some(id) => position(Some(id)).or_else(|| {
+            eprintln!("Warning: warmup agent {} is not among the agents scanned; warming up with the first one", id);
+            position(None)
+        }),
+        None => position(None),
+    };
+    let Some((g, a)) = found else {
+        return Ok(None);
+    };
+
+    let group = targets[g].0.clone();
+    let agent = targets[g].1.remove(a);
+    info!("\nWarmup: running the queries against agent {} ({}) first", agent.name, agent.id);
+    let work = vec![(shared.query_files_for(&group, &agent), agent)];
+    let result = scan_group(shared, client.clone(), conduit.clone(), group, work).await;
+    conduit.connected_before = true;
+
+    let failed = result.queries.iter().filter(|q| q.failed()).count();
+    if failed == 0 {
+        info!("Warmup succeeded; scanning the other agents");
+        return Ok(Some((g, result.queries)));
+    }
+    if warmup.continue_on_failure {
+        eprintln!("Warmup: {} of {} queries failed; scanning the other agents anyway", failed, result.queries.len());
+        return Ok(Some((g, result.queries)));
+    }
+    eprintln!(
+        "Warmup: {} of {} queries failed; the other agents are not scanned (--warmup-continue scans them anyway)",
+        failed,
+        result.queries.len()
+    );
+    let mut groups = Vec::with_capacity(targets.len());
+    for (index, (group, agents)) in targets.iter().enumerate() {
+        let mut queries = if index == g { result.queries.clone() } else { Vec::new() };
+        for agent in agents {
+            for query_file in shared.query_files_for(group, agent) {
+                let skipped = QueryResult {
+                    agent: agent.clone(),
+                    query: query_name(&shared.config.queries_dir, query_file),
+                    bytes: 0,
+                    latency: Duration::ZERO,
+                    timings: None,
+                    outcome: QueryOutcome::Skipped { reason: WARMUP_FAILED.into() },
+                };
+                queries.push(publish(shared, group, skipped).await);
+            }
+        }
+        if !queries.is_empty() {
+            groups.push(GroupResult { manager: shared.manager.map(str::to_string), group: group.clone(), queries });
+        }
+    }
+    Err(groups)
+}
+
+/// Keeps each agent only in the first group it was found in, dropping groups
+/// left empty. The agent's `groups` gains every scanned group it was found
+/// in, so the groups it was dropped from are still recorded.
+fn unique_agents(targets: Vec<(Group, Vec<Agent>)>) -> Vec<(Group, Vec<Agent>)> {
+    let mut seen: HashMap<String, (usize, usize)> = HashMap::new();
+    let mut unique: Vec<(Group, Vec<Agent>)> = Vec::with_capacity(targets.len());
+    let mut duplicates = 0;
+    for (group, agents) in targets {
+        let g = unique.len();
+        let mut kept = Vec::with_capacity(agents.len());
+        for mut agent in agents {
+            match seen.get(&agent.id) {
+                Some(&(first_group, a)) => {
+                    duplicates += 1;
+                    let first = &mut unique[first_group].1[a];
+                    if !first.groups.contains(&group.name) {
+                        first.groups.push(group.name.clone());
+                    }
+                }
+                None => {
+                    if !agent.groups.contains(&group.name) {
+                        agent.groups.push(group.name.clone());
+                    }
+                    seen.insert(agent.id.clone(), (g, kept.len()));
+                    kept.push(agent);
+                }
+            }
+        }
+        unique.push((group, kept));
+    }
+    if duplicates > 0 {
+        info!("Skipping {} repeat memberships of agents already scanned in another group", duplicates);
+    }
+    unique.retain(|(_, agents)| !agents.is_empty());
+    unique
+}
+
+/// Keeps the agents on `config.nodes` with one of `config.statuses`, as a
+/// scan does, dropping groups left empty.
+pub fn filter_agents(config: &ScanConfig, targets: Vec<(Group, Vec<Agent>)>) -> Vec<(Group, Vec<Agent>)> {
+    let targets = filter_nodes(targets, &config.nodes);
+    filter_statuses(targets, &config.statuses, &config.agents)
+}
+
+/// Keeps the agents on the requested nodes, dropping groups left empty.
+fn filter_nodes(targets: Vec<(Group, Vec<Agent>)>, nodes: &[String]) -> Vec<(Group, Vec<Agent>)> {
+    if nodes.is_empty() {
+        return targets;
+    }
+    let total = agent_count(&targets);
+    let targets = retain_agents(targets, |a| nodes.iter().any(|n| n == a.node_name()));
+    info!("Kept {} of {} agents on node(s) {}", agent_count(&targets), total, nodes.join(", "));
+    targets
+}
+
+/// Keeps the agents with one of the requested connection statuses, dropping
+/// groups left empty. Agents without a status are kept, with a warning, and
+/// each agent of `named` that is dropped is warned about, since it was asked
+/// for by id.
+fn filter_statuses(targets: Vec<(Group, Vec<Agent>)>, statuses: &[AgentStatus], named: &[String]) -> Vec<(Group, Vec<Agent>)> {
+    if statuses.is_empty() || statuses.contains(&AgentStatus::All) {
+        return targets;
+    }
+    let total = agent_count(&targets);
+    let mut unknown = 0;
+    let mut dropped = Vec::new();
+    let targets = retain_agents(targets, |a| match &a.status {
+        Some(status) => {
+            let keep = statuses.iter().any(|s| s.matches(status));
+            if !keep && named.contains(&a.id) {
+                dropped.push(format!("{} ({})", a.id, status));
+            }
+            keep
+        }
+        None => {
+            unknown += 1;
+            true
+        }
+    });
+    if unknown > 0 {
+        eprintln!("Warning: {} agents report no connection status; scanning them anyway", unknown);
+    }
+    let statuses: Vec<&str> = statuses.iter().map(|s| s.as_str()).collect();
+    if !dropped.is_empty() {
+        eprintln!(
+            "Warning: skipping requested agents whose status is not {}: {}",
+            statuses.join(" or "),
+            dropped.join(", ")
+        );
+    }
+    info!("Kept {} of {} agents with status {}", agent_count(&targets), total, statuses.join(", "));
+    targets
+}
+
+/// Keeps the agents `keep` accepts, dropping groups left empty.
+fn retain_agents(targets: Vec<(Group, Vec<Agent>)>, mut keep: impl FnMut(&Agent) -> bool) -> Vec<(Group, Vec<Agent>)> {
+    targets
+        .into_iter()
+        .map(|(group, mut agents)| {
+            agents.retain(&mut keep);
+            (group, agents)
+        })
+        .filter(|(_, agents)| !agents.is_empty())
+        .collect()
+}
+
+fn agent_count(targets: &[(Group, Vec<Agent>)]) -> usize {
+    targets.iter().map(|(_, agents)| agents.len()).sum()
+}
+
+/// State shared by every group scanned for one manager.
+struct GroupScan<'a> {
+    config: &'a ScanConfig,
+    manager: Option<&'a str>,
+    progress: &'a ScanProgress,
+    query_files: &'a [PathBuf],
+    output_dir: &'a str,
+    /// [`ScanReport::started_at`] in unix seconds.
+    started_at: u64,
+    /// Receives a copy of each result for [`scan_stream`].
+    results: Option<&'a mpsc::Sender<StreamedResult>>,
+}
+
+impl<'a> GroupScan<'a> {
+    /// The query files to run against `agent`, narrowed by `ScanConfig.only`.
+    fn query_files_for(&self, group: &Group, agent: &Agent) -> Vec<&'a PathBuf> {
+        queries_for(self.config, self.manager, self.query_files, group, agent)
+    }
+}
+
+/// The query files to run against `agent` of `manager`, narrowed by `ScanConfig.only`.
+fn queries_for<'a>(
+    config: &ScanConfig,
+    manager: Option<&str>,
+    query_files: &'a [PathBuf],
+    group: &Group,
+    agent: &Agent,
+) -> Vec<&'a PathBuf> {
+    let Some(only) = &config.only else {
+        return query_files.iter().collect();
+    };
+    query_files
+        .iter()
+        .filter(|query_file| {
+            only.contains(&ScanItem {
+                manager: manager.map(str::to_string),
+                group: group.name.clone(),
+                agent_id: agent.id.clone(),
+                query: query_name(&config.queries_dir, query_file),
+            })
+        })
+        .collect()
+}
+
+/// The query files to run against an agent.
+type AgentWork<'a> = (Vec<&'a PathBuf>, Agent);
+
+/// Runs the given queries against each agent of one group, reporting the
+/// results in the order of `work`. Failures are recorded per query, so one
+/// group can never abort another.
+///
+/// Up to `max_concurrency_per_group` agents are scanned at once. An agent
+/// takes a slot of its group's limit before one of the scan-wide
+/// `max_concurrency`, so agents queued behind their own group's limit hold
+/// nothing another group could use, and a large group cannot crowd out the
+/// small ones.
+async fn scan_group(
+    shared: &GroupScan<'_>,
+    client: Client,
+    conduit: ConduitConnector,
+    group: Group,
+    work: Vec<AgentWork<'_>>,
+) -> GroupResult {
+    shared.progress.set_group(&group.name);
+    let group_limit = Semaphore::new(shared.config.max_concurrency_per_group.max(1));
+    // Agents scanned one after another reuse one client, and with it one
+    // session; another is cloned only for an agent that overlaps the others.
+    let spare = (client.clone(), ConduitConnector { connected_before: true, ..conduit.clone() });
+    let idle = std::sync::Mutex::new(vec![(client, conduit)]);
+    let (group_limit, spare, idle, scanned) = (&group_limit, &spare, &idle, &group);
+    let agents = work.into_iter().map(|(query_files, agent)| async move {
+        let _in_group = group_limit.acquire().await.expect("the semaphore is never closed");
+        let _in_scan = match &spare.1.agent_limit {
+            Some(limit) => Some(limit.acquire().await.expect("the semaphore is never closed")),
+            None => None,
+        };
+        let (mut client, mut conduit) = idle.lock().unwrap().pop().unwrap_or_else(|| spare.clone());
+        let results = scan_agent(shared, &mut client, &mut conduit, scanned, &query_files, &agent).await;
+        idle.lock().unwrap().push((client, conduit));
+        results
+    });
+    let results = future::join_all(agents).await.into_iter().flatten().collect();
+    GroupResult { manager: shared.manager.map(str::to_string), group, queries: results }
+}
+
+/// Runs the given queries against one agent, one after another, skipping
+/// the rest once the agent's time budget or the scan's deadline runs out.
+async fn scan_agent(
+    shared: &GroupScan<'_>,
+    client: &mut Client,
+    conduit: &mut ConduitConnector,
+    group: &Group,
+    query_files: &[&PathBuf],
+    agent: &Agent,
+) -> Vec<QueryResult> {
+    let mut results = Vec::new();
+    let started = Instant::now();
+    for (index, query_file) in query_files.iter().enumerate() {
+        let budget = shared.config.agent_timeout.map(|limit| limit.saturating_sub(started.elapsed()));
+        let until_deadline = time_until_deadline(shared.config);
+        let skipped = &query_files[index..];
+        let reason = if until_deadline == Some(Duration::ZERO) {
+            info!("Scan deadline reached; skipping {} queries for agent {}", skipped.len(), agent.name);
+            DEADLINE_REACHED
+        } else if budget == Some(Duration::ZERO) {
+            info!("Agent {} used up its time budget; skipping {} queries", agent.name, skipped.len());
+            "agent time budget exhausted"
+        } else {
+            let grace = shared.config.deadline_grace;
+            let budget = budget.into_iter().chain(until_deadline.map(|left| left + grace)).min();
+            results.push(scan_query(shared, client, conduit, group, agent, query_file, budget).await);
+            continue;
+        };
+        for query_file in skipped {
+            let result = QueryResult {
+                agent: agent.clone(),
+                query: query_name(&shared.config.queries_dir, query_file),
+                bytes: 0,
+                latency: Duration::ZERO,
+                timings: None,
+                outcome: QueryOutcome::Skipped { reason: reason.into() },
+            };
+            results.push(publish(shared, group, result).await);
+        }
+        break;
+    }
+    results
+}
+
+/// Time left before the scan's deadline; zero once it has passed.
+fn time_until_deadline(config: &ScanConfig) -> Option<Duration> {
+    config.deadline.map(|deadline| deadline.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+}
+
+/// Runs one query against one agent, recording any failure in the result.
+/// A query still running after `budget` is abandoned as failed.
+async fn scan_query(
+    shared: &GroupScan<'_>,
+    client: &mut Client,
+    conduit: &mut ConduitConnector,
+    group: &Group,
+    agent: &Agent,
+    query_file: &Path,
+    budget: Option<Duration>,
+) -> QueryResult {
+    let GroupScan { config, output_dir, .. } = *shared;
+    let agent_dir = format!("{}/{}", output_dir, output_subdir(config.organize_by, group, agent));
+    info!("\nExecuting query for agent {}: {:?}", agent.name, query_file);
+
+    let query_started = Instant::now();
+    let outcome = match fs::create_dir_all(&agent_dir) {
+        Err(e) => Err(format!("Failed to create {}: {}", agent_dir, e).into()),
+        Ok(()) => run_query(client, shared, conduit, group, agent, query_file, &agent_dir, budget).await,
+    };
+    let (outcome, bytes, timings) = match outcome {
+        Ok(done) => done,
+        Err(e) => {
+            eprintln!("Query error for agent {}: {}", agent.name, e);
+            (QueryOutcome::Error { message: e.to_string() }, 0, None)
+        }
+    };
+
+    let result = QueryResult {
+        agent: agent.clone(),
+        query: query_name(&config.queries_dir, query_file),
+        bytes,
+        latency: query_started.elapsed(),
+        timings,
+        outcome,
+    };
+    publish(shared, group, result).await
+}
+
+/// Counts a finished query and hands a copy to [`scan_stream`], if streaming.
+async fn publish(shared: &GroupScan<'_>, group: &Group, result: QueryResult) -> QueryResult {
+    let GroupScan { manager, progress, results: stream, .. } = *shared;
+    if let Some(stream) = stream {
+        // A dropped stream only stops the copies; the report keeps every result.
+        let _ = stream
+            .send(StreamedResult {
+                manager: manager.map(str::to_string),
+                group: group.clone(),
+                result: result.clone(),
+            })
+            .await;
+    }
+    progress.query_done();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::{SignatureAlgorithm, SignatureEncoding};
+
+    fn agent(id: &str, groups: &[&str]) -> Agent {
+        Agent {
+            id: id.to_string(),
+            name: format!("agent-{}", id),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            platform: None,
+            node: None,
+            ip: None,
+            status: None,
+        }
+    }
+
+    fn config(dir: &Path) -> ScanConfig {
+        ScanConfig {
+            server: "127.0.0.1:8080".to_string(),
+            managers: Vec::new(),
+            http: HttpOptions::default(),
+            token_refresh_buffer: Duration::from_secs(60),
+            client: ClientConfig {
+                client_id: "client1".to_string(),
+                client_key: "test_key_1".to_string(),
+                server_key: "server_key".to_string(),
+                max_clock_skew: crate::client::MAX_CLOCK_SKEW,
+                clock_offset: crate::client::ClockOffset::None,
+                max_in_memory: crate::client::MAX_IN_MEMORY,
+                max_response_size: crate::client::MAX_RESPONSE_SIZE,
+                spool_checkpoint: 0,
+                session_file: dir.join("session.json"),
+                session_cipher: None,
+                sign_query: false,
+                require_query_hash: false,
+                format: ResultFormat::Json,
+                signature_algorithm: SignatureAlgorithm::Sha256,
+                signature_encoding: SignatureEncoding::Base64,
+                wire_encoding: crate::protocol::WireEncoding::Json,
+                verbosity: 0,
+            },
+            tls: TlsOptions::default(),
+            retry: RetryPolicy::default(),
+            reconnect_delay: ReconnectDelay::default(),
+            queries_dir: dir.join("queries"),
+            queries: Vec::new(),
+            query_depth: 8,
+            query_order: QueryOrder::Name,
+            query_vars: QueryVars::default(),
+            agents: Vec::new(),
+            groups: Vec::new(),
+            nodes: Vec::new(),
+            statuses: Vec::new(),
+            unique_agents: false,
+            strict_failed_items: false,
+            output_dir: dir.join("results"),
+            organize_by: OrganizeBy::Group,
+            output_template: OutputTemplate::default(),
+            retention: Retention::default(),
+            output_cipher: None,
+            inventory: None,
+            group_concurrency: 1,
+            max_concurrency_per_group: 1,
+            max_concurrency: None,
+            adaptive_concurrency: false,
+            adaptive_state: None,
+            warmup: None,
+            sample: None,
+            retry_passes: 0,
+            retry_empty: 0,
+            agent_timeout: None,
+            deadline: None,
+            deadline_grace: Duration::ZERO,
+            cache: None,
+            only: None,
+            progress: false,
+            json_output: JsonOutput::Passthrough,
+            fields: None,
+            include_metadata: false,
+            compression: Compression::None,
+            sink: Arc::new(crate::sink::FileSink),
+            run: 1,
+            wazuh_tokens: HashMap::new(),
+        }
+    }
+
+    fn names(targets: &[(Group, Vec<Agent>)]) -> Vec<(String, Vec<String>)> {
+        targets
+            .iter()
+            .map(|(group, agents)| (group.name.clone(), agents.iter().map(|a| a.id.clone()).collect()))
+            .collect()
+    }
+
+    fn pairs(groups: &[(&str, &[&str])]) -> Vec<(String, Vec<String>)> {
+        groups
+            .iter()
+            .map(|(group, ids)| (group.to_string(), ids.iter().map(|id| id.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn inventory_targets_group_agents_by_their_first_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let inventory = [agent("001", &["web", "linux"]), agent("002", &["db"]), agent("003", &["web"])];
+        let targets = inventory_targets(&inventory, &config(dir.path())).unwrap();
+        assert_eq!(names(&targets), pairs(&[("web", &["001", "003"]), ("db", &["002"])]));
+    }
+
+    #[test]
+    fn inventory_group_filter_gathers_every_member() {
+        let dir = tempfile::tempdir().unwrap();
+        let inventory = [agent("001", &["web", "linux"]), agent("002", &["db"]), agent("003", &["linux"])];
+        let config = ScanConfig { groups: vec!["linux".to_string()], ..config(dir.path()) };
+        let targets = inventory_targets(&inventory, &config).unwrap();
+        assert_eq!(names(&targets), pairs(&[("linux", &["001", "003"])]));
+
+        let config = ScanConfig { groups: vec!["mail".to_string()], ..config };
+        let error = inventory_targets(&inventory, &config).unwrap_err();
+        assert_eq!(error.to_string(), "No listed agents in group(s): mail");
+    }
+
+    #[test]
+    fn inventory_agent_filter_rejects_unknown_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let inventory = [agent("001", &["web"]), agent("002", &["db"])];
+        let config = ScanConfig { agents: vec!["002".to_string(), "009".to_string()], ..config(dir.path()) };
+        let error = inventory_targets(&inventory, &config).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown agent id(s): 009");
+    }
+
+    #[test]
+    fn requested_agents_win_over_requested_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let inventory = [agent("001", &["web"]), agent("002", &["db"])];
+        let config = ScanConfig {
+            agents: vec!["002".to_string()],
+            groups: vec!["web".to_string()],
+            ..config(dir.path())
+        };
+        let targets = inventory_targets(&inventory, &config).unwrap();
+        assert_eq!(names(&targets), pairs(&[("db", &["002"])]));
+    }
+
+    #[test]
+    fn organize_by_picks_the_agent_directory() {
+        let group = Group { id: "web".to_string(), name: "web frontend".to_string() };
+        let agent = Agent {
+            name: "web-1".to_string(),
+            platform: Some("Ubuntu".to_string()),
+            ..agent("001", &["web"])
+        };
+        assert_eq!(output_subdir(OrganizeBy::Group, &group, &agent), "web_frontend");
+        assert_eq!(output_subdir(OrganizeBy::Os, &group, &agent), "linux");
+        assert_eq!(output_subdir(OrganizeBy::Agent, &group, &agent), "web-1");
+
+        let hostile = Group { id: "x".to_string(), name: "../../tmp".to_string() };
+        let agent = Agent { name: "..".to_string(), ..agent };
+        assert_eq!(output_subdir(OrganizeBy::Group, &hostile, &agent), ".._.._tmp");
+        assert_eq!(output_subdir(OrganizeBy::Agent, &hostile, &agent), "_");
+    }
+
+    #[tokio::test]
+    async fn only_reconnections_wait_for_the_reconnect_delay() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let tls = TlsConfig::new(&TlsOptions::default()).unwrap();
+        let delay = ReconnectDelay { base: Duration::from_millis(300), jitter: 0.0 };
+        let mut conduit = ConduitConnector::new(server, tls, delay);
+        let once = RetryPolicy { max_attempts: 1, base_delay: Duration::ZERO };
+
+        let started = Instant::now();
+        assert!(conduit.connect(once).await.is_err());
+        assert!(started.elapsed() < delay.base, "the first connection waited {:?}", started.elapsed());
+
+        let started = Instant::now();
+        assert!(conduit.connect(once).await.is_err());
+        assert!(started.elapsed() >= delay.base, "a reconnection waited only {:?}", started.elapsed());
+    }
+
+    #[cfg(not(feature = "gateway"))]
+    #[tokio::test]
+    async fn without_the_gateway_an_inventory_is_required() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        fs::create_dir_all(&config.queries_dir).unwrap();
+        fs::write(config.queries_dir.join("alerts.json"), "{}").unwrap();
+
+        let error = scan(config).await.unwrap_err();
+        assert!(error.to_string().contains("supply an explicit agent inventory"), "{}", error);
+    }
+
+    #[test]
+    fn json_results_are_checked_and_reformatted_as_asked() {
+        let mut data = r#"{"hits": {"total": 1}}"#.to_string();
+        assert!(!check_json(&mut data, None, JsonOutput::Passthrough, None).unwrap());
+        assert_eq!(data, r#"{"hits": {"total": 1}}"#);
+        assert!(check_json(&mut data, None, JsonOutput::Pretty, None).unwrap());
+        assert_eq!(data, "{\n  \"hits\": {\n    \"total\": 1\n  }\n}");
+
+        let mut data = "agent.id\n001\n".to_string();
+        let error = check_json(&mut data, None, JsonOutput::Passthrough, None).unwrap_err();
+        assert!(error.to_string().starts_with("Result is not valid JSON"), "{}", error);
+    }
+
+    #[test]
+    fn json_results_are_compacted_and_pretty_printed_alike() {
+        let sample = "{ \"hits\": { \"hits\": [ {\"_id\": \"a1\", \"level\": 3},\n {\"_id\": \"a2\"} ] } }\n";
+        let mut compact = sample.to_string();
+        assert!(check_json(&mut compact, None, JsonOutput::Compact, None).unwrap());
+        assert_eq!(compact, r#"{"hits":{"hits":[{"_id":"a1","level":3},{"_id":"a2"}]}}"#);
+
+        let mut pretty = sample.to_string();
+        assert!(check_json(&mut pretty, None, JsonOutput::Pretty, None).unwrap());
+        let lines = [
+            "{",
+            r#"  "hits": {"#,
+            r#"    "hits": ["#,
+            "      {",
+            r#"        "_id": "a1","#,
+            r#"        "level": 3"#,
+            "      },",
+            "      {",
+            r#"        "_id": "a2""#,
+            "      }",
+            "    ]",
+            "  }",
+            "}",
+        ];
+        assert_eq!(pretty, lines.join("\n"));
+    }
+
+    #[test]
+    fn spooled_json_is_checked_and_reformatted_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spooled");
+        let sample = "{ \"hits\": [1, 2] }\n";
+        for (output, expected) in [
+            (JsonOutput::Passthrough, sample),
+            (JsonOutput::Pretty, "{\n  \"hits\": [\n    1,\n    2\n  ]\n}"),
+            (JsonOutput::Compact, r#"{"hits":[1,2]}"#),
+        ] {
+            fs::write(&path, sample).unwrap();
+            let rewritten = check_json(&mut String::new(), Some(&path), output, None).unwrap();
+            assert_eq!(rewritten, output != JsonOutput::Passthrough);
+            assert_eq!(fs::read_to_string(&path).unwrap(), expected);
+        }
+        fs::write(&path, "{\"hits\": [1, 2").unwrap();
+        assert!(check_json(&mut String::new(), Some(&path), JsonOutput::Passthrough, None).is_err());
+    }
+
+    #[test]
+    fn invalid_json_is_saved_as_received_when_it_cannot_be_reformatted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spooled");
+        for output in [JsonOutput::Pretty, JsonOutput::Compact] {
+            let mut data = "{\"hits\": [1, 2".to_string();
+            assert!(!check_json(&mut data, None, output, None).unwrap());
+            assert_eq!(data, "{\"hits\": [1, 2");
+
+            fs::write(&path, "{\"hits\": [1, 2").unwrap();
+            assert!(!check_json(&mut String::new(), Some(&path), output, None).unwrap());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "{\"hits\": [1, 2");
+        }
+    }
+
+    #[test]
+    fn invalid_json_is_saved_whole_when_it_cannot_be_trimmed_to_fields() {
+        let fields: FieldProjection = "hits.total".parse().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spooled");
+        for output in [JsonOutput::Passthrough, JsonOutput::Pretty] {
+            let mut data = "{\"hits\": {\"total\": 1".to_string();
+            assert!(!check_json(&mut data, None, output, Some(&fields)).unwrap());
+            assert_eq!(data, "{\"hits\": {\"total\": 1");
+
+            fs::write(&path, "{\"hits\": {\"total\": 1").unwrap();
+            assert!(!check_json(&mut String::new(), Some(&path), output, Some(&fields)).unwrap());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "{\"hits\": {\"total\": 1");
+        }
+    }
+
+    fn touch(dir: &Path, file: &str) {
+        let path = dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "{}").unwrap();
+    }
+
+    #[test]
+    fn queries_sharing_a_stem_are_named_by_their_subdirectory() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), "windows/logons.json");
+        touch(dir.path(), "linux/logons.json");
+        let files = get_wql_query_files(dir.path(), 1).unwrap();
+        let names: Vec<_> = files.iter().map(|f| query_name(dir.path(), f)).collect();
+        assert_eq!(names, ["linux/logons", "windows/logons"]);
+    }
+
+    #[test]
+    fn query_names_differing_only_in_case_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), "Alerts.json");
+        touch(dir.path(), "alerts.json");
+        let error = get_wql_query_files(dir.path(), 0).unwrap_err();
+        assert!(error.to_string().contains("would overwrite each other's results"), "{}", error);
+    }
+
+    #[test]
+    fn a_query_format_is_declared_by_its_meta_file_and_defaults_to_json_when_unknown() {
+        let dir = tempfile::tempdir().unwrap();
+        let query = dir.path().join("alerts.json");
+        let meta = query_meta_path(&query);
+        assert_eq!(query_format(&query, ResultFormat::Raw).unwrap(), ResultFormat::Raw);
+        for (declared, format) in [(r#"{"format":"CSV"}"#, ResultFormat::Csv), (r#"{"format":"xml"}"#, ResultFormat::Json)] {
+            fs::write(&meta, declared).unwrap();
+            assert_eq!(query_format(&query, ResultFormat::Raw).unwrap(), format, "{}", declared);
+        }
+        fs::write(&meta, "{}").unwrap();
+        assert_eq!(query_format(&query, ResultFormat::Raw).unwrap(), ResultFormat::Raw);
+        fs::write(&meta, "format = csv").unwrap();
+        let error = query_format(&query, ResultFormat::Json).unwrap_err();
+        assert!(error.to_string().contains("is not a JSON object"), "{}", error);
+    }
+
+    #[test]
+    fn nested_queries_are_found_down_to_the_depth_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["alerts.json", "windows/logons.json", "windows/audit/policy.json", "notes.txt"] {
+            touch(dir.path(), file);
+        }
+        let names = |depth| -> Vec<String> {
+            let files = get_wql_query_files(dir.path(), depth).unwrap();
+            files.iter().map(|f| query_name(dir.path(), f)).collect()
+        };
+        assert_eq!(names(8), ["alerts", "windows/audit/policy", "windows/logons"]);
+        assert_eq!(names(1), ["alerts", "windows/logons"]);
+        assert_eq!(names(0), ["alerts"]);
+    }
+
+    #[test]
+    fn queries_run_in_the_same_order_however_they_were_created() {
+        let files = ["10_alerts.json", "2_logons.json", "audit.json", "1_policy.json", "windows/3_events.json"];
+        let orders = [files.to_vec(), files.iter().rev().copied().collect()];
+        let names: Vec<Vec<String>> = orders
+            .iter()
+            .map(|order| {
+                let dir = tempfile::tempdir().unwrap();
+                for file in order {
+                    touch(dir.path(), file);
+                }
+                let files = get_wql_query_files(dir.path(), 1).unwrap();
+                files.iter().map(|f| query_name(dir.path(), f)).collect()
+            })
+            .collect();
+        assert_eq!(names[0], ["1_policy", "2_logons", "10_alerts", "audit", "windows/3_events"]);
+        assert_eq!(names[1], names[0]);
+    }
+
+    #[test]
+    fn listed_order_runs_queries_as_they_were_named() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["alerts.json", "logons.json", "policy.json"] {
+            touch(dir.path(), file);
+        }
+        let names = |order| -> Vec<String> {
+            let wanted = ["policy", "alerts", "policy"].map(str::to_string);
+            let files = load_query_files(dir.path(), &wanted, 0, order).unwrap();
+            files.iter().map(|f| query_name(dir.path(), f)).collect()
+        };
+        assert_eq!(names(QueryOrder::Name), ["alerts", "policy"]);
+        assert_eq!(names(QueryOrder::Listed), ["policy", "alerts"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loops_are_not_followed_forever() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), "linux/logons.json");
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("linux/again")).unwrap();
+        let files = get_wql_query_files(dir.path(), 64).unwrap();
+        let names: Vec<_> = files.iter().map(|f| query_name(dir.path(), f)).collect();
+        assert_eq!(names, ["linux/logons"]);
+    }
+
+    #[test]
+    fn a_seed_always_samples_the_same_agents_whatever_their_order() {
+        let agents: Vec<Agent> = (1..=20).map(|i| agent(&format!("{:03}", i), &["web"])).collect();
+        let sample = Sample { size: SampleSize::Count(5), seed: 42 };
+        let ids = |agents: Vec<Agent>| -> Vec<String> {
+            let mut ids: Vec<String> = agents.into_iter().map(|a| a.id).collect();
+            ids.sort();
+            ids
+        };
+
+        let chosen = ids(sample.apply(agents.clone()));
+        assert_eq!(chosen.len(), 5);
+        assert_eq!(ids(sample.apply(agents.iter().rev().cloned().collect())), chosen);
+        let other = Sample { seed: 7, ..sample };
+        assert_ne!(ids(other.apply(agents.clone())), chosen);
+
+        let kept = sample.apply(agents.clone());
+        assert!(kept.windows(2).all(|pair| pair[0].id < pair[1].id), "the sample lost the discovery order");
+    }
+
+    #[test]
+    fn a_sample_larger_than_the_group_keeps_every_agent() {
+        let agents: Vec<Agent> = (1..=3).map(|i| agent(&format!("{:03}", i), &["web"])).collect();
+        let all = Sample { size: SampleSize::Count(10), seed: 1 }.apply(agents.clone());
+        assert_eq!(all.len(), 3);
+        let percent = Sample { size: SampleSize::Percent(10), seed: 1 }.apply(agents);
+        assert_eq!(percent.len(), 1, "a percentage keeps at least one agent");
+    }
+
+    #[test]
+    fn cache_entries_stay_inside_the_cache_whatever_the_agent_or_manager_is_called() {
+        let cache = ResultCache { dir: PathBuf::from("cache"), ttl: Duration::from_secs(60), refresh: false };
+        let entry = |manager: Option<&str>, id: &str| {
+            cache.entry(manager, &agent(id, &["web"]), "alerts", "{}", ResultFormat::Json, None, "json")
+        };
+        for (manager, id) in [(None, ".."), (Some(".."), "001"), (Some("."), "."), (Some("a/../.."), "c:\\x"), (None, "con")] {
+            let path = entry(manager, id);
+            assert!(path.starts_with("cache"), "{}", path.display());
+            assert!(path.components().all(|c| matches!(c, Component::Normal(_))), "{}", path.display());
+            assert_eq!(path.components().count(), 4, "{}", path.display());
+        }
+        assert!(entry(None, "..").starts_with("cache/default/_"));
+    }
+
+    #[test]
+    fn node_and_status_filters_keep_the_matching_agents() {
+        let on = |id: &str, group: &str, node: Option<&str>, status: Option<&str>| Agent {
+            node: node.map(str::to_string),
+            status: status.map(str::to_string),
+            ..agent(id, &[group])
+        };
+        let targets = || {
+            vec![
+                (Group { id: "web".to_string(), name: "web".to_string() }, vec![
+                    on("001", "web", Some("worker-1"), Some("active")),
+                    on("002", "web", None, Some("disconnected")),
+                ]),
+                (Group { id: "db".to_string(), name: "db".to_string() }, vec![
+                    on("003", "db", Some("worker-2"), Some("never_connected")),
+                    on("004", "db", Some("worker-2"), None),
+                ]),
+            ]
+        };
+        type Filter = fn(Vec<(Group, Vec<Agent>)>, &[&str]) -> Vec<(Group, Vec<Agent>)>;
+        type Kept<'a> = &'a [(&'a str, &'a [&'a str])];
+        let filter_nodes: Filter = |targets, nodes| filter_nodes(targets, &nodes.iter().map(|n| n.to_string()).collect::<Vec<_>>());
+        let filter_statuses: Filter = |targets, statuses| {
+            let statuses: Vec<AgentStatus> = statuses.iter().map(|s| AgentStatus::from_str(s, false).unwrap()).collect();
+            filter_statuses(targets, &statuses, &["002".to_string()])
+        };
+        let cases: [(Filter, &[&str], Kept); 8] = [
+            (filter_nodes, &[], &[("web", &["001", "002"]), ("db", &["003", "004"])]),
+            (filter_nodes, &["worker-1"], &[("web", &["001"])]),
+            (filter_nodes, &["worker-2", "worker-1"], &[("web", &["001"]), ("db", &["003", "004"])]),
+            // Agents of a single-node deployment report no node and are on the implicit one.
+            (filter_nodes, &[crate::gateway::IMPLICIT_NODE], &[("web", &["002"])]),
+            (filter_statuses, &[], &[("web", &["001", "002"]), ("db", &["003", "004"])]),
+            // An agent without a status is kept whatever is asked for.
+            (filter_statuses, &["active"], &[("web", &["001"]), ("db", &["004"])]),
+            (filter_statuses, &["disconnected", "never_connected"], &[("web", &["002"]), ("db", &["003", "004"])]),
+            (filter_statuses, &["pending", "all"], &[("web", &["001", "002"]), ("db", &["003", "004"])]),
+        ];
+        for (filter, values, expected) in cases {
+            assert_eq!(names(&filter(targets(), values)), pairs(expected), "{:?}", values);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::encryption::OutputCipher;
+use crate::info;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long after it was created a cached session is still offered to the
+/// server. A session exactly this old is still used.
+pub(crate) const SESSION_TTL: Duration = Duration::from_secs(3600);
+
+/// Conduit session cached between runs in the session file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SessionInfo {
+    pub(crate) session_id: String,
+    pub(crate) client_id: String,
+    pub(crate) created_at: u64,
+    pub(crate) last_used: u64,
+}
+
+impl SessionInfo {
+    /// A session the server just handed out at `now` (Unix seconds).
+    pub(crate) fn new(session_id: String, client_id: String, now: u64) -> Self {
+        Self { session_id, client_id, created_at: now, last_used: now }
+    }
+
+    /// Still within [`SESSION_TTL`] at `now`.
+    pub(crate) fn is_fresh(&self, now: u64) -> bool {
+        now.saturating_sub(self.created_at) <= SESSION_TTL.as_secs()
+    }
+
+    /// The session cached at `path` for `client_id`, unless there is none,
+    /// it cannot be read or decrypted, or it is no longer fresh at `now`.
+    pub(crate) fn load(path: &Path, client_id: &str, cipher: Option<&OutputCipher>, now: u64) -> Option<Self> {
+        let content = fs::read(path).ok()?;
+        let content = match cipher {
+            Some(cipher) => cipher.decrypt(&content).ok()?,
+            None => content,
+        };
+        let session = serde_json::from_slice::<Self>(&content).ok()?;
+
+        if session.is_fresh(now) && session.client_id == client_id {
+            info!("Loaded existing session: {}", session.session_id);
+            return Some(session);
+        }
+        None
+    }
+
+    /// Writes the session to `path`, encrypted with `cipher` if set. It is
+    /// written and synced to a file of its own, then renamed over `path`, so
+    /// neither a crash mid-save nor clients saving at the same time can
+    /// leave `path` truncated or garbled.
+    pub(crate) fn save(&self, path: &Path, cipher: Option<&OutputCipher>) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        let content = match cipher {
+            Some(cipher) => cipher.encrypt(content.as_bytes())?,
+            None => content.into_bytes(),
+        };
+        let partial = partial_path(path);
+        let written = File::create(&partial)
+            .and_then(|mut file| file.write_all(&content).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&partial, path));
+        if written.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        written?;
+        info!("Session saved: {}", self.session_id);
+        Ok(())
+    }
+}
+
+/// `dir/session.json` is written as `dir/.session.json.<uuid>.partial`,
+/// unique to each save.
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.partial", name, uuid::Uuid::new_v4()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+    const SESSION_ID: &str = "0f8fad5b-d9cb-469f-a165-70867728950e";
+
+    fn session() -> SessionInfo {
+        SessionInfo::new(SESSION_ID.to_string(), "client1".to_string(), NOW)
+    }
+
+    #[test]
+    fn a_saved_session_loads_back_identically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        let saved = SessionInfo { last_used: NOW + 30, ..session() };
+        saved.save(&path, None).unwrap();
+        assert_eq!(SessionInfo::load(&path, "client1", None, NOW + 60), Some(saved));
+    }
+
+    #[test]
+    fn an_encrypted_session_loads_back_only_with_its_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        let cipher = OutputCipher::from_passphrase("correct horse").unwrap();
+        session().save(&path, Some(&cipher)).unwrap();
+        assert!(!fs::read_to_string(&path).unwrap_or_default().contains(SESSION_ID));
+        assert_eq!(SessionInfo::load(&path, "client1", Some(&cipher), NOW), Some(session()));
+        assert_eq!(SessionInfo::load(&path, "client1", Some(&OutputCipher::from_passphrase("wrong horse").unwrap()), NOW), None);
+        assert_eq!(SessionInfo::load(&path, "client1", None, NOW), None);
+    }
+
+    #[test]
+    fn a_session_is_used_up_to_exactly_its_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        session().save(&path, None).unwrap();
+        let ttl = SESSION_TTL.as_secs();
+        assert_eq!(SessionInfo::load(&path, "client1", None, NOW + ttl), Some(session()));
+        assert_eq!(SessionInfo::load(&path, "client1", None, NOW + ttl + 1), None);
+    }
+
+    #[test]
+    fn a_session_is_not_loaded_for_another_client_or_from_a_damaged_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        assert_eq!(SessionInfo::load(&path, "client1", None, NOW), None);
+        session().save(&path, None).unwrap();
+        assert_eq!(SessionInfo::load(&path, "client2", None, NOW), None);
+        fs::write(&path, "{\"session_id\":").unwrap();
+        assert_eq!(SessionInfo::load(&path, "client1", None, NOW), None);
+    }
+
+    #[test]
+    fn an_interrupted_save_leaves_the_previous_session_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        session().save(&path, None).unwrap();
+        // A save that crashed before its rename leaves only its own file.
+        let newer = serde_json::to_string(&SessionInfo { last_used: NOW + 5, ..session() }).unwrap();
+        fs::write(partial_path(&path), &newer[..newer.len() / 2]).unwrap();
+        assert_eq!(SessionInfo::load(&path, "client1", None, NOW), Some(session()));
+        // The next save replaces it whole.
+        SessionInfo { last_used: NOW + 5, ..session() }.save(&path, None).unwrap();
+        assert_eq!(SessionInfo::load(&path, "client1", None, NOW).map(|s| s.last_used), Some(NOW + 5));
+    }
+
+    #[test]
+    fn concurrent_saves_never_leave_a_damaged_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        session().save(&path, None).unwrap();
+        std::thread::scope(|scope| {
+            for writer in 0..4 {
+                let path = &path;
+                scope.spawn(move || {
+                    for n in 0..25 {
+                        SessionInfo { last_used: NOW + writer * 100 + n, ..session() }.save(path, None).unwrap();
+                    }
+                });
+            }
+            for _ in 0..100 {
+                assert!(SessionInfo::load(&path, "client1", None, NOW).is_some());
+            }
+        });
+        let left: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(left, ["session.json"]);
+    }
+}
@@ -0,0 +1,315 @@
+//! Request and response signatures.
+//!
+//! A [`Signer`] names its algorithm in `AuthRequest.signature_algorithm`, and
+//! the server signs its response with the algorithm the request named. The
+//! original `SHA256(data || key)` construction sends no name, so servers that
+//! predate the field keep working with it. Signatures travel as base64 unless
+//! the request names another [`SignatureEncoding`].
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::sync::Arc;
+
+/// Signs data with a shared key. Implementations are incremental so spooled
+/// responses can be verified without holding them in memory.
+pub trait Signer: Send + Sync {
+    /// Identifier sent as `AuthRequest.signature_algorithm`, or `None` for
+    /// the original scheme that servers assume when the field is absent.
+    fn algorithm(&self) -> Option<&str>;
+
+    /// Starts a signature over data fed in pieces.
+    fn begin(&self, key: &[u8]) -> Box<dyn SignatureState>;
+
+    /// The signature of `data`, written in `encoding`.
+    fn sign(&self, data: &[u8], key: &[u8], encoding: SignatureEncoding) -> String {
+        let mut state = self.begin(key);
+        state.update(data);
+        encoding.encode(&state.finish())
+    }
+
+    fn verify(&self, data: &[u8], key: &[u8], signature: &str, encoding: SignatureEncoding) -> Result<(), InvalidSignature> {
+        let mut state = self.begin(key);
+        state.update(data);
+        check_signature(&state.finish(), signature, encoding)
+    }
+}
+
+/// A signature in progress; see [`Signer::begin`].
+pub trait SignatureState: Send {
+    fn update(&mut self, data: &[u8]);
+    /// The signature of everything fed to `update`, before it is encoded.
+    fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+/// How signatures are written in requests and responses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    /// Standard padded base64 (the original encoding)
+    #[default]
+    Base64,
+    /// Lowercase hexadecimal; either case is accepted from the server
+    Hex,
+}
+
+impl SignatureEncoding {
+    const ALL: [SignatureEncoding; 2] = [Self::Base64, Self::Hex];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Base64 => "base64",
+            Self::Hex => "hex",
+        }
+    }
+
+    pub fn encode(self, signature: &[u8]) -> String {
+        match self {
+            Self::Base64 => BASE64.encode(signature),
+            Self::Hex => signature.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+
+    /// `None` when `signature` is not written in this encoding.
+    pub fn decode(self, signature: &str) -> Option<Vec<u8>> {
+        match self {
+            Self::Base64 => BASE64.decode(signature).ok(),
+            Self::Hex if signature.len().is_multiple_of(2) && signature.is_ascii() => (0..signature.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).ok())
+                .collect(),
+            Self::Hex => None,
+        }
+    }
+}
+
+impl fmt::Display for SignatureEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A response signature that does not verify.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidSignature {
+    /// The signature is well formed but was not made over this data and key.
+    Mismatch,
+    /// The signature is not a signature of the expected length in the
+    /// configured encoding; `found` names the encoding it is in, if any.
+    Encoding { expected: SignatureEncoding, found: Option<SignatureEncoding> },
+}
+
+impl fmt::Display for InvalidSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mismatch => write!(f, "Invalid response signature"),
+            Self::Encoding { expected, found: Some(found) } => write!(
+                f,
+                "Invalid response signature: expected {} but the server sent {}; set the signature encoding to {}",
+                expected, found, found
+            ),
+            Self::Encoding { expected, found: None } => {
+                write!(f, "Invalid response signature: not a {} signature of the expected length", expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidSignature {}
+
+/// Compares `signature`, written in `encoding`, with the `expected` raw
+/// signature. A hex signature is also valid base64, so the decoded length
+/// tells a signature in the wrong encoding from one over the wrong data.
+pub fn check_signature(expected: &[u8], signature: &str, encoding: SignatureEncoding) -> Result<(), InvalidSignature> {
+    let decodes = |encoding: SignatureEncoding| encoding.decode(signature).filter(|s| s.len() == expected.len());
+    match decodes(encoding) {
+        Some(received) if received == expected => Ok(()),
+        Some(_) => Err(InvalidSignature::Mismatch),
+        None => Err(InvalidSignature::Encoding {
+            expected: encoding,
+            found: SignatureEncoding::ALL.into_iter().filter(|e| *e != encoding).find(|e| decodes(*e).is_some()),
+        }),
+    }
+}
+
+/// Built-in signature algorithms, selectable from the configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignatureAlgorithm {
+    /// SHA-256 over the data followed by the key (the original scheme)
+    #[default]
+    Sha256,
+    /// HMAC-SHA256
+    HmacSha256,
+    /// HMAC-SHA512
+    HmacSha512,
+}
+
+impl SignatureAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::HmacSha256 => "hmac-sha256",
+            Self::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    pub fn signer(self) -> Arc<dyn Signer> {
+        match self {
+            Self::Sha256 => Arc::new(Sha256Concat),
+            Self::HmacSha256 => Arc::new(HmacSigner::<Sha256>::new(self)),
+            Self::HmacSha512 => Arc::new(HmacSigner::<Sha512>::new(self)),
+        }
+    }
+}
+
+/// `SHA256(data || key)`.
+pub struct Sha256Concat;
+
+struct Sha256ConcatState {
+    hasher: Sha256,
+    key: Vec<u8>,
+}
+
+impl Signer for Sha256Concat {
+    fn algorithm(&self) -> Option<&str> {
+        None
+    }
+
+    fn begin(&self, key: &[u8]) -> Box<dyn SignatureState> {
+        Box::new(Sha256ConcatState { hasher: Sha256::new(), key: key.to_vec() })
+    }
+}
+
+impl SignatureState for Sha256ConcatState {
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn finish(mut self: Box<Self>) -> Vec<u8> {
+        self.hasher.update(&self.key);
+        self.hasher.finalize().to_vec()
+    }
+}
+
+/// HMAC over SHA-256 or SHA-512.
+pub struct HmacSigner<D> {
+    algorithm: SignatureAlgorithm,
+    digest: std::marker::PhantomData<D>,
+}
+
+impl<D> HmacSigner<D> {
+    fn new(algorithm: SignatureAlgorithm) -> Self {
+        Self { algorithm, digest: std::marker::PhantomData }
+    }
+}
+
+macro_rules! hmac_signer {
+    ($digest:ty) => {
+        impl Signer for HmacSigner<$digest> {
+            fn algorithm(&self) -> Option<&str> {
+                Some(self.algorithm.as_str())
+            }
+
+            fn begin(&self, key: &[u8]) -> Box<dyn SignatureState> {
+                Box::new(Hmac::<$digest>::new_from_slice(key).expect("HMAC accepts keys of any length"))
+            }
+        }
+
+        impl SignatureState for Hmac<$digest> {
+            fn update(&mut self, data: &[u8]) {
+                Mac::update(self, data);
+            }
+
+            fn finish(self: Box<Self>) -> Vec<u8> {
+                self.finalize().into_bytes().to_vec()
+            }
+        }
+    };
+}
+
+hmac_signer!(Sha256);
+hmac_signer!(Sha512);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [SignatureAlgorithm; 3] =
+        [SignatureAlgorithm::Sha256, SignatureAlgorithm::HmacSha256, SignatureAlgorithm::HmacSha512];
+
+    #[test]
+    fn every_algorithm_round_trips_whole_and_in_pieces_in_either_encoding() {
+        for algorithm in ALGORITHMS {
+            for encoding in SignatureEncoding::ALL {
+                let signer = algorithm.signer();
+                let signature = signer.sign(b"client1:1700000000:n0nce", b"key", encoding);
+                assert_eq!(signer.verify(b"client1:1700000000:n0nce", b"key", &signature, encoding), Ok(()));
+                let mismatch = Err(InvalidSignature::Mismatch);
+                assert_eq!(signer.verify(b"client1:1700000000:n0nce", b"other key", &signature, encoding), mismatch);
+                assert_eq!(signer.verify(b"client1:1700000001:n0nce", b"key", &signature, encoding), mismatch);
+
+                let mut state = signer.begin(b"key");
+                for piece in [&b"client1:"[..], b"1700000000", b":n0nce"] {
+                    state.update(piece);
+                }
+                assert_eq!(encoding.encode(&state.finish()), signature, "{:?} {:?}", algorithm, encoding);
+            }
+        }
+    }
+
+    #[test]
+    fn a_hex_signature_verifies_when_hex_is_configured() {
+        let signer = SignatureAlgorithm::HmacSha256.signer();
+        let hex = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        let data = b"what do ya want for nothing?";
+        assert_eq!(signer.verify(data, b"Jefe", hex, SignatureEncoding::Hex), Ok(()));
+        assert_eq!(signer.verify(data, b"Jefe", &hex.to_uppercase(), SignatureEncoding::Hex), Ok(()));
+        assert_eq!(signer.sign(data, b"Jefe", SignatureEncoding::Hex), hex);
+    }
+
+    #[test]
+    fn a_signature_in_the_other_encoding_is_named_in_the_error() {
+        let signer = SignatureAlgorithm::Sha256.signer();
+        for (signed_as, expected) in [(SignatureEncoding::Hex, SignatureEncoding::Base64), (SignatureEncoding::Base64, SignatureEncoding::Hex)] {
+            let signature = signer.sign(b"data", b"key", signed_as);
+            let error = signer.verify(b"data", b"key", &signature, expected).unwrap_err();
+            assert_eq!(error, InvalidSignature::Encoding { expected, found: Some(signed_as) });
+            assert!(error.to_string().contains(&format!("set the signature encoding to {}", signed_as)), "{}", error);
+        }
+        let error = signer.verify(b"data", b"key", "not a signature", SignatureEncoding::Hex).unwrap_err();
+        assert_eq!(error, InvalidSignature::Encoding { expected: SignatureEncoding::Hex, found: None });
+    }
+
+    #[test]
+    fn only_the_original_scheme_goes_unnamed() {
+        let names: Vec<_> = ALGORITHMS.iter().map(|a| a.signer().algorithm().map(str::to_string)).collect();
+        assert_eq!(names, [None, Some("hmac-sha256".to_string()), Some("hmac-sha512".to_string())]);
+    }
+
+    #[test]
+    fn a_signature_from_another_algorithm_is_rejected() {
+        for signed_with in ALGORITHMS {
+            let signature = signed_with.signer().sign(b"data", b"key", SignatureEncoding::Base64);
+            for verified_with in ALGORITHMS.into_iter().filter(|a| *a != signed_with) {
+                let verified = verified_with.signer().verify(b"data", b"key", &signature, SignatureEncoding::Base64);
+                assert!(verified.is_err(), "{:?}", verified_with);
+            }
+        }
+    }
+
+    #[test]
+    fn hmac_signers_match_the_rfc_4231_vectors() {
+        let hex = |algorithm: SignatureAlgorithm| {
+            algorithm.signer().sign(b"what do ya want for nothing?", b"Jefe", SignatureEncoding::Hex)
+        };
+        assert_eq!(hex(SignatureAlgorithm::HmacSha256), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(
+            hex(SignatureAlgorithm::HmacSha512),
+            "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea2505549758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737"
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Where saved results go.
+//!
+//! A scan hands every successful result to an [`OutputSink`]. [`FileSink`],
+//! the default, keeps the original behaviour of writing each result under the
+//! output directory. [`StdoutSink`] prints results instead, [`CombinedSink`]
+//! gathers them into one JSON array and, with the `gateway` feature,
+//! [`HttpSink`] POSTs each one to an ingestion endpoint.
+
+use crate::compression::Compression;
+use crate::protocol::ResultFormat;
+use crate::Result;
+use futures::future::LocalBoxFuture;
+use serde::de::IgnoredAny;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+#[cfg(feature = "gateway")]
+use {
+    crate::retry::RetryPolicy,
+    reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE},
+    tokio::time::sleep,
+};
+
+/// What a result is, passed to the sink alongside its bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct ResultMeta<'a> {
+    pub manager: Option<&'a str>,
+    pub group: &'a str,
+    pub agent_id: &'a str,
+    pub agent_name: &'a str,
+    pub query: &'a str,
+    pub format: ResultFormat,
+    pub compression: Compression,
+    /// Whether the bytes are encrypted with the output key.
+    pub encrypted: bool,
+    /// Where the output template places the result. [`FileSink`] writes it
+    /// there; other sinks only report it.
+    pub path: &'a Path,
+}
+
+/// A destination for saved results.
+pub trait OutputSink: Send + Sync {
+    /// Delivers one result.
+    fn write<'a>(&'a self, meta: &'a ResultMeta<'a>, bytes: &'a [u8]) -> LocalBoxFuture<'a, Result<()>>;
+
+    /// Delivers a result staged in `file`, which the sink takes over. The
+    /// default reads it back into memory for [`write`](Self::write) and
+    /// removes it; sinks that can move files override it.
+    fn write_file<'a>(&'a self, meta: &'a ResultMeta<'a>, file: &'a Path) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let bytes = fs::read(file);
+            let _ = fs::remove_file(file);
+            self.write(meta, &bytes?).await
+        })
+    }
+
+    /// Where `meta`'s result ends up, for log messages.
+    fn destination(&self, meta: &ResultMeta) -> String;
+
+    /// Called once the scan has delivered every result; sinks that gather
+    /// results complete their output here.
+    fn finish(&self) -> LocalBoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Writes each result to [`ResultMeta::path`], never leaving a truncated file
+/// there.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSink;
+
+impl OutputSink for FileSink {
+    fn write<'a>(&'a self, meta: &'a ResultMeta<'a>, bytes: &'a [u8]) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let partial = partial_path(meta.path);
+            let written = File::create(&partial)
+                .and_then(|mut file| file.write_all(bytes).and_then(|_| file.sync_all()))
+                .and_then(|_| fs::rename(&partial, meta.path));
+            if written.is_err() {
+                let _ = fs::remove_file(&partial);
+            }
+            Ok(written?)
+        })
+    }
+
+    /// Renames `file` into place, so large spooled results are never read back.
+    fn write_file<'a>(&'a self, meta: &'a ResultMeta<'a>, file: &'a Path) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move { Ok(fs::rename(file, meta.path)?) })
+    }
+
+    fn destination(&self, meta: &ResultMeta) -> String {
+        meta.path.display().to_string()
+    }
+}
+
+/// `dir/result.json` is written as `dir/.result.json.partial`.
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.partial", name))
+}
+
+/// Prints each result to stdout, followed by a newline unless it ends in one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn write<'a>(&'a self, _meta: &'a ResultMeta<'a>, bytes: &'a [u8]) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(bytes)?;
+            if !bytes.ends_with(b"\n") {
+                stdout.write_all(b"\n")?;
+            }
+            Ok(stdout.flush()?)
+        })
+    }
+
+    fn destination(&self, _meta: &ResultMeta) -> String {
+        "stdout".to_string()
+    }
+}
+
+/// Gathers every result of a scan into one JSON array at `path`, each
+/// element an object with the result's group, agent and query next to the
+/// result itself under `result`.
+///
+/// Elements are appended to a hidden `.partial` file as results arrive, so
+/// only one result is held in memory at a time, and the file is renamed into
+/// place by [`finish`](OutputSink::finish). Only plain JSON results can be
+/// gathered; compressed or encrypted ones are rejected.
+#[derive(Debug)]
+pub struct CombinedSink {
+    path: PathBuf,
+    writer: Mutex<Option<CombinedWriter>>,
+}
+
+#[derive(Debug)]
+struct CombinedWriter {
+    file: BufWriter<File>,
+    elements: usize,
+}
+
+/// The fields written before `result` in each [`CombinedSink`] element.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager: Option<&'a str>,
+    group: &'a str,
+    agent_id: &'a str,
+    agent_name: &'a str,
+    query: &'a str,
+}
+
+impl CombinedSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), writer: Mutex::new(None) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends one element, its result read from `result`. Results are
+    /// delivered concurrently, so the lock is held for the whole element.
+    fn append(&self, meta: &ResultMeta, result: impl Read) -> Result<()> {
+        if meta.encrypted || meta.compression != Compression::None || meta.format != ResultFormat::Json {
+            return Err(format!(
+                "{} gathers plain JSON results only, not {} results{}{}",
+                self.path.display(),
+                meta.format.extension(),
+                if meta.compression != Compression::None { ", compressed" } else { "" },
+                if meta.encrypted { ", encrypted" } else { "" }
+            ).into());
+        }
+        let mut head = serde_json::to_vec(&Envelope {
+            manager: meta.manager,
+            group: meta.group,
+            agent_id: meta.agent_id,
+            agent_name: meta.agent_name,
+            query: meta.query,
+        })?;
+        head.pop();
+        head.extend_from_slice(br#","result":"#);
+
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let writer = match &mut *writer {
+            Some(writer) => writer,
+            None => {
+                let file = BufWriter::new(File::create(partial_path(&self.path))?);
+                writer.insert(CombinedWriter { file, elements: 0 })
+            }
+        };
+        writer.file.write_all(if writer.elements == 0 { b"[\n" } else { b",\n" })?;
+        writer.file.write_all(&head)?;
+        io::copy(&mut BufReader::new(result), &mut writer.file)?;
+        writer.file.write_all(b"}")?;
+        writer.elements += 1;
+        Ok(())
+    }
+}
+
+impl OutputSink for CombinedSink {
+    fn write<'a>(&'a self, meta: &'a ResultMeta<'a>, bytes: &'a [u8]) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            serde_json::from_slice::<IgnoredAny>(bytes).map_err(|e| format!("Result is not valid JSON: {}", e))?;
+            self.append(meta, bytes)
+        })
+    }
+
+    /// Copies `file` into the array, so spooled results are never read into memory.
+    fn write_file<'a>(&'a self, meta: &'a ResultMeta<'a>, file: &'a Path) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let appended = serde_json::from_reader::<_, IgnoredAny>(BufReader::new(File::open(file)?))
+                .map_err(|e| format!("Result is not valid JSON: {}", e).into())
+                .and_then(|_| self.append(meta, File::open(file)?));
+            let _ = fs::remove_file(file);
+            appended
+        })
+    }
+
+    fn destination(&self, _meta: &ResultMeta) -> String {
+        self.path.display().to_string()
+    }
+
+    /// Closes the array and renames it into place; a scan without results
+    /// leaves an empty array. The next scan through this sink starts over.
+    fn finish(&self) -> LocalBoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let partial = partial_path(&self.path);
+            let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner()).take();
+            let file = match writer {
+                Some(mut writer) => {
+                    writer.file.write_all(b"\n]\n")?;
+                    writer.file.into_inner().map_err(|e| e.into_error())?
+                }
+                None => {
+                    let mut file = File::create(&partial)?;
+                    file.write_all(b"[]\n")?;
+                    file
+                }
+            };
+            file.sync_all()?;
+            fs::rename(&partial, &self.path)?;
+            Ok(())
+        })
+    }
+}
+
+/// POSTs each result to `url` with its [`ResultMeta`] in `X-Conduit-*`
+/// headers. Connection failures, timeouts, 429 and 5xx answers are retried
+/// with the scan's [`RetryPolicy`].
+#[cfg(feature = "gateway")]
+#[derive(Debug, Clone)]
+pub struct HttpSink {
+    url: String,
+    http: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+#[cfg(feature = "gateway")]
+impl HttpSink {
+    pub fn new(url: impl Into<String>, http: reqwest::Client, retry: RetryPolicy) -> Self {
+        Self { url: url.into(), http, retry }
+    }
+
+    fn headers(meta: &ResultMeta) -> Result<HeaderMap> {
+        let content_type = match (meta.encrypted, meta.format) {
+            (true, _) => "application/octet-stream",
+            (false, ResultFormat::Json) => "application/json",
+            (false, ResultFormat::Csv) => "text/csv",
+            (false, ResultFormat::Raw) => "text/plain",
+        };
+        let encrypted = if meta.encrypted { "true" } else { "false" };
+        let mut fields = vec![
+            ("x-conduit-group", meta.group),
+            ("x-conduit-agent-id", meta.agent_id),
+            ("x-conduit-agent-name", meta.agent_name),
+            ("x-conduit-query", meta.query),
+            ("x-conduit-format", meta.format.extension()),
+            ("x-conduit-encrypted", encrypted),
+        ];
+        if let Some(manager) = meta.manager {
+            fields.push(("x-conduit-manager", manager));
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        // Ciphertext is opaque, so only a plaintext body declares its encoding.
+        let encoding = match meta.compression {
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+            Compression::None => None,
+        };
+        if let Some(encoding) = encoding.filter(|_| !meta.encrypted) {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        for (name, value) in fields {
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| format!("{:?} cannot be sent in the {} header", value, name))?;
+            headers.insert(HeaderName::from_static(name), value);
+        }
+        Ok(headers)
+    }
+}
+
+#[cfg(feature = "gateway")]
+impl OutputSink for HttpSink {
+    fn write<'a>(&'a self, meta: &'a ResultMeta<'a>, bytes: &'a [u8]) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let headers = Self::headers(meta)?;
+            let max_attempts = self.retry.max_attempts.max(1);
+            let mut attempt = 1;
+            loop {
+                let sent = self.http.post(&self.url).headers(headers.clone()).body(bytes.to_vec()).send().await;
+                let error = match sent {
+                    Ok(response) if response.status().is_success() => return Ok(()),
+                    Ok(response) => {
+                        let status = response.status();
+                        let message = format!("{} answered {}", self.url, status);
+                        if !(status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS) {
+                            return Err(message.into());
+                        }
+                        message
+                    }
+                    Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => format!("POST to {} failed: {}", self.url, e),
+                    Err(e) => return Err(e.into()),
+                };
+                if attempt >= max_attempts {
+                    return Err(format!("{} (after {} attempts)", error, attempt).into());
+                }
+                let delay = self.retry.delay(attempt);
+                eprintln!("{}; retrying in {} ms", error, delay.as_millis());
+                sleep(delay).await;
+                attempt += 1;
+            }
+        })
+    }
+
+    fn destination(&self, _meta: &ResultMeta) -> String {
+        self.url.clone()
+    }
+}
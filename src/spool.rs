@@ -0,0 +1,341 @@
+use crate::protocol::Response;
+use crate::signing::SignatureState;
+use crate::Result;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+pub(crate) enum ReceivedBody {
+    Memory(String),
+    /// A [`WireEncoding::MessagePack`](crate::protocol::WireEncoding) envelope.
+    MessagePack(Vec<u8>),
+    Spooled {
+        path: PathBuf,
+        envelope: Box<Response>,
+        digest: Box<dyn SignatureState>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SpoolState {
+    Data,
+    Escape,
+    Unicode { value: u16, digits: u8 },
+    Trailer,
+}
+
+/// Streams an oversized response envelope to disk.
+///
+/// The server signs `serde_json::to_string(&Response)` with an empty signature,
+/// so the signed bytes are exactly the wire bytes up to the end of `data`
+/// followed by the re-serialized remaining fields. The `data` string is
+/// unescaped straight into the spool file while the wire bytes are signed, and
+/// only the short trailer (`session_id`, `timestamp`, `signature`) is buffered.
+///
+/// Every `checkpoint` bytes the file is flushed and synced, so a crash leaves
+/// the data received up to the last checkpoint. The file keeps its `.partial`
+/// name until the caller renames it into place, which marks it incomplete.
+pub(crate) struct ResponseSpooler {
+    path: PathBuf,
+    file: tokio::io::BufWriter<tokio::fs::File>,
+    checkpoint: u64,
+    unsynced: u64,
+    hasher: Box<dyn SignatureState>,
+    status: bool,
+    state: SpoolState,
+    pending_high_surrogate: Option<u16>,
+    trailer: Vec<u8>,
+}
+
+const SPOOL_MAX_TRAILER: usize = 4096;
+
+impl ResponseSpooler {
+    /// Starts spooling from the bytes buffered so far, or returns `None` if the
+    /// envelope isn't in the canonical field order and must be verified in memory.
+    pub(crate) async fn start(
+        path: &Path,
+        buffered: &[u8],
+        mut hasher: Box<dyn SignatureState>,
+        checkpoint: u64,
+    ) -> Result<Option<Self>> {
+        const PREFIXES: [(&[u8], bool); 2] = [
+            (br#"{"status":true,"data":""#, true),
+            (br#"{"status":false,"data":""#, false),
+        ];
+
+        let Some((prefix, status)) = PREFIXES.iter().find(|(p, _)| buffered.starts_with(p)) else {
+            return Ok(None);
+        };
+
+        let file = tokio::fs::File::create(path)
+            .await
+            .map_err(|e| format!("Failed to create spool file {}: {}", path.display(), e))?;
+        hasher.update(prefix);
+
+        let mut spooler = Self {
+            path: path.to_path_buf(),
+            file: tokio::io::BufWriter::new(file),
+            checkpoint,
+            unsynced: 0,
+            hasher,
+            status: *status,
+            state: SpoolState::Data,
+            pending_high_surrogate: None,
+            trailer: Vec::new(),
+        };
+        spooler.feed(&buffered[prefix.len()..]).await?;
+        Ok(Some(spooler))
+    }
+
+    pub(crate) async fn feed(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut i = 0;
+        while i < bytes.len() {
+            match self.state {
+                SpoolState::Trailer => {
+                    self.trailer.extend_from_slice(&bytes[i..]);
+                    if self.trailer.len() > SPOOL_MAX_TRAILER {
+                        return Err("Response trailer too large".into());
+                    }
+                    return Ok(());
+                }
+                SpoolState::Data => {
+                    let run_end = bytes[i..]
+                        .iter()
+                        .position(|&b| b == b'\\' || b == b'"')
+                        .map_or(bytes.len(), |p| i + p);
+                    if run_end > i {
+                        if self.pending_high_surrogate.is_some() {
+                            return Err("Unpaired surrogate in response data".into());
+                        }
+                        self.hasher.update(&bytes[i..run_end]);
+                        self.write(&bytes[i..run_end]).await?;
+                        i = run_end;
+                    }
+                    if let Some(&b) = bytes.get(i) {
+                        self.hasher.update(&[b]);
+                        i += 1;
+                        if b == b'"' {
+                            if self.pending_high_surrogate.is_some() {
+                                return Err("Unpaired surrogate in response data".into());
+                            }
+                            self.state = SpoolState::Trailer;
+                        } else {
+                            self.state = SpoolState::Escape;
+                        }
+                    }
+                }
+                SpoolState::Escape => {
+                    let b = bytes[i];
+                    self.hasher.update(&[b]);
+                    i += 1;
+                    if self.pending_high_surrogate.is_some() && b != b'u' {
+                        return Err("Unpaired surrogate in response data".into());
+                    }
+                    let unescaped = match b {
+                        b'"' => b'"',
+                        b'\\' => b'\\',
+                        b'/' => b'/',
+                        b'b' => 0x08,
+                        b'f' => 0x0c,
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'u' => {
+                            self.state = SpoolState::Unicode { value: 0, digits: 0 };
+                            continue;
+                        }
+                        other => return Err(format!("Invalid escape '\\{}' in response data", other as char).into()),
+                    };
+                    self.write(&[unescaped]).await?;
+                    self.state = SpoolState::Data;
+                }
+                SpoolState::Unicode { value, digits } => {
+                    let b = bytes[i];
+                    self.hasher.update(&[b]);
+                    i += 1;
+                    let digit = (b as char)
+                        .to_digit(16)
+                        .ok_or("Invalid unicode escape in response data")? as u16;
+                    let value = (value << 4) | digit;
+                    if digits + 1 == 4 {
+                        self.push_code_unit(value).await?;
+                        self.state = SpoolState::Data;
+                    } else {
+                        self.state = SpoolState::Unicode { value, digits: digits + 1 };
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn push_code_unit(&mut self, unit: u16) -> Result<()> {
+        let code_point = match (unit, self.pending_high_surrogate.take()) {
+            (0xD800..=0xDBFF, None) => {
+                self.pending_high_surrogate = Some(unit);
+                return Ok(());
+            }
+            (0xDC00..=0xDFFF, Some(high)) => {
+                0x10000 + ((u32::from(high) - 0xD800) << 10) + (u32::from(unit) - 0xDC00)
+            }
+            (0xD800..=0xDFFF, _) | (_, Some(_)) => {
+                return Err("Unpaired surrogate in response data".into());
+            }
+            (unit, None) => u32::from(unit),
+        };
+        let c = char::from_u32(code_point).ok_or("Invalid unicode escape in response data")?;
+        let mut utf8 = [0u8; 4];
+        self.write(c.encode_utf8(&mut utf8).as_bytes()).await
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.file.write_all(bytes).await?;
+        self.unsynced += bytes.len() as u64;
+        if self.checkpoint > 0 && self.unsynced >= self.checkpoint {
+            self.sync().await?;
+        }
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> Result<()> {
+        self.file.flush().await?;
+        self.file
+            .get_ref()
+            .sync_data()
+            .await
+            .map_err(|e| format!("Failed to sync spool file {}: {}", self.path.display(), e))?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    pub(crate) async fn finish(mut self) -> Result<ReceivedBody> {
+        if !matches!(self.state, SpoolState::Trailer) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Response ended before the data field was complete",
+            )));
+        }
+        self.sync().await?;
+
+        let trailer = std::str::from_utf8(&self.trailer)
+            .map_err(|e| format!("Response trailer is not valid UTF-8 at byte {}: {}", e.valid_up_to(), e))?;
+        let envelope: Response = serde_json::from_str(&format!(
+            r#"{{"status":{},"data":""{}"#,
+            self.status, trailer
+        ))?;
+
+        let mut unsigned = envelope.clone();
+        unsigned.signature = String::new();
+        let canonical = serde_json::to_string(&unsigned)?;
+        let empty_data = r#""data":"""#;
+        let tail_start = canonical
+            .find(empty_data)
+            .ok_or("Unexpected response envelope layout")? + empty_data.len();
+        self.hasher.update(&canonical.as_bytes()[tail_start..]);
+
+        Ok(ReceivedBody::Spooled {
+            path: self.path,
+            envelope: Box::new(envelope),
+            digest: self.hasher,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::{SignatureAlgorithm, SignatureEncoding};
+
+    const KEY: &[u8] = b"server_key";
+
+    /// A signed envelope carrying `data`, as the server writes it.
+    fn envelope(data: &str) -> (Vec<u8>, String) {
+        let mut response = Response {
+            status: true,
+            data: data.to_string(),
+            session_id: "0f8fad5b-d9cb-469f-a165-70867728950e".to_string(),
+            timestamp: 1_700_000_000,
+            signature: String::new(),
+            error_code: None,
+            request_id: Some("request-1".to_string()),
+            format: None,
+            query_hash: None,
+        };
+        let signature = SignatureAlgorithm::Sha256.signer().sign(serde_json::to_string(&response).unwrap().as_bytes(), KEY, SignatureEncoding::Base64);
+        response.signature = signature.clone();
+        (serde_json::to_vec(&response).unwrap(), signature)
+    }
+
+    /// Spools `wire` fed `chunk` bytes at a time after the first `buffered`.
+    async fn spool(path: &Path, wire: &[u8], buffered: usize, chunk: usize) -> Result<ReceivedBody> {
+        let digest = SignatureAlgorithm::Sha256.signer().begin(KEY);
+        let mut spooler = ResponseSpooler::start(path, &wire[..buffered], digest, 7).await?.expect("canonical envelope");
+        for piece in wire[buffered..].chunks(chunk) {
+            spooler.feed(piece).await?;
+        }
+        spooler.finish().await
+    }
+
+    #[tokio::test]
+    async fn data_is_unescaped_to_disk_and_the_signature_covers_the_wire_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool");
+        let data = "line\n\"quoted\" \\ tab\t é 😀 \u{1}";
+        let (wire, signature) = envelope(data);
+        for chunk in [1, 3, 64] {
+            let ReceivedBody::Spooled { envelope, digest, .. } = spool(&path, &wire, 30, chunk).await.unwrap() else {
+                panic!("expected a spooled body");
+            };
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), data);
+            assert_eq!(envelope.data, "");
+            assert_eq!(envelope.signature, signature);
+            assert_eq!(SignatureEncoding::Base64.encode(&digest.finish()), signature);
+        }
+    }
+
+    #[tokio::test]
+    async fn an_envelope_in_another_field_order_is_not_spooled() {
+        let dir = tempfile::tempdir().unwrap();
+        let digest = SignatureAlgorithm::Sha256.signer().begin(KEY);
+        let buffered = br#"{"data":"abc","status":true"#;
+        let spooler = ResponseSpooler::start(&dir.path().join("spool"), buffered, digest, 0).await.unwrap();
+        assert!(spooler.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_response_cut_short_in_data_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wire, _) = envelope("a fairly long payload");
+        let error = spool(&dir.path().join("spool"), &wire[..40], 30, 4).await.err().unwrap();
+        assert_eq!(error.to_string(), "Response ended before the data field was complete");
+    }
+
+    #[tokio::test]
+    async fn unpaired_surrogates_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let digest = SignatureAlgorithm::Sha256.signer().begin(KEY);
+        let wire = br#"{"status":true,"data":"\ud800x","session_id":""#;
+        let error = ResponseSpooler::start(&dir.path().join("spool"), wire, digest, 0).await.err().unwrap();
+        assert_eq!(error.to_string(), "Unpaired surrogate in response data");
+    }
+
+    #[tokio::test]
+    async fn the_file_is_synced_every_checkpoint_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool");
+        let (wire, _) = envelope("0123456789abcdefghijklmnopqrstuvwxyz");
+        let prefix = br#"{"status":true,"data":""#.len();
+        let digest = SignatureAlgorithm::Sha256.signer().begin(KEY);
+        let mut spooler = ResponseSpooler::start(&path, &wire[..prefix], digest, 10).await.unwrap().unwrap();
+
+        for (written, byte) in wire[prefix..prefix + 36].iter().enumerate() {
+            spooler.feed(std::slice::from_ref(byte)).await.unwrap();
+            // Bytes past the last checkpoint are still in the write buffer.
+            let on_disk = std::fs::metadata(&path).unwrap().len();
+            assert_eq!(on_disk, (written as u64 + 1) / 10 * 10, "after {} bytes", written + 1);
+        }
+        spooler.feed(&wire[prefix + 36..]).await.unwrap();
+        spooler.finish().await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 36);
+    }
+}
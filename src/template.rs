@@ -0,0 +1,402 @@
+use std::fmt;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+/// Matches the historical `{query}_{agent}_{ts}.json` naming for JSON results.
+pub const DEFAULT_OUTPUT_TEMPLATE: &str = "{query}_{agent_name}_{timestamp}.{ext}";
+
+const PLACEHOLDERS: &[&str] = &["group", "agent_id", "agent_name", "query", "timestamp", "date", "run", "ext"];
+
+/// Result file name pattern, relative to the agent's output directory.
+///
+/// Placeholders are `{group}`, `{agent_id}`, `{agent_name}`, `{query}`,
+/// `{timestamp}` (unix seconds when the scan started, so a retried query
+/// overwrites its earlier result), `{date}` (UTC, `YYYY-MM-DD`), `{run}` (the
+/// iteration number with `--interval`, otherwise 1) and `{ext}` (`json`,
+/// `csv` or `txt` for the result format); `{{` and `}}` are literal braces.
+/// Substituted values are made safe with [`path_component`], so only
+/// literal text can introduce directories. The exception is `{query}`, which
+/// keeps the `/` of a nested query's name so results mirror the query tree.
+#[derive(Debug, Clone)]
+pub struct OutputTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Field(&'static str),
+}
+
+/// Longest name, in bytes, [`path_component`] returns; most filesystems
+/// allow 255, which leaves room for the suffixes added to result files.
+const MAX_COMPONENT_LEN: usize = 200;
+
+/// Windows device names, which cannot be used as file names there whatever
+/// the extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1",
+    "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns a group, agent, manager or server name into one file name that is
+/// valid on Linux, macOS and Windows and cannot leave its directory.
+///
+/// Path separators, whitespace, control characters and the characters
+/// Windows forbids (`:*?"<>|`) become `_`; so do trailing dots, which
+/// Windows drops. Names that are empty or only dots (`.`, `..`) become `_`,
+/// device names such as `CON` gain a `_`, and long names are cut at
+/// [`MAX_COMPONENT_LEN`] bytes. Other Unicode is kept. The original names are
+/// still recorded in `--include-metadata` sidecars.
+pub fn path_component(name: &str) -> String {
+    if name.chars().all(|c| c == '.') {
+        return "_".to_string();
+    }
+    let unsafe_char = |c: char| {
+        c.is_whitespace() || c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|')
+    };
+    let mut safe: String = name.chars().map(|c| if unsafe_char(c) { '_' } else { c }).collect();
+    if safe.len() > MAX_COMPONENT_LEN {
+        let mut end = MAX_COMPONENT_LEN;
+        while !safe.is_char_boundary(end) {
+            end -= 1;
+        }
+        safe.truncate(end);
+    }
+    let dots = safe.len() - safe.trim_end_matches('.').len();
+    if dots > 0 {
+        safe.truncate(safe.len() - dots);
+        safe.push_str(&"_".repeat(dots));
+    }
+    let stem = safe.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved)) {
+        safe.insert(stem.len(), '_');
+    }
+    safe
+}
+
+/// Values substituted into an [`OutputTemplate`].
+pub struct TemplateValues<'a> {
+    pub group: &'a str,
+    pub agent_id: &'a str,
+    pub agent_name: &'a str,
+    /// Query name; `/` separates the subdirectories of nested queries.
+    pub query: &'a str,
+    /// Unix timestamp in seconds; the scan's start, not the result's arrival.
+    pub timestamp: u64,
+    pub run: u64,
+    /// Extension for the result format, without the dot.
+    pub ext: &'a str,
+}
+
+impl Default for OutputTemplate {
+    fn default() -> Self {
+        DEFAULT_OUTPUT_TEMPLATE.parse().expect("default template is valid")
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = String;
+
+    fn from_str(source: &str) -> std::result::Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err("unclosed '{' in output template; use '{{' for a literal brace".into()),
+                        }
+                    }
+                    let field = PLACEHOLDERS.iter().find(|p| **p == name).ok_or_else(|| {
+                        format!(
+                            "unknown placeholder {{{}}} in output template; expected one of {}",
+                            name,
+                            PLACEHOLDERS.iter().map(|p| format!("{{{}}}", p)).collect::<Vec<_>>().join(", ")
+                        )
+                    })?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => return Err("unmatched '}' in output template; use '}}' for a literal brace".into()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        if parts.is_empty() {
+            return Err("output template is empty".into());
+        }
+        let path = Path::new(source);
+        if path.is_absolute() || path.components().any(|c| matches!(c, Component::ParentDir)) {
+            return Err("output template must stay inside the output directory".into());
+        }
+        Ok(Self { source: source.to_string(), parts })
+    }
+}
+
+impl fmt::Display for OutputTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl OutputTemplate {
+    pub fn render(&self, values: &TemplateValues) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.clone(),
+                Part::Field(field) => render_field(field, values),
+            })
+            .collect()
+    }
+
+    /// Whether the template uses `{placeholder}`, given without braces.
+    pub fn uses(&self, placeholder: &str) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::Field(field) if *field == placeholder))
+    }
+
+    /// Matches every name this template gives the results of `values`,
+    /// whatever the scan's `{timestamp}`, `{date}` and `{run}`, followed by
+    /// `suffix`. `None` when the template uses none of those, so every scan
+    /// writes the same name.
+    pub fn pattern(&self, values: &TemplateValues, suffix: &str) -> Option<ResultPattern> {
+        let mut parts = Vec::new();
+        let mut varies = false;
+        for part in &self.parts {
+            let part = match part {
+                Part::Field("timestamp" | "run") => PatternPart::Digits,
+                Part::Field("date") => PatternPart::Date,
+                Part::Literal(text) => PatternPart::Literal(text.clone()),
+                Part::Field(field) => PatternPart::Literal(render_field(field, values)),
+            };
+            varies |= !matches!(part, PatternPart::Literal(_));
+            parts.push(part);
+        }
+        parts.push(PatternPart::Literal(suffix.to_string()));
+        varies.then_some(ResultPattern { parts })
+    }
+}
+
+fn render_field(field: &str, values: &TemplateValues) -> String {
+    match field {
+        "group" => path_component(values.group),
+        "agent_id" => path_component(values.agent_id),
+        "agent_name" => path_component(values.agent_name),
+        "query" => values.query.split('/').map(path_component).collect::<Vec<_>>().join("/"),
+        "timestamp" => values.timestamp.to_string(),
+        "date" => utc_date(values.timestamp),
+        "run" => values.run.to_string(),
+        "ext" => values.ext.to_string(),
+        other => unreachable!("unvalidated placeholder {}", other),
+    }
+}
+
+/// Result names of one agent and query across scans; see
+/// [`OutputTemplate::pattern`].
+#[derive(Debug, Clone)]
+pub struct ResultPattern {
+    parts: Vec<PatternPart>,
+}
+
+#[derive(Debug, Clone)]
+enum PatternPart {
+    Literal(String),
+    /// `{timestamp}` or `{run}`: one or more ASCII digits.
+    Digits,
+    /// `{date}`: `YYYY-MM-DD`.
+    Date,
+}
+
+impl ResultPattern {
+    /// Whether `path`, relative to the agent's directory and with `/`
+    /// between components, is one of the matched names.
+    pub fn matches(&self, path: &str) -> bool {
+        matches_parts(&self.parts, path)
+    }
+
+    /// Subdirectory levels below the agent's directory that matched names
+    /// are found at.
+    pub fn depth(&self) -> usize {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                PatternPart::Literal(text) => text.matches('/').count(),
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+fn matches_parts(parts: &[PatternPart], name: &str) -> bool {
+    let Some((part, rest)) = parts.split_first() else {
+        return name.is_empty();
+    };
+    match part {
+        PatternPart::Literal(text) => name.strip_prefix(text.as_str()).is_some_and(|name| matches_parts(rest, name)),
+        PatternPart::Date => {
+            let shape = name.len() >= 10 && name.bytes().take(10).enumerate().all(|(i, b)| match i {
+                4 | 7 => b == b'-',
+                _ => b.is_ascii_digit(),
+            });
+            shape && matches_parts(rest, &name[10..])
+        }
+        PatternPart::Digits => {
+            let digits = name.len() - name.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            (1..=digits).any(|len| matches_parts(rest, &name[len..]))
+        }
+    }
+}
+
+/// Formats unix seconds as a UTC `YYYY-MM-DD` date (civil-from-days).
+fn utc_date(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> TemplateValues<'static> {
+        TemplateValues {
+            group: "web servers",
+            agent_id: "001",
+            agent_name: "web-1",
+            query: "windows/logons",
+            timestamp: 1_700_000_000,
+            run: 2,
+            ext: "json",
+        }
+    }
+
+    fn render(template: &str) -> String {
+        template.parse::<OutputTemplate>().unwrap().render(&values())
+    }
+
+    #[test]
+    fn every_placeholder_is_substituted() {
+        assert_eq!(render(DEFAULT_OUTPUT_TEMPLATE), "windows/logons_web-1_1700000000.json");
+        assert_eq!(
+            render("{group}/{agent_id}/{date}-{run}-{query}.{ext}"),
+            "web_servers/001/2023-11-14-2-windows/logons.json"
+        );
+        assert_eq!(render("{{literal}}_{agent_id}"), "{literal}_001");
+    }
+
+    #[test]
+    fn names_become_single_safe_path_components() {
+        for (name, expected) in [
+            ("web servers", "web_servers"),
+            ("../../etc/passwd", ".._.._etc_passwd"),
+            ("..", "_"),
+            (".", "_"),
+            ("", "_"),
+            ("dmz\\hosts", "dmz_hosts"),
+            ("C:evil", "C_evil"),
+            ("a*b?c\"d<e>f|g", "a_b_c_d_e_f_g"),
+            ("line\nbreak\ttab\u{7f}", "line_break_tab_"),
+            ("trailing.", "trailing_"),
+            ("CON", "CON_"),
+            ("nul.json", "nul_.json"),
+            ("console", "console"),
+            ("serveurs-été", "serveurs-été"),
+            ("ウェブ", "ウェブ"),
+        ] {
+            assert_eq!(path_component(name), expected, "{:?}", name);
+        }
+
+        let long = path_component(&"é".repeat(150));
+        assert!(long.len() <= MAX_COMPONENT_LEN && long.chars().all(|c| c == 'é'), "{}", long);
+    }
+
+    #[test]
+    fn substituted_names_cannot_leave_the_output_directory() {
+        let template: OutputTemplate = "{group}/{agent_name}/{query}.{ext}".parse().unwrap();
+        let rendered = template.render(&TemplateValues {
+            group: "..",
+            agent_name: "../../root/.ssh/authorized_keys",
+            query: "../escape",
+            ..values()
+        });
+        assert_eq!(rendered, "_/.._.._root_.ssh_authorized_keys/_/escape.json");
+    }
+
+    #[test]
+    fn malformed_templates_are_rejected() {
+        for (template, error) in [
+            ("{host}.json", "unknown placeholder {host}"),
+            ("{query", "unclosed '{'"),
+            ("query}", "unmatched '}'"),
+            ("", "empty"),
+            ("../{query}.json", "inside the output directory"),
+            ("/tmp/{query}.json", "inside the output directory"),
+        ] {
+            let message = template.parse::<OutputTemplate>().unwrap_err();
+            assert!(message.contains(error), "{:?}: {}", template, message);
+        }
+    }
+
+    #[test]
+    fn patterns_match_other_scans_of_the_same_result_only() {
+        let template: OutputTemplate = DEFAULT_OUTPUT_TEMPLATE.parse().unwrap();
+        let pattern = template.pattern(&values(), ".gz").unwrap();
+        assert_eq!(pattern.depth(), 1);
+        assert!(pattern.matches("windows/logons_web-1_1700000000.json.gz"));
+        assert!(pattern.matches("windows/logons_web-1_1.json.gz"));
+        for other in [
+            "windows/logons_web-1_1700000000.json",
+            "windows/logons_web-1_.json.gz",
+            "windows/logons_web-1_17x0.json.gz",
+            "windows/logons_web-2_1700000000.json.gz",
+            "windows/.logons_web-1_1700000000.json.gz.partial",
+        ] {
+            assert!(!pattern.matches(other), "{}", other);
+        }
+
+        let template: OutputTemplate = "{date}/{run}{timestamp}-{agent_id}.{ext}".parse().unwrap();
+        let pattern = template.pattern(&values(), "").unwrap();
+        assert!(pattern.matches("2023-11-14/21700000000-001.json"));
+        assert!(!pattern.matches("2023-11/21700000000-001.json"));
+        assert!(!pattern.matches("2023-11-14/-001.json"));
+
+        let template: OutputTemplate = "{query}_{agent_id}.{ext}".parse().unwrap();
+        assert!(template.pattern(&values(), "").is_none());
+        assert!(template.uses("agent_id") && !template.uses("agent_name"));
+    }
+
+    #[test]
+    fn dates_are_utc_calendar_days() {
+        assert_eq!(utc_date(0), "1970-01-01");
+        assert_eq!(utc_date(951_782_400), "2000-02-29");
+        assert_eq!(utc_date(1_709_251_199), "2024-02-29");
+        assert_eq!(utc_date(1_709_251_200), "2024-03-01");
+    }
+}
@@ -0,0 +1,373 @@
+use crate::info;
+use crate::retry::RetryPolicy;
+use crate::Result;
+use clap::ValueEnum;
+use native_tls::{Certificate, Identity, Protocol, TlsConnector};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::sleep;
+use tokio_native_tls::TlsConnector as TokioTlsConnector;
+
+pub type TlsStream = tokio_native_tls::TlsStream<TcpStream>;
+
+/// Name the server certificate is checked against when none is configured.
+pub const DEFAULT_SERVER_NAME: &str = "localhost";
+
+/// TLS protocol version, for the bounds the connector will negotiate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum TlsVersion {
+    #[value(name = "1.0")]
+    Tls10,
+    #[value(name = "1.1")]
+    Tls11,
+    #[default]
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn protocol(self) -> Protocol {
+        match self {
+            Self::Tls10 => Protocol::Tlsv10,
+            Self::Tls11 => Protocol::Tlsv11,
+            Self::Tls12 => Protocol::Tlsv12,
+            Self::Tls13 => Protocol::Tlsv13,
+        }
+    }
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = match self {
+            Self::Tls10 => "1.0",
+            Self::Tls11 => "1.1",
+            Self::Tls12 => "1.2",
+            Self::Tls13 => "1.3",
+        };
+        write!(f, "TLS {}", version)
+    }
+}
+
+/// PEM certificate and PKCS#8 private key presented when the server asks
+/// for a client certificate.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// How the conduit server's certificate is verified, as configured. Turn it
+/// into a [`TlsConfig`] to connect.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// Accept any certificate (disables verification).
+    pub insecure: bool,
+    /// PEM CA certificates to trust in addition to the system store.
+    pub ca_certs: Vec<PathBuf>,
+    /// Name sent as SNI and required in the server certificate; defaults to
+    /// [`DEFAULT_SERVER_NAME`].
+    pub server_name: Option<String>,
+    /// Oldest protocol the handshake may settle on; the default is TLS 1.2.
+    pub min_version: TlsVersion,
+    /// Newest protocol offered; `None` leaves it to the TLS library.
+    pub max_version: Option<TlsVersion>,
+    pub client_identity: Option<ClientIdentity>,
+    /// Log the peer certificate after every handshake (`-v`).
+    pub log_handshake: bool,
+}
+
+impl TlsOptions {
+    pub fn server_name(&self) -> &str {
+        self.server_name.as_deref().unwrap_or(DEFAULT_SERVER_NAME)
+    }
+}
+
+/// Every TLS decision for conduit connections, checked as one unit: how the
+/// certificate is verified, the name it must carry, the protocol bounds and
+/// the client identity. Settings that contradict each other are rejected
+/// here instead of being silently ignored.
+#[derive(Clone)]
+pub struct TlsConfig {
+    connector: TokioTlsConnector,
+    server_name: String,
+    insecure: bool,
+    min_version: TlsVersion,
+    max_version: Option<TlsVersion>,
+    log_handshake: bool,
+}
+
+impl TlsConfig {
+    pub fn new(tls: &TlsOptions) -> Result<Self> {
+        if tls.insecure && !tls.ca_certs.is_empty() {
+            return Err("--insecure skips certificate verification, so --cacert would have no effect; \
+                        pass one or the other".into());
+        }
+        if let Some(max) = tls.max_version.filter(|max| *max < tls.min_version) {
+            return Err(format!(
+                "TLS maximum version {} is older than the minimum version {}",
+                max, tls.min_version
+            ).into());
+        }
+
+        let mut builder = TlsConnector::builder();
+        builder.min_protocol_version(Some(tls.min_version.protocol()));
+        builder.max_protocol_version(tls.max_version.map(TlsVersion::protocol));
+        for path in &tls.ca_certs {
+            let pem = fs::read(path)
+                .map_err(|e| format!("Failed to read CA certificate {}: {}", path.display(), e))?;
+            let cert = Certificate::from_pem(&pem)
+                .map_err(|e| format!("Invalid PEM CA certificate {}: {}", path.display(), e))?;
+            builder.add_root_certificate(cert);
+        }
+        if let Some(identity) = &tls.client_identity {
+            let read = |path: &PathBuf, what: &str| {
+                fs::read(path).map_err(|e| format!("Failed to read client {} {}: {}", what, path.display(), e))
+            };
+            let identity = Identity::from_pkcs8(&read(&identity.cert, "certificate")?, &read(&identity.key, "key")?)
+                .map_err(|e| format!(
+                    "Invalid client identity {} / {}: {}",
+                    identity.cert.display(),
+                    identity.key.display(),
+                    e
+                ))?;
+            builder.identity(identity);
+        }
+        if tls.insecure {
+            eprintln!("==================================================================");
+            eprintln!("WARNING: TLS certificate verification is DISABLED (--insecure).");
+            eprintln!("The conduit server's identity is not checked; traffic can be");
+            eprintln!("intercepted. Do not use this mode outside of testing.");
+            eprintln!("==================================================================");
+            builder.danger_accept_invalid_certs(true);
+        }
+
+        Ok(Self {
+            connector: TokioTlsConnector::from(builder.build()?),
+            server_name: tls.server_name().to_string(),
+            insecure: tls.insecure,
+            min_version: tls.min_version,
+            max_version: tls.max_version,
+            log_handshake: tls.log_handshake,
+        })
+    }
+
+    /// Name sent as SNI and, unless verification is off, required in the certificate.
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    pub fn verifies_certificates(&self) -> bool {
+        !self.insecure
+    }
+}
+
+/// What is known about an established TLS session. native-tls does not
+/// report the negotiated protocol version or cipher suite on any platform,
+/// so only the peer certificate is described; the protocol is known to lie
+/// within the configured bounds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandshakeDetails {
+    /// Distinguished name of the server certificate, e.g. `CN=localhost`.
+    pub peer_subject: Option<String>,
+    pub peer_issuer: Option<String>,
+    pub peer_not_after: Option<String>,
+    /// SHA-256 of the DER certificate, as colon-separated hex.
+    pub peer_fingerprint: Option<String>,
+}
+
+impl HandshakeDetails {
+    pub fn of(stream: &TlsStream) -> Self {
+        let Ok(Some(cert)) = stream.get_ref().peer_certificate() else {
+            return Self::default();
+        };
+        let Ok(der) = cert.to_der() else {
+            return Self::default();
+        };
+        let fingerprint = Sha256::digest(&der).iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":");
+        let mut details = Self { peer_fingerprint: Some(fingerprint), ..Self::default() };
+        if let Ok((_, cert)) = x509_parser::parse_x509_certificate(&der) {
+            details.peer_subject = Some(cert.subject().to_string());
+            details.peer_issuer = Some(cert.issuer().to_string());
+            details.peer_not_after = Some(cert.validity().not_after.to_string());
+        }
+        details
+    }
+}
+
+impl fmt::Display for HandshakeDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = |field: &Option<String>| field.clone().unwrap_or_else(|| "unknown".to_string());
+        write!(
+            f,
+            "peer certificate subject {}, issuer {}, expires {}, SHA-256 {}",
+            unknown(&self.peer_subject),
+            unknown(&self.peer_issuer),
+            unknown(&self.peer_not_after),
+            unknown(&self.peer_fingerprint)
+        )
+    }
+}
+
+/// Whether a failed lookup may succeed if repeated (`EAI_AGAIN`). The
+/// resolver only reports its reason as text, so this matches on it; anything
+/// else, like an unknown name, is taken as permanent.
+fn is_transient_dns_error(error: &std::io::Error) -> bool {
+    let message = error.to_string().to_lowercase();
+    message.contains("temporary failure") || message.contains("try again")
+}
+
+/// Resolves `addr` once, retrying only lookups that failed transiently.
+async fn resolve(addr: &str, retry: RetryPolicy) -> Result<Vec<SocketAddr>> {
+    let mut attempt = 1;
+    loop {
+        match lookup_host(addr).await {
+            Ok(addrs) => {
+                let addrs: Vec<SocketAddr> = addrs.collect();
+                if addrs.is_empty() {
+                    return Err(format!("{} did not resolve to any address", addr).into());
+                }
+                return Ok(addrs);
+            }
+            Err(e) if is_transient_dns_error(&e) && attempt < retry.max_attempts => {
+                info!("Resolving {} failed ({}); retrying", addr, e);
+                sleep(retry.delay(attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(format!("Failed to resolve {}: {}", addr, e).into()),
+        }
+    }
+}
+
+/// Connects to `addr`, resolving it once up front. Refused or timed-out
+/// connections are retried; a name that does not exist fails at once.
+///
+/// Every connection makes a full handshake. native-tls has no way to keep
+/// a session for resumption: its OpenSSL backend configures a fresh client
+/// session per connection without offering a cached one, and no hook lets
+/// us set one. Resuming would mean dropping native-tls for a TLS library
+/// that exposes its sessions.
+pub async fn connect_with_retry(addr: &str, tls: &TlsConfig, retry: RetryPolicy) -> Result<TlsStream> {
+    let addrs = resolve(addr, retry).await?;
+    let mut last_error = None;
+    for attempt in 1..=retry.max_attempts {
+        match TcpStream::connect(&addrs[..]).await {
+            Ok(stream) => {
+                let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_else(|_| addr.to_string());
+                let stream = tls.connector.connect(&tls.server_name, stream).await.map_err(|e| {
+                    let hint = match tls.verifies_certificates() {
+                        true => "If the server uses a private CA, pass it with --cacert; \
+                                 --insecure skips verification entirely. ",
+                        false => "",
+                    };
+                    format!(
+                        "TLS handshake failed: {}. {}A server that only offers protocols older \
+                         than --tls-min-version is rejected too",
+                        e, hint
+                    )
+                })?;
+                if tls.log_handshake {
+                    let max = tls.max_version.map_or("the library maximum".to_string(), |v| v.to_string());
+                    info!(
+                        "TLS handshake with {} ({}): {} to {} (negotiated protocol and cipher suite are not \
+                         reported by the TLS library); {}",
+                        tls.server_name,
+                        peer,
+                        tls.min_version,
+                        max,
+                        HandshakeDetails::of(&stream)
+                    );
+                }
+                return Ok(stream);
+            }
+            Err(e) => {
+                last_error = Some(e);
+                if attempt < retry.max_attempts {
+                    sleep(retry.delay(attempt)).await;
+                }
+            }
+        }
+    }
+    Err(format!("Failed to connect after {} retries: {:?}", retry.max_attempts, last_error.unwrap()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn certificates_are_verified_unless_insecure_is_asked_for() {
+        let secure = TlsConfig::new(&TlsOptions::default()).unwrap();
+        assert!(secure.verifies_certificates());
+        assert_eq!(secure.server_name(), DEFAULT_SERVER_NAME);
+
+        let insecure = TlsConfig::new(&TlsOptions { insecure: true, ..Default::default() }).unwrap();
+        assert!(!insecure.verifies_certificates());
+    }
+
+    #[test]
+    fn insecure_with_a_ca_certificate_is_rejected() {
+        let options = TlsOptions { insecure: true, ca_certs: vec![PathBuf::from("ca.pem")], ..Default::default() };
+        let error = TlsConfig::new(&options).err().unwrap();
+        assert!(error.to_string().contains("--insecure"), "{}", error);
+    }
+
+    #[test]
+    fn the_minimum_version_defaults_to_tls_1_2_and_must_not_exceed_the_maximum() {
+        assert_eq!(TlsOptions::default().min_version, TlsVersion::Tls12);
+        assert!(TlsConfig::new(&TlsOptions { max_version: Some(TlsVersion::Tls12), ..Default::default() }).is_ok());
+
+        let options =
+            TlsOptions { min_version: TlsVersion::Tls13, max_version: Some(TlsVersion::Tls12), ..Default::default() };
+        let error = TlsConfig::new(&options).err().unwrap();
+        assert_eq!(error.to_string(), "TLS maximum version TLS 1.2 is older than the minimum version TLS 1.3");
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    #[test]
+    fn each_valid_combination_is_accepted() {
+        let identity = || Some(ClientIdentity { cert: fixture("server.pem"), key: fixture("server.key") });
+        let combinations = [
+            TlsOptions { ca_certs: vec![fixture("ca.pem")], ..Default::default() },
+            TlsOptions { server_name: Some("conduit.internal".to_string()), ..Default::default() },
+            TlsOptions { client_identity: identity(), ..Default::default() },
+            TlsOptions { ca_certs: vec![fixture("ca.pem")], client_identity: identity(), ..Default::default() },
+            TlsOptions { insecure: true, client_identity: identity(), ..Default::default() },
+        ];
+        for options in combinations {
+            let tls = TlsConfig::new(&options).unwrap_or_else(|e| panic!("{:?}: {}", options, e));
+            assert_eq!(tls.verifies_certificates(), !options.insecure);
+            assert_eq!(tls.server_name(), options.server_name());
+        }
+    }
+
+    #[test]
+    fn an_unreadable_or_invalid_client_identity_is_rejected() {
+        let options = |cert: &str, key: &str| TlsOptions {
+            client_identity: Some(ClientIdentity { cert: fixture(cert), key: fixture(key) }),
+            ..Default::default()
+        };
+
+        let error = TlsConfig::new(&options("server.pem", "missing.key")).err().unwrap();
+        assert!(error.to_string().starts_with("Failed to read client key"), "{}", error);
+        let error = TlsConfig::new(&options("server.pem", "ca.pem")).err().unwrap();
+        assert!(error.to_string().starts_with("Invalid client identity"), "{}", error);
+    }
+
+    #[test]
+    fn only_temporary_lookup_failures_are_transient() {
+        let error = |message: &str| std::io::Error::other(message.to_string());
+        assert!(is_transient_dns_error(&error("Temporary failure in name resolution")));
+        assert!(is_transient_dns_error(&error("Try again")));
+        assert!(!is_transient_dns_error(&error("failed to lookup address information: Name or service not known")));
+        assert!(!is_transient_dns_error(&error("Connection refused")));
+    }
+}
@@ -0,0 +1,189 @@
+//! `{{name}}` placeholders in query files.
+//!
+//! Every query may use `{{agent_id}}` and `{{agent_name}}`, filled in per
+//! agent. A variables file (`--vars`) adds shared values, such as time
+//! ranges or thresholds, under those agent fields.
+
+use crate::gateway::Agent;
+use crate::Result;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+/// Placeholders filled from the agent being queried.
+pub const AGENT_PLACEHOLDERS: &[&str] = &["agent_id", "agent_name"];
+
+/// Shared values for query placeholders.
+#[derive(Debug, Clone, Default)]
+pub struct QueryVars {
+    values: HashMap<String, String>,
+    strict: bool,
+}
+
+impl QueryVars {
+    /// Variables named like an agent placeholder are shadowed by the agent's
+    /// value, with a warning, or rejected when `strict`. A `strict` set also
+    /// fails queries that use a placeholder it has no value for.
+    pub fn new(values: HashMap<String, String>, strict: bool) -> Result<Self> {
+        let shadowed: Vec<&str> = AGENT_PLACEHOLDERS.iter().copied().filter(|p| values.contains_key(*p)).collect();
+        if !shadowed.is_empty() {
+            let message = format!("Query variable(s) {} are agent fields", shadowed.join(", "));
+            if strict {
+                return Err(message.into());
+            }
+            eprintln!("Warning: {}; the agent's values are used", message);
+        }
+        Ok(Self { values, strict })
+    }
+
+    /// Reads a JSON object of variables. String values are substituted as
+    /// they are; other values as their JSON text, so `{"min_level": 3}`
+    /// fills `{{min_level}}` with `3`.
+    pub fn load(path: &Path, strict: bool) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read query variables {}: {}", path.display(), e))?;
+        let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&text)
+            .map_err(|e| format!("Query variables {} are not a JSON object: {}", path.display(), e))?;
+        let values = object
+            .into_iter()
+            .map(|(name, value)| match value {
+                serde_json::Value::String(s) => (name, s),
+                other => (name, other.to_string()),
+            })
+            .collect();
+        Self::new(values, strict)
+    }
+
+    /// Fills the placeholders of `query` for `agent`. Placeholders without a
+    /// value are left as written unless the set is strict.
+    pub fn fill(&self, query: &str, agent: &Agent) -> Result<String> {
+        let (filled, undefined) = self.substitute(query, &agent.id, Some(&agent.name));
+        if self.strict && !undefined.is_empty() {
+            let names: Vec<&str> = undefined.into_iter().collect();
+            return Err(format!("Query uses undefined variable(s): {}", names.join(", ")).into());
+        }
+        Ok(filled)
+    }
+
+    /// Fills `query` as [`fill`](Self::fill) would for an agent whose name
+    /// may be unknown, even when strict, and also returns the placeholders
+    /// left as written, in name order.
+    pub fn render(&self, query: &str, agent_id: &str, agent_name: Option<&str>) -> (String, Vec<String>) {
+        let (filled, undefined) = self.substitute(query, agent_id, agent_name);
+        (filled, undefined.into_iter().map(str::to_string).collect())
+    }
+
+    fn substitute<'q>(&self, query: &'q str, agent_id: &str, agent_name: Option<&str>) -> (String, BTreeSet<&'q str>) {
+        let mut filled = String::with_capacity(query.len());
+        let mut undefined = BTreeSet::new();
+        let mut rest = query;
+        while let Some(start) = rest.find("{{") {
+            filled.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let name = after.find("}}").map(|end| &after[..end]).filter(|name| is_placeholder_name(name));
+            let Some(name) = name else {
+                // The second brace may open a placeholder, as in `{{{agent_id}}}`.
+                filled.push('{');
+                rest = &rest[start + 1..];
+                continue;
+            };
+            let value = match name {
+                "agent_id" => Some(agent_id),
+                "agent_name" => agent_name,
+                _ => self.values.get(name).map(String::as_str),
+            };
+            match value {
+                Some(value) => filled.push_str(value),
+                None => {
+                    undefined.insert(name);
+                    filled.push_str(&rest[start..start + name.len() + 4]);
+                }
+            }
+            rest = &after[name.len() + 2..];
+        }
+        filled.push_str(rest);
+        (filled, undefined)
+    }
+}
+
+/// Only `{{` followed by a name and `}}` is a placeholder, so other braces
+/// in a query are kept.
+fn is_placeholder_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent() -> Agent {
+        Agent {
+            id: "001".to_string(),
+            name: "web-1".to_string(),
+            groups: vec!["web".to_string()],
+            platform: None,
+            node: None,
+            ip: None,
+            status: None,
+        }
+    }
+
+    fn vars(pairs: &[(&str, &str)], strict: bool) -> Result<QueryVars> {
+        QueryVars::new(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(), strict)
+    }
+
+    #[test]
+    fn shared_variables_fill_every_query_alongside_the_agent_fields() {
+        let vars = vars(&[("since", "now-3m"), ("min_level", "3")], false).unwrap();
+        let query = r#"{"agent":"{{agent_id}}","name":"{{agent_name}}","gte":"{{since}}","level":{{min_level}}}"#;
+        assert_eq!(
+            vars.fill(query, &agent()).unwrap(),
+            r#"{"agent":"001","name":"web-1","gte":"now-3m","level":3}"#
+        );
+        // Braces that do not enclose a name are not placeholders.
+        assert_eq!(vars.fill(r#"{"a":{{"b":1}}}"#, &agent()).unwrap(), r#"{"a":{{"b":1}}}"#);
+        assert_eq!(vars.fill("{{{agent_id}}}", &agent()).unwrap(), "{001}");
+    }
+
+    #[test]
+    fn agent_fields_win_over_variables_of_the_same_name_unless_strict() {
+        let lenient = vars(&[("agent_id", "999")], false).unwrap();
+        assert_eq!(lenient.fill("{{agent_id}}", &agent()).unwrap(), "001");
+
+        let error = vars(&[("agent_id", "999")], true).unwrap_err();
+        assert!(error.to_string().contains("agent_id"), "{}", error);
+    }
+
+    #[test]
+    fn undefined_variables_are_kept_or_rejected_in_strict_mode() {
+        let query = r#"{"gte":"{{since}}","lte":"{{until}}"}"#;
+        assert_eq!(vars(&[], false).unwrap().fill(query, &agent()).unwrap(), query);
+
+        let error = vars(&[("since", "now-1h")], true).unwrap().fill(query, &agent()).unwrap_err();
+        assert_eq!(error.to_string(), "Query uses undefined variable(s): until");
+    }
+
+    #[test]
+    fn rendering_reports_the_placeholders_left_unfilled() {
+        let vars = vars(&[("since", "now-1h")], true).unwrap();
+        let query = r#"{"agent":"{{agent_id}}","name":"{{agent_name}}","gte":"{{since}}","lte":"{{until}}"}"#;
+        assert_eq!(
+            vars.render(query, "001", Some("web-1")),
+            (r#"{"agent":"001","name":"web-1","gte":"now-1h","lte":"{{until}}"}"#.to_string(), vec!["until".to_string()])
+        );
+        let (rendered, undefined) = vars.render(query, "001", None);
+        assert!(rendered.contains(r#""name":"{{agent_name}}""#), "{}", rendered);
+        assert_eq!(undefined, ["agent_name", "until"]);
+    }
+
+    #[test]
+    fn variables_files_are_json_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vars.json");
+        std::fs::write(&path, r#"{"since":"now-3m","min_level":3,"enabled":true}"#).unwrap();
+        let vars = QueryVars::load(&path, true).unwrap();
+        assert_eq!(vars.fill("{{since}} {{min_level}} {{enabled}}", &agent()).unwrap(), "now-3m 3 true");
+
+        std::fs::write(&path, r#"["since"]"#).unwrap();
+        assert!(QueryVars::load(&path, false).is_err());
+    }
+}
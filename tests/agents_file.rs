@@ -0,0 +1,95 @@
+//! `scan --agents-file`, run as the `client` binary: the inventory replaces
+//! gateway discovery.
+
+mod common;
+
+use common::{client_command, fixture, write_query, MockConduit};
+#[cfg(feature = "gateway")]
+use common::{agent, MockGateway};
+
+#[cfg(feature = "gateway")]
+#[tokio::test]
+async fn an_agents_file_scan_never_calls_the_gateway() {
+    let gateway = MockGateway::start(vec![agent("009", "other", &["other"])]).await;
+    let conduit = MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    write_query(dir.path(), "logins", r#"{"query":{"match":{"rule.groups":"authentication"}}}"#);
+    std::fs::write(dir.path().join("agents.csv"), "id,name,group,status\n001,web-1,web,active\n002,db-1,db,active\n").unwrap();
+
+    let output = client_command(dir.path())
+        .args(["scan", &conduit.addr, "--agents-file", "agents.csv", "--queries-dir", "queries", "--query", "alerts"])
+        .args(["--gateway-url", &gateway.url, "--output-template", "{agent_name}/{query}.{ext}"])
+        .arg("--cacert")
+        .arg(fixture("ca.pem"))
+        .output()
+        .await
+        .unwrap();
+    assert!(output.status.success(), "scan failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    assert!(gateway.calls.lock().unwrap().is_empty(), "{:?}", gateway.calls.lock().unwrap());
+    assert_eq!(conduit.received(), 2);
+    for name in ["web/web-1", "db/db-1"] {
+        let results = dir.path().join("query_results").join(name);
+        assert!(results.join("alerts.json").exists(), "{} has no alerts result", name);
+        assert!(!results.join("logins.json").exists(), "{} ran the filtered-out query", name);
+    }
+}
+
+#[tokio::test]
+async fn query_timings_reach_the_summary_and_the_timings_csv() {
+    let conduit = MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    std::fs::write(dir.path().join("agents.csv"), "id,name,group,status\n001,web-1,web,active\n").unwrap();
+
+    let output = client_command(dir.path())
+        .args(["scan", &conduit.addr, "--agents-file", "agents.csv", "--queries-dir", "queries"])
+        .args(["--summary-json", "summary.json", "--timings-csv", "timings.csv"])
+        .arg("--cacert")
+        .arg(fixture("ca.pem"))
+        .output()
+        .await
+        .unwrap();
+    assert!(output.status.success(), "scan failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    let summary: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.path().join("summary.json")).unwrap()).unwrap();
+    let timings = &summary["results"][0]["timings"];
+    let phases: Vec<u64> = ["connect_ms", "send_ms", "first_byte_ms", "receive_ms"]
+        .iter()
+        .map(|phase| timings[phase].as_u64().unwrap_or_else(|| panic!("no {} in {}", phase, timings)))
+        .collect();
+    assert!(phases.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", phases);
+
+    let csv = std::fs::read_to_string(dir.path().join("timings.csv")).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(
+        lines[0],
+        "manager,group,agent_id,agent_name,query,status,bytes,connect_ms,send_ms,first_byte_ms,receive_ms,latency_ms"
+    );
+    let row: Vec<&str> = lines[1].split(',').collect();
+    assert_eq!(row[..7], ["", "web", "001", "web-1", "alerts", "saved", "20"]);
+    let columns: Vec<u64> = row[7..11].iter().map(|ms| ms.parse().unwrap()).collect();
+    assert_eq!(columns, phases);
+    assert_eq!(lines.len(), 2);
+}
+
+#[cfg(not(feature = "gateway"))]
+#[tokio::test]
+async fn without_the_gateway_a_scan_needs_an_agents_file() {
+    let conduit = MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+
+    let output = client_command(dir.path())
+        .args(["scan", &conduit.addr, "--queries-dir", "queries"])
+        .arg("--cacert")
+        .arg(fixture("ca.pem"))
+        .output()
+        .await
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("supply an explicit agent inventory"), "{}", stderr);
+    assert_eq!(conduit.received(), 0);
+}
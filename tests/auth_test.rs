@@ -0,0 +1,84 @@
+//! The `auth-test` health check, run as the `client` binary against the
+//! loopback gateway and conduit.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{agent, client_command, closed_port, fixture, MockConduit, MockGateway, WAZUH_TOKEN};
+use std::process::Output;
+
+async fn auth_test(gateway_url: &str, server: &str, args: &[&str]) -> Output {
+    let dir = tempfile::tempdir().unwrap();
+    client_command(dir.path())
+        .args(["auth-test", server, "--gateway-url", gateway_url])
+        .args(args)
+        .args(["--wazuh-url", "https://wazuh.test:55000", "--wazuh-username", "wazuh", "--wazuh-password", "secret"])
+        .arg("--cacert")
+        .arg(fixture("ca.pem"))
+        .output()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn every_stage_passes_against_a_healthy_deployment() {
+    let gateway = MockGateway::start(vec![agent("001", "web-1", &["web"])]).await;
+    let conduit = MockConduit::answering(r#"{"hits":{"total":{"value":0},"hits":[]}}"#).await;
+
+    let output = auth_test(&gateway.url, &conduit.addr, &[]).await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "auth-test failed:\n{}", stdout);
+    assert!(stdout.contains("[PASS] gateway authentication"), "{}", stdout);
+    assert!(stdout.contains("[PASS] group listing") && stdout.contains("1 groups visible"), "{}", stdout);
+    assert!(stdout.contains("[PASS] conduit ping"), "{}", stdout);
+    assert!(stdout.contains("All health checks passed"), "{}", stdout);
+    assert_eq!(*gateway.calls.lock().unwrap(), ["/auth", "/groups"]);
+    assert_eq!(conduit.received(), 1);
+}
+
+#[tokio::test]
+async fn a_gateway_that_is_down_fails_the_check_but_the_conduit_is_still_pinged() {
+    let conduit = MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await;
+
+    let output = auth_test(&format!("http://{}", closed_port()), &conduit.addr, &[]).await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!output.status.success(), "auth-test passed:\n{}", stdout);
+    assert!(stdout.contains("[FAIL] gateway authentication"), "{}", stdout);
+    assert!(stdout.contains("[SKIP] group listing"), "{}", stdout);
+    assert!(stdout.contains("[PASS] conduit ping"), "{}", stdout);
+    assert!(!stdout.contains("All health checks passed"), "{}", stdout);
+}
+
+#[tokio::test]
+async fn gateway_bodies_are_logged_only_when_verbose_and_always_redacted() {
+    let gateway = MockGateway::start(vec![agent("001", "web-1", &["web"])]).await;
+    let conduit = MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await;
+
+    let output = auth_test(&gateway.url, &conduit.addr, &[]).await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Auth response status: 200 OK"), "{}", stdout);
+    assert!(!stdout.contains("body:"), "{}", stdout);
+    assert!(!stdout.contains("TLS handshake with"), "{}", stdout);
+
+    let output = auth_test(&gateway.url, &conduit.addr, &["-vvv"]).await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Auth response body:") && stdout.contains("[REDACTED]"), "{}", stdout);
+    assert!(stdout.contains("Response body:") && stdout.contains("web"), "{}", stdout);
+    assert!(!stdout.contains(WAZUH_TOKEN), "{}", stdout);
+}
+
+#[tokio::test]
+async fn the_conduit_handshake_is_logged_when_verbose() {
+    let gateway = MockGateway::start(vec![agent("001", "web-1", &["web"])]).await;
+    let conduit = MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await;
+
+    let output = auth_test(&gateway.url, &conduit.addr, &["-v"]).await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("TLS handshake with localhost"), "{}", stdout);
+    assert!(stdout.contains("TLS 1.2 to the library maximum"), "{}", stdout);
+    assert!(stdout.contains("subject CN=localhost") && stdout.contains("SHA-256"), "{}", stdout);
+    assert!(!stdout.contains("Auth response body:"), "{}", stdout);
+}
@@ -0,0 +1,21 @@
+//! Errors the `client` binary reports before it contacts anything.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::client_command;
+
+#[tokio::test]
+async fn missing_wazuh_settings_are_reported_without_a_panic() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = client_command(dir.path()).args(["scan", "127.0.0.1:8080"]).output().await.unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("Missing required Wazuh settings"), "{}", stderr);
+    for name in ["WAZUH_URL", "WAZUH_USERNAME", "WAZUH_PASSWORD"] {
+        assert!(stderr.contains(name), "{}", stderr);
+    }
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
@@ -0,0 +1,411 @@
+//! Loopback stand-ins for the conduit server and the Wazuh API gateway,
+//! shared by the integration tests.
+//!
+//! The conduit mock presents `fixtures/server.pem`, issued for `localhost`
+//! by `fixtures/ca.pem`, and signs its responses with [`SERVER_KEY`] using
+//! SHA-256 in base64, the client's defaults.
+
+#![allow(dead_code)]
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Request, Response as HttpResponse, Server};
+use sensex_conduit::client::{ClientConfig, ClockOffset, MAX_CLOCK_SKEW, MAX_IN_MEMORY, MAX_RESPONSE_SIZE};
+use sensex_conduit::compression::Compression;
+use sensex_conduit::protocol::{AuthRequest, Response, ResultFormat, WireEncoding};
+use sensex_conduit::retry::{ReconnectDelay, RetryPolicy};
+use sensex_conduit::signing::{SignatureAlgorithm, SignatureEncoding};
+use sensex_conduit::sink::FileSink;
+use sensex_conduit::template::OutputTemplate;
+use sensex_conduit::tls::TlsOptions;
+use sensex_conduit::vars::QueryVars;
+use sensex_conduit::scan::{JsonOutput, Retention};
+use sensex_conduit::{Agent, GatewayConfig, HttpOptions, OrganizeBy, QueryOrder, ScanConfig};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+/// Key the client verifies responses with unless told otherwise.
+pub const SERVER_KEY: &str = "server_key";
+/// Token the mock gateway issues.
+pub const WAZUH_TOKEN: &str = "mock-wazuh-token";
+
+/// A file under `tests/fixtures`.
+pub fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// The `client` binary, run in `dir` with an empty environment so only the
+/// arguments, env files and config files given to it apply.
+pub fn client_command(dir: &Path) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(env!("CARGO_BIN_EXE_client"));
+    command.current_dir(dir).env_clear();
+    command
+}
+
+/// What the conduit mock sends back for one request; `None` closes the
+/// connection without answering.
+pub type Respond = dyn Fn(&AuthRequest) -> Option<Vec<u8>> + Send + Sync;
+
+/// A TLS conduit server on a loopback port, answering each connection's
+/// request with `respond`.
+pub struct MockConduit {
+    /// `host:port` to connect to.
+    pub addr: String,
+    /// Every request received, oldest first.
+    pub requests: Arc<Mutex<Vec<AuthRequest>>>,
+}
+
+impl MockConduit {
+    pub async fn start(respond: impl Fn(&AuthRequest) -> Option<Vec<u8>> + Send + Sync + 'static) -> Self {
+        Self::start_delayed(|_| Duration::ZERO, respond).await
+    }
+
+    /// A server that waits `delay` before answering each request.
+    pub async fn start_delayed(
+        delay: impl Fn(&AuthRequest) -> Duration + Send + Sync + 'static,
+        respond: impl Fn(&AuthRequest) -> Option<Vec<u8>> + Send + Sync + 'static,
+    ) -> Self {
+        let cert = std::fs::read(fixture("server.pem")).unwrap();
+        let key = std::fs::read(fixture("server.key")).unwrap();
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key).unwrap();
+        let acceptor = tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let respond: Arc<Respond> = Arc::new(respond);
+        let delay: Arc<dyn Fn(&AuthRequest) -> Duration + Send + Sync> = Arc::new(delay);
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (acceptor, respond, received) = (acceptor.clone(), respond.clone(), received.clone());
+                let delay = delay.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let Some(request) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    tokio::time::sleep(delay(&request)).await;
+                    let reply = respond(&request);
+                    received.lock().unwrap().push(request);
+                    if let Some(reply) = reply {
+                        let _ = stream.write_all(&reply).await;
+                    }
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        Self { addr, requests }
+    }
+
+    /// A server answering every query with `data`.
+    pub async fn answering(data: &'static str) -> Self {
+        Self::start(move |request| Some(signed(reply(request, data)))).await
+    }
+
+    /// How many requests have been received.
+    pub fn received(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+}
+
+/// Reads until the bytes received parse as a whole request.
+async fn read_request(stream: &mut (impl AsyncReadExt + Unpin)) -> Option<AuthRequest> {
+    let mut received = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        received.extend_from_slice(&chunk[..n]);
+        if let Ok(request) = serde_json::from_slice(&received) {
+            return Some(request);
+        }
+    }
+}
+
+/// A successful response to `request` carrying `data`, as the server builds
+/// it: in the request's session, or a new one.
+pub fn reply(request: &AuthRequest, data: &str) -> Response {
+    Response {
+        status: true,
+        data: data.to_string(),
+        session_id: request.session_id.clone().unwrap_or_else(|| Uuid::new_v4().to_string()),
+        timestamp: request.timestamp,
+        signature: String::new(),
+        error_code: None,
+        request_id: Some(request.request_id.clone()),
+        format: Some(request.format),
+        query_hash: Some(sensex_conduit::protocol::query_hash(&request.wql_query)),
+    }
+}
+
+/// `response` signed with [`SERVER_KEY`], as sent on the wire.
+pub fn signed(mut response: Response) -> Vec<u8> {
+    response.signature = String::new();
+    let unsigned = serde_json::to_string(&response).unwrap();
+    response.signature = SignatureAlgorithm::Sha256.signer().sign(unsigned.as_bytes(), SERVER_KEY.as_bytes(), SignatureEncoding::Base64);
+    serde_json::to_vec(&response).unwrap()
+}
+
+/// A Wazuh API gateway on a loopback port, serving `/auth`, `/groups`,
+/// `/groups/{group}/agents` and `/agents` from a fixed list of agents.
+pub struct MockGateway {
+    pub url: String,
+    /// Path of every call received, oldest first.
+    pub calls: Arc<Mutex<Vec<String>>>,
+    /// `Authorization` header of every call received, oldest first.
+    pub authorizations: Arc<Mutex<Vec<Option<String>>>>,
+    /// All headers of every call received, oldest first.
+    pub headers: Arc<Mutex<Vec<HeaderMap>>>,
+}
+
+impl MockGateway {
+    /// A gateway listing `agents`, Wazuh agent items with an `id`, `name`
+    /// and `group` list; the groups are those the agents belong to.
+    pub async fn start(agents: Vec<Value>) -> Self {
+        Self::start_behind_proxy(agents, None).await
+    }
+
+    /// A gateway behind a proxy that answers 401 with a `WWW-Authenticate`
+    /// challenge unless the `Authorization` header is `required`.
+    pub async fn start_behind_proxy(agents: Vec<Value>, required: Option<&str>) -> Self {
+        Self::start_with(agents, required, Vec::new(), None).await
+    }
+
+    /// A gateway whose agent listings also report `failed_items`, Wazuh
+    /// `{"error": {...}, "id": [...]}` entries.
+    pub async fn start_with_failed_items(agents: Vec<Value>, failed_items: Vec<Value>) -> Self {
+        Self::start_with(agents, None, failed_items, None).await
+    }
+
+    /// A gateway listening on the Unix domain socket `path`, reached through
+    /// a `unix://` URL.
+    #[cfg(unix)]
+    pub async fn start_on_socket(agents: Vec<Value>, path: &Path) -> Self {
+        Self::start_with(agents, None, Vec::new(), Some(path)).await
+    }
+
+    async fn start_with(
+        agents: Vec<Value>,
+        required: Option<&str>,
+        failed_items: Vec<Value>,
+        #[cfg_attr(not(unix), allow(unused_variables))] socket: Option<&Path>,
+    ) -> Self {
+        let agents = Arc::new(agents);
+        let failed_items = Arc::new(failed_items);
+        let required = Arc::new(required.map(str::to_string));
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let authorizations = Arc::new(Mutex::new(Vec::new()));
+        let headers = Arc::new(Mutex::new(Vec::new()));
+        let (recorded, authorized, seen) = (calls.clone(), authorizations.clone(), headers.clone());
+        let respond = move |request: Request<Body>| {
+            let (agents, required, recorded) = (agents.clone(), required.clone(), recorded.clone());
+            let failed_items = failed_items.clone();
+            let authorization = request
+                .headers()
+                .get(hyper::header::AUTHORIZATION)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+            authorized.lock().unwrap().push(authorization.clone());
+            seen.lock().unwrap().push(request.headers().clone());
+            async move {
+                if required.is_some() && authorization != *required {
+                    let challenge = HttpResponse::builder()
+                        .status(401)
+                        .header(hyper::header::WWW_AUTHENTICATE, r#"Basic realm="gateway""#)
+                        .body(Body::empty())
+                        .unwrap();
+                    return Ok::<_, Infallible>(challenge);
+                }
+                Ok::<_, Infallible>(route(request, &agents, &failed_items, &recorded).await)
+            }
+        };
+        #[cfg(unix)]
+        if let Some(path) = socket {
+            let listener = tokio::net::UnixListener::bind(path).unwrap();
+            let accept = hyper::server::accept::poll_fn(move |cx| listener.poll_accept(cx).map(|accepted| Some(accepted.map(|(stream, _)| stream))));
+            let make_service = make_service_fn(move |_: &tokio::net::UnixStream| {
+                let respond = respond.clone();
+                async move { Ok::<_, Infallible>(service_fn(respond)) }
+            });
+            tokio::spawn(Server::builder(accept).serve(make_service));
+            let url = format!("unix://{}", path.display());
+            return Self { url, calls, authorizations, headers };
+        }
+        let make_service = make_service_fn(move |_| {
+            let respond = respond.clone();
+            async move { Ok::<_, Infallible>(service_fn(respond)) }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        Self { url, calls, authorizations, headers }
+    }
+}
+
+/// An agent item as the Wazuh API lists it.
+pub fn agent(id: &str, name: &str, groups: &[&str]) -> Value {
+    json!({"id": id, "name": name, "group": groups, "status": "active", "os": {"platform": "ubuntu"}})
+}
+
+async fn route(request: Request<Body>, agents: &[Value], failed_items: &[Value], calls: &Mutex<Vec<String>>) -> HttpResponse<Body> {
+    let path = request.uri().path().to_string();
+    calls.lock().unwrap().push(path.clone());
+    let body = hyper::body::to_bytes(request.into_body()).await.unwrap_or_default();
+    let params = serde_json::from_slice::<Value>(&body).map(|body| body["params"].clone()).unwrap_or_default();
+
+    let listing = |items: Vec<Value>| json!({"data": {"affected_items": items, "total_affected_items": items.len()}});
+    let reply = match path.split('/').collect::<Vec<_>>().as_slice() {
+        ["", "auth"] => json!({"token": WAZUH_TOKEN}),
+        ["", "groups"] => {
+            let mut groups: Vec<&str> =
+                agents.iter().flat_map(|a| a["group"].as_array().unwrap()).filter_map(Value::as_str).collect();
+            groups.sort();
+            groups.dedup();
+            listing(groups.into_iter().map(|name| json!({"name": name})).collect())
+        }
+        ["", "groups", group, "agents"] => {
+            let members = agents.iter().filter(|a| a["group"].as_array().unwrap().iter().any(|g| g == group));
+            let mut reply = listing(members.cloned().collect());
+            reply["data"]["failed_items"] = json!(failed_items);
+            reply
+        }
+        ["", "agents"] => {
+            let wanted = params["agents_list"].as_str().unwrap_or_default().split(',').collect::<Vec<_>>();
+            listing(agents.iter().filter(|a| wanted.contains(&a["id"].as_str().unwrap())).cloned().collect())
+        }
+        _ => return HttpResponse::builder().status(404).body(Body::from("{}")).unwrap(),
+    };
+    HttpResponse::new(Body::from(reply.to_string()))
+}
+
+/// `host:port` of a loopback port nothing listens on.
+pub fn closed_port() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Writes the query file `queries/<name>.json` under `dir`.
+pub fn write_query(dir: &Path, name: &str, query: &str) {
+    std::fs::create_dir_all(dir.join("queries")).unwrap();
+    std::fs::write(dir.join("queries").join(format!("{}.json", name)), query).unwrap();
+}
+
+/// An agent as an inventory file lists it.
+pub fn inventory_agent(id: &str, group: &str) -> Agent {
+    Agent {
+        id: id.to_string(),
+        name: format!("agent-{}", id),
+        groups: vec![group.to_string()],
+        platform: Some("ubuntu".to_string()),
+        node: None,
+        ip: None,
+        status: Some("active".to_string()),
+    }
+}
+
+/// An inventory scan of `agents` against the conduit at `server`, trusting
+/// the test CA, with queries read from `dir/queries` and results written to
+/// `dir/results`. Retries and reconnects are immediate.
+pub fn scan_config(dir: &Path, server: &str, agents: Vec<Agent>) -> ScanConfig {
+    ScanConfig {
+        server: server.to_string(),
+        managers: Vec::new(),
+        http: HttpOptions::default(),
+        token_refresh_buffer: Duration::from_secs(60),
+        client: ClientConfig {
+            client_id: "client1".to_string(),
+            client_key: "test_key_1".to_string(),
+            server_key: SERVER_KEY.to_string(),
+            max_clock_skew: MAX_CLOCK_SKEW,
+            clock_offset: ClockOffset::None,
+            max_in_memory: MAX_IN_MEMORY,
+            max_response_size: MAX_RESPONSE_SIZE,
+            spool_checkpoint: 0,
+            session_file: dir.join("session.json"),
+            session_cipher: None,
+            sign_query: false,
+            require_query_hash: false,
+            format: ResultFormat::Json,
+            signature_algorithm: SignatureAlgorithm::Sha256,
+            signature_encoding: SignatureEncoding::Base64,
+            wire_encoding: WireEncoding::Json,
+            verbosity: 0,
+        },
+        tls: TlsOptions { ca_certs: vec![fixture("ca.pem")], ..Default::default() },
+        retry: RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1) },
+        reconnect_delay: ReconnectDelay { base: Duration::ZERO, jitter: 0.0 },
+        queries_dir: dir.join("queries"),
+        queries: Vec::new(),
+        query_depth: 8,
+        query_order: QueryOrder::Name,
+        query_vars: QueryVars::default(),
+        agents: Vec::new(),
+        groups: Vec::new(),
+        nodes: Vec::new(),
+        statuses: Vec::new(),
+        unique_agents: false,
+        strict_failed_items: false,
+        output_dir: dir.join("results"),
+        organize_by: OrganizeBy::Group,
+        output_template: OutputTemplate::default(),
+        retention: Retention::default(),
+        output_cipher: None,
+        inventory: Some(agents),
+        group_concurrency: 1,
+        max_concurrency_per_group: 1,
+        max_concurrency: None,
+        adaptive_state: None,
+        adaptive_concurrency: false,
+        warmup: None,
+        sample: None,
+        retry_passes: 0,
+        retry_empty: 0,
+        agent_timeout: None,
+        deadline: None,
+        deadline_grace: Duration::ZERO,
+        cache: None,
+        only: None,
+        progress: false,
+        json_output: JsonOutput::Passthrough,
+        fields: None,
+        include_metadata: false,
+        compression: Compression::None,
+        sink: Arc::new(FileSink),
+        run: 1,
+        wazuh_tokens: HashMap::new(),
+    }
+}
+
+/// A scan of every agent behind `gateway`, an unnamed manager, configured
+/// otherwise as [`scan_config`] does.
+pub fn gateway_scan_config(dir: &Path, server: &str, gateway: &MockGateway) -> ScanConfig {
+    ScanConfig {
+        managers: vec![GatewayConfig { name: None, ..manager("wazuh", &gateway.url) }],
+        inventory: None,
+        ..scan_config(dir, server, Vec::new())
+    }
+}
+
+/// A manager reached through the gateway at `url`, signing in with a
+/// username and password.
+pub fn manager(name: &str, url: &str) -> GatewayConfig {
+    GatewayConfig {
+        name: Some(name.to_string()),
+        url: url.to_string(),
+        wazuh_url: format!("https://{}.wazuh.test:55000", name),
+        username: "wazuh".to_string(),
+        password: "secret".to_string(),
+        token: None,
+    }
+}
@@ -0,0 +1,87 @@
+//! Precedence of the settings sources: flags, then the `--config` file, then
+//! the environment, where `.env.<name>` wins over `.env`. Each test points
+//! the layers at a working and a down gateway and runs `auth-test` to see
+//! which one was used.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{agent, client_command, closed_port, fixture, MockConduit, MockGateway};
+use std::fs;
+use std::path::Path;
+
+struct Deployment {
+    up: String,
+    down: String,
+    _gateway: MockGateway,
+    conduit: MockConduit,
+}
+
+async fn deployment() -> Deployment {
+    let gateway = MockGateway::start(vec![agent("001", "web-1", &["web"])]).await;
+    Deployment {
+        up: gateway.url.clone(),
+        down: format!("http://{}", closed_port()),
+        _gateway: gateway,
+        conduit: MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await,
+    }
+}
+
+/// Runs `auth-test` in `dir` with `args` and returns whether the gateway
+/// authentication stage passed.
+async fn authenticated(dir: &Path, deployment: &Deployment, args: &[&str]) -> bool {
+    let output = client_command(dir)
+        .args(["auth-test", &deployment.conduit.addr])
+        .args(["--wazuh-url", "https://wazuh.test:55000", "--wazuh-username", "wazuh", "--wazuh-password", "secret"])
+        .arg("--cacert")
+        .arg(fixture("ca.pem"))
+        .args(args)
+        .output()
+        .await
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[PASS] conduit ping"), "{}", stdout);
+    stdout.contains("[PASS] gateway authentication")
+}
+
+#[tokio::test]
+async fn the_named_env_file_wins_over_the_default_one() {
+    let deployment = deployment().await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(".env"), format!("GATEWAY_URL={}\n", deployment.down)).unwrap();
+    fs::write(dir.path().join(".env.staging"), format!("GATEWAY_URL={}\n", deployment.up)).unwrap();
+
+    assert!(!authenticated(dir.path(), &deployment, &[]).await);
+    assert!(authenticated(dir.path(), &deployment, &["--env", "staging"]).await);
+}
+
+#[tokio::test]
+async fn a_missing_named_env_file_falls_back_to_the_default_one() {
+    let deployment = deployment().await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(".env"), format!("GATEWAY_URL={}\n", deployment.up)).unwrap();
+
+    assert!(authenticated(dir.path(), &deployment, &["--env", "prod"]).await);
+}
+
+#[tokio::test]
+async fn the_config_file_wins_over_the_environment() {
+    let deployment = deployment().await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(".env"), format!("GATEWAY_URL={}\n", deployment.down)).unwrap();
+    fs::write(dir.path().join("conduit.toml"), format!("[gateway]\nurl = \"{}\"\n", deployment.up)).unwrap();
+
+    assert!(authenticated(dir.path(), &deployment, &["--config", "conduit.toml"]).await);
+}
+
+#[tokio::test]
+async fn flags_win_over_the_config_file() {
+    let deployment = deployment().await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("conduit.yaml"), format!("gateway:\n  url: {}\n", deployment.down)).unwrap();
+
+    assert!(!authenticated(dir.path(), &deployment, &["--config", "conduit.yaml"]).await);
+    let flags = ["--config", "conduit.yaml", "--gateway-url", &deployment.up];
+    assert!(authenticated(dir.path(), &deployment, &flags).await);
+}
@@ -0,0 +1,133 @@
+//! Credentials for an authenticating proxy in front of the gateway, sent to
+//! the loopback gateway as the `Authorization` header, the other headers
+//! every gateway call carries and the middleware every call passes through.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{agent, scan_config, MockGateway};
+use sensex_conduit::gateway::{check_header, DEFAULT_USER_AGENT};
+use sensex_conduit::reqwest_middleware::{Middleware, Next};
+use sensex_conduit::{Client, GatewayAuth, GatewayAuthError, GatewayMiddleware, HttpOptions};
+use std::sync::{Arc, Mutex};
+use task_local_extensions::Extensions;
+
+fn client(gateway: &MockGateway, auth: Option<GatewayAuth>) -> Client {
+    let dir = tempfile::tempdir().unwrap();
+    let config = scan_config(dir.path(), "127.0.0.1:1", Vec::new());
+    Client::new(config.client, config.retry)
+        .with_gateway(gateway.url.clone(), "https://wazuh.test:55000".to_string())
+        .with_http_options(&HttpOptions { auth, ..Default::default() })
+        .unwrap()
+}
+
+#[tokio::test]
+async fn bearer_and_basic_credentials_are_sent_on_every_gateway_call() {
+    let cases = [
+        (GatewayAuth::Bearer("proxy-token".to_string()), "Bearer proxy-token"),
+        // base64 of "proxy:hunter2".
+        (
+            GatewayAuth::Basic { username: "proxy".to_string(), password: Some("hunter2".to_string()) },
+            "Basic cHJveHk6aHVudGVyMg==",
+        ),
+        (GatewayAuth::Basic { username: "proxy".to_string(), password: None }, "Basic cHJveHk6"),
+    ];
+    for (auth, header) in cases {
+        let gateway = MockGateway::start_behind_proxy(vec![agent("001", "web-1", &["web"])], Some(header)).await;
+        let mut client = client(&gateway, Some(auth));
+
+        client.authenticate("wazuh", "secret").await.unwrap();
+        client.fetch_agents("web").await.unwrap();
+        assert_eq!(*gateway.authorizations.lock().unwrap(), [Some(header.to_string()), Some(header.to_string())]);
+    }
+}
+
+#[tokio::test]
+async fn a_401_from_the_proxy_is_reported_as_a_gateway_auth_error() {
+    let gateway = MockGateway::start_behind_proxy(vec![agent("001", "web-1", &["web"])], Some("Bearer right")).await;
+
+    let error = client(&gateway, None).authenticate("wazuh", "secret").await.unwrap_err();
+    let refused = error.downcast_ref::<GatewayAuthError>().unwrap_or_else(|| panic!("{}", error));
+    assert_eq!(refused.challenge, r#"Basic realm="gateway""#);
+    assert!(!refused.credentials_sent);
+    assert!(error.to_string().contains("separate from the Wazuh login"), "{}", error);
+
+    let mut client = client(&gateway, Some(GatewayAuth::Bearer("wrong".to_string())));
+    let error = client.authenticate("wazuh", "secret").await.unwrap_err();
+    assert!(error.downcast_ref::<GatewayAuthError>().unwrap().credentials_sent);
+    assert!(error.to_string().contains("the gateway credentials were rejected"), "{}", error);
+    assert!(gateway.calls.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn the_user_agent_and_extra_headers_are_sent_on_every_gateway_call() {
+    let gateway = MockGateway::start(vec![agent("001", "web-1", &["web"])]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let config = scan_config(dir.path(), "127.0.0.1:1", Vec::new());
+    let options = HttpOptions {
+        headers: vec![("X-Route".to_string(), "soc-eu".to_string()), ("X-Trace".to_string(), "a b".to_string())],
+        ..Default::default()
+    };
+    let mut client = Client::new(config.client, config.retry)
+        .with_gateway(gateway.url.clone(), "https://wazuh.test:55000".to_string())
+        .with_http_options(&options)
+        .unwrap();
+
+    client.authenticate("wazuh", "secret").await.unwrap();
+    client.fetch_agents("web").await.unwrap();
+    let headers = gateway.headers.lock().unwrap();
+    assert_eq!(headers.len(), 2);
+    for headers in headers.iter() {
+        assert_eq!(headers["user-agent"], DEFAULT_USER_AGENT);
+        assert!(DEFAULT_USER_AGENT.starts_with("sensex_conduit/"), "{}", DEFAULT_USER_AGENT);
+        assert_eq!(headers["x-route"], "soc-eu");
+        assert_eq!(headers["x-trace"], "a b");
+    }
+}
+
+/// Records the path of each request it passes on.
+struct Counting(Arc<Mutex<Vec<String>>>);
+
+#[async_trait::async_trait]
+impl Middleware for Counting {
+    async fn handle(
+        &self,
+        request: reqwest::Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> sensex_conduit::reqwest_middleware::Result<reqwest::Response> {
+        self.0.lock().unwrap().push(request.url().path().to_string());
+        next.run(request, extensions).await
+    }
+}
+
+#[tokio::test]
+async fn every_gateway_call_goes_through_the_middleware_stack() {
+    let gateway = MockGateway::start(vec![agent("001", "web-1", &["web"])]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let config = scan_config(dir.path(), "127.0.0.1:1", Vec::new());
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let options = HttpOptions { middleware: GatewayMiddleware::default().with(Counting(seen.clone())), ..Default::default() };
+    let mut client = Client::new(config.client, config.retry)
+        .with_gateway(gateway.url.clone(), "https://wazuh.test:55000".to_string())
+        .with_http_options(&options)
+        .unwrap();
+
+    client.authenticate("wazuh", "secret").await.unwrap();
+    client.fetch_groups().await.unwrap();
+    client.fetch_agents("web").await.unwrap();
+    assert_eq!(*seen.lock().unwrap(), ["/auth", "/groups", "/groups/web/agents"]);
+    assert_eq!(gateway.headers.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn invalid_or_reserved_extra_headers_are_refused() {
+    assert!(check_header("X-Route", "soc-eu").is_ok());
+    assert!(check_header("X Route", "soc-eu").unwrap_err().contains("invalid header name"));
+    assert!(check_header("X-Route", "soc\neu").unwrap_err().contains("invalid value"));
+    assert!(check_header("User-Agent", "curl").unwrap_err().contains("set by the client"));
+    let options = HttpOptions { headers: vec![("Authorization".to_string(), "Bearer x".to_string())], ..Default::default() };
+    let error = options.build().unwrap_err();
+    assert!(error.to_string().starts_with("Gateway header: the Authorization header"), "{}", error);
+}
@@ -0,0 +1,49 @@
+//! A gateway on a Unix domain socket, named by a `unix://` gateway URL.
+
+#![cfg(all(unix, feature = "gateway"))]
+
+mod common;
+
+use common::{agent, scan_config, MockGateway};
+use sensex_conduit::gateway::DEFAULT_USER_AGENT;
+use sensex_conduit::{Client, HttpOptions};
+
+#[tokio::test]
+async fn gateway_calls_go_over_the_unix_socket_of_a_unix_url() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("gateway.sock");
+    let gateway = MockGateway::start_on_socket(vec![agent("001", "web-1", &["web"]), agent("002", "db-1", &["db"])], &socket).await;
+    assert_eq!(gateway.url, format!("unix://{}", socket.display()));
+
+    let config = scan_config(dir.path(), "127.0.0.1:1", Vec::new());
+    let options = HttpOptions { headers: vec![("X-Route".to_string(), "soc-eu".to_string())], ..Default::default() };
+    let mut client = Client::new(config.client, config.retry)
+        .with_gateway(gateway.url.clone(), "https://wazuh.test:55000".to_string())
+        .with_http_options(&options)
+        .unwrap();
+
+    client.authenticate("wazuh", "secret").await.unwrap();
+    let groups: Vec<String> = client.fetch_groups().await.unwrap().into_iter().map(|g| g.name).collect();
+    assert_eq!(groups, ["db", "web"]);
+    let agents = client.fetch_agents("web").await.unwrap();
+    assert_eq!(agents.iter().map(|a| a.id.as_str()).collect::<Vec<_>>(), ["001"]);
+
+    assert_eq!(*gateway.calls.lock().unwrap(), ["/auth", "/groups", "/groups/web/agents"]);
+    for headers in gateway.headers.lock().unwrap().iter() {
+        assert_eq!(headers["host"], "localhost");
+        assert_eq!(headers["user-agent"], DEFAULT_USER_AGENT);
+        assert_eq!(headers["x-route"], "soc-eu");
+    }
+}
+
+#[tokio::test]
+async fn a_missing_socket_fails_the_call_naming_the_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("absent.sock");
+    let config = scan_config(dir.path(), "127.0.0.1:1", Vec::new());
+    let mut client = Client::new(config.client, config.retry)
+        .with_gateway(format!("unix://{}", socket.display()), "https://wazuh.test:55000".to_string());
+
+    let error = client.authenticate("wazuh", "secret").await.unwrap_err();
+    assert!(error.to_string().contains(&format!("gateway socket {}", socket.display())), "{}", error);
+}
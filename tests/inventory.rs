@@ -0,0 +1,36 @@
+//! `scan --inventory`, run as the `client` binary against two loopback
+//! conduit servers.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{client_command, fixture, write_query, MockConduit};
+
+#[tokio::test]
+async fn every_inventory_server_is_scanned_into_its_own_directory() {
+    let east = MockConduit::answering(r#"{"hits":{"hits":[{"_source":{"site":"east"}}]}}"#).await;
+    let west = MockConduit::answering(r#"{"hits":{"hits":[{"_source":{"site":"west"}}]}}"#).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    std::fs::write(dir.path().join("agents.csv"), "id,name,group,status\n001,web-1,web,active\n").unwrap();
+    let ca = fixture("ca.pem");
+    let server = |name: &str, addr: &str| format!("  - name: {}\n    address: {}\n    cacert: [{}]\n", name, addr, ca.display());
+    let inventory = format!("servers:\n{}{}    sni: localhost\n", server("east", &east.addr), server("west", &west.addr));
+    std::fs::write(dir.path().join("inventory.yaml"), inventory).unwrap();
+
+    let output = client_command(dir.path())
+        .args(["scan", "--inventory", "inventory.yaml", "--agents-file", "agents.csv", "--queries-dir", "queries"])
+        .args(["--output-template", "{query}.{ext}"])
+        .output()
+        .await
+        .unwrap();
+    assert!(output.status.success(), "scan failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!((east.received(), west.received()), (1, 1));
+    for site in ["east", "west"] {
+        let result = std::fs::read_to_string(dir.path().join("query_results").join(site).join("web/alerts.json")).unwrap();
+        assert!(result.contains(site), "{}", result);
+        assert!(dir.path().join(format!("session.{}.json", site)).exists(), "{} has no session file of its own", site);
+    }
+}
@@ -0,0 +1,87 @@
+//! Scanning several Wazuh managers, each behind its own gateway, in one run.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{agent, closed_port, manager, scan_config, write_query, MockConduit, MockGateway};
+use sensex_conduit::{scan, FailedItem, QueryOutcome};
+use serde_json::json;
+
+#[tokio::test]
+async fn every_manager_is_scanned_apart_and_a_failing_one_does_not_stop_the_others() {
+    let eu = MockGateway::start(vec![agent("001", "eu-web", &["web"])]).await;
+    let apac = MockGateway::start(vec![agent("001", "apac-db", &["db"]), agent("002", "apac-web", &["web"])]).await;
+    let conduit = MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    // Named managers keep their sessions in the working directory.
+    std::env::set_current_dir(dir.path()).unwrap();
+    let mut config = scan_config(dir.path(), &conduit.addr, Vec::new());
+    config.inventory = None;
+    config.managers = vec![
+        manager("eu", &eu.url),
+        manager("us", &format!("http://{}", closed_port())),
+        manager("apac", &apac.url),
+    ];
+
+    let report = scan(config).await.unwrap();
+
+    let failures: Vec<&str> = report.manager_failures.iter().map(|f| f.manager.as_str()).collect();
+    assert_eq!(failures, ["us"]);
+    let groups: Vec<(Option<&str>, &str)> =
+        report.groups.iter().map(|g| (g.manager.as_deref(), g.group.name.as_str())).collect();
+    assert_eq!(groups, [(Some("eu"), "web"), (Some("apac"), "db"), (Some("apac"), "web")]);
+
+    // Agent 001 exists on both managers; each result lands under its manager.
+    for group in &report.groups {
+        let manager = dir.path().join("results").join(group.manager.as_deref().unwrap());
+        for result in &group.queries {
+            let QueryOutcome::Saved { path, .. } = &result.outcome else {
+                panic!("{} failed: {:?}", result.agent.name, result.outcome);
+            };
+            assert!(path.starts_with(&manager), "{}", path.display());
+        }
+    }
+    assert!(dir.path().join("session.eu.json").exists());
+    assert!(dir.path().join("session.apac.json").exists());
+    assert!(!dir.path().join("session.json").exists());
+}
+
+#[tokio::test]
+async fn agents_wazuh_failed_to_list_are_reported_or_fail_discovery_when_strict() {
+    let failed = json!({"error": {"code": 1701, "message": "Agent does not exist"}, "id": ["007", "008"]});
+    let gateway = MockGateway::start_with_failed_items(vec![agent("001", "web-1", &["web"])], vec![failed]).await;
+    let conduit = MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    let mut config = scan_config(dir.path(), &conduit.addr, Vec::new());
+    config.inventory = None;
+    config.managers = vec![manager("eu", &gateway.url)];
+    config.client.session_file = dir.path().join("session.json");
+
+    let report = scan(config.clone()).await.unwrap();
+
+    assert!(report.manager_failures.is_empty());
+    assert_eq!(report.succeeded(), 1);
+    let expected = FailedItem {
+        manager: Some("eu".to_string()),
+        listing: "agents for group web".to_string(),
+        ids: vec!["007".to_string(), "008".to_string()],
+        code: Some(1701),
+        message: "Agent does not exist".to_string(),
+    };
+    assert_eq!(report.failed_items, [expected]);
+    assert_eq!(
+        report.failed_items[0].to_string(),
+        "manager eu: agents for group web 007, 008: Agent does not exist (code 1701)"
+    );
+
+    config.strict_failed_items = true;
+    let report = scan(config).await.unwrap();
+
+    assert_eq!(report.manager_failures.len(), 1);
+    assert!(report.manager_failures[0].message.contains("007, 008"), "{}", report.manager_failures[0].message);
+    assert!(report.groups.is_empty());
+    assert_eq!(conduit.received(), 1);
+}
@@ -0,0 +1,47 @@
+//! `--quiet`, run as the `client` binary: a scan prints nothing to stdout
+//! and still reports errors on stderr.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{client_command, closed_port, fixture, write_query, MockConduit};
+use std::path::Path;
+use std::process::Output;
+
+async fn scan(dir: &Path, server: &str, quiet: bool) -> Output {
+    write_query(dir, "alerts", r#"{"query":{"match_all":{}}}"#);
+    std::fs::write(dir.join("agents.csv"), "id,name,group,status\n001,web-1,web,active\n").unwrap();
+    client_command(dir)
+        .args(["scan", server, "--agents-file", "agents.csv", "--queries-dir", "queries", "--max-attempts", "1"])
+        .args(quiet.then_some("--quiet"))
+        .arg("--cacert")
+        .arg(fixture("ca.pem"))
+        .output()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn a_quiet_scan_prints_nothing_to_stdout() {
+    let conduit = MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = scan(dir.path(), &conduit.addr, false).await;
+    assert!(output.status.success(), "scan failed:\n{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!output.stdout.is_empty());
+
+    let output = scan(dir.path(), &conduit.addr, true).await;
+    assert!(output.status.success(), "scan failed:\n{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert!(dir.path().join("query_results/web").read_dir().unwrap().next().is_some());
+}
+
+#[tokio::test]
+async fn a_quiet_scan_still_reports_errors_on_stderr() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = scan(dir.path(), &closed_port(), true).await;
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert!(!output.stderr.is_empty());
+}
@@ -0,0 +1,47 @@
+//! `scan --interval --repeat`, run as the `client` binary against the
+//! loopback conduit.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{client_command, fixture, reply, signed, write_query, MockConduit};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn runs_are_spaced_by_the_interval_and_reuse_the_session() {
+    let arrivals = Arc::new(Mutex::new(Vec::new()));
+    let recorded = arrivals.clone();
+    let conduit = MockConduit::start(move |request| {
+        recorded.lock().unwrap().push(Instant::now());
+        Some(signed(reply(request, r#"{"hits":{"hits":[]}}"#)))
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    std::fs::write(dir.path().join("agents.csv"), "id,name,group,status\n001,web-1,web,active\n").unwrap();
+
+    let output = client_command(dir.path())
+        .args(["scan", &conduit.addr, "--agents-file", "agents.csv", "--queries-dir", "queries"])
+        .args(["--interval", "1s", "--repeat", "2", "--output-template", "run{run}/{query}.{ext}"])
+        .arg("--cacert")
+        .arg(fixture("ca.pem"))
+        .output()
+        .await
+        .unwrap();
+    assert!(output.status.success(), "scan failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    let arrivals = arrivals.lock().unwrap();
+    assert_eq!(arrivals.len(), 2);
+    let spacing = arrivals[1] - arrivals[0];
+    assert!(spacing >= Duration::from_millis(900) && spacing < Duration::from_millis(1900), "{:?}", spacing);
+
+    let requests = conduit.requests.lock().unwrap();
+    assert_eq!(requests[0].session_id, None);
+    assert!(requests[1].session_id.is_some(), "the second run started a new session");
+    for run in ["run1", "run2"] {
+        let result = dir.path().join("query_results/web").join(run).join("alerts.json");
+        assert!(result.exists(), "{} is missing", result.display());
+    }
+}
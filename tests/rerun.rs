@@ -0,0 +1,100 @@
+//! `scan --rerun`, run as the `client` binary: only the queries an earlier
+//! summary lists as unfinished are sent again.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{client_command, fixture, reply, signed, write_query, MockConduit};
+use serde_json::Value;
+use std::path::Path;
+use std::process::Output;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+async fn scan(dir: &Path, server: &str, args: &[&str]) -> Output {
+    client_command(dir)
+        .args(["scan", server, "--agents-file", "agents.csv", "--queries-dir", "queries", "--max-attempts", "1"])
+        .args(["--reconnect-delay-ms", "0", "--summary-json", "summary.json"])
+        .args(args)
+        .arg("--cacert")
+        .arg(fixture("ca.pem"))
+        .output()
+        .await
+        .unwrap()
+}
+
+fn statuses(dir: &Path) -> Vec<(String, String, String)> {
+    let summary: Value = serde_json::from_slice(&std::fs::read(dir.join("summary.json")).unwrap()).unwrap();
+    let mut statuses: Vec<_> = summary["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            let field = |name: &str| entry[name].as_str().unwrap().to_string();
+            (field("agent_id"), field("query"), field("status"))
+        })
+        .collect();
+    statuses.sort();
+    statuses
+}
+
+#[tokio::test]
+async fn a_rerun_sends_only_the_failed_queries_and_merges_the_summary() {
+    // The logins query of agent 002 fails until the flag is cleared.
+    let broken = Arc::new(AtomicBool::new(true));
+    let failing = broken.clone();
+    let conduit = MockConduit::start(move |request| {
+        let fails = request.wql_query.contains("002") && request.wql_query.contains("logins");
+        match fails && failing.load(Ordering::SeqCst) {
+            true => None,
+            false => Some(signed(reply(request, r#"{"hits":{"hits":[]}}"#))),
+        }
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"term":{"agent.id":"{{agent_id}}"}},"name":"alerts"}"#);
+    write_query(dir.path(), "logins", r#"{"query":{"term":{"agent.id":"{{agent_id}}"}},"name":"logins"}"#);
+    std::fs::write(dir.path().join("agents.csv"), "id,name,group,status\n001,web-1,web,active\n002,web-2,web,active\n")
+        .unwrap();
+
+    scan(dir.path(), &conduit.addr, &[]).await;
+    assert_eq!(conduit.received(), 4);
+    let status = |agent: &str, query: &str, status: &str| (agent.to_string(), query.to_string(), status.to_string());
+    assert_eq!(
+        statuses(dir.path()),
+        [
+            status("001", "alerts", "saved"),
+            status("001", "logins", "saved"),
+            status("002", "alerts", "saved"),
+            status("002", "logins", "error"),
+        ]
+    );
+
+    broken.store(false, Ordering::SeqCst);
+    let output = scan(dir.path(), &conduit.addr, &["--rerun", "summary.json"]).await;
+    assert!(output.status.success(), "rerun failed:\n{}", String::from_utf8_lossy(&output.stderr));
+    let requests = conduit.requests.lock().unwrap();
+    assert_eq!(requests.len(), 5);
+    let query = &requests[4].wql_query;
+    assert!(query.contains("002") && query.contains("logins"), "{}", query);
+    assert!(statuses(dir.path()).iter().all(|(_, _, status)| status == "saved"), "{:?}", statuses(dir.path()));
+    assert_eq!(statuses(dir.path()).len(), 4);
+}
+
+#[tokio::test]
+async fn a_summary_naming_queries_that_no_longer_exist_is_refused() {
+    let conduit = MockConduit::start(|_| None).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    write_query(dir.path(), "logins", r#"{"query":{"match_all":{}}}"#);
+    std::fs::write(dir.path().join("agents.csv"), "id,name,group,status\n001,web-1,web,active\n").unwrap();
+    scan(dir.path(), &conduit.addr, &[]).await;
+    std::fs::remove_file(dir.path().join("queries/logins.json")).unwrap();
+
+    let output = scan(dir.path(), &conduit.addr, &["--rerun", "summary.json"]).await;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("not in the current query set: logins"), "{}", stderr);
+    assert_eq!(conduit.received(), 2);
+}
@@ -0,0 +1,95 @@
+//! Whole query exchanges are retried, reconnecting, when the transport fails.
+
+mod common;
+
+use common::{inventory_agent, reply, scan_config, signed, write_query, MockConduit};
+use sensex_conduit::{scan, QueryOutcome};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const QUERY: &str = r#"{"query":{"match_all":{}}}"#;
+const DATA: &str = r#"{"hits":{"hits":[]}}"#;
+
+#[tokio::test]
+async fn a_connection_dropped_before_the_response_is_retried_on_a_new_one() {
+    let attempts = AtomicUsize::new(0);
+    let conduit = MockConduit::start(move |request| match attempts.fetch_add(1, Ordering::SeqCst) {
+        0 => None,
+        _ => Some(signed(reply(request, DATA))),
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", QUERY);
+
+    let report = scan(scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")])).await.unwrap();
+
+    assert_eq!(conduit.received(), 2);
+    let result = report.results().next().unwrap();
+    let QueryOutcome::Saved { path, .. } = &result.outcome else {
+        panic!("query did not succeed: {:?}", result.outcome);
+    };
+    assert_eq!(std::fs::read_to_string(path).unwrap(), DATA);
+}
+
+#[tokio::test]
+async fn a_bad_signature_is_not_retried() {
+    let conduit = MockConduit::start(|request| {
+        let mut wire: serde_json::Value = serde_json::from_slice(&signed(reply(request, DATA))).unwrap();
+        wire["data"] = "tampered".into();
+        Some(wire.to_string().into_bytes())
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", QUERY);
+
+    let report = scan(scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")])).await.unwrap();
+
+    assert_eq!(conduit.received(), 1);
+    assert!(!report.results().next().unwrap().succeeded());
+}
+
+#[tokio::test]
+async fn an_empty_success_is_retried_until_data_arrives() {
+    let attempts = AtomicUsize::new(0);
+    let conduit = MockConduit::start(move |request| match attempts.fetch_add(1, Ordering::SeqCst) {
+        0 | 1 => Some(signed(reply(request, ""))),
+        _ => Some(signed(reply(request, DATA))),
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", QUERY);
+    let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+    config.retry_empty = 2;
+
+    let report = scan(config).await.unwrap();
+
+    assert_eq!(conduit.received(), 3);
+    let result = report.results().next().unwrap();
+    let QueryOutcome::Saved { path, .. } = &result.outcome else {
+        panic!("query did not succeed: {:?}", result.outcome);
+    };
+    assert_eq!(std::fs::read_to_string(path).unwrap(), DATA);
+}
+
+#[tokio::test]
+async fn an_empty_success_is_reported_as_empty_without_a_file() {
+    let conduit = MockConduit::answering("").await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", QUERY);
+    let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+    config.retry_empty = 1;
+
+    let report = scan(config).await.unwrap();
+
+    assert_eq!(conduit.received(), 2);
+    assert!(matches!(report.results().next().unwrap().outcome, QueryOutcome::Empty));
+    assert_eq!((report.empty(), report.failed()), (1, 0));
+    let results = dir.path().join("results");
+    assert!(!results.exists() || holds_no_files(&results));
+}
+
+fn holds_no_files(dir: &std::path::Path) -> bool {
+    std::fs::read_dir(dir).unwrap().all(|entry| {
+        let path = entry.unwrap().path();
+        path.is_dir() && holds_no_files(&path)
+    })
+}
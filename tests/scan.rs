@@ -0,0 +1,980 @@
+//! The library-level [`scan`] against the loopback gateway and conduit.
+
+mod common;
+
+use common::{closed_port, inventory_agent, reply, scan_config, signed, write_query, MockConduit};
+#[cfg(feature = "gateway")]
+use common::{agent, gateway_scan_config, MockGateway};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+use sensex_conduit::client::MAX_IN_MEMORY;
+use sensex_conduit::compression::Compression;
+use sensex_conduit::encryption::OutputCipher;
+use sensex_conduit::protocol::{query_hash, Response, ResultFormat};
+use sensex_conduit::scan::{Warmup, DEADLINE_REACHED, WARMUP_FAILED};
+use sensex_conduit::sink::{FileSink, OutputSink, ResultMeta};
+use sensex_conduit::{plan, scan, scan_stream, AgentStatus, QueryOutcome, ResultCache};
+
+const DATA: &str = r#"{"hits":{"hits":[{"_source":{"rule":{"level":3}}}]}}"#;
+
+#[cfg(feature = "gateway")]
+#[tokio::test]
+async fn the_report_holds_every_group_agent_and_query() {
+    let gateway = MockGateway::start(vec![
+        agent("001", "web-1", &["web"]),
+        agent("002", "db-1", &["db"]),
+        agent("003", "web-2", &["web"]),
+    ])
+    .await;
+    let conduit = MockConduit::start(|request| {
+        let mut response = reply(request, DATA);
+        if request.wql_query.contains("broken") {
+            response.status = false;
+            response.data = "index not found".to_string();
+        }
+        Some(signed(response))
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    write_query(dir.path(), "broken", r#"{"index":"broken"}"#);
+
+    let report = scan(gateway_scan_config(dir.path(), &conduit.addr, &gateway)).await.unwrap();
+
+    let layout: Vec<(&str, Vec<(&str, &str)>)> = report
+        .groups
+        .iter()
+        .map(|group| {
+            let queries = group.queries.iter().map(|r| (r.agent.id.as_str(), r.query.as_str())).collect();
+            (group.group.name.as_str(), queries)
+        })
+        .collect();
+    assert_eq!(
+        layout,
+        [
+            ("db", vec![("002", "alerts"), ("002", "broken")]),
+            ("web", vec![("001", "alerts"), ("001", "broken"), ("003", "alerts"), ("003", "broken")]),
+        ]
+    );
+    assert_eq!((report.total(), report.succeeded()), (6, 3));
+    assert!(report.manager_failures.is_empty());
+
+    for result in report.results() {
+        match (&result.outcome, result.query.as_str()) {
+            (QueryOutcome::Saved { path, format, cached, .. }, "alerts") => {
+                assert_eq!(result.bytes, DATA.len() as u64);
+                assert_eq!((*format, *cached), (ResultFormat::Json, false));
+                assert!(path.starts_with(dir.path().join("results")), "{}", path.display());
+                assert_eq!(std::fs::read_to_string(path).unwrap(), DATA);
+            }
+            (QueryOutcome::Rejected { message }, "broken") => assert!(message.contains("index not found"), "{}", message),
+            (outcome, query) => panic!("unexpected outcome for {}: {:?}", query, outcome),
+        }
+    }
+    assert_eq!(conduit.received(), 6);
+}
+
+#[cfg(feature = "gateway")]
+#[tokio::test]
+async fn concurrent_groups_are_reported_in_discovery_order() {
+    // Groups discovered first answer last.
+    let delay = |request: &sensex_conduit::protocol::AuthRequest| match request.wql_query.as_str() {
+        q if q.contains("001") => Duration::from_millis(2000),
+        q if q.contains("002") => Duration::from_millis(1000),
+        _ => Duration::ZERO,
+    };
+    let gateway = MockGateway::start(vec![
+        agent("001", "app-1", &["app"]),
+        agent("002", "db-1", &["db"]),
+        agent("003", "web-1", &["web"]),
+    ])
+    .await;
+    let conduit = MockConduit::start_delayed(delay, |request| Some(signed(reply(request, DATA)))).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"agent":"{{agent_id}}"}"#);
+    let mut config = gateway_scan_config(dir.path(), &conduit.addr, &gateway);
+    config.group_concurrency = 3;
+
+    let started = Instant::now();
+    let report = scan(config).await.unwrap();
+
+    let elapsed = started.elapsed();
+    // One after the other, the groups would take 3s.
+    assert!(elapsed >= Duration::from_millis(2000) && elapsed < Duration::from_millis(2600), "{:?}", elapsed);
+    let groups: Vec<&str> = report.groups.iter().map(|g| g.group.name.as_str()).collect();
+    assert_eq!(groups, ["app", "db", "web"]);
+    assert_eq!(report.succeeded(), 3);
+}
+
+#[tokio::test]
+async fn the_report_of_an_inventory_scan_holds_every_group_agent_and_query() {
+    let conduit = MockConduit::start(|request| {
+        let mut response = reply(request, DATA);
+        if request.wql_query.contains("broken") {
+            response.status = false;
+            response.data = "index not found".to_string();
+        }
+        Some(signed(response))
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    write_query(dir.path(), "broken", r#"{"index":"broken"}"#);
+    let agents = vec![inventory_agent("001", "web"), inventory_agent("002", "db"), inventory_agent("003", "web")];
+
+    let report = scan(scan_config(dir.path(), &conduit.addr, agents)).await.unwrap();
+
+    let layout: Vec<(&str, Vec<(&str, &str)>)> = report
+        .groups
+        .iter()
+        .map(|group| {
+            let queries = group.queries.iter().map(|r| (r.agent.id.as_str(), r.query.as_str())).collect();
+            (group.group.name.as_str(), queries)
+        })
+        .collect();
+    assert_eq!(
+        layout,
+        [
+            ("web", vec![("001", "alerts"), ("001", "broken"), ("003", "alerts"), ("003", "broken")]),
+            ("db", vec![("002", "alerts"), ("002", "broken")]),
+        ]
+    );
+    assert_eq!((report.total(), report.succeeded()), (6, 3));
+    assert!(report.manager_failures.is_empty());
+
+    for result in report.results() {
+        match (&result.outcome, result.query.as_str()) {
+            (QueryOutcome::Saved { path, format, cached, .. }, "alerts") => {
+                assert_eq!(result.bytes, DATA.len() as u64);
+                assert_eq!((*format, *cached), (ResultFormat::Json, false));
+                assert!(path.starts_with(dir.path().join("results")), "{}", path.display());
+                assert_eq!(std::fs::read_to_string(path).unwrap(), DATA);
+            }
+            (QueryOutcome::Rejected { message }, "broken") => assert!(message.contains("index not found"), "{}", message),
+            (outcome, query) => panic!("unexpected outcome for {}: {:?}", query, outcome),
+        }
+    }
+    assert_eq!(conduit.received(), 6);
+}
+
+#[tokio::test]
+async fn concurrent_inventory_groups_are_reported_in_inventory_order() {
+    // Groups listed first answer last.
+    let delay = |request: &sensex_conduit::protocol::AuthRequest| match request.wql_query.as_str() {
+        q if q.contains("001") => Duration::from_millis(2000),
+        q if q.contains("002") => Duration::from_millis(1000),
+        _ => Duration::ZERO,
+    };
+    let conduit = MockConduit::start_delayed(delay, |request| Some(signed(reply(request, DATA)))).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"agent":"{{agent_id}}"}"#);
+    let agents = vec![inventory_agent("001", "web"), inventory_agent("002", "db"), inventory_agent("003", "app")];
+    let mut config = scan_config(dir.path(), &conduit.addr, agents);
+    config.group_concurrency = 3;
+
+    let started = Instant::now();
+    let report = scan(config).await.unwrap();
+
+    let elapsed = started.elapsed();
+    // One after the other, the groups would take 3s.
+    assert!(elapsed >= Duration::from_millis(2000) && elapsed < Duration::from_millis(2600), "{:?}", elapsed);
+    let groups: Vec<&str> = report.groups.iter().map(|g| g.group.name.as_str()).collect();
+    assert_eq!(groups, ["web", "db", "app"]);
+    assert_eq!(report.succeeded(), 3);
+}
+
+#[tokio::test]
+async fn agents_run_concurrently_within_both_the_group_and_the_global_limit() {
+    // Requests in flight per group, keyed by the first letter of the agent
+    // id, and the most ever seen at once.
+    #[derive(Default)]
+    struct InFlight {
+        now: HashMap<char, usize>,
+        peak: HashMap<char, usize>,
+        peak_total: usize,
+    }
+    let in_flight = Arc::new(Mutex::new(InFlight::default()));
+    let group_of = |request: &sensex_conduit::protocol::AuthRequest| request.wql_query.split('"').nth(3).unwrap().chars().next().unwrap();
+    let arrived = in_flight.clone();
+    let answered = in_flight.clone();
+    let conduit = MockConduit::start_delayed(
+        move |request| {
+            let mut state = arrived.lock().unwrap();
+            let group = group_of(request);
+            *state.now.entry(group).or_default() += 1;
+            let (now, total) = (state.now[&group], state.now.values().sum());
+            let peak = state.peak.entry(group).or_default();
+            *peak = (*peak).max(now);
+            state.peak_total = state.peak_total.max(total);
+            Duration::from_millis(200)
+        },
+        move |request| {
+            *answered.lock().unwrap().now.get_mut(&group_of(request)).unwrap() -= 1;
+            Some(signed(reply(request, DATA)))
+        },
+    )
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"agent":"{{agent_id}}"}"#);
+    let mut agents: Vec<_> = (1..=8).map(|n| inventory_agent(&format!("b{:02}", n), "big")).collect();
+    agents.extend((1..=3).map(|n| inventory_agent(&format!("m{:02}", n), "mid")));
+    agents.push(inventory_agent("s01", "small"));
+    let mut config = scan_config(dir.path(), &conduit.addr, agents);
+    config.group_concurrency = 3;
+    config.max_concurrency_per_group = 2;
+    config.max_concurrency = Some(4);
+
+    let report = scan(config).await.unwrap();
+
+    assert_eq!((report.total(), report.succeeded()), (12, 12));
+    let big: Vec<&str> = report.groups[0].queries.iter().map(|q| q.agent.id.as_str()).collect();
+    assert_eq!(big, ["b01", "b02", "b03", "b04", "b05", "b06", "b07", "b08"]);
+    let state = in_flight.lock().unwrap();
+    // Two per group across three groups would be five; the global limit
+    // holds it to four, while both limits are still reached.
+    assert_eq!(state.peak_total, 4);
+    assert_eq!((state.peak[&'b'], state.peak[&'m'], state.peak[&'s']), (2, 2, 1));
+}
+
+#[tokio::test]
+async fn a_second_scan_resumes_the_concurrency_the_first_learned() {
+    // Requests in flight now, and as each request arrived, counting itself.
+    let in_flight = Arc::new(Mutex::new((0, Vec::new())));
+    let (arrived, answered) = (in_flight.clone(), in_flight.clone());
+    let conduit = MockConduit::start_delayed(
+        move |_| {
+            let mut state = arrived.lock().unwrap();
+            state.0 += 1;
+            let now = state.0;
+            state.1.push(now);
+            Duration::from_millis(150)
+        },
+        move |request| {
+            answered.lock().unwrap().0 -= 1;
+            Some(signed(reply(request, DATA)))
+        },
+    )
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"agent":"{{agent_id}}"}"#);
+    write_query(dir.path(), "logons", r#"{"logons":"{{agent_id}}"}"#);
+    let agents: Vec<_> = ["web", "db", "app", "mail"].iter().enumerate().map(|(n, group)| inventory_agent(&format!("00{}", n + 1), group)).collect();
+    let mut config = scan_config(dir.path(), &conduit.addr, agents);
+    config.group_concurrency = 4;
+    config.adaptive_concurrency = true;
+    let run = |config| {
+        let in_flight = in_flight.clone();
+        async move {
+            in_flight.lock().unwrap().1.clear();
+            let report = scan(config).await.unwrap();
+            (report, in_flight.lock().unwrap().1.clone())
+        }
+    };
+
+    // A fresh scan sends its first request alone and works its way up.
+    let (first, arrivals) = run(config.clone()).await;
+    assert_eq!(arrivals[..2], [1, 1]);
+    let learned = first.adaptive_state.unwrap();
+    assert_eq!(learned.limit, 4);
+
+    // Resumed, all four agents start at once.
+    config.adaptive_state = Some(learned);
+    let (second, arrivals) = run(config.clone()).await;
+    assert_eq!(arrivals[..4], [1, 2, 3, 4]);
+    assert_eq!(second.succeeded(), 8);
+    assert_eq!(second.adaptive_state.unwrap().limit, 4);
+
+    // A different ceiling starts afresh.
+    config.group_concurrency = 3;
+    let (_, arrivals) = run(config).await;
+    assert_eq!(arrivals[..2], [1, 1]);
+}
+
+#[tokio::test]
+async fn each_received_result_records_when_its_phases_ended() {
+    let delay = |request: &sensex_conduit::protocol::AuthRequest| match request.wql_query.contains("slow") {
+        true => Duration::from_millis(400),
+        false => Duration::ZERO,
+    };
+    let conduit = MockConduit::start_delayed(delay, |request| Some(signed(reply(request, DATA)))).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    write_query(dir.path(), "slow", r#"{"query":{"slow":{}}}"#);
+    let config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+
+    let report = scan(config).await.unwrap();
+
+    let results = &report.groups[0].queries;
+    assert_eq!(results.len(), 2);
+    for result in results {
+        let timings = result.timings.unwrap_or_else(|| panic!("no timings for {}", result.query));
+        assert!(timings.connected > Duration::ZERO, "{:?}", timings);
+        assert!(timings.connected <= timings.sent, "{:?}", timings);
+        assert!(timings.sent <= timings.first_byte, "{:?}", timings);
+        assert!(timings.first_byte <= timings.received, "{:?}", timings);
+        assert!(timings.received <= result.latency, "{:?} > {:?}", timings, result.latency);
+    }
+    let slow = results.iter().find(|r| r.query == "slow").unwrap().timings.unwrap();
+    assert!(slow.first_byte - slow.sent >= Duration::from_millis(400), "{:?}", slow);
+    let fast = results.iter().find(|r| r.query == "alerts").unwrap().timings.unwrap();
+    assert!(fast.first_byte - fast.sent < Duration::from_millis(400), "{:?}", fast);
+}
+
+#[tokio::test]
+async fn csv_results_get_a_csv_extension_and_are_not_parsed_as_json() {
+    let csv = "agent.id,rule.level\n001,3\n";
+    let conduit = MockConduit::start(move |request| Some(signed(reply(request, csv)))).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+    config.client.format = ResultFormat::Csv;
+
+    let report = scan(config).await.unwrap();
+
+    let result = report.results().next().unwrap();
+    let QueryOutcome::Saved { path, format, .. } = &result.outcome else {
+        panic!("unexpected outcome: {:?}", result.outcome);
+    };
+    assert_eq!(*format, ResultFormat::Csv);
+    assert_eq!(path.extension().unwrap(), "csv");
+    assert_eq!(std::fs::read_to_string(path).unwrap(), csv);
+    assert_eq!(conduit.requests.lock().unwrap()[0].format, ResultFormat::Csv);
+}
+
+#[tokio::test]
+async fn a_query_declaring_csv_output_is_requested_and_saved_as_csv() {
+    let conduit = MockConduit::start(|request| {
+        let data = match request.format {
+            ResultFormat::Csv => "rule.level\n3\n",
+            _ => DATA,
+        };
+        Some(signed(reply(request, data)))
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    write_query(dir.path(), "levels", r#"{"query":{"match_all":{}}}"#);
+    write_query(dir.path(), "other", r#"{"query":{"match_all":{}}}"#);
+    std::fs::write(dir.path().join("queries").join("levels.meta"), r#"{"format":"csv"}"#).unwrap();
+    std::fs::write(dir.path().join("queries").join("other.meta"), r#"{"format":"xml"}"#).unwrap();
+
+    let report = scan(scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")])).await.unwrap();
+
+    let saved: Vec<(&str, ResultFormat, String)> = report
+        .results()
+        .map(|result| match &result.outcome {
+            QueryOutcome::Saved { path, format, .. } => {
+                (result.query.as_str(), *format, path.extension().unwrap().to_string_lossy().into_owned())
+            }
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        })
+        .collect();
+    assert_eq!(
+        saved,
+        [
+            ("alerts", ResultFormat::Json, "json".to_string()),
+            ("levels", ResultFormat::Csv, "csv".to_string()),
+            ("other", ResultFormat::Json, "json".to_string()),
+        ]
+    );
+    let requested: Vec<ResultFormat> = conduit.requests.lock().unwrap().iter().map(|request| request.format).collect();
+    assert_eq!(requested, [ResultFormat::Json, ResultFormat::Csv, ResultFormat::Json]);
+}
+
+#[tokio::test]
+async fn json_results_are_trimmed_to_the_fields_of_the_scan_or_the_query() {
+    let nested = r#"{"took":7,"hits":{"total":2,"hits":[
+        {"_id":"a","_source":{"rule":{"level":3,"id":"5501"},"agent":{"id":"001","name":"web-1"},"full_log":"sshd: accepted"}},
+        {"_id":"b","_source":{"rule":{"level":10,"id":"5712"},"agent":{"id":"001","name":"web-1"},"full_log":"sshd: failed"}}
+    ]}}"#;
+    let conduit = MockConduit::start(move |request| Some(signed(reply(request, nested)))).await;
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["alerts", "agents", "raw"] {
+        write_query(dir.path(), name, r#"{"query":{"match_all":{}}}"#);
+    }
+    std::fs::write(dir.path().join("queries").join("agents.meta"), r#"{"fields":["$.hits.hits[*]._source.agent.name"]}"#).unwrap();
+    std::fs::write(dir.path().join("queries").join("raw.meta"), r#"{"fields":[]}"#).unwrap();
+
+    for max_in_memory in [MAX_IN_MEMORY, 64] {
+        let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+        config.fields = Some("hits.hits._source.rule.level,hits.total,missing".parse().unwrap());
+        config.client.max_in_memory = max_in_memory;
+        let report = scan(config).await.unwrap();
+
+        let saved: HashMap<&str, serde_json::Value> = report
+            .results()
+            .map(|result| match &result.outcome {
+                QueryOutcome::Saved { path, .. } => {
+                    (result.query.as_str(), serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap())
+                }
+                outcome => panic!("unexpected outcome: {:?}", outcome),
+            })
+            .collect();
+        assert_eq!(saved["alerts"], serde_json::json!({"hits": {"total": 2, "hits": [{"_source": {"rule": {"level": 3}}}, {"_source": {"rule": {"level": 10}}}]}}));
+        assert_eq!(saved["agents"], serde_json::json!({"hits": {"hits": [{"_source": {"agent": {"name": "web-1"}}}, {"_source": {"agent": {"name": "web-1"}}}]}}));
+        assert_eq!(saved["raw"], serde_json::from_str::<serde_json::Value>(nested).unwrap());
+    }
+}
+
+#[tokio::test]
+async fn json_results_that_do_not_parse_are_saved_whole_instead_of_trimmed() {
+    let truncated = r#"{"hits":{"total":2,"hits":[{"_id":"a"}"#;
+    let conduit = MockConduit::start(move |request| Some(signed(reply(request, truncated)))).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+
+    for max_in_memory in [MAX_IN_MEMORY, 16] {
+        let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+        config.fields = Some("hits.total".parse().unwrap());
+        config.client.max_in_memory = max_in_memory;
+        let report = scan(config).await.unwrap();
+
+        let result = report.results().next().unwrap();
+        match &result.outcome {
+            QueryOutcome::Saved { path, .. } => assert_eq!(std::fs::read_to_string(path).unwrap(), truncated),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
+}
+
+#[tokio::test]
+async fn the_stream_yields_every_result_in_order_within_each_group() {
+    let conduit = MockConduit::start(|request| {
+        let mut response = reply(request, DATA);
+        if request.wql_query.contains("broken") {
+            response.status = false;
+            response.data = "index not found".to_string();
+        }
+        Some(signed(response))
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    write_query(dir.path(), "broken", r#"{"index":"broken"}"#);
+    let agents = vec![inventory_agent("001", "web"), inventory_agent("002", "db"), inventory_agent("003", "web")];
+
+    let mut stream = scan_stream(scan_config(dir.path(), &conduit.addr, agents));
+    let mut streamed = Vec::new();
+    while let Some(item) = stream.next().await {
+        let saved = matches!(item.result.outcome, QueryOutcome::Saved { .. });
+        streamed.push((item.group.name, item.result.agent.id, item.result.query, saved));
+    }
+    let report = stream.finish().await.unwrap();
+
+    let web: Vec<_> = streamed.iter().filter(|(group, ..)| group == "web").collect();
+    let expected = [("001", "alerts", true), ("001", "broken", false), ("003", "alerts", true), ("003", "broken", false)];
+    assert_eq!(web.len(), expected.len());
+    for ((_, agent, query, saved), (want_agent, want_query, want_saved)) in web.into_iter().zip(expected) {
+        assert_eq!((agent.as_str(), query.as_str(), *saved), (want_agent, want_query, want_saved));
+    }
+    assert_eq!(streamed.len(), 6);
+    assert_eq!((report.total(), report.succeeded()), (6, 3));
+}
+
+/// A conduit that rejects the first attempt at each query and answers the
+/// next ones after `retry_delay`.
+async fn failing_once(retry_delay: Duration) -> MockConduit {
+    let attempts: Arc<Mutex<HashMap<String, usize>>> = Arc::default();
+    let counted = attempts.clone();
+    let delay = move |request: &sensex_conduit::protocol::AuthRequest| {
+        let mut attempts = counted.lock().unwrap();
+        let attempt = attempts.entry(request.wql_query.clone()).or_default();
+        *attempt += 1;
+        if *attempt == 1 { Duration::ZERO } else { retry_delay }
+    };
+    MockConduit::start_delayed(delay, move |request| {
+        let mut response = reply(request, DATA);
+        if attempts.lock().unwrap()[&request.wql_query] == 1 {
+            response.status = false;
+            response.data = "shard unavailable".to_string();
+        }
+        Some(signed(response))
+    })
+    .await
+}
+
+#[tokio::test]
+async fn an_agent_in_two_groups_is_queried_once_with_unique_agents() {
+    let conduit = MockConduit::answering(DATA).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"agent":"{{agent_id}}"}"#);
+    let mut both = inventory_agent("001", "web");
+    both.groups.push("db".to_string());
+    let agents = vec![both, inventory_agent("002", "db")];
+
+    let mut config = scan_config(dir.path(), &conduit.addr, agents.clone());
+    config.groups = vec!["web".to_string(), "db".to_string()];
+    let report = scan(config).await.unwrap();
+    assert_eq!(report.total(), 3);
+
+    let mut config = scan_config(dir.path(), &conduit.addr, agents);
+    config.groups = vec!["web".to_string(), "db".to_string()];
+    config.unique_agents = true;
+    config.include_metadata = true;
+    let report = scan(config).await.unwrap();
+
+    let queried: Vec<(&str, &str)> =
+        report.groups.iter().flat_map(|g| g.queries.iter().map(|q| (g.group.name.as_str(), q.agent.id.as_str()))).collect();
+    assert_eq!(queried, [("web", "001"), ("db", "002")]);
+    assert_eq!(conduit.received(), 3 + 2);
+    let QueryOutcome::Saved { path, .. } = &report.groups[0].queries[0].outcome else {
+        panic!("{:?}", report.groups[0].queries[0].outcome);
+    };
+    let metadata: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(format!("{}.meta.json", path.display())).unwrap()).unwrap();
+    assert_eq!(metadata["agent_groups"], serde_json::json!(["web", "db"]));
+}
+
+#[tokio::test]
+async fn a_plan_counts_the_filtered_agents_and_queries_without_querying() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["alerts", "logons", "packages"] {
+        write_query(dir.path(), name, "{}");
+    }
+    let mut disconnected = inventory_agent("002", "web");
+    disconnected.status = Some("disconnected".to_string());
+    let agents = vec![inventory_agent("001", "web"), disconnected, inventory_agent("003", "db"), inventory_agent("004", "db")];
+
+    let mut config = scan_config(dir.path(), &closed_port(), agents);
+    config.queries = vec!["alerts".to_string(), "logons".to_string()];
+    config.statuses = vec![AgentStatus::Active];
+    config.group_concurrency = 2;
+    let plan = plan(&config).await.unwrap();
+
+    let groups: Vec<(&str, usize, usize)> = plan.groups.iter().map(|g| (g.group.name.as_str(), g.agents, g.queries)).collect();
+    assert_eq!(groups, [("web", 1, 2), ("db", 2, 4)]);
+    assert_eq!((plan.total_agents(), plan.total_queries(), plan.query_files), (3, 6, 2));
+    // The db group's four queries outlast web's two running beside them.
+    assert_eq!(plan.estimate(Duration::from_secs(1)), Duration::from_secs(4));
+    assert!(!dir.path().join("results").exists());
+}
+
+#[tokio::test]
+async fn retry_passes_run_groups_concurrently_and_keep_each_result_in_place() {
+    let conduit = failing_once(Duration::from_millis(400)).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"agent":"{{agent_id}}"}"#);
+    let agents = vec![inventory_agent("001", "web"), inventory_agent("002", "db"), inventory_agent("003", "app")];
+    let mut config = scan_config(dir.path(), &conduit.addr, agents);
+    config.group_concurrency = 3;
+    config.retry_passes = 1;
+
+    let started = Instant::now();
+    let report = scan(config).await.unwrap();
+    let elapsed = started.elapsed();
+
+    assert_eq!((report.total(), report.succeeded()), (3, 3));
+    let agents: Vec<(&str, &str)> = report
+        .groups
+        .iter()
+        .flat_map(|g| g.queries.iter().map(move |q| (g.group.name.as_str(), q.agent.id.as_str())))
+        .collect();
+    assert_eq!(agents, [("web", "001"), ("db", "002"), ("app", "003")]);
+    assert_eq!(conduit.received(), 6);
+    // Rerun one group at a time, the three retries would take 1200ms.
+    assert!(elapsed >= Duration::from_millis(400) && elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+}
+
+/// Saves each result with [`FileSink`], then reports the first save as
+/// failed a second later, as when a write lands but its sync errors.
+struct FailingAfterFirstSave {
+    failed: AtomicBool,
+}
+
+impl OutputSink for FailingAfterFirstSave {
+    fn write<'a>(&'a self, meta: &'a ResultMeta<'a>, bytes: &'a [u8]) -> LocalBoxFuture<'a, sensex_conduit::Result<()>> {
+        FileSink.write(meta, bytes)
+    }
+
+    fn write_file<'a>(&'a self, meta: &'a ResultMeta<'a>, file: &'a Path) -> LocalBoxFuture<'a, sensex_conduit::Result<()>> {
+        Box::pin(async move {
+            FileSink.write_file(meta, file).await?;
+            if self.failed.swap(true, Ordering::SeqCst) {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(1100)).await;
+            Err("Simulated failure after the result was written".into())
+        })
+    }
+
+    fn destination(&self, meta: &ResultMeta) -> String {
+        FileSink.destination(meta)
+    }
+}
+
+#[tokio::test]
+async fn a_query_retried_after_its_write_failed_leaves_one_result_file() {
+    let conduit = MockConduit::answering(DATA).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+    config.sink = Arc::new(FailingAfterFirstSave { failed: AtomicBool::new(false) });
+    config.retry_passes = 1;
+
+    let report = scan(config).await.unwrap();
+
+    assert_eq!(conduit.received(), 2);
+    let result = report.results().next().unwrap();
+    let QueryOutcome::Saved { path, .. } = &result.outcome else {
+        panic!("the retry did not save the result: {:?}", result.outcome);
+    };
+    let started = report.started_at.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    assert!(path.ends_with(format!("alerts_agent-001_{}.json", started)), "{}", path.display());
+    let files: Vec<_> = std::fs::read_dir(path.parent().unwrap()).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(files, [path.file_name().unwrap()]);
+}
+
+#[tokio::test]
+async fn only_the_newest_results_of_each_agent_and_query_are_retained() {
+    let conduit = MockConduit::answering(DATA).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    let web = dir.path().join("results").join("web");
+    std::fs::create_dir_all(&web).unwrap();
+    let now = SystemTime::now();
+    let earlier = |name: &str, hours_ago: u64| {
+        let file = std::fs::File::create(web.join(name)).unwrap();
+        file.set_modified(now - Duration::from_secs(hours_ago * 3600)).unwrap();
+    };
+    for (timestamp, hours_ago) in [(1000, 4), (2000, 3), (3000, 2), (4000, 1)] {
+        earlier(&format!("alerts_agent-001_{}.json", timestamp), hours_ago);
+    }
+    earlier("alerts_agent-001_1000.json.meta.json", 4);
+    earlier("alerts_agent-002_1000.json", 4);
+    earlier("alerts_agent-001_notes.json", 4);
+    let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+    config.retention.keep = Some(3);
+
+    let report = scan(config.clone()).await.unwrap();
+
+    let started = report.started_at.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut files: Vec<String> =
+        std::fs::read_dir(&web).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+    files.sort();
+    let saved = format!("alerts_agent-001_{}.json", started);
+    let mut expected = vec![
+        "alerts_agent-001_3000.json",
+        "alerts_agent-001_4000.json",
+        "alerts_agent-001_notes.json",
+        "alerts_agent-002_1000.json",
+        &saved,
+    ];
+    expected.sort();
+    assert_eq!(files, expected);
+
+    config.retention = Default::default();
+    config.retention.max_age = Some(Duration::from_secs(90 * 60));
+    scan(config).await.unwrap();
+    assert!(!web.join("alerts_agent-001_3000.json").exists());
+    assert!(web.join("alerts_agent-001_4000.json").exists());
+    assert!(web.join(&saved).exists());
+}
+
+#[tokio::test]
+async fn retry_passes_keep_to_the_agent_time_budget() {
+    let conduit = failing_once(Duration::from_secs(3)).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+    config.agent_timeout = Some(Duration::from_millis(300));
+    config.retry_passes = 1;
+
+    let started = Instant::now();
+    let report = scan(config).await.unwrap();
+
+    assert!(started.elapsed() < Duration::from_secs(2), "the retry ran for {:?}", started.elapsed());
+    let result = report.results().next().unwrap();
+    assert!(matches!(result.outcome, QueryOutcome::Error { .. }), "{:?}", result.outcome);
+}
+
+#[tokio::test]
+async fn an_agent_over_its_time_budget_has_its_remaining_queries_skipped() {
+    let slow = |_: &sensex_conduit::protocol::AuthRequest| Duration::from_millis(500);
+    let conduit = MockConduit::start_delayed(slow, |request| Some(signed(reply(request, DATA)))).await;
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["1_first", "2_second", "3_third"] {
+        write_query(dir.path(), name, &format!(r#"{{"name":"{}","agent":"{{{{agent_id}}}}"}}"#, name));
+    }
+    let agents = vec![inventory_agent("001", "web"), inventory_agent("002", "web")];
+    let mut config = scan_config(dir.path(), &conduit.addr, agents);
+    config.agent_timeout = Some(Duration::from_millis(800));
+
+    let report = scan(config).await.unwrap();
+
+    for agent in ["001", "002"] {
+        let outcomes: Vec<_> = report.results().filter(|r| r.agent.id == agent).map(|r| &r.outcome).collect();
+        assert!(matches!(outcomes[0], QueryOutcome::Saved { .. }), "{:?}", outcomes);
+        assert!(matches!(outcomes[1], QueryOutcome::Error { .. }), "{:?}", outcomes);
+        assert!(
+            matches!(outcomes[2], QueryOutcome::Skipped { reason } if reason == "agent time budget exhausted"),
+            "{:?}",
+            outcomes
+        );
+    }
+    // The mock records a request once it has answered it, abandoned or not.
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    let requests = conduit.requests.lock().unwrap();
+    assert_eq!(requests.len(), 4);
+    assert!(requests.iter().all(|r| !r.wql_query.contains("3_third")), "a skipped query was sent");
+}
+
+#[tokio::test]
+async fn no_query_starts_once_the_scan_deadline_has_passed() {
+    let conduit = MockConduit::start(|request| Some(signed(reply(request, DATA)))).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    write_query(dir.path(), "vulns", r#"{"query":{"match_all":{}}}"#);
+    let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+    config.deadline = Some(SystemTime::now() - Duration::from_secs(1));
+
+    let report = scan(config).await.unwrap();
+
+    assert!(report.deadline_reached());
+    let outcomes: Vec<_> = report.results().map(|r| &r.outcome).collect();
+    assert_eq!(outcomes.len(), 2);
+    assert!(
+        outcomes.iter().all(|o| matches!(o, QueryOutcome::Skipped { reason } if reason == DEADLINE_REACHED)),
+        "{:?}",
+        outcomes
+    );
+    assert!(conduit.requests.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn a_failed_warmup_skips_every_other_agent_without_querying_it() {
+    let conduit = MockConduit::start(|request| {
+        let mut response = reply(request, DATA);
+        if request.wql_query.contains("broken") {
+            response.status = false;
+            response.data = "parsing_exception".to_string();
+        }
+        Some(signed(response))
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"agent":"{{agent_id}}"}"#);
+    write_query(dir.path(), "broken", r#"{"agent":"{{agent_id}}","broken":true}"#);
+    let agents = vec![inventory_agent("001", "web"), inventory_agent("002", "web"), inventory_agent("003", "db")];
+    let mut config = scan_config(dir.path(), &conduit.addr, agents);
+    config.warmup = Some(Warmup { agent: Some("002".to_string()), continue_on_failure: false });
+
+    let report = scan(config).await.unwrap();
+
+    let queried: Vec<String> = conduit.requests.lock().unwrap().iter().map(|r| r.wql_query.clone()).collect();
+    assert_eq!(queried.len(), 2);
+    assert!(queried.iter().all(|query| query.contains("\"002\"")), "{:?}", queried);
+    let outcomes: Vec<(&str, &str, &str)> = report
+        .results()
+        .map(|r| {
+            let outcome = match &r.outcome {
+                QueryOutcome::Saved { .. } => "saved",
+                QueryOutcome::Rejected { .. } => "rejected",
+                QueryOutcome::Skipped { reason } if reason == WARMUP_FAILED => "skipped",
+                outcome => panic!("unexpected outcome: {:?}", outcome),
+            };
+            (r.agent.id.as_str(), r.query.as_str(), outcome)
+        })
+        .collect();
+    assert_eq!(
+        outcomes,
+        [
+            ("002", "alerts", "saved"),
+            ("002", "broken", "rejected"),
+            ("001", "alerts", "skipped"),
+            ("001", "broken", "skipped"),
+            ("003", "alerts", "skipped"),
+            ("003", "broken", "skipped"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn a_successful_warmup_goes_on_to_every_agent() {
+    let conduit = MockConduit::start(|request| Some(signed(reply(request, DATA)))).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"agent":"{{agent_id}}"}"#);
+    let agents = vec![inventory_agent("001", "web"), inventory_agent("002", "db")];
+    let mut config = scan_config(dir.path(), &conduit.addr, agents);
+    config.warmup = Some(Warmup::default());
+
+    let report = scan(config).await.unwrap();
+
+    assert_eq!((report.total(), report.succeeded()), (2, 2));
+    let ids: Vec<&str> = report.results().map(|r| r.agent.id.as_str()).collect();
+    assert_eq!(ids, ["001", "002"]);
+    assert_eq!(report.groups.len(), 2);
+}
+
+#[tokio::test]
+async fn cached_results_are_reused_only_for_the_same_rendered_query_and_format() {
+    let conduit = MockConduit::answering(DATA).await;
+    let dir = tempfile::tempdir().unwrap();
+    let run = |query: &'static str, format: ResultFormat| {
+        write_query(dir.path(), "alerts", query);
+        let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+        config.cache = Some(ResultCache { dir: dir.path().join("cache"), ttl: Duration::from_secs(3600), refresh: false });
+        config.client.format = format;
+        async move {
+            let report = scan(config).await.unwrap();
+            let cached = match &report.results().next().unwrap().outcome {
+                QueryOutcome::Saved { cached, .. } => *cached,
+                outcome => panic!("unexpected outcome: {:?}", outcome),
+            };
+            cached
+        }
+    };
+
+    assert!(!run(r#"{"query":{"match_all":{}}}"#, ResultFormat::Json).await);
+    assert!(run(r#"{"query":{"match_all":{}}}"#, ResultFormat::Json).await);
+    assert!(!run(r#"{"query":{"match":{"rule.level":12}}}"#, ResultFormat::Json).await, "an edited query reused the cache");
+    assert!(!run(r#"{"query":{"match_all":{}}}"#, ResultFormat::Raw).await, "another format reused the cache");
+    assert_eq!(conduit.received(), 3);
+}
+
+#[tokio::test]
+async fn an_agent_named_dot_dot_is_saved_and_cached_inside_their_directories() {
+    let conduit = MockConduit::answering(DATA).await;
+    let dir = tempfile::tempdir().unwrap();
+    let scanned = dir.path().join("scanned");
+    write_query(&scanned, "alerts", r#"{"query":{"match_all":{}}}"#);
+    let mut config = scan_config(&scanned, &conduit.addr, vec![inventory_agent("..", "web")]);
+    config.cache = Some(ResultCache { dir: scanned.join("cache"), ttl: Duration::from_secs(3600), refresh: false });
+
+    let report = scan(config).await.unwrap();
+
+    assert_eq!(report.succeeded(), 1);
+    fn files(dir: &Path, found: &mut Vec<std::path::PathBuf>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            match path.is_dir() {
+                true => files(&path, found),
+                false => found.push(path),
+            }
+        }
+    }
+    let mut found = Vec::new();
+    files(dir.path(), &mut found);
+    let outside: Vec<_> = found
+        .iter()
+        .filter(|path| !["queries", "results", "cache", "session.json"].iter().any(|sub| path.starts_with(scanned.join(sub))))
+        .collect();
+    assert!(outside.is_empty(), "{:?}", outside);
+    assert!(found.iter().any(|path| path.starts_with(scanned.join("cache"))), "{:?}", found);
+}
+
+#[tokio::test]
+async fn a_result_cut_short_while_spooled_is_never_saved() {
+    let conduit = MockConduit::start(|request| {
+        let wire = signed(reply(request, &"x".repeat(4096)));
+        Some(wire[..2000].to_vec())
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+    config.client.max_in_memory = 64;
+    config.client.spool_checkpoint = 512;
+
+    let report = scan(config).await.unwrap();
+
+    let result = report.results().next().unwrap();
+    assert!(matches!(result.outcome, QueryOutcome::Error { .. }), "{:?}", result.outcome);
+    let left: Vec<_> =
+        std::fs::read_dir(dir.path().join("results/web")).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert!(left.is_empty(), "{:?}", left);
+}
+
+#[tokio::test]
+async fn a_partial_result_left_by_an_earlier_run_is_discarded_and_fetched_again() {
+    let conduit = MockConduit::answering(DATA).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    let leftover = dir.path().join("results/web/.001_alerts.partial");
+    std::fs::create_dir_all(leftover.parent().unwrap()).unwrap();
+    std::fs::write(&leftover, "half a res").unwrap();
+
+    let report = scan(scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")])).await.unwrap();
+
+    let QueryOutcome::Saved { path, .. } = &report.results().next().unwrap().outcome else {
+        panic!("{:?}", report.results().next().unwrap().outcome);
+    };
+    assert_eq!(std::fs::read_to_string(path).unwrap(), DATA);
+    assert!(!leftover.exists());
+    assert_eq!(conduit.received(), 1);
+}
+
+#[tokio::test]
+async fn a_metadata_sidecar_records_where_each_result_came_from() {
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let responses = sent.clone();
+    let conduit = MockConduit::start(move |request| {
+        let wire = signed(reply(request, DATA));
+        responses.lock().unwrap().push(serde_json::from_slice::<Response>(&wire).unwrap());
+        Some(wire)
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"term":{"agent.id":"{{agent_id}}"}}}"#);
+    let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+    config.include_metadata = true;
+
+    let report = scan(config).await.unwrap();
+
+    let QueryOutcome::Saved { path, .. } = &report.results().next().unwrap().outcome else {
+        panic!("{:?}", report.results().next().unwrap().outcome);
+    };
+    let sidecar = std::path::PathBuf::from(format!("{}.meta.json", path.display()));
+    let metadata: serde_json::Value = serde_json::from_slice(&std::fs::read(&sidecar).unwrap()).unwrap();
+    let response = sent.lock().unwrap()[0].clone();
+    assert_eq!(metadata["server"], conduit.addr);
+    assert_eq!(metadata["group"], "web");
+    assert_eq!((&metadata["agent_id"], &metadata["agent_name"]), (&"001".into(), &"agent-001".into()));
+    assert_eq!(metadata["query"], "alerts");
+    assert_eq!(metadata["query_hash"], query_hash(r#"{"query":{"term":{"agent.id":"001"}}}"#));
+    assert_eq!(metadata["session_id"], response.session_id);
+    assert_eq!(metadata["request_id"], response.request_id.unwrap());
+    assert_eq!(metadata["timestamp"], response.timestamp);
+    assert_eq!(metadata["signature"], response.signature);
+    assert_eq!(metadata["signature_algorithm"], "sha256");
+    assert_eq!((&metadata["signature_encoding"], &metadata["verifiable"]), (&"base64".into(), &true.into()));
+    assert_eq!(metadata["client_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(metadata["encrypted"], false);
+}
+
+#[tokio::test]
+async fn results_are_compressed_before_they_are_encrypted() {
+    let data = format!("[{}]", vec![DATA; 100].join(","));
+    let answer = data.clone();
+    let conduit = MockConduit::start(move |request| Some(signed(reply(request, &answer)))).await;
+    let cipher = OutputCipher::from_passphrase("correct horse").unwrap();
+    // Small results are compressed in memory, spooled ones as a file.
+    for (compression, max_in_memory) in [(Compression::Gzip, MAX_IN_MEMORY), (Compression::Zstd, 64)] {
+        let dir = tempfile::tempdir().unwrap();
+        write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+        let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+        config.compression = compression;
+        config.output_cipher = Some(cipher.clone());
+        config.client.max_in_memory = max_in_memory;
+
+        let report = scan(config).await.unwrap();
+
+        let result = report.results().next().unwrap();
+        let QueryOutcome::Saved { path, stored_bytes, .. } = &result.outcome else {
+            panic!("{:?}", result.outcome);
+        };
+        let ext = compression.extension().unwrap();
+        assert!(path.to_string_lossy().ends_with(&format!(".json.{}.enc", ext)), "{}", path.display());
+        let saved = std::fs::read(path).unwrap();
+        assert_eq!((result.bytes, *stored_bytes), (data.len() as u64, saved.len() as u64));
+        assert!(*stored_bytes < result.bytes, "{:?} did not shrink the result", compression);
+        let decrypted = cipher.decrypt(&saved).unwrap();
+        assert_eq!(compression.decompress(&decrypted).unwrap(), data.as_bytes());
+        let leftovers: Vec<_> = std::fs::read_dir(path.parent().unwrap()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(leftovers, [path.file_name().unwrap()]);
+    }
+}
@@ -0,0 +1,39 @@
+//! Conduit sessions across scans: reused while the server accepts them and
+//! replaced once it reports them expired.
+
+mod common;
+
+use common::{inventory_agent, reply, scan_config, signed, write_query, MockConduit};
+use sensex_conduit::protocol::{Response, SESSION_EXPIRED};
+use sensex_conduit::scan;
+
+const ISSUED: &str = "7c9e6679-7425-40de-944b-e07fc1f90ae7";
+
+#[tokio::test]
+async fn an_expired_session_is_replaced_and_the_query_retried() {
+    // The server forgets every session after the first scan.
+    let conduit = MockConduit::start(|request| match &request.session_id {
+        Some(_) => Some(signed(Response {
+            status: false,
+            data: "Session expired".to_string(),
+            session_id: String::new(),
+            error_code: Some(SESSION_EXPIRED.to_string()),
+            ..reply(request, "")
+        })),
+        None => Some(signed(Response { session_id: ISSUED.to_string(), ..reply(request, r#"{"hits":{"hits":[]}}"#) })),
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    let config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web")]);
+
+    let first = scan(config.clone()).await.unwrap();
+    assert_eq!(first.succeeded(), 1);
+    assert!(config.client.session_file.exists());
+
+    let second = scan(config).await.unwrap();
+    assert_eq!(second.succeeded(), 1);
+
+    let sessions: Vec<Option<String>> = conduit.requests.lock().unwrap().iter().map(|r| r.session_id.clone()).collect();
+    assert_eq!(sessions, [None, Some(ISSUED.to_string()), None]);
+}
@@ -0,0 +1,71 @@
+//! The `sign-debug` subcommand, run as the `client` binary: its output for
+//! fixed inputs is what the client itself would sign and send.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{client_command, SERVER_KEY};
+use sensex_conduit::protocol::{signing_payload, AuthRequest, ResultFormat, SIGNATURE_SCHEME_V2};
+use sensex_conduit::signing::{SignatureAlgorithm, SignatureEncoding};
+use std::process::Output;
+
+const NONCE: &str = "5f0c6a3e-8d1b-4c2a-9e7f-0a1b2c3d4e5f";
+const SESSION_ID: &str = "1b4e28ba-2fa1-11d2-883f-0016d3cca427";
+
+async fn sign_debug(args: &[&str]) -> Output {
+    let dir = tempfile::tempdir().unwrap();
+    client_command(dir.path()).arg("sign-debug").args(args).output().await.unwrap()
+}
+
+#[tokio::test]
+async fn fixed_inputs_give_the_payload_and_signature_the_client_sends() {
+    let args = ["--timestamp", "1700000000", "--nonce", NONCE, "--session-id", SESSION_ID, "--query", "SELECT 1"];
+    for sign_query in [false, true] {
+        let mut args = args.to_vec();
+        args.extend(sign_query.then_some("--sign-query"));
+        let output = sign_debug(&args).await;
+        assert!(output.status.success(), "sign-debug failed:\n{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(sign_debug(&args).await.stdout, output.stdout, "output differs between runs");
+
+        let request = AuthRequest {
+            client_id: "client1".to_string(),
+            timestamp: 1_700_000_000,
+            nonce: NONCE.to_string(),
+            signature: String::new(),
+            session_id: Some(SESSION_ID.to_string()),
+            wql_query: "SELECT 1".to_string(),
+            request_id: String::new(),
+            signature_scheme: sign_query.then(|| SIGNATURE_SCHEME_V2.to_string()),
+            signature_algorithm: None,
+            signature_encoding: None,
+            format: ResultFormat::default(),
+            encoding: None,
+        };
+        let payload = signing_payload(&request).unwrap();
+        let signature = SignatureAlgorithm::Sha256.signer().sign(payload.as_bytes(), b"test_key_1", SignatureEncoding::Base64);
+        let expected = format!(
+            "algorithm: sha256\nscheme: {}\ndata_to_sign: {}\nsignature: {}\n",
+            if sign_query { "v2" } else { "v1" },
+            payload,
+            signature
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    }
+}
+
+#[tokio::test]
+async fn a_response_signature_is_checked_against_the_server_key() {
+    let data = r#"{"hits":{"hits":[]}}"#;
+    let signature = SignatureAlgorithm::Sha256.signer().sign(data.as_bytes(), SERVER_KEY.as_bytes(), SignatureEncoding::Base64);
+
+    let output = sign_debug(&["--verify-data", data, "--signature", &signature]).await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains(&format!("expected signature: {}\n", signature)), "{}", stdout);
+    assert!(stdout.ends_with("signature: valid\n"), "{}", stdout);
+
+    let output = sign_debug(&["--verify-data", data, "--signature", &signature, "--server-key", "other"]).await;
+    assert!(!output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("signature: valid"));
+}
@@ -0,0 +1,130 @@
+//! Results delivered through an [`HttpSink`] instead of written to files.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{inventory_agent, scan_config, write_query, MockConduit};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use sensex_conduit::retry::RetryPolicy;
+use sensex_conduit::sink::{CombinedSink, HttpSink};
+use sensex_conduit::{scan, QueryOutcome};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DATA: &str = r#"{"hits":{"hits":[]}}"#;
+
+/// The `x-conduit-*` headers and body of one POST.
+type Post = (HashMap<String, String>, Vec<u8>);
+
+/// An ingestion endpoint recording every POST, answering the first with 503.
+async fn ingestion_endpoint() -> (String, Arc<Mutex<Vec<Post>>>) {
+    let posts = Arc::new(Mutex::new(Vec::new()));
+    let recorded = posts.clone();
+    let make_service = make_service_fn(move |_| {
+        let recorded = recorded.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let recorded = recorded.clone();
+                async move {
+                    let headers = request
+                        .headers()
+                        .iter()
+                        .filter(|(name, _)| name.as_str().starts_with("x-conduit-") || *name == hyper::header::CONTENT_TYPE)
+                        .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+                        .collect();
+                    let body = hyper::body::to_bytes(request.into_body()).await.unwrap().to_vec();
+                    let mut posts = recorded.lock().unwrap();
+                    posts.push((headers, body));
+                    let status = if posts.len() == 1 { 503 } else { 200 };
+                    Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let url = format!("http://{}/ingest", server.local_addr());
+    tokio::spawn(server);
+    (url, posts)
+}
+
+#[tokio::test]
+async fn each_result_is_posted_with_its_metadata_in_headers() {
+    let conduit = MockConduit::answering(DATA).await;
+    let (url, posts) = ingestion_endpoint().await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    let mut config = scan_config(dir.path(), &conduit.addr, vec![inventory_agent("001", "web"), inventory_agent("002", "db")]);
+    let retry = RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1) };
+    config.sink = Arc::new(HttpSink::new(url, reqwest::Client::new(), retry));
+
+    let report = scan(config).await.unwrap();
+
+    assert_eq!(report.succeeded(), 2);
+    let posts = posts.lock().unwrap();
+    // The first POST was answered 503 and retried.
+    assert_eq!(posts.len(), 3);
+    assert_eq!(posts[0], posts[1]);
+    for ((headers, body), agent) in posts[1..].iter().zip([("001", "web"), ("002", "db")]) {
+        assert_eq!(body, DATA.as_bytes());
+        let header = |name: &str| headers.get(name).map(String::as_str);
+        assert_eq!(header("content-type"), Some("application/json"));
+        assert_eq!(header("x-conduit-agent-id"), Some(agent.0));
+        assert_eq!(header("x-conduit-agent-name"), Some(format!("agent-{}", agent.0).as_str()));
+        assert_eq!(header("x-conduit-group"), Some(agent.1));
+        assert_eq!(header("x-conduit-query"), Some("alerts"));
+        assert_eq!(header("x-conduit-format"), Some("json"));
+        assert_eq!(header("x-conduit-encrypted"), Some("false"));
+        assert_eq!(header("x-conduit-manager"), None);
+    }
+    for result in report.results() {
+        let QueryOutcome::Saved { path, .. } = &result.outcome else {
+            panic!("{:?}", result.outcome);
+        };
+        assert!(!path.exists(), "{} was written locally", path.display());
+    }
+}
+
+#[tokio::test]
+async fn results_are_gathered_into_one_json_array() {
+    let conduit = MockConduit::answering(DATA).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    write_query(dir.path(), "vulns", r#"{"query":{"match_all":{}}}"#);
+    let agents = vec![inventory_agent("001", "web"), inventory_agent("002", "db"), inventory_agent("003", "web")];
+    let mut config = scan_config(dir.path(), &conduit.addr, agents);
+    config.group_concurrency = 2;
+    let combined = dir.path().join("combined.json");
+    config.sink = Arc::new(CombinedSink::new(&combined));
+
+    let report = scan(config).await.unwrap();
+
+    assert_eq!(report.succeeded(), 6);
+    let elements: Vec<serde_json::Value> = serde_json::from_slice(&std::fs::read(&combined).unwrap()).unwrap();
+    assert_eq!(elements.len(), 6);
+    let mut seen: Vec<(&str, &str, &str)> = elements
+        .iter()
+        .map(|element| {
+            assert_eq!(element["result"], serde_json::json!({"hits":{"hits":[]}}));
+            let field = |name: &str| element[name].as_str().unwrap();
+            (field("agent_id"), field("group"), field("query"))
+        })
+        .collect();
+    seen.sort_unstable();
+    assert_eq!(
+        seen,
+        [
+            ("001", "web", "alerts"),
+            ("001", "web", "vulns"),
+            ("002", "db", "alerts"),
+            ("002", "db", "vulns"),
+            ("003", "web", "alerts"),
+            ("003", "web", "vulns"),
+        ]
+    );
+    assert!(!dir.path().join(".combined.json.partial").exists());
+}
@@ -0,0 +1,141 @@
+//! Certificate verification against the loopback conduit, whose certificate
+//! is issued by the test CA in `tests/fixtures`.
+
+mod common;
+
+use common::{closed_port, fixture, MockConduit};
+use sensex_conduit::retry::RetryPolicy;
+use sensex_conduit::tls::{connect_with_retry, HandshakeDetails, TlsConfig, TlsOptions, TlsVersion};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+const ONCE: RetryPolicy = RetryPolicy { max_attempts: 1, base_delay: std::time::Duration::ZERO };
+
+#[tokio::test]
+async fn a_certificate_from_an_unknown_ca_is_rejected_by_default() {
+    let conduit = MockConduit::answering("{}").await;
+    let tls = TlsConfig::new(&TlsOptions::default()).unwrap();
+
+    let error = connect_with_retry(&conduit.addr, &tls, ONCE).await.err().unwrap();
+    assert!(error.to_string().contains("TLS handshake failed"), "{}", error);
+    assert!(error.to_string().contains("--cacert"), "{}", error);
+}
+
+#[tokio::test]
+async fn insecure_mode_accepts_any_certificate() {
+    let conduit = MockConduit::answering("{}").await;
+    let tls = TlsConfig::new(&TlsOptions { insecure: true, ..Default::default() }).unwrap();
+
+    connect_with_retry(&conduit.addr, &tls, ONCE).await.unwrap();
+}
+
+#[tokio::test]
+async fn a_certificate_from_a_trusted_private_ca_is_accepted() {
+    let conduit = MockConduit::answering("{}").await;
+    let tls = TlsConfig::new(&TlsOptions { ca_certs: vec![fixture("ca.pem")], ..Default::default() }).unwrap();
+    assert!(tls.verifies_certificates());
+
+    connect_with_retry(&conduit.addr, &tls, ONCE).await.unwrap();
+}
+
+#[tokio::test]
+async fn the_peer_certificate_is_described_after_a_handshake() {
+    let conduit = MockConduit::answering("{}").await;
+    let options = TlsOptions { ca_certs: vec![fixture("ca.pem")], log_handshake: true, ..Default::default() };
+    let stream = connect_with_retry(&conduit.addr, &TlsConfig::new(&options).unwrap(), ONCE).await.unwrap();
+
+    let details = HandshakeDetails::of(&stream);
+    assert_eq!(details.peer_subject.as_deref(), Some("CN=localhost"));
+    assert!(details.peer_not_after.as_deref().is_some_and(|t| t.contains("2126")), "{:?}", details);
+    assert_eq!(details.peer_fingerprint.as_deref().map(str::len), Some(32 * 3 - 1));
+    let logged = details.to_string();
+    assert!(logged.contains("subject CN=localhost") && logged.contains("expires"), "{}", logged);
+}
+
+#[test]
+fn a_malformed_ca_certificate_is_rejected_up_front() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ca.pem");
+    std::fs::write(&path, "-----BEGIN CERTIFICATE-----\nnot base64\n-----END CERTIFICATE-----\n").unwrap();
+
+    let error = TlsConfig::new(&TlsOptions { ca_certs: vec![path], ..Default::default() }).err().unwrap();
+    assert!(error.to_string().starts_with("Invalid PEM CA certificate"), "{}", error);
+}
+
+#[test]
+fn a_missing_ca_certificate_is_rejected_up_front() {
+    let options = TlsOptions { ca_certs: vec![fixture("missing.pem")], ..Default::default() };
+    let error = TlsConfig::new(&options).err().unwrap();
+    assert!(error.to_string().starts_with("Failed to read CA certificate"), "{}", error);
+}
+
+/// A server that completes TLS handshakes up to TLS 1.2 and then hangs up.
+async fn tls12_server() -> String {
+    let cert = std::fs::read(fixture("server.pem")).unwrap();
+    let key = std::fs::read(fixture("server.key")).unwrap();
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key).unwrap();
+    let acceptor = native_tls::TlsAcceptor::builder(identity)
+        .max_protocol_version(Some(native_tls::Protocol::Tlsv12))
+        .build()
+        .unwrap();
+    let acceptor = tokio_native_tls::TlsAcceptor::from(acceptor);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let _ = acceptor.accept(stream).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn a_server_below_the_minimum_version_fails_the_handshake() {
+    let addr = tls12_server().await;
+    let options = TlsOptions { ca_certs: vec![fixture("ca.pem")], ..Default::default() };
+
+    connect_with_retry(&addr, &TlsConfig::new(&options).unwrap(), ONCE).await.unwrap();
+
+    let tls13 = TlsConfig::new(&TlsOptions { min_version: TlsVersion::Tls13, ..options }).unwrap();
+    let error = connect_with_retry(&addr, &tls13, ONCE).await.err().unwrap();
+    assert!(error.to_string().contains("TLS handshake failed"), "{}", error);
+}
+
+#[tokio::test]
+async fn a_certificate_for_another_name_is_rejected() {
+    let conduit = MockConduit::answering("{}").await;
+    let options = TlsOptions {
+        ca_certs: vec![fixture("ca.pem")],
+        server_name: Some("conduit.internal".to_string()),
+        ..Default::default()
+    };
+
+    let error = connect_with_retry(&conduit.addr, &TlsConfig::new(&options).unwrap(), ONCE).await.err().unwrap();
+    assert!(error.to_string().contains("TLS handshake failed"), "{}", error);
+}
+
+#[tokio::test]
+async fn a_name_that_does_not_exist_fails_without_retrying() {
+    let tls = TlsConfig::new(&TlsOptions::default()).unwrap();
+    let retry = RetryPolicy { max_attempts: 3, base_delay: Duration::from_secs(2) };
+
+    let started = Instant::now();
+    let error = connect_with_retry("conduit.invalid:8443", &tls, retry).await.err().unwrap();
+    assert!(error.to_string().starts_with("Failed to resolve conduit.invalid:8443"), "{}", error);
+    assert!(started.elapsed() < retry.base_delay, "waited {:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn a_refused_connection_is_retried() {
+    let tls = TlsConfig::new(&TlsOptions::default()).unwrap();
+    let retry = RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(100) };
+
+    let started = Instant::now();
+    let error = connect_with_retry(&closed_port(), &tls, retry).await.err().unwrap();
+    assert!(error.to_string().starts_with("Failed to connect after 3 retries"), "{}", error);
+    // 100ms after the first attempt and 200ms after the second.
+    assert!(started.elapsed() >= Duration::from_millis(300), "waited {:?}", started.elapsed());
+}
@@ -0,0 +1,74 @@
+//! Renewing the Wazuh token ahead of its `exp` claim, against the loopback
+//! gateway.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use common::{agent, manager, scan_config, write_query, MockConduit, MockGateway, WAZUH_TOKEN};
+use sensex_conduit::{scan, Client, GatewayConfig};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An unsigned JWT expiring `seconds` from now.
+fn token_expiring_in(seconds: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let part = |json: String| URL_SAFE_NO_PAD.encode(json);
+    format!("{}.{}.sig", part(r#"{"alg":"HS256"}"#.to_string()), part(format!(r#"{{"exp":{}}}"#, now + seconds)))
+}
+
+async fn groups_with_token(token: String) -> (Option<String>, Vec<String>) {
+    let gateway = MockGateway::start(vec![agent("001", "web-1", &["web"])]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let config = scan_config(dir.path(), "127.0.0.1:1", Vec::new());
+    let mut client = Client::new(config.client, config.retry).with_gateway(gateway.url.clone(), "https://wazuh.test:55000".to_string());
+    client.set_wazuh_token(token);
+    client.set_wazuh_credentials("wazuh", "secret");
+    client.set_token_refresh_buffer(Duration::from_secs(60));
+
+    let groups = client.fetch_groups().await.unwrap();
+    assert_eq!(groups.len(), 1);
+    let calls = gateway.calls.lock().unwrap().clone();
+    (client.wazuh_token().map(str::to_string), calls)
+}
+
+#[tokio::test]
+async fn a_token_near_expiry_is_renewed_before_the_next_call() {
+    let (token, calls) = groups_with_token(token_expiring_in(30)).await;
+    assert_eq!(calls, ["/auth", "/groups"]);
+    assert_eq!(token.as_deref(), Some(WAZUH_TOKEN));
+}
+
+#[tokio::test]
+async fn a_token_far_from_expiry_is_kept() {
+    let fresh = token_expiring_in(3600);
+    let (token, calls) = groups_with_token(fresh.clone()).await;
+    assert_eq!(calls, ["/groups"]);
+    assert_eq!(token, Some(fresh));
+}
+
+#[tokio::test]
+async fn a_supplied_token_is_used_without_signing_in() {
+    let gateway = MockGateway::start(vec![agent("001", "web-1", &["web"])]).await;
+    let conduit = MockConduit::answering(r#"{"hits":{"hits":[]}}"#).await;
+    let dir = tempfile::tempdir().unwrap();
+    write_query(dir.path(), "alerts", r#"{"query":{"match_all":{}}}"#);
+    let mut config = scan_config(dir.path(), &conduit.addr, Vec::new());
+    config.inventory = None;
+    let token = token_expiring_in(3600);
+    config.managers = vec![GatewayConfig {
+        name: None,
+        username: String::new(),
+        password: String::new(),
+        token: Some(token.clone()),
+        ..manager("eu", &gateway.url)
+    }];
+
+    let report = scan(config).await.unwrap();
+
+    assert_eq!(report.succeeded(), 1);
+    let calls = gateway.calls.lock().unwrap().clone();
+    assert!(!calls.iter().any(|call| call == "/auth"), "{:?}", calls);
+    assert_eq!(report.wazuh_tokens.values().collect::<Vec<_>>(), [&token]);
+}
@@ -0,0 +1,60 @@
+//! The `topology` subcommand, run as the `client` binary against the
+//! loopback gateway.
+
+#![cfg(feature = "gateway")]
+
+mod common;
+
+use common::{agent, client_command, MockGateway};
+use sensex_conduit::inventory::load_agents_file;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::process::Output;
+
+async fn topology(dir: &Path, gateway_url: &str, args: &[&str]) -> Output {
+    client_command(dir)
+        .args(["topology", "--gateway-url", gateway_url])
+        .args(["--wazuh-url", "https://wazuh.test:55000", "--wazuh-username", "wazuh", "--wazuh-password", "secret"])
+        .args(args)
+        .output()
+        .await
+        .unwrap()
+}
+
+fn fleet() -> Vec<Value> {
+    vec![agent("001", "web-1", &["web"]), agent("002", "db-1", &["db", "web"])]
+}
+
+#[tokio::test]
+async fn the_json_topology_nests_each_groups_agents() {
+    let gateway = MockGateway::start(fleet()).await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = topology(dir.path(), &gateway.url, &[]).await;
+    assert!(output.status.success(), "topology failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    let topology: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let names =
+        |group: &Value| group["agents"].as_array().unwrap().iter().map(|a| a["name"].clone()).collect::<Vec<_>>();
+    let groups = topology.as_array().unwrap();
+    assert_eq!(groups.iter().map(|g| g["group"].clone()).collect::<Vec<_>>(), [json!("db"), json!("web")]);
+    assert_eq!(names(&groups[0]), [json!("db-1")]);
+    assert_eq!(names(&groups[1]), [json!("web-1"), json!("db-1")]);
+    assert_eq!(groups[1]["agents"][0]["status"], "active");
+    assert_eq!(groups[1]["agents"][0]["platform"], "ubuntu");
+    assert_eq!(*gateway.calls.lock().unwrap(), ["/auth", "/groups", "/groups/db/agents", "/groups/web/agents"]);
+}
+
+#[tokio::test]
+async fn the_csv_topology_loads_as_an_agents_file() {
+    let gateway = MockGateway::start(fleet()).await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = topology(dir.path(), &gateway.url, &["--format", "csv", "--output", "agents.csv"]).await;
+    assert!(output.status.success(), "topology failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    let agents = load_agents_file(&dir.path().join("agents.csv")).unwrap();
+    let rows: Vec<_> = agents.iter().map(|a| (a.id.as_str(), a.name.as_str(), a.groups.join(";"))).collect();
+    assert_eq!(rows, [("002", "db-1", "db;web".to_string()), ("001", "web-1", "web".to_string())]);
+    assert_eq!(agents[0].status.as_deref(), Some("active"));
+}
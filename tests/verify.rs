@@ -0,0 +1,111 @@
+//! Offline checks of archived results against their recorded signatures.
+
+mod common;
+
+use common::{inventory_agent, scan_config, write_query, MockConduit, SERVER_KEY};
+#[cfg(feature = "gateway")]
+use sensex_conduit::compression::Compression;
+use sensex_conduit::signing::InvalidSignature;
+use sensex_conduit::{scan, verify_saved_result, QueryOutcome, SavedSignature, ScanConfig};
+use std::path::{Path, PathBuf};
+
+const DATA: &str = r#"{"hits":{"hits":[{"_source":{"rule":{"level":3}}}]}}"#;
+
+/// Scans one agent with `--include-metadata` and returns the saved result.
+async fn archived_result(dir: &Path, configure: impl FnOnce(&mut ScanConfig)) -> PathBuf {
+    let conduit = MockConduit::answering(DATA).await;
+    write_query(dir, "alerts", r#"{"query":{"match_all":{}}}"#);
+    let mut config = scan_config(dir, &conduit.addr, vec![inventory_agent("001", "web")]);
+    config.include_metadata = true;
+    configure(&mut config);
+    let report = scan(config).await.unwrap();
+    let saved = match &report.results().next().unwrap().outcome {
+        QueryOutcome::Saved { path, .. } => path.clone(),
+        outcome => panic!("{:?}", outcome),
+    };
+    saved
+}
+
+#[tokio::test]
+async fn an_archived_result_verifies_until_it_is_tampered_with() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = archived_result(dir.path(), |_| {}).await;
+    let saved = SavedSignature::load(&path).unwrap();
+    assert!(saved.verifiable);
+
+    let data = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(verify_saved_result(&data, &saved, SERVER_KEY), Ok(()));
+    let tampered = data.replace("\"level\":3", "\"level\":1");
+    assert_eq!(verify_saved_result(&tampered, &saved, SERVER_KEY), Err(InvalidSignature::Mismatch));
+    assert_eq!(verify_saved_result(&data, &saved, "other key"), Err(InvalidSignature::Mismatch));
+}
+
+#[cfg(feature = "gateway")]
+#[tokio::test]
+async fn the_verify_subcommand_checks_each_file_against_its_sidecar() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = archived_result(dir.path(), |config| config.compression = Compression::Gzip).await;
+    let verify = |files: Vec<&Path>| {
+        let mut command = common::client_command(dir.path());
+        command.arg("verify").args(files).args(["--server-key", SERVER_KEY]);
+        async move { command.output().await.unwrap() }
+    };
+
+    let output = verify(vec![&path]).await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout, format!("{}: signature valid\n", path.display()));
+
+    let tampered = path.with_file_name("tampered.json.gz");
+    let data = DATA.replace("\"level\":3", "\"level\":1");
+    std::fs::write(&tampered, Compression::Gzip.compress(data.as_bytes()).unwrap()).unwrap();
+    std::fs::copy(format!("{}.meta.json", path.display()), format!("{}.meta.json", tampered.display())).unwrap();
+
+    let output = verify(vec![&path, &tampered]).await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(stdout.contains(&format!("{}: signature valid\n", path.display())), "{}", stdout);
+    assert!(stdout.contains(&format!("{}: Invalid response signature\n", tampered.display())), "{}", stdout);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 of 2 results failed verification"));
+}
+
+#[cfg(feature = "gateway")]
+#[tokio::test]
+async fn verify_dir_tallies_every_archived_result_and_lists_the_failures() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = archived_result(dir.path(), |_| {}).await;
+    let results = path.parent().unwrap();
+    let sidecar = |file: &Path| format!("{}.meta.json", file.display());
+    let nested = results.join("older");
+    std::fs::create_dir(&nested).unwrap();
+    let copy = nested.join("copy.json");
+    std::fs::copy(&path, &copy).unwrap();
+    std::fs::copy(sidecar(&path), sidecar(&copy)).unwrap();
+    let tampered = nested.join("tampered.json");
+    std::fs::write(&tampered, DATA.replace("\"level\":3", "\"level\":1")).unwrap();
+    std::fs::copy(sidecar(&path), sidecar(&tampered)).unwrap();
+    let missing = results.join("missing.json");
+    std::fs::copy(sidecar(&path), sidecar(&missing)).unwrap();
+    std::fs::write(results.join("notes.txt"), "no sidecar").unwrap();
+
+    let mut command = common::client_command(dir.path());
+    command.arg("verify-dir").arg(results).args(["--server-key", SERVER_KEY]);
+    let output = command.output().await.unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!output.status.success(), "{}", stdout);
+    assert!(stdout.starts_with("2 passed, 2 failed\n"), "{}", stdout);
+    let failures: Vec<&str> = stdout.lines().filter(|line| line.starts_with("FAIL")).collect();
+    assert_eq!(failures.len(), 2, "{}", stdout);
+    assert!(failures[0].starts_with(&format!("FAIL  {}: Failed to read", missing.display())), "{}", stdout);
+    assert_eq!(failures[1], format!("FAIL  {}: Invalid response signature", tampered.display()));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("2 of 4 results failed verification"));
+
+    std::fs::remove_file(sidecar(&missing)).unwrap();
+    std::fs::remove_file(sidecar(&tampered)).unwrap();
+    let mut command = common::client_command(dir.path());
+    command.arg("verify-dir").arg(results).args(["--server-key", SERVER_KEY]);
+    let output = command.output().await.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "2 passed, 0 failed\n");
+}